@@ -1,7 +1,7 @@
 use firepilot::{
     builder::{
-        drive::DriveBuilder, executor::FirecrackerExecutorBuilder, kernel::KernelBuilder, Builder,
-        Configuration,
+        drive::DriveBuilder, executor::FirecrackerExecutorBuilder, kernel::KernelBuilder,
+        machine_config::MachineConfigurationBuilder, Builder, Configuration,
     },
     machine::Machine,
 };
@@ -73,11 +73,12 @@ async fn fetch_url(url: hyper::Uri, target_path: PathBuf) {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
-    let path = Path::new("examples/resources");
+    // Artifacts are architecture specific, keep one cache entry per architecture
+    let path = Path::new("examples/resources").join(std::env::consts::ARCH);
     let kernel_path = path.join("kernel.bin");
     let rootfs_path = path.join("rootfs.ext4");
     // Download the kernel and rootfs in a temporary directory
-    std::fs::create_dir_all(path).unwrap();
+    std::fs::create_dir_all(&path).unwrap();
     fetch_url(rootfs_url(), rootfs_path.clone()).await;
     fetch_url(kernel_url(), kernel_path.clone()).await;
 
@@ -98,11 +99,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
         .try_build()
         .unwrap();
+    let machine_config = MachineConfigurationBuilder::new()
+        .with_vcpu_count(1)
+        .with_mem_size_mib(1024)
+        .try_build()
+        .unwrap();
     let config = Configuration::new("simple_vm".to_string())
         .with_kernel(kernel)
-        .with_executor(executor)
-        .with_drive(drive);
-    let mut machine = Machine::new();
+        .with_drive(drive)
+        .with_machine_config(machine_config);
+    let mut machine = Machine::new(executor)?;
     machine.create(config).await.unwrap();
 
     info!("Boot micro vm");
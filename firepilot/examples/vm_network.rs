@@ -74,11 +74,12 @@ async fn fetch_url(url: hyper::Uri, target_path: PathBuf) {
 /// This test needs to be running as root
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let path = Path::new("examples/resources");
+    // Artifacts are architecture specific, keep one cache entry per architecture
+    let path = Path::new("examples/resources").join(std::env::consts::ARCH);
     let kernel_path = path.join("kernel.bin");
     let rootfs_path = path.join("rootfs.ext4");
     // Download the kernel and rootfs in a temporary directory
-    std::fs::create_dir_all(path).unwrap();
+    std::fs::create_dir_all(&path).unwrap();
     fetch_url(rootfs_url(), rootfs_path.clone()).await;
     fetch_url(kernel_url(), kernel_path.clone()).await;
 
@@ -107,10 +108,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap();
     let config = Configuration::new("vm_network".to_string())
         .with_kernel(kernel)
-        .with_executor(executor)
         .with_drive(drive)
         .with_interface(iface);
-    let mut machine = Machine::new();
+    let mut machine = Machine::new(executor)?;
     machine.create(config).await.expect("Could not create VM");
     println!("Booting the VM");
     machine.start().await.unwrap();
@@ -0,0 +1,812 @@
+//! # Typed client for the Firecracker API
+//!
+//! Firecracker is configured through a REST API exposed on a Unix socket. This
+//! module gives one typed method per endpoint of the API, with the request and
+//! response models coming from `firepilot_models`. Method names follow the
+//! `operationId` of the Firecracker OpenAPI definition.
+//!
+//! You usually get a client from [Executor::api], which points to the socket
+//! spawned by the executor, so you can drive the API directly while still
+//! relying on firepilot to manage the process.
+//!
+//! ## Example
+//!
+//! ```ignore
+//! use firepilot_models::models::Vm;
+//! use firepilot_models::models::vm::State;
+//!
+//! let client = executor.api();
+//! let info = client.describe_instance().await?;
+//! println!("{} is {:?}", info.id, info.state);
+//! client.patch_vm(&Vm::new(State::Paused)).await?;
+//! ```
+//!
+//! ## Transport
+//!
+//! Requests are sent through a [Transport], which is by default an HTTP client
+//! over Unix sockets. You can provide your own implementation with
+//! [FirecrackerClient::with_transport] or [Executor::with_transport], to add
+//! middlewares (latency injection, request capture) or to test your code
+//! without any firecracker process.
+//!
+//! [Executor::api]: crate::executor::Executor::api
+//! [Executor::with_transport]: crate::executor::Executor::with_transport
+use std::{
+    error::Error,
+    future::Future,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    body::Bytes, header::CONTENT_TYPE, Body, Client, Method, Request, Response, StatusCode,
+};
+use hyperlocal::{UnixClientExt, UnixConnector, Uri};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::sleep;
+use tracing::{debug, error, trace};
+
+use crate::audit::AuditLog;
+use crate::executor::ExecuteError;
+use crate::telemetry;
+#[cfg(feature = "firecracker-1-4")]
+use firepilot_models::models::EntropyDevice;
+use firepilot_models::models::{
+    Balloon, BalloonStats, BalloonStatsUpdate, BalloonUpdate, BootSource, Drive,
+    FirecrackerVersion, FullVmConfiguration, InstanceActionInfo, InstanceInfo, Logger,
+    MachineConfiguration, Metrics, MmdsConfig, NetworkInterface, PartialDrive,
+    PartialNetworkInterface, SnapshotCreateParams, SnapshotLoadParams, Vm, Vsock,
+};
+
+/// Error returned by a [Transport] when a request could not be sent
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Future returned by a [Transport] which resolves to the response of the API
+pub type TransportFuture =
+    Pin<Box<dyn Future<Output = Result<Response<Body>, TransportError>> + Send>>;
+
+/// Interface to determine how requests are sent to the API socket
+///
+/// The request URI is built with [hyperlocal::Uri], so it contains the path to
+/// the socket as well as the path of the endpoint.
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    /// Send the request and return the response given by the API
+    fn send(&self, request: Request<Body>) -> TransportFuture;
+}
+
+impl Transport for Client<UnixConnector> {
+    fn send(&self, request: Request<Body>) -> TransportFuture {
+        let response = self.request(request);
+        Box::pin(async move { response.await.map_err(TransportError::from) })
+    }
+}
+
+/// How requests failing before reaching firecracker are sent again
+///
+/// Only errors connecting to the socket are retried: it doesn't exist yet or
+/// refuses connections, e.g. right after the process was spawned. The request
+/// didn't reach firecracker, sending it again is safe. Errors once connected,
+/// and the ones returned by the API like a `400 Bad Request`, are never
+/// retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of times a request is sent again, 0 disables retries
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Retry 3 times, after 10ms, 20ms then 40ms
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Never retry, the first error is returned
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::new()
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> RetryPolicy {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+}
+
+/// Whether an error of the transport means the connection to the socket
+/// failed, so the request didn't reach firecracker and can be sent again
+fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        // Part of the request may have been sent once connected
+        if let Some(error) = error.downcast_ref::<hyper::Error>() {
+            if !error.is_connect() {
+                return false;
+            }
+        }
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            return matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            );
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Client able to send requests to the API socket of a Firecracker process
+#[derive(Debug, Clone)]
+pub struct FirecrackerClient {
+    /// Path to the Unix socket exposed by firecracker (`--api-sock`)
+    socket: PathBuf,
+    /// How requests are sent to the socket, HTTP over Unix sockets by default
+    transport: Arc<dyn Transport>,
+    /// Where requests are recorded, if anywhere
+    audit: Option<AuditLog>,
+    /// How requests are sent again after a transient error
+    retry: RetryPolicy,
+}
+
+impl FirecrackerClient {
+    /// Create a client which talks to the API socket at the given path
+    pub fn new(socket: PathBuf) -> FirecrackerClient {
+        FirecrackerClient {
+            socket,
+            transport: Arc::new(Client::unix()),
+            audit: None,
+            retry: RetryPolicy::new(),
+        }
+    }
+
+    /// Create a client which sends its requests through the given [Transport]
+    pub fn with_transport(socket: PathBuf, transport: Arc<dyn Transport>) -> FirecrackerClient {
+        FirecrackerClient {
+            socket,
+            transport,
+            audit: None,
+            retry: RetryPolicy::new(),
+        }
+    }
+
+    /// Record every request sent by this client in the given [AuditLog]
+    pub fn with_audit(self, audit: AuditLog) -> FirecrackerClient {
+        FirecrackerClient {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    /// Send the requests again after a transient error with the given policy,
+    /// instead of [RetryPolicy::new]
+    pub fn with_retry(self, retry: RetryPolicy) -> FirecrackerClient {
+        FirecrackerClient { retry, ..self }
+    }
+
+    /// Path to the API socket this client talks to
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    fn uri(&self, path: &str) -> hyper::Uri {
+        Uri::new(&self.socket, path).into()
+    }
+
+    /// Send a request to the socket and return the response with its raw body
+    ///
+    /// Any response status which is not a success is turned into an error,
+    /// with the `fault_message` given by firecracker when there is one.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(socket = %self.socket.display())))]
+    pub(crate) async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<Response<Bytes>, ExecuteError> {
+        let audited = self.audit.as_ref().map(|audit| (audit, body.clone()));
+        let operation = format!("{} {}", method, path);
+        let result = self.send_request(method, path, body).await;
+        if let Some((audit, body)) = audited {
+            audit.record(&operation, body.as_deref().map(str::as_bytes), &result);
+        }
+        result
+    }
+
+    async fn send_request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<Response<Bytes>, ExecuteError> {
+        let url = self.uri(path);
+        debug!("Send {} request to socket: {}", method, url);
+        if let Some(body) = &body {
+            trace!("Sent body to socket [{}]: {}", url, body);
+        }
+        let mut backoff = self.retry.initial_backoff;
+        let mut retries = 0;
+        let response = loop {
+            let mut request = Request::builder()
+                .method(method.clone())
+                .uri(url.clone())
+                .header("Accept", "application/json");
+            let body = match &body {
+                Some(body) => {
+                    request = request.header("Content-Type", "application/json");
+                    Body::from(body.clone())
+                }
+                None => Body::empty(),
+            };
+            let request = request
+                .body(body)
+                .map_err(|e| ExecuteError::Request(url.clone(), e.into()))?;
+
+            let started = Instant::now();
+            let response = self.transport.send(request).await;
+            telemetry::record_request(started.elapsed());
+            match response {
+                Err(e) if retries < self.retry.max_retries && is_transient(e.as_ref()) => {
+                    debug!(
+                        "Request to socket failed [{}]: {}, retrying in {:?}",
+                        url, e, backoff
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.retry.max_backoff);
+                    retries += 1;
+                }
+                response => break response.map_err(|e| ExecuteError::Request(url.clone(), e))?,
+            }
+        };
+
+        let (parts, body) = response.into_parts();
+        let status = parts.status;
+        trace!("Response status: {:#?}", status);
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| ExecuteError::Request(url.clone(), e.into()))?;
+
+        if !status.is_success() {
+            error!("Request to socket failed [{}]: {:#?}", url, status);
+            let fault = fault_message(&body);
+            error!("Request [{}] body: {}", url, fault);
+            return Err(ExecuteError::Api {
+                uri: url,
+                status,
+                fault,
+            });
+        }
+
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Send a `PUT` request on the given path with the serialized body, it is
+    /// used to configure devices before the microVM boots
+    pub async fn put<B: Serialize>(&self, path: &str, body: &B) -> Result<(), ExecuteError> {
+        let json = serde_json::to_string(body)?;
+        self.request(Method::PUT, path, Some(json)).await?;
+        Ok(())
+    }
+
+    /// Send a `PATCH` request on the given path with the serialized body, it
+    /// is used to update resources which already exist, mostly after boot
+    pub async fn patch<B: Serialize>(&self, path: &str, body: &B) -> Result<(), ExecuteError> {
+        let json = serde_json::to_string(body)?;
+        self.request(Method::PATCH, path, Some(json)).await?;
+        Ok(())
+    }
+
+    /// Send a `GET` request on the given path and deserialize the response
+    ///
+    /// When the response carries a `Content-Type` header, it must be JSON,
+    /// otherwise [ExecuteError::ContentType] is returned. A body which cannot
+    /// be deserialized into `T` gives [ExecuteError::Deserialize].
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ExecuteError> {
+        let response = self.request(Method::GET, path, None).await?;
+        trace!(
+            "Received body from socket [{}]: {:?}",
+            path,
+            response.body()
+        );
+        if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+            let content_type = String::from_utf8_lossy(content_type.as_bytes()).to_string();
+            if !content_type.starts_with("application/json") {
+                return Err(ExecuteError::ContentType(self.uri(path), content_type));
+            }
+        }
+        serde_json::from_slice(response.body())
+            .map_err(|e| ExecuteError::Deserialize(self.uri(path), e))
+    }
+
+    /// `GET /` without recording it in the audit log, it succeeds once
+    /// firecracker answers with `200 OK`
+    pub async fn ping(&self) -> Result<(), ExecuteError> {
+        let response = self.send_request(Method::GET, "/", None).await?;
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            status => Err(ExecuteError::Api {
+                uri: self.uri("/"),
+                status,
+                fault: fault_message(response.body()),
+            }),
+        }
+    }
+
+    /// `GET /`: returns general information about an instance
+    pub async fn describe_instance(&self) -> Result<InstanceInfo, ExecuteError> {
+        self.get("/").await
+    }
+
+    /// `PUT /actions`: creates a synchronous action
+    pub async fn create_sync_action(&self, info: &InstanceActionInfo) -> Result<(), ExecuteError> {
+        self.put("/actions", info).await
+    }
+
+    /// `GET /balloon`: returns the current balloon device configuration
+    pub async fn describe_balloon_config(&self) -> Result<Balloon, ExecuteError> {
+        self.get("/balloon").await
+    }
+
+    /// `PUT /balloon`: creates or updates a balloon device, only before boot
+    pub async fn put_balloon(&self, balloon: &Balloon) -> Result<(), ExecuteError> {
+        self.put("/balloon", balloon).await
+    }
+
+    /// `PATCH /balloon`: updates the target size of the balloon, only after boot
+    pub async fn patch_balloon(&self, update: &BalloonUpdate) -> Result<(), ExecuteError> {
+        self.patch("/balloon", update).await
+    }
+
+    /// `GET /balloon/statistics`: returns the latest balloon device statistics
+    pub async fn describe_balloon_stats(&self) -> Result<BalloonStats, ExecuteError> {
+        self.get("/balloon/statistics").await
+    }
+
+    /// `PATCH /balloon/statistics`: updates the balloon statistics polling interval
+    pub async fn patch_balloon_stats_interval(
+        &self,
+        update: &BalloonStatsUpdate,
+    ) -> Result<(), ExecuteError> {
+        self.patch("/balloon/statistics", update).await
+    }
+
+    /// `PUT /boot-source`: creates or updates the boot source, only before boot
+    pub async fn put_guest_boot_source(
+        &self,
+        boot_source: &BootSource,
+    ) -> Result<(), ExecuteError> {
+        self.put("/boot-source", boot_source).await
+    }
+
+    /// `PUT /drives/{drive_id}`: creates or updates a drive, only before boot
+    pub async fn put_guest_drive_by_id(&self, drive: &Drive) -> Result<(), ExecuteError> {
+        self.put(&format!("/drives/{}", drive.drive_id), drive)
+            .await
+    }
+
+    /// `PATCH /drives/{drive_id}`: updates the properties of a drive, only after boot
+    pub async fn patch_guest_drive_by_id(&self, drive: &PartialDrive) -> Result<(), ExecuteError> {
+        self.patch(&format!("/drives/{}", drive.drive_id), drive)
+            .await
+    }
+
+    /// `PUT /entropy`: creates the entropy device, only before boot
+    #[cfg(feature = "firecracker-1-4")]
+    pub async fn put_entropy_device(&self, entropy: &EntropyDevice) -> Result<(), ExecuteError> {
+        self.put("/entropy", entropy).await
+    }
+
+    /// `PUT /logger`: initializes the logger
+    pub async fn put_logger(&self, logger: &Logger) -> Result<(), ExecuteError> {
+        self.put("/logger", logger).await
+    }
+
+    /// `GET /machine-config`: returns the machine configuration of the VM
+    pub async fn get_machine_configuration(&self) -> Result<MachineConfiguration, ExecuteError> {
+        self.get("/machine-config").await
+    }
+
+    /// `PUT /machine-config`: updates the machine configuration, only before boot
+    pub async fn put_machine_configuration(
+        &self,
+        machine_config: &MachineConfiguration,
+    ) -> Result<(), ExecuteError> {
+        self.put("/machine-config", machine_config).await
+    }
+
+    /// `PATCH /machine-config`: partially updates the machine configuration,
+    /// only before boot
+    pub async fn patch_machine_configuration(
+        &self,
+        machine_config: &MachineConfiguration,
+    ) -> Result<(), ExecuteError> {
+        self.patch("/machine-config", machine_config).await
+    }
+
+    /// `PUT /metrics`: initializes the metrics system
+    pub async fn put_metrics(&self, metrics: &Metrics) -> Result<(), ExecuteError> {
+        self.put("/metrics", metrics).await
+    }
+
+    /// `GET /mmds`: returns the content of the MMDS data store
+    pub async fn get_mmds(&self) -> Result<serde_json::Value, ExecuteError> {
+        self.get("/mmds").await
+    }
+
+    /// `PUT /mmds`: creates or replaces the content of the MMDS data store
+    pub async fn put_mmds(&self, content: &serde_json::Value) -> Result<(), ExecuteError> {
+        self.put("/mmds", content).await
+    }
+
+    /// `PATCH /mmds`: updates the content of the MMDS data store
+    pub async fn patch_mmds(&self, content: &serde_json::Value) -> Result<(), ExecuteError> {
+        self.patch("/mmds", content).await
+    }
+
+    /// `PUT /mmds/config`: configures MMDS, only before boot
+    pub async fn put_mmds_config(&self, config: &MmdsConfig) -> Result<(), ExecuteError> {
+        self.put("/mmds/config", config).await
+    }
+
+    /// `PUT /network-interfaces/{iface_id}`: creates a network interface, only
+    /// before boot
+    pub async fn put_guest_network_interface_by_id(
+        &self,
+        network_interface: &NetworkInterface,
+    ) -> Result<(), ExecuteError> {
+        let path = format!("/network-interfaces/{}", network_interface.iface_id);
+        self.put(&path, network_interface).await
+    }
+
+    /// `PATCH /network-interfaces/{iface_id}`: updates the rate limiters of a
+    /// network interface, only after boot
+    pub async fn patch_guest_network_interface_by_id(
+        &self,
+        network_interface: &PartialNetworkInterface,
+    ) -> Result<(), ExecuteError> {
+        let path = format!("/network-interfaces/{}", network_interface.iface_id);
+        self.patch(&path, network_interface).await
+    }
+
+    /// `PUT /snapshot/create`: creates a full or diff snapshot, only after boot
+    /// and while the VM is paused
+    pub async fn create_snapshot(&self, params: &SnapshotCreateParams) -> Result<(), ExecuteError> {
+        self.put("/snapshot/create", params).await
+    }
+
+    /// `PUT /snapshot/load`: loads a snapshot, only before boot
+    pub async fn load_snapshot(&self, params: &SnapshotLoadParams) -> Result<(), ExecuteError> {
+        self.put("/snapshot/load", params).await
+    }
+
+    /// `GET /version`: returns the version of the firecracker process
+    pub async fn get_firecracker_version(&self) -> Result<FirecrackerVersion, ExecuteError> {
+        self.get("/version").await
+    }
+
+    /// `PATCH /vm`: updates the state of the microVM (pause or resume)
+    pub async fn patch_vm(&self, vm: &Vm) -> Result<(), ExecuteError> {
+        self.patch("/vm", vm).await
+    }
+
+    /// `GET /vm/config`: returns the full configuration of the microVM
+    pub async fn get_export_vm_config(&self) -> Result<FullVmConfiguration, ExecuteError> {
+        self.get("/vm/config").await
+    }
+
+    /// `PUT /vsock`: creates or updates the vsock device, only before boot
+    pub async fn put_guest_vsock(&self, vsock: &Vsock) -> Result<(), ExecuteError> {
+        self.put("/vsock", vsock).await
+    }
+}
+
+/// Extract the `fault_message` from an error body returned by firecracker,
+/// falls back to the raw body when it cannot be parsed
+fn fault_message(body: &[u8]) -> String {
+    match serde_json::from_slice::<firepilot_models::models::Error>(body) {
+        Ok(firepilot_models::models::Error {
+            fault_message: Some(message),
+        }) => message,
+        _ => String::from_utf8_lossy(body).to_string(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use hyper::{Body, Method, Request, Response, StatusCode};
+
+    use super::{Transport, TransportFuture};
+
+    /// Request received by the [MockTransport]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) struct RecordedRequest {
+        pub(crate) method: Method,
+        pub(crate) path: String,
+        pub(crate) body: String,
+    }
+
+    /// In-memory [Transport] which records requests and answers with canned
+    /// responses, in the order they were pushed. When no response is left, it
+    /// answers with `204 No Content`.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct MockTransport {
+        pub(crate) requests: Arc<Mutex<Vec<RecordedRequest>>>,
+        responses: Arc<Mutex<VecDeque<(StatusCode, String)>>>,
+    }
+
+    impl MockTransport {
+        pub(crate) fn new() -> MockTransport {
+            MockTransport::default()
+        }
+
+        /// Queue a response which will be returned by the next request
+        pub(crate) fn respond(&self, status: StatusCode, body: &str) -> &MockTransport {
+            self.responses
+                .lock()
+                .unwrap()
+                .push_back((status, body.to_string()));
+            self
+        }
+
+        pub(crate) fn requests(&self) -> Vec<RecordedRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send(&self, request: Request<Body>) -> TransportFuture {
+            let requests = self.requests.clone();
+            let response = self.responses.lock().unwrap().pop_front();
+            Box::pin(async move {
+                let method = request.method().clone();
+                let path = request.uri().path().to_string();
+                let body = hyper::body::to_bytes(request.into_body()).await?;
+                requests.lock().unwrap().push(RecordedRequest {
+                    method,
+                    path,
+                    body: String::from_utf8_lossy(&body).to_string(),
+                });
+                let (status, body) = response.unwrap_or((StatusCode::NO_CONTENT, String::new()));
+                let mut response = Response::builder().status(status);
+                if !body.is_empty() {
+                    response = response.header("Content-Type", "application/json");
+                }
+                Ok(response.body(Body::from(body))?)
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::MockTransport;
+    use super::*;
+
+    use firepilot_models::models::vm::State;
+    use hyper::StatusCode;
+
+    fn client(transport: &MockTransport) -> FirecrackerClient {
+        FirecrackerClient::with_transport(
+            PathBuf::from("/tmp/firecracker.socket"),
+            Arc::new(transport.clone()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_deserialize_response() {
+        let transport = MockTransport::new();
+        transport.respond(
+            StatusCode::OK,
+            r#"{"app_name": "Firecracker", "id": "vm", "state": "Running", "vmm_version": "1.3.0"}"#,
+        );
+        let info = client(&transport).describe_instance().await.unwrap();
+        assert_eq!(info.id, "vm");
+        assert_eq!(
+            info.state,
+            firepilot_models::models::instance_info::State::Running
+        );
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Method::GET);
+        assert_eq!(requests[0].path, "/");
+    }
+
+    #[tokio::test]
+    async fn test_get_invalid_body() {
+        let transport = MockTransport::new();
+        transport.respond(StatusCode::OK, r#"{"unexpected": true}"#);
+        let err = client(&transport)
+            .get_firecracker_version()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ExecuteError::Deserialize(_, _)));
+    }
+
+    #[cfg(feature = "firecracker-1-4")]
+    #[tokio::test]
+    async fn test_put_entropy_device() {
+        let transport = MockTransport::new();
+        client(&transport)
+            .put_entropy_device(&EntropyDevice::new())
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Method::PUT);
+        assert_eq!(requests[0].path, "/entropy");
+        assert_eq!(requests[0].body, "{}");
+    }
+
+    /// Refuses the connection a given number of times, then answers
+    #[derive(Debug)]
+    struct RefusingTransport {
+        refusals: std::sync::Mutex<u32>,
+        kind: ErrorKind,
+        inner: MockTransport,
+    }
+
+    impl Transport for RefusingTransport {
+        fn send(&self, request: Request<Body>) -> TransportFuture {
+            let mut refusals = self.refusals.lock().unwrap();
+            if *refusals > 0 {
+                *refusals -= 1;
+                let error = std::io::Error::from(self.kind);
+                return Box::pin(async move { Err(TransportError::from(error)) });
+            }
+            self.inner.send(request)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let refusing = |refusals, kind| {
+            let inner = MockTransport::new();
+            let client = FirecrackerClient::with_transport(
+                PathBuf::from("/tmp/firecracker.socket"),
+                Arc::new(RefusingTransport {
+                    refusals: std::sync::Mutex::new(refusals),
+                    kind,
+                    inner: inner.clone(),
+                }),
+            )
+            .with_retry(RetryPolicy::new().with_backoff(Duration::ZERO, Duration::ZERO));
+            (client, inner)
+        };
+
+        let (client, inner) = refusing(3, ErrorKind::ConnectionRefused);
+        client.patch_vm(&Vm::new(State::Paused)).await.unwrap();
+        assert_eq!(inner.requests().len(), 1);
+        assert_eq!(inner.requests()[0].body, r#"{"state":"Paused"}"#);
+
+        let (client, inner) = refusing(4, ErrorKind::ConnectionRefused);
+        let err = client.patch_vm(&Vm::new(State::Paused)).await.unwrap_err();
+        assert!(matches!(err, ExecuteError::Request(_, _)));
+        assert!(inner.requests().is_empty());
+
+        // The socket isn't created yet
+        let (client, inner) = refusing(2, ErrorKind::NotFound);
+        client.patch_vm(&Vm::new(State::Paused)).await.unwrap();
+        assert_eq!(inner.requests().len(), 1);
+
+        // Only errors before the request reached firecracker are retried
+        for kind in [
+            ErrorKind::BrokenPipe,
+            ErrorKind::WouldBlock,
+            ErrorKind::Interrupted,
+        ] {
+            let (client, inner) = refusing(1, kind);
+            assert!(client.patch_vm(&Vm::new(State::Paused)).await.is_err());
+            assert!(inner.requests().is_empty());
+        }
+
+        let (client, inner) = refusing(0, ErrorKind::ConnectionRefused);
+        inner.respond(StatusCode::BAD_REQUEST, r#"{"fault_message": "invalid"}"#);
+        let err = client.patch_vm(&Vm::new(State::Paused)).await.unwrap_err();
+        assert!(matches!(err, ExecuteError::Api { .. }));
+        assert_eq!(inner.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_unexpected_content_type() {
+        #[derive(Debug)]
+        struct PlainTransport;
+        impl Transport for PlainTransport {
+            fn send(&self, _request: Request<Body>) -> TransportFuture {
+                Box::pin(async {
+                    Ok(Response::builder()
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("1.3.0"))?)
+                })
+            }
+        }
+        let client = FirecrackerClient::with_transport(
+            PathBuf::from("/tmp/firecracker.socket"),
+            Arc::new(PlainTransport),
+        );
+        let err = client.get_firecracker_version().await.unwrap_err();
+        assert!(matches!(err, ExecuteError::ContentType(_, ref t) if t == "text/plain"));
+    }
+
+    #[tokio::test]
+    async fn test_patch_sends_body() {
+        let transport = MockTransport::new();
+        client(&transport)
+            .patch_vm(&Vm::new(State::Paused))
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Method::PATCH);
+        assert_eq!(requests[0].path, "/vm");
+        assert_eq!(requests[0].body, r#"{"state":"Paused"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_error_status_contains_fault_message() {
+        let transport = MockTransport::new();
+        transport.respond(
+            StatusCode::BAD_REQUEST,
+            r#"{"fault_message": "Invalid drive"}"#,
+        );
+        let drive = Drive::new("rootfs".to_string(), false, true, "/rootfs".to_string());
+        let err = client(&transport)
+            .put_guest_drive_by_id(&drive)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid drive"));
+        assert!(matches!(
+            err,
+            ExecuteError::Api {
+                status: StatusCode::BAD_REQUEST,
+                ..
+            }
+        ));
+        assert_eq!(transport.requests()[0].path, "/drives/rootfs");
+    }
+
+    #[test]
+    fn test_fault_message_from_json() {
+        let body = br#"{"fault_message": "The requested operation is not supported"}"#;
+        assert_eq!(
+            fault_message(body),
+            "The requested operation is not supported"
+        );
+    }
+
+    #[test]
+    fn test_fault_message_fallback_raw_body() {
+        assert_eq!(fault_message(b"not json"), "not json");
+    }
+
+    #[test]
+    fn test_client_uri() {
+        let client = FirecrackerClient::new(PathBuf::from("/tmp/firecracker.socket"));
+        let uri = client.uri("/drives/rootfs");
+        assert_eq!(uri.path(), "/drives/rootfs");
+        assert_eq!(client.socket(), Path::new("/tmp/firecracker.socket"));
+    }
+}
@@ -0,0 +1,111 @@
+//! # Audit log of a microVM
+//!
+//! Every API call and every operation on the firecracker process made by an
+//! [Executor](crate::executor::Executor) is appended to [AUDIT_LOG_FILE] in
+//! its workspace, so what was done to a microVM, and when, can be
+//! reconstructed after an incident. The log outlives the process and is only
+//! removed along with the workspace.
+//!
+//! Each line is a JSON object:
+//!
+//! ```json
+//! {"timestamp_ms":1697449200000,"operation":"PUT /drives/rootfs","params_sha256":"9f86d0...","outcome":"ok"}
+//! ```
+//!
+//! Parameters are only stored as a SHA-256 digest, as they may contain
+//! secrets (e.g. MMDS metadata). Failing to write a record is logged but never
+//! fails the operation.
+
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// Name of the audit log in each workspace
+pub const AUDIT_LOG_FILE: &str = "events.log";
+
+/// Append-only audit log of a workspace
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Audit log stored in the given workspace
+    pub fn new(workspace: &Path) -> AuditLog {
+        AuditLog {
+            path: workspace.join(AUDIT_LOG_FILE),
+        }
+    }
+
+    /// Path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a record of the operation, with the digest of its parameters
+    /// when it has some
+    pub(crate) fn record<T, E: Display>(
+        &self,
+        operation: &str,
+        params: Option<&[u8]>,
+        outcome: &Result<T, E>,
+    ) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+        let record = json!({
+            "timestamp_ms": timestamp_ms as u64,
+            "operation": operation,
+            "params_sha256": params.map(|params| format!("{:x}", Sha256::digest(params))),
+            "outcome": match outcome {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("error: {}", e),
+            },
+        });
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", record));
+        if let Err(e) = written {
+            warn!("Failed to write audit record to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(dir.path());
+        audit.record::<(), String>("PUT /drives/rootfs", Some(b"test"), &Ok(()));
+        audit.record::<(), String>("kill", None, &Err("no process".to_string()));
+
+        let content = std::fs::read_to_string(audit.path()).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["operation"], "PUT /drives/rootfs");
+        assert_eq!(
+            records[0]["params_sha256"],
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert_eq!(records[0]["outcome"], "ok");
+        assert!(records[1]["params_sha256"].is_null());
+        assert_eq!(records[1]["outcome"], "error: no process");
+    }
+}
@@ -0,0 +1,46 @@
+//! Daemon exposing the management of microVMs over a local HTTP API, see
+//! [firepilot::daemon]
+//!
+//! It is configured with environment variables:
+//!
+//! - `FIREPILOT_DAEMON_TOKEN` (required): token expected from clients
+//! - `FIREPILOT_DAEMON_ADDR`: address to listen on, `127.0.0.1:7070` by default
+//! - `FIREPILOT_CHROOT`: chroot of the machines, `/tmp/firepilot` by default
+//! - `FIREPILOT_FIRECRACKER`: firecracker binary, `/usr/bin/firecracker` by
+//!   default
+use std::{env, path::PathBuf, process::exit};
+
+use firepilot::daemon::{Daemon, DaemonConfig};
+
+fn var_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let token = match env::var("FIREPILOT_DAEMON_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => {
+            eprintln!("FIREPILOT_DAEMON_TOKEN must be set");
+            exit(1);
+        }
+    };
+    let addr = match var_or("FIREPILOT_DAEMON_ADDR", "127.0.0.1:7070").parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid FIREPILOT_DAEMON_ADDR: {}", e);
+            exit(1);
+        }
+    };
+    let daemon = Daemon::new(DaemonConfig {
+        addr,
+        token,
+        chroot: var_or("FIREPILOT_CHROOT", "/tmp/firepilot"),
+        exec_binary: PathBuf::from(var_or("FIREPILOT_FIRECRACKER", "/usr/bin/firecracker")),
+    });
+    if let Err(e) = daemon.serve().await {
+        eprintln!("firepilot daemon stopped: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,206 @@
+//! # Boot progress of the guest
+//!
+//! The serial console of the guest is the standard output of firecracker. It
+//! is read by the executor, which recognizes well-known milestones printed by
+//! the kernel and init, and publishes them as [BootEvent]s. They give the
+//! duration of each boot phase and can be used to wait for a guest without an
+//! agent.
+//!
+//! ```no_run
+//! # async fn example(mut machine: firepilot::machine::Machine) {
+//! use firepilot::boot::BootMilestone;
+//!
+//! let mut events = machine.boot_events();
+//! machine.start().await.unwrap();
+//! while let Ok(event) = events.recv().await {
+//!     println!("{:?} after {:?}", event.milestone, event.elapsed);
+//!     if event.milestone == BootMilestone::LoginPrompt {
+//!         break;
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! Guests must be booted with `console=ttyS0` for the console to be available.
+//! It can also be kept in [CONSOLE_LOG_FILE], see
+//! [Executor::with_console_log](crate::executor::Executor::with_console_log).
+
+use std::time::{Duration, Instant};
+
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::ChildStdout;
+use tokio::sync::broadcast::Sender;
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+/// Name of the log of the serial console in each workspace, when it is kept
+pub const CONSOLE_LOG_FILE: &str = "console.log";
+
+/// Number of events kept for receivers which are lagging behind
+pub(crate) const BOOT_EVENTS_CAPACITY: usize = 16;
+
+/// Milestone of the boot of the guest, in the order they usually happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BootMilestone {
+    /// The kernel printed its banner
+    KernelStarted,
+    /// The root filesystem was mounted
+    RootfsMounted,
+    /// The kernel handed over to the init process
+    InitStarted,
+    /// A login prompt is shown on the console
+    LoginPrompt,
+}
+
+impl BootMilestone {
+    /// Milestone announced by a line of the console, if any
+    pub fn detect(line: &str) -> Option<BootMilestone> {
+        if line.contains("Linux version ") {
+            Some(BootMilestone::KernelStarted)
+        } else if line.contains("VFS: Mounted root") {
+            Some(BootMilestone::RootfsMounted)
+        } else if line.contains("Run ") && line.contains(" as init process") {
+            Some(BootMilestone::InitStarted)
+        } else if line.trim_end().ends_with("login:") {
+            Some(BootMilestone::LoginPrompt)
+        } else {
+            None
+        }
+    }
+}
+
+/// A milestone reached by the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootEvent {
+    pub milestone: BootMilestone,
+    /// Time elapsed since the firecracker process was spawned
+    pub elapsed: Duration,
+}
+
+/// Read the console until it is closed and publish the milestones it shows,
+/// each one only once. Everything read is also appended to `log` if given.
+pub(crate) fn capture_console(
+    stdout: ChildStdout,
+    id: String,
+    events: Sender<BootEvent>,
+    spawned: Instant,
+    log: Option<File>,
+) -> JoinHandle<()> {
+    tokio::spawn(read_console(stdout, id, events, spawned, log))
+}
+
+async fn read_console<R: AsyncRead + Unpin>(
+    mut console: R,
+    id: String,
+    events: Sender<BootEvent>,
+    spawned: Instant,
+    mut log: Option<File>,
+) {
+    let mut seen = Vec::new();
+    let mut pending = Vec::new();
+    let mut buffer = [0; 4096];
+    while let Ok(read) = console.read(&mut buffer).await {
+        if read == 0 {
+            break;
+        }
+        if let Some(file) = &mut log {
+            if let Err(e) = file.write_all(&buffer[..read]).await {
+                warn!(
+                    "Failed to write the console of {}, not keeping it: {}",
+                    id, e
+                );
+                log = None;
+            }
+        }
+        pending.extend_from_slice(&buffer[..read]);
+        // Complete lines, and the unterminated one as a login prompt waits for
+        // input without a newline
+        let complete = pending.iter().rposition(|byte| *byte == b'\n');
+        let lines = String::from_utf8_lossy(&pending).into_owned();
+        for line in lines.split('\n') {
+            trace!("[{} console] {}", id, line);
+            match BootMilestone::detect(line) {
+                Some(milestone) if !seen.contains(&milestone) => {
+                    let event = BootEvent {
+                        milestone,
+                        elapsed: spawned.elapsed(),
+                    };
+                    debug!("Guest {} reached {:?}", id, event);
+                    seen.push(milestone);
+                    // No receiver is not an error, nobody is interested yet
+                    let _ = events.send(event);
+                }
+                _ => {}
+            }
+        }
+        if let Some(end) = complete {
+            pending.drain(..=end);
+        }
+    }
+    // Writes of tokio files complete in the background, wait for the last one
+    if let Some(file) = &mut log {
+        let _ = file.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    #[test]
+    fn test_detect_milestone() {
+        assert_eq!(
+            BootMilestone::detect("[    0.000000] Linux version 5.10.186 (root@host)"),
+            Some(BootMilestone::KernelStarted)
+        );
+        assert_eq!(
+            BootMilestone::detect(
+                "[    0.532110] VFS: Mounted root (ext4 filesystem) on device 254:0."
+            ),
+            Some(BootMilestone::RootfsMounted)
+        );
+        assert_eq!(
+            BootMilestone::detect("[    0.540321] Run /sbin/init as init process"),
+            Some(BootMilestone::InitStarted)
+        );
+        assert_eq!(
+            BootMilestone::detect("ubuntu-fc-uvm login: "),
+            Some(BootMilestone::LoginPrompt)
+        );
+        assert_eq!(BootMilestone::detect("Starting OpenSSH"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_console() {
+        let console: &[u8] = b"[    0.000000] Linux version 5.10\n\
+            VFS: Mounted root (ext4 filesystem)\n\
+            VFS: Mounted root (ext4 filesystem)\n\
+            Run /sbin/init as init process\n\
+            ubuntu-fc-uvm login: ";
+        let (sender, mut receiver) = broadcast::channel(BOOT_EVENTS_CAPACITY);
+        let dir = tempfile::tempdir().unwrap();
+        let log = File::create(dir.path().join(CONSOLE_LOG_FILE))
+            .await
+            .unwrap();
+        read_console(console, "vm".to_string(), sender, Instant::now(), Some(log)).await;
+
+        let mut milestones = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            milestones.push(event.milestone);
+        }
+        assert_eq!(
+            milestones,
+            vec![
+                BootMilestone::KernelStarted,
+                BootMilestone::RootfsMounted,
+                BootMilestone::InitStarted,
+                BootMilestone::LoginPrompt,
+            ]
+        );
+        let kept = std::fs::read(dir.path().join(CONSOLE_LOG_FILE)).unwrap();
+        assert_eq!(kept, console);
+    }
+}
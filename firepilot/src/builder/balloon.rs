@@ -0,0 +1,88 @@
+use firepilot_models::models::Balloon;
+
+use super::{Builder, BuilderError};
+
+/// Configure the balloon device of the microVM, which lets the host reclaim
+/// memory from the guest and is required to overcommit memory
+///
+/// The balloon starts inflated to `amount_mib`, its size can be changed after
+/// boot.
+#[derive(Debug, Default)]
+pub struct BalloonBuilder {
+    pub amount_mib: i32,
+    pub deflate_on_oom: bool,
+    pub stats_polling_interval_s: Option<i32>,
+}
+
+impl BalloonBuilder {
+    pub fn new() -> BalloonBuilder {
+        BalloonBuilder::default()
+    }
+
+    pub fn with_amount_mib(mut self, amount_mib: i32) -> BalloonBuilder {
+        self.amount_mib = amount_mib;
+        self
+    }
+
+    /// Let the guest take memory back from the balloon when it is under memory
+    /// pressure, instead of running out of memory
+    pub fn with_deflate_on_oom(mut self, deflate_on_oom: bool) -> BalloonBuilder {
+        self.deflate_on_oom = deflate_on_oom;
+        self
+    }
+
+    /// Enable the statistics of the balloon, refreshed at the given interval
+    pub fn with_stats_polling_interval_s(mut self, interval_s: i32) -> BalloonBuilder {
+        self.stats_polling_interval_s = Some(interval_s);
+        self
+    }
+}
+
+impl Builder<Balloon> for BalloonBuilder {
+    fn try_build(self) -> Result<Balloon, BuilderError> {
+        if self.amount_mib < 0 {
+            return Err(BuilderError::InvalidValue(format!(
+                "amount_mib can't be negative, got {}",
+                self.amount_mib
+            )));
+        }
+        if let Some(interval) = self
+            .stats_polling_interval_s
+            .filter(|interval| *interval < 0)
+        {
+            return Err(BuilderError::InvalidValue(format!(
+                "stats_polling_interval_s can't be negative, got {}",
+                interval
+            )));
+        }
+        Ok(Balloon {
+            amount_mib: self.amount_mib,
+            deflate_on_oom: self.deflate_on_oom,
+            stats_polling_interval_s: self.stats_polling_interval_s,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balloon_full() {
+        let balloon = BalloonBuilder::new()
+            .with_amount_mib(256)
+            .with_deflate_on_oom(true)
+            .with_stats_polling_interval_s(5)
+            .try_build()
+            .unwrap();
+        assert_eq!(balloon.amount_mib, 256);
+        assert!(balloon.deflate_on_oom);
+        assert_eq!(balloon.stats_polling_interval_s, Some(5));
+    }
+
+    #[test]
+    fn balloon_negative_amount() {
+        let balloon = BalloonBuilder::new().with_amount_mib(-1).try_build();
+        assert!(matches!(balloon, Err(BuilderError::InvalidValue(_))));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::builder::{Builder, BuilderError};
+use crate::host::Arch;
+use crate::network::guest::GuestNetworkConfig;
+
+/// Arguments which the kernel accepts more than once, e.g. to print the
+/// console on several devices
+const REPEATABLE_ARGS: &[&str] = &["console"];
+
+/// Separator after which arguments are passed to init instead of the kernel
+const INIT_ARGS_SEPARATOR: &str = "--";
+
+/// Compose the kernel boot arguments of the microVM
+///
+/// Defaults depend on the architecture: firecracker emulates a 16550 UART on
+/// both x86_64 and aarch64, so the console is `ttyS0` in both cases, but on
+/// aarch64 the boot console must be kept (`keep_bootcon`), otherwise the
+/// output stops as soon as the kernel switches consoles and the serial stays
+/// blank.
+///
+/// ## Example
+///
+/// ```rust
+/// use firepilot::builder::boot_args::BootArgsBuilder;
+/// use firepilot::host::Arch;
+///
+/// let boot_args = BootArgsBuilder::defaults_for(Arch::Aarch64)
+///     .with_arg("root=/dev/vda".to_string())
+///     .build();
+/// assert_eq!(boot_args, "keep_bootcon console=ttyS0 reboot=k panic=1 pci=off root=/dev/vda");
+/// ```
+///
+/// Typed setters replace any previous value of their argument, and
+/// [Builder::try_build] rejects arguments given more than once:
+///
+/// ```rust
+/// use firepilot::builder::Builder;
+/// use firepilot::builder::boot_args::BootArgsBuilder;
+///
+/// let boot_args = BootArgsBuilder::new()
+///     .with_console("ttyS0")
+///     .with_panic(1)
+///     .with_root("/dev/vda", true)
+///     .with_init("/sbin/overlay-init")
+///     .with_param("overlay_root", "vdb")
+///     .try_build()
+///     .unwrap();
+/// assert_eq!(
+///     boot_args,
+///     "console=ttyS0 panic=1 root=/dev/vda ro init=/sbin/overlay-init overlay_root=vdb"
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BootArgsBuilder {
+    args: Vec<String>,
+}
+
+impl BootArgsBuilder {
+    /// Create an empty set of boot arguments
+    pub fn new() -> BootArgsBuilder {
+        BootArgsBuilder { args: Vec::new() }
+    }
+
+    /// Boot arguments recommended by firecracker for the given architecture
+    pub fn defaults_for(arch: Arch) -> BootArgsBuilder {
+        let defaults: &[&str] = match arch {
+            Arch::X86_64 => &["console=ttyS0", "reboot=k", "panic=1", "pci=off"],
+            Arch::Aarch64 => &[
+                "keep_bootcon",
+                "console=ttyS0",
+                "reboot=k",
+                "panic=1",
+                "pci=off",
+            ],
+        };
+        BootArgsBuilder {
+            args: defaults.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    /// Split an existing command line, e.g. the `boot_args` of a boot source
+    pub fn from_cmdline(cmdline: &str) -> BootArgsBuilder {
+        BootArgsBuilder {
+            args: cmdline.split_whitespace().map(str::to_string).collect(),
+        }
+    }
+
+    /// Boot arguments recommended by firecracker for the host architecture
+    pub fn defaults() -> BootArgsBuilder {
+        Self::defaults_for(Arch::host())
+    }
+
+    /// Append an argument, e.g. `root=/dev/vda` or `quiet`
+    pub fn with_arg(mut self, arg: String) -> BootArgsBuilder {
+        self.args.push(arg);
+        self
+    }
+
+    /// Set `key=value`, replacing any previous value of `key`. It is kept
+    /// before `--`, the arguments after it are left to init.
+    pub fn with_param<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> BootArgsBuilder {
+        let key = key.as_ref();
+        let mut init_args = match self.args.iter().position(|arg| arg == INIT_ARGS_SEPARATOR) {
+            Some(separator) => self.args.split_off(separator),
+            None => Vec::new(),
+        };
+        self.args.retain(|arg| arg_key(arg) != key);
+        self.args.push(format!("{}={}", key, value.as_ref()));
+        self.args.append(&mut init_args);
+        self
+    }
+
+    /// Device the kernel prints its console to, e.g. `ttyS0`
+    pub fn with_console<S: AsRef<str>>(self, console: S) -> BootArgsBuilder {
+        self.with_param("console", console)
+    }
+
+    /// Seconds before rebooting after a kernel panic, 0 waits forever and a
+    /// negative value reboots immediately
+    pub fn with_panic(self, seconds: i32) -> BootArgsBuilder {
+        self.with_param("panic", seconds.to_string())
+    }
+
+    /// How the guest reboots, firecracker needs `k` (through the keyboard
+    /// controller) on x86_64 so a reboot stops the microVM
+    pub fn with_reboot<S: AsRef<str>>(self, mode: S) -> BootArgsBuilder {
+        self.with_param("reboot", mode)
+    }
+
+    /// Device of the root filesystem, mounted read-only or read-write
+    pub fn with_root<S: AsRef<str>>(mut self, device: S, read_only: bool) -> BootArgsBuilder {
+        self.args.retain(|arg| arg != "ro" && arg != "rw");
+        let mode = if read_only { "ro" } else { "rw" };
+        let mut builder = self.with_param("root", device);
+        builder.args.push(mode.to_string());
+        builder
+    }
+
+    /// Static address of a guest interface, configured by the kernel itself
+    /// before init runs, see [GuestNetworkConfig::kernel_ip_param]
+    pub fn with_ip(self, config: &GuestNetworkConfig) -> BootArgsBuilder {
+        self.with_param("ip", config.kernel_ip_param())
+    }
+
+    /// Program run as init instead of `/sbin/init`
+    pub fn with_init<S: AsRef<str>>(self, init: S) -> BootArgsBuilder {
+        self.with_param("init", init)
+    }
+
+    /// Assemble the arguments in the string expected by the boot source
+    pub fn build(self) -> String {
+        self.args.join(" ")
+    }
+}
+
+/// Name of an argument, the part before `=` if any
+fn arg_key(arg: &str) -> &str {
+    arg.split('=').next().unwrap_or(arg)
+}
+
+impl Builder<String> for BootArgsBuilder {
+    fn try_build(self) -> Result<String, BuilderError> {
+        let mut seen: Vec<&str> = Vec::new();
+        for arg in self.args.iter() {
+            if arg == INIT_ARGS_SEPARATOR {
+                break;
+            }
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                return Err(BuilderError::InvalidValue(format!(
+                    "Boot argument {:?} must be a single non-empty word",
+                    arg
+                )));
+            }
+            let key = arg_key(arg);
+            if seen.contains(&key) && !REPEATABLE_ARGS.contains(&key) {
+                return Err(BuilderError::InvalidValue(format!(
+                    "Boot argument {} is given more than once",
+                    key
+                )));
+            }
+            seen.push(key);
+        }
+        Ok(self.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_args_defaults_x86() {
+        assert_eq!(
+            BootArgsBuilder::defaults_for(Arch::X86_64).build(),
+            "console=ttyS0 reboot=k panic=1 pci=off"
+        );
+    }
+
+    #[test]
+    fn boot_args_defaults_aarch64_keep_bootcon() {
+        let boot_args = BootArgsBuilder::defaults_for(Arch::Aarch64).build();
+        assert!(boot_args.starts_with("keep_bootcon console=ttyS0"));
+    }
+
+    #[test]
+    fn boot_args_custom() {
+        let boot_args = BootArgsBuilder::new()
+            .with_arg("quiet".to_string())
+            .with_arg("init=/sbin/init".to_string())
+            .build();
+        assert_eq!(boot_args, "quiet init=/sbin/init");
+    }
+
+    #[test]
+    fn boot_args_typed_replace() {
+        let boot_args = BootArgsBuilder::defaults_for(Arch::X86_64)
+            .with_panic(-1)
+            .with_root("/dev/vda", false)
+            .with_root("/dev/vdb", true)
+            .try_build()
+            .unwrap();
+        assert_eq!(
+            boot_args,
+            "console=ttyS0 reboot=k pci=off panic=-1 root=/dev/vdb ro"
+        );
+    }
+
+    #[test]
+    fn boot_args_ip() {
+        let config = GuestNetworkConfig::new("eth0".to_string(), "172.16.0.2".parse().unwrap(), 24)
+            .with_gateway("172.16.0.1".parse().unwrap());
+        let boot_args = BootArgsBuilder::from_cmdline("console=ttyS0 ip=dhcp -- -v")
+            .with_ip(&config)
+            .build();
+        assert_eq!(
+            boot_args,
+            "console=ttyS0 ip=172.16.0.2::172.16.0.1:255.255.255.0::eth0:off -- -v"
+        );
+    }
+
+    #[test]
+    fn boot_args_duplicates() {
+        let duplicate = BootArgsBuilder::new()
+            .with_init("/sbin/init")
+            .with_arg("init=/bin/sh".to_string())
+            .try_build();
+        assert!(matches!(duplicate, Err(BuilderError::InvalidValue(_))));
+
+        let allowed = BootArgsBuilder::new()
+            .with_arg("console=tty0".to_string())
+            .with_arg("console=ttyS0".to_string())
+            .with_arg("--".to_string())
+            .with_arg("-v".to_string())
+            .with_arg("-v".to_string())
+            .try_build();
+        assert!(allowed.is_ok());
+    }
+}
@@ -0,0 +1,277 @@
+//! Conversion between [Configuration] and the JSON file firecracker accepts
+//! with `--config-file`
+
+use std::path::Path;
+
+use firepilot_models::models::FullVmConfiguration;
+use log::warn;
+use serde_json::{Map, Value};
+
+use super::Configuration;
+use crate::machine::FirepilotError;
+
+/// Section of the configuration file with the path of a custom CPU template
+const CPU_CONFIG_SECTION: &str = "cpu-config";
+
+/// Sections of the configuration file which are part of [FullVmConfiguration]
+const KNOWN_SECTIONS: &[&str] = &[
+    "balloon",
+    "boot-source",
+    "drives",
+    #[cfg(feature = "firecracker-1-4")]
+    "entropy",
+    "logger",
+    "machine-config",
+    "metrics",
+    "mmds-config",
+    "network-interfaces",
+    "vsock",
+];
+
+impl Configuration {
+    /// Import an existing firecracker `--config-file` JSON, the file stem is
+    /// used as the microVM id
+    ///
+    /// Boot source, drives, machine configuration, balloon, MMDS, metrics,
+    /// network interfaces and vsock are imported, along with the custom CPU
+    /// template `cpu-config` points to, relative to the directory of the file.
+    /// Sections which can't be represented in a [Configuration], e.g. from a
+    /// newer firecracker, are skipped with a warning. An executor must still
+    /// be provided before creating a machine.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use firepilot::builder::Configuration;
+    ///
+    /// let config = Configuration::from_firecracker_json("vm_config.json").unwrap();
+    /// assert_eq!(config.vm_id, "vm_config");
+    /// ```
+    pub fn from_firecracker_json<P: AsRef<Path>>(path: P) -> Result<Configuration, FirepilotError> {
+        let path = path.as_ref();
+        let vm_id = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            FirepilotError::setup(&vm_id, format!("Failed to read {}", path.display()))
+                .with_source(e)
+        })?;
+        let invalid = |e: serde_json::Error| {
+            FirepilotError::setup(
+                &vm_id,
+                format!("Invalid firecracker configuration {}", path.display()),
+            )
+            .with_source(e)
+        };
+        let mut sections: Map<String, Value> = serde_json::from_str(&content).map_err(invalid)?;
+
+        let cpu_config = match sections.remove(CPU_CONFIG_SECTION) {
+            Some(Value::String(template)) => {
+                let template = path.parent().unwrap_or(Path::new("")).join(template);
+                let content = std::fs::read_to_string(&template).map_err(|e| {
+                    FirepilotError::setup(
+                        &vm_id,
+                        format!("Failed to read CPU template {}", template.display()),
+                    )
+                    .with_source(e)
+                })?;
+                Some(serde_json::from_str(&content).map_err(invalid)?)
+            }
+            // Inline template
+            Some(Value::Object(template)) => Some(Value::Object(template)),
+            Some(_) | None => None,
+        };
+        let unknown: Vec<String> = sections
+            .keys()
+            .filter(|section| !KNOWN_SECTIONS.contains(&section.as_str()))
+            .cloned()
+            .collect();
+        for section in unknown {
+            warn!(
+                "Section {} of the firecracker configuration of {} is not supported, skipping it",
+                section, vm_id
+            );
+            sections.remove(&section);
+        }
+
+        let vm_config: FullVmConfiguration =
+            serde_json::from_value(Value::Object(sections)).map_err(invalid)?;
+        let mut config = Configuration::from_full_vm_config(vm_id, vm_config);
+        config.cpu_config = cpu_config;
+        Ok(config)
+    }
+
+    pub(crate) fn from_full_vm_config(
+        vm_id: String,
+        vm_config: FullVmConfiguration,
+    ) -> Configuration {
+        if vm_config.logger.is_some() {
+            warn!(
+                "Section logger of the firecracker configuration of {} is not supported, skipping it",
+                vm_id
+            );
+        }
+
+        let mut config = Configuration::new(vm_id);
+        config.kernel = vm_config.boot_source.map(|boot_source| *boot_source);
+        config.storage = vm_config.drives.unwrap_or_default();
+        config.interfaces = vm_config.network_interfaces.unwrap_or_default();
+        config.vsock = vm_config.vsock.map(|vsock| *vsock);
+        config.machine_config = vm_config
+            .machine_config
+            .map(|machine_config| *machine_config);
+        config.balloon = vm_config.balloon.map(|balloon| *balloon);
+        config.mmds = vm_config.mmds_config.map(|mmds| *mmds);
+        config.metrics = vm_config.metrics.map(|metrics| *metrics);
+        #[cfg(feature = "firecracker-1-4")]
+        {
+            config.entropy = vm_config.entropy.map(|entropy| *entropy);
+        }
+        config
+    }
+
+    /// Export the configuration as the JSON firecracker accepts with
+    /// `--config-file`, e.g. to boot the microVM without firepilot
+    ///
+    /// Paths are exported as given: drives and the kernel aren't staged, and
+    /// a relative vsock socket isn't resolved in the workspace. The CPU
+    /// template is only referenced by path in this file, it is left out with
+    /// a warning.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use firepilot::builder::Configuration;
+    /// use firepilot_models::models::BootSource;
+    ///
+    /// let config = Configuration::new("vm".to_string())
+    ///     .with_kernel(BootSource::new("vmlinux.bin".to_string()));
+    /// let json = config.to_firecracker_json().unwrap();
+    /// assert!(json.contains("\"boot-source\""));
+    /// ```
+    pub fn to_firecracker_json(&self) -> Result<String, FirepilotError> {
+        serde_json::to_string_pretty(&self.to_full_vm_config()).map_err(|e| {
+            FirepilotError::setup(&self.vm_id, "Failed to serialize the configuration")
+                .with_source(e)
+        })
+    }
+
+    pub(crate) fn to_full_vm_config(&self) -> FullVmConfiguration {
+        if self.cpu_config.is_some() {
+            warn!(
+                "The CPU template of {} must be written to its own file to be referenced by cpu-config, skipping it",
+                self.vm_id
+            );
+        }
+        let mut vm_config = FullVmConfiguration::new();
+        vm_config.boot_source = self.kernel.clone().map(Box::new);
+        // Required by firecracker, even when empty
+        vm_config.drives = Some(self.storage.clone());
+        vm_config.network_interfaces = Some(self.interfaces.clone());
+        vm_config.vsock = self.vsock.clone().map(Box::new);
+        vm_config.machine_config = self.machine_config.clone().map(Box::new);
+        vm_config.balloon = self.balloon.clone().map(Box::new);
+        vm_config.mmds_config = self.mmds.clone().map(Box::new);
+        vm_config.metrics = self.metrics.clone().map(Box::new);
+        #[cfg(feature = "firecracker-1-4")]
+        {
+            vm_config.entropy = self.entropy.clone().map(Box::new);
+        }
+        vm_config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VM_CONFIG: &str = r#"{
+        "boot-source": {
+            "kernel_image_path": "vmlinux.bin",
+            "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+        },
+        "drives": [{
+            "drive_id": "rootfs",
+            "path_on_host": "bionic.rootfs.ext4",
+            "is_root_device": true,
+            "is_read_only": false
+        }],
+        "network-interfaces": [{
+            "iface_id": "eth0",
+            "guest_mac": "AA:FC:00:00:00:01",
+            "host_dev_name": "tap0"
+        }],
+        "machine-config": {
+            "vcpu_count": 2,
+            "mem_size_mib": 1024
+        }
+    }"#;
+
+    #[test]
+    fn test_from_firecracker_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("web-1.json");
+        std::fs::write(&path, VM_CONFIG).unwrap();
+
+        let config = Configuration::from_firecracker_json(&path).unwrap();
+        assert_eq!(config.vm_id, "web-1");
+        assert_eq!(config.kernel.unwrap().kernel_image_path, "vmlinux.bin");
+        assert_eq!(config.storage.len(), 1);
+        assert!(config.storage[0].is_root_device);
+        assert_eq!(config.interfaces[0].host_dev_name, "tap0");
+        assert_eq!(config.machine_config.unwrap().vcpu_count, 2);
+    }
+
+    #[test]
+    fn test_to_firecracker_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("web-1.json");
+        std::fs::write(&path, VM_CONFIG).unwrap();
+
+        let config = Configuration::from_firecracker_json(&path).unwrap();
+        let exported: serde_json::Value =
+            serde_json::from_str(&config.to_firecracker_json().unwrap()).unwrap();
+        let original: serde_json::Value = serde_json::from_str(VM_CONFIG).unwrap();
+        assert_eq!(exported, original);
+    }
+
+    #[test]
+    fn test_from_firecracker_json_extra_sections() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("template.json"),
+            r#"{"kvm_capabilities": []}"#,
+        )
+        .unwrap();
+        let mut vm_config: Value = serde_json::from_str(VM_CONFIG).unwrap();
+        vm_config["cpu-config"] = Value::from("template.json");
+        vm_config["entropy"] = serde_json::json!({});
+        let path = dir.path().join("web-2.json");
+        std::fs::write(&path, vm_config.to_string()).unwrap();
+
+        let config = Configuration::from_firecracker_json(&path).unwrap();
+        assert_eq!(
+            config.cpu_config,
+            Some(serde_json::json!({"kvm_capabilities": []}))
+        );
+        assert_eq!(config.storage.len(), 1);
+        #[cfg(feature = "firecracker-1-4")]
+        assert_eq!(
+            config.entropy,
+            Some(firepilot_models::models::EntropyDevice::new())
+        );
+    }
+
+    #[test]
+    fn test_from_firecracker_json_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.json");
+        std::fs::write(&path, r#"{"drives": [{"drive_id": 1}]}"#).unwrap();
+        assert!(matches!(
+            Configuration::from_firecracker_json(&path),
+            Err(FirepilotError::Setup { .. })
+        ));
+        assert!(Configuration::from_firecracker_json(dir.path().join("missing.json")).is_err());
+    }
+}
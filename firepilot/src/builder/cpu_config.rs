@@ -0,0 +1,187 @@
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::builder::{Builder, BuilderError};
+use crate::host::Arch;
+
+/// Sections of a custom CPU template understood by firecracker on both
+/// architectures
+const COMMON_SECTIONS: &[&str] = &["kvm_capabilities"];
+
+/// Sections only understood on x86_64
+const X86_64_SECTIONS: &[&str] = &["cpuid_modifiers", "msr_modifiers"];
+
+/// Sections only understood on aarch64
+const AARCH64_SECTIONS: &[&str] = &["reg_modifiers", "vcpu_features"];
+
+/// Load a custom CPU template, applied with `PUT /cpu-config` to mask CPUID
+/// leaves, MSRs or registers so every host of a fleet exposes the same CPU
+///
+/// The template is the JSON documented by firecracker, it is only checked to
+/// be an object with sections known on the target architecture, which is the
+/// host one by default. It requires firecracker 1.4 and replaces the static
+/// `cpu_template` of the machine configuration.
+///
+/// ## Example
+///
+/// ```rust
+/// use firepilot::builder::Builder;
+/// use firepilot::builder::cpu_config::CpuConfigBuilder;
+/// use firepilot::host::Arch;
+/// use serde_json::json;
+///
+/// let template = CpuConfigBuilder::new()
+///     .with_template(json!({
+///         "cpuid_modifiers": [{
+///             "leaf": "0x1",
+///             "subleaf": "0x0",
+///             "flags": 0,
+///             "modifiers": [{ "register": "ecx", "bitmap": "0bxxxx0xxxxxxxxxxxxxxxxxxxxxxxxxxx" }]
+///         }]
+///     }))
+///     .for_arch(Arch::X86_64)
+///     .try_build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct CpuConfigBuilder {
+    pub template: Option<Value>,
+    pub template_path: Option<PathBuf>,
+    pub arch: Arch,
+}
+
+impl Default for CpuConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuConfigBuilder {
+    pub fn new() -> CpuConfigBuilder {
+        CpuConfigBuilder {
+            template: None,
+            template_path: None,
+            arch: Arch::host(),
+        }
+    }
+
+    pub fn with_template(mut self, template: Value) -> CpuConfigBuilder {
+        self.template = Some(template);
+        self.template_path = None;
+        self
+    }
+
+    /// Read the template from a JSON file when building
+    pub fn with_template_path<P: Into<PathBuf>>(mut self, path: P) -> CpuConfigBuilder {
+        self.template_path = Some(path.into());
+        self.template = None;
+        self
+    }
+
+    /// Validate the template for another architecture than the host one
+    pub fn for_arch(mut self, arch: Arch) -> CpuConfigBuilder {
+        self.arch = arch;
+        self
+    }
+}
+
+impl Builder<Value> for CpuConfigBuilder {
+    fn try_build(self) -> Result<Value, BuilderError> {
+        let template = match (self.template, &self.template_path) {
+            (Some(template), _) => template,
+            (None, Some(path)) => {
+                let content = std::fs::read(path).map_err(|e| {
+                    BuilderError::InvalidValue(format!("Failed to read {:?}: {}", path, e))
+                })?;
+                serde_json::from_slice(&content).map_err(|e| {
+                    BuilderError::InvalidValue(format!("{:?} is not valid JSON: {}", path, e))
+                })?
+            }
+            (None, None) => {
+                return Err(BuilderError::missing(
+                    "CpuConfigBuilder",
+                    "template",
+                    "with_template or with_template_path",
+                ))
+            }
+        };
+        let sections = template.as_object().ok_or_else(|| {
+            BuilderError::InvalidValue("CPU template must be a JSON object".to_string())
+        })?;
+        let (supported, other) = match self.arch {
+            Arch::X86_64 => (X86_64_SECTIONS, AARCH64_SECTIONS),
+            Arch::Aarch64 => (AARCH64_SECTIONS, X86_64_SECTIONS),
+        };
+        for section in sections.keys() {
+            if other.contains(&section.as_str()) {
+                return Err(BuilderError::IncompatibleConfiguration(format!(
+                    "CPU template section {} is not available on {}",
+                    section, self.arch
+                )));
+            }
+            if !supported.contains(&section.as_str())
+                && !COMMON_SECTIONS.contains(&section.as_str())
+            {
+                return Err(BuilderError::InvalidValue(format!(
+                    "Unknown CPU template section {}",
+                    section
+                )));
+            }
+        }
+        Ok(template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn cpu_config_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("template.json");
+        std::fs::write(
+            &path,
+            r#"{"kvm_capabilities": ["!56"], "reg_modifiers": []}"#,
+        )
+        .unwrap();
+        let template = CpuConfigBuilder::new()
+            .with_template_path(&path)
+            .for_arch(Arch::Aarch64)
+            .try_build()
+            .unwrap();
+        assert_eq!(template["kvm_capabilities"], json!(["!56"]));
+    }
+
+    #[test]
+    fn cpu_config_invalid() {
+        let result = CpuConfigBuilder::new().try_build();
+        assert!(matches!(
+            result,
+            Err(BuilderError::MissingRequiredField { .. })
+        ));
+
+        let result = CpuConfigBuilder::new()
+            .with_template(json!(["cpuid_modifiers"]))
+            .try_build();
+        assert!(matches!(result, Err(BuilderError::InvalidValue(_))));
+
+        let result = CpuConfigBuilder::new()
+            .with_template(json!({ "cpuid_modifers": [] }))
+            .for_arch(Arch::X86_64)
+            .try_build();
+        assert!(matches!(result, Err(BuilderError::InvalidValue(_))));
+
+        let result = CpuConfigBuilder::new()
+            .with_template(json!({ "msr_modifiers": [] }))
+            .for_arch(Arch::Aarch64)
+            .try_build();
+        assert!(matches!(
+            result,
+            Err(BuilderError::IncompatibleConfiguration(_))
+        ));
+    }
+}
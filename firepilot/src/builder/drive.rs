@@ -1,7 +1,171 @@
-use std::path::PathBuf;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
 
 use crate::builder::{assert_not_none, Builder, BuilderError};
-use firepilot_models::models::Drive;
+use crate::host;
+use crate::overlay::OverlayOrigin;
+use crate::thin::ThinOrigin;
+use firepilot_models::models::drive::IoEngine;
+use firepilot_models::models::{Drive, RateLimiter};
+use log::{debug, warn};
+
+/// Size of the sectors of virtio block devices, drive files must be a multiple
+/// of it
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Binary formatting scratch drives
+pub const MKFS_BINARY: &str = "mkfs.ext4";
+
+/// Filesystems detected on drive images, with the offset and value of their
+/// magic
+const FILESYSTEM_MAGICS: [(&str, u64, &[u8]); 5] = [
+    ("ext2/3/4", 1080, b"\x53\xef"),
+    ("squashfs", 0, b"hsqs"),
+    ("xfs", 0, b"XFSB"),
+    ("vfat", 82, b"FAT32"),
+    ("btrfs", 0x10040, b"_BHRfS_M"),
+];
+
+/// Name of the filesystem found on the drive image, if it is a known one
+pub fn detect_filesystem(path: &Path) -> Option<&'static str> {
+    let mut file = File::open(path).ok()?;
+    FILESYSTEM_MAGICS.iter().find_map(|(name, offset, magic)| {
+        let mut buffer = vec![0; magic.len()];
+        file.seek(SeekFrom::Start(*offset)).ok()?;
+        file.read_exact(&mut buffer).ok()?;
+        match buffer == *magic {
+            true => Some(*name),
+            false => None,
+        }
+    })
+}
+
+/// Check the drive image can be attached to the microVM: it must be a block
+/// device or a regular file whose size is a multiple of [SECTOR_SIZE]
+///
+/// For root devices, a warning is logged when no known filesystem is found,
+/// as the guest would fail to mount it.
+pub fn validate_drive_image(path: &Path, is_root_device: bool) -> Result<(), BuilderError> {
+    let metadata = std::fs::metadata(path).map_err(|e| {
+        BuilderError::InvalidValue(format!("Drive {} can't be read: {}", path.display(), e))
+    })?;
+    let file_type = metadata.file_type();
+    if file_type.is_file() {
+        if metadata.len() == 0 || metadata.len() % SECTOR_SIZE != 0 {
+            return Err(BuilderError::InvalidValue(format!(
+                "Drive {} is {} bytes, it must be a non-empty multiple of {} bytes",
+                path.display(),
+                metadata.len(),
+                SECTOR_SIZE
+            )));
+        }
+    } else if !file_type.is_block_device() {
+        return Err(BuilderError::InvalidValue(format!(
+            "Drive {} is neither a regular file nor a block device",
+            path.display()
+        )));
+    }
+    if is_root_device {
+        match detect_filesystem(path) {
+            Some(filesystem) => debug!("Root drive {} is {}", path.display(), filesystem),
+            None => warn!(
+                "No known filesystem found on root drive {}, the guest may fail to mount it",
+                path.display()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the drive is backed by a block device, e.g. an LVM logical volume.
+/// Such drives are used in place instead of being staged in the workspace.
+pub fn is_block_device(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Check the block device can be opened with the access the drive needs, so
+/// a permission issue is reported before firecracker is configured
+pub fn check_block_device_access(path: &Path, is_read_only: bool) -> io::Result<()> {
+    OpenOptions::new()
+        .read(true)
+        .write(!is_read_only)
+        .open(path)
+        .map(|_| ())
+}
+
+/// Create a sparse file of the given size and format it as ext4, for a scratch
+/// drive
+pub async fn create_scratch_image(path: &Path, size_mib: u64) -> io::Result<()> {
+    debug!(
+        "Create scratch image {} of {} MiB",
+        path.display(),
+        size_mib
+    );
+    File::create(path)?.set_len(size_mib << 20)?;
+    let output = tokio::process::Command::new(MKFS_BINARY)
+        .args(["-q", "-F"])
+        .arg(path)
+        .output()
+        .await?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(path);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} failed: {}",
+                MKFS_BINARY,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Options describing how a drive is staged on the host before being handed to
+/// the microVM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriveStaging {
+    /// Base directory where the copy of the drive lives, defaults to the
+    /// machine workspace
+    pub dir: Option<PathBuf>,
+    /// Thin pool volume to snapshot for each microVM instead of copying the
+    /// drive
+    pub thin: Option<ThinOrigin>,
+    /// Size in MiB of the empty ext4 image created in the workspace instead
+    /// of copying a drive, see [DriveBuilder::as_scratch]
+    pub scratch: Option<u64>,
+    /// Read-only base image the drive is a copy-on-write overlay of, see
+    /// [DriveBuilder::with_overlay]
+    pub overlay: Option<OverlayOrigin>,
+    /// Hand `path_on_host` to firecracker as is instead of staging a copy,
+    /// see [DriveBuilder::without_staging]
+    pub no_stage: bool,
+}
+
+impl DriveStaging {
+    /// Path of the staged copy of the drive. When a base directory is given,
+    /// copies are grouped per microVM so several machines can share it.
+    pub(crate) fn location(&self, workspace: &Path, vm_id: &str, drive_id: &str) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.join(vm_id).join(drive_id),
+            None => workspace.join(drive_id),
+        }
+    }
+}
+
+/// A drive along with the options used to stage it, see
+/// [DriveBuilder::try_build_staged]
+#[derive(Debug, Clone)]
+pub struct StagedDrive {
+    pub drive: Drive,
+    pub staging: DriveStaging,
+}
 
 #[derive(Debug)]
 pub struct DriveBuilder {
@@ -9,6 +173,16 @@ pub struct DriveBuilder {
     pub path_on_host: Option<PathBuf>,
     pub is_root_device: bool,
     pub is_read_only: bool,
+    pub io_engine: Option<IoEngine>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub staging: DriveStaging,
+    pub validate_image: bool,
+}
+
+impl Default for DriveBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DriveBuilder {
@@ -18,6 +192,10 @@ impl DriveBuilder {
             path_on_host: None,
             is_root_device: false,
             is_read_only: false,
+            io_engine: None,
+            rate_limiter: None,
+            staging: DriveStaging::default(),
+            validate_image: false,
         }
     }
 
@@ -40,12 +218,164 @@ impl DriveBuilder {
         self.is_read_only = true;
         self
     }
+
+    /// Set the io engine of the drive, `Async` requires a host kernel newer
+    /// than 5.10.51 which is checked before the drive is configured
+    pub fn with_io_engine(mut self, io_engine: IoEngine) -> DriveBuilder {
+        self.io_engine = Some(io_engine);
+        self
+    }
+
+    /// Use the `Async` io engine when the host kernel supports it, and fall
+    /// back to `Sync` otherwise
+    pub fn with_best_io_engine(self) -> DriveBuilder {
+        let io_engine = match host::supports_async_io() {
+            true => IoEngine::Async,
+            false => IoEngine::Sync,
+        };
+        self.with_io_engine(io_engine)
+    }
+
+    /// Limit the IO of the drive, see
+    /// [RateLimiterBuilder](crate::builder::rate_limiter::RateLimiterBuilder)
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> DriveBuilder {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Check the drive file when building, see [validate_drive_image]
+    pub fn with_image_validation(mut self) -> DriveBuilder {
+        self.validate_image = true;
+        self
+    }
+
+    /// Store the staged copy of the drive under the given directory instead of
+    /// the machine workspace, e.g. to keep the rootfs on a fast disk
+    pub fn with_staging_dir<P: Into<PathBuf>>(mut self, dir: P) -> DriveBuilder {
+        self.staging.dir = Some(dir.into());
+        self
+    }
+
+    /// Back the drive with a writable snapshot of a thin pool volume, created
+    /// for each microVM instead of copying the drive, see [crate::thin]. The
+    /// size must be the size of the volume in bytes, `path_on_host` doesn't
+    /// need to be set as it is replaced by the snapshot device.
+    pub fn with_thin_snapshot(mut self, pool: String, origin_id: u32, size: u64) -> DriveBuilder {
+        self.staging.thin = Some(ThinOrigin {
+            pool,
+            origin_id,
+            size,
+        });
+        self
+    }
+
+    /// Back the drive with an empty ext4 image of the given size, created in
+    /// the machine workspace and removed along with it. The drive is writable
+    /// and can't be the root device, `path_on_host` doesn't need to be set.
+    /// The image is named after the drive id, which must be a file name.
+    pub fn as_scratch(mut self, size_mib: u64) -> DriveBuilder {
+        self.staging.scratch = Some(size_mib);
+        self
+    }
+
+    /// Give firecracker the original `path_on_host` instead of staging a copy
+    /// of the drive, whatever the copy strategy. A writable drive is then
+    /// modified in place by the guest.
+    pub fn without_staging(mut self) -> DriveBuilder {
+        self.staging.no_stage = true;
+        self
+    }
+
+    /// Make the drive a copy-on-write overlay of `path_on_host`, which is kept
+    /// read-only and can be shared by many microVMs, see [crate::overlay].
+    /// Up to `cow_size` bytes written by the guest are stored in a sparse
+    /// file of the machine workspace.
+    pub fn with_overlay(mut self, cow_size: u64) -> DriveBuilder {
+        // The base is only known once the builder is complete
+        self.staging.overlay = Some(OverlayOrigin {
+            base: PathBuf::new(),
+            cow_size,
+        });
+        self
+    }
+
+    /// Same as [Builder::try_build] but keeps the staging options, so the
+    /// drive can be given to [Configuration::with_staged_drive]
+    ///
+    /// [Configuration::with_staged_drive]: crate::builder::Configuration::with_staged_drive
+    pub fn try_build_staged(mut self) -> Result<StagedDrive, BuilderError> {
+        if let Some(thin) = &self.staging.thin {
+            if thin.size == 0 || thin.size % SECTOR_SIZE != 0 {
+                return Err(BuilderError::InvalidValue(format!(
+                    "Thin volume size must be a non-empty multiple of {} bytes, got {}",
+                    SECTOR_SIZE, thin.size
+                )));
+            }
+            if self.path_on_host.is_none() {
+                self.path_on_host =
+                    Some(PathBuf::from(crate::thin::DEVICE_MAPPER_DIR).join(&thin.pool));
+            }
+        }
+        if let Some(size_mib) = self.staging.scratch {
+            if size_mib == 0 || self.is_root_device || self.is_read_only {
+                return Err(BuilderError::InvalidValue(
+                    "Scratch drives must be non-empty, writable and not the root device"
+                        .to_string(),
+                ));
+            }
+            if self.staging.thin.is_some() || self.staging.overlay.is_some() {
+                return Err(BuilderError::IncompatibleConfiguration(
+                    "Scratch drives can't be thin snapshots or overlays".to_string(),
+                ));
+            }
+            // The image is created in the workspace under the id of the drive
+            if let Some(drive_id) = &self.drive_id {
+                crate::staging::check_file_name(drive_id).map_err(|_| {
+                    BuilderError::InvalidValue(format!(
+                        "Scratch drive id {:?} must be a valid file name",
+                        drive_id
+                    ))
+                })?;
+            }
+            if self.path_on_host.is_none() {
+                self.path_on_host = self.drive_id.clone().map(PathBuf::from);
+            }
+        }
+        if let Some(overlay) = &mut self.staging.overlay {
+            assert_not_none(
+                "DriveBuilder",
+                "path_on_host",
+                "with_path_on_host",
+                &self.path_on_host,
+            )?;
+            if overlay.cow_size == 0 || overlay.cow_size % SECTOR_SIZE != 0 {
+                return Err(BuilderError::InvalidValue(format!(
+                    "Overlay size must be a non-empty multiple of {} bytes, got {}",
+                    SECTOR_SIZE, overlay.cow_size
+                )));
+            }
+            overlay.base = self.path_on_host.clone().unwrap();
+        }
+        let staging = std::mem::take(&mut self.staging);
+        Ok(StagedDrive {
+            drive: self.try_build()?,
+            staging,
+        })
+    }
 }
 
 impl Builder<Drive> for DriveBuilder {
     fn try_build(self) -> Result<Drive, BuilderError> {
-        assert_not_none(stringify!(self.drive_id), &self.drive_id)?;
-        assert_not_none(stringify!(self.path_on_host), &self.path_on_host)?;
+        assert_not_none("DriveBuilder", "drive_id", "with_drive_id", &self.drive_id)?;
+        assert_not_none(
+            "DriveBuilder",
+            "path_on_host",
+            "with_path_on_host",
+            &self.path_on_host,
+        )?;
+        if self.validate_image {
+            validate_drive_image(self.path_on_host.as_ref().unwrap(), self.is_root_device)?;
+        }
         Ok(Drive {
             drive_id: self.drive_id.unwrap(),
             // FIXME: This is a hack to convert PathBuf to String
@@ -59,8 +389,8 @@ impl Builder<Drive> for DriveBuilder {
             is_read_only: self.is_read_only,
             cache_type: None,
             partuuid: None,
-            rate_limiter: None,
-            io_engine: None,
+            rate_limiter: self.rate_limiter.map(Box::new),
+            io_engine: self.io_engine,
         })
     }
 }
@@ -77,7 +407,183 @@ mod tests {
             .as_root_device()
             .as_read_only()
             .try_build();
-        assert_eq!(drive.is_ok(), true);
+        assert!(drive.is_ok());
+    }
+
+    #[test]
+    fn drive_image_validation() {
+        use crate::builder::drive::{detect_filesystem, validate_drive_image};
+
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs.ext4");
+        let mut content = vec![0; 4096];
+        content[1080..1082].copy_from_slice(b"\x53\xef");
+        std::fs::write(&rootfs, &content).unwrap();
+        assert_eq!(detect_filesystem(&rootfs), Some("ext2/3/4"));
+        assert_eq!(validate_drive_image(&rootfs, true), Ok(()));
+
+        let unaligned = dir.path().join("unaligned");
+        std::fs::write(&unaligned, [0; 1000]).unwrap();
+        assert_eq!(detect_filesystem(&unaligned), None);
+        assert!(matches!(
+            validate_drive_image(&unaligned, false),
+            Err(BuilderError::InvalidValue(_))
+        ));
+        assert!(validate_drive_image(dir.path(), false).is_err());
+
+        let drive = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_path_on_host(dir.path().join("missing"))
+            .with_image_validation()
+            .try_build();
+        assert!(drive.is_err());
+    }
+
+    #[test]
+    fn drive_block_device() {
+        use crate::builder::drive::{check_block_device_access, is_block_device};
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("rootfs.ext4");
+        std::fs::write(&file, [0; 512]).unwrap();
+        assert!(!is_block_device(&file));
+        assert!(!is_block_device(&dir.path().join("missing")));
+        assert!(check_block_device_access(&file, true).is_ok());
+        assert!(check_block_device_access(&dir.path().join("missing"), true).is_err());
+    }
+
+    #[test]
+    fn drive_best_io_engine() {
+        let drive = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_path_on_host("/path/to/rootfs".into())
+            .with_best_io_engine()
+            .try_build()
+            .unwrap();
+        assert!(drive.io_engine.is_some());
+    }
+
+    #[test]
+    fn drive_staging_location() {
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("scratch".to_string())
+            .with_path_on_host("/path/to/scratch".into())
+            .with_staging_dir("/mnt/hdd")
+            .try_build_staged()
+            .unwrap();
+        let workspace = std::path::Path::new("/tmp/firepilot/vm");
+        assert_eq!(
+            staged.staging.location(workspace, "vm", "scratch"),
+            std::path::PathBuf::from("/mnt/hdd/vm/scratch")
+        );
+        assert_eq!(
+            crate::builder::drive::DriveStaging::default().location(workspace, "vm", "rootfs"),
+            std::path::PathBuf::from("/tmp/firepilot/vm/rootfs")
+        );
+    }
+
+    #[test]
+    fn drive_thin_snapshot() {
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_thin_snapshot("pool".to_string(), 1, 1 << 30)
+            .try_build_staged()
+            .unwrap();
+        assert_eq!(staged.staging.thin.unwrap().origin_id, 1);
+
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_thin_snapshot("pool".to_string(), 1, 1000)
+            .try_build_staged();
+        assert!(matches!(staged, Err(BuilderError::InvalidValue(_))));
+    }
+
+    #[test]
+    fn drive_without_staging() {
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_path_on_host("/images/rootfs.ext4".into())
+            .without_staging()
+            .try_build_staged()
+            .unwrap();
+        assert!(staged.staging.no_stage);
+        assert_eq!(staged.drive.path_on_host, "/images/rootfs.ext4");
+    }
+
+    #[test]
+    fn drive_overlay() {
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_overlay(1 << 30)
+            .with_path_on_host("/images/rootfs.ext4".into())
+            .as_root_device()
+            .try_build_staged()
+            .unwrap();
+        let overlay = staged.staging.overlay.unwrap();
+        assert_eq!(
+            overlay.base,
+            std::path::PathBuf::from("/images/rootfs.ext4")
+        );
+        assert_eq!(overlay.cow_size, 1 << 30);
+
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_path_on_host("/images/rootfs.ext4".into())
+            .with_overlay(1000)
+            .try_build_staged();
+        assert!(matches!(staged, Err(BuilderError::InvalidValue(_))));
+    }
+
+    #[tokio::test]
+    async fn drive_scratch() {
+        use crate::builder::drive::{create_scratch_image, detect_filesystem};
+
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("scratch".to_string())
+            .as_scratch(16)
+            .try_build_staged()
+            .unwrap();
+        assert_eq!(staged.staging.scratch, Some(16));
+        assert!(!staged.drive.is_read_only);
+
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("scratch".to_string())
+            .as_scratch(16)
+            .as_root_device()
+            .try_build_staged();
+        assert!(matches!(staged, Err(BuilderError::InvalidValue(_))));
+
+        // The image would be created out of the workspace
+        for drive_id in ["../scratch", "data/scratch", "/scratch", ".."] {
+            let staged = crate::builder::drive::DriveBuilder::new()
+                .with_drive_id(drive_id.to_string())
+                .as_scratch(16)
+                .try_build_staged();
+            assert!(
+                matches!(staged, Err(BuilderError::InvalidValue(_))),
+                "{}",
+                drive_id
+            );
+        }
+
+        let staged = crate::builder::drive::DriveBuilder::new()
+            .with_drive_id("scratch".to_string())
+            .as_scratch(16)
+            .with_thin_snapshot("pool".to_string(), 1, 1 << 20)
+            .try_build_staged();
+        assert!(matches!(
+            staged,
+            Err(BuilderError::IncompatibleConfiguration(_))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scratch");
+        if create_scratch_image(&image, 16).await.is_err() {
+            // e2fsprogs isn't installed
+            return;
+        }
+        assert_eq!(std::fs::metadata(&image).unwrap().len(), 16 << 20);
+        assert_eq!(detect_filesystem(&image), Some("ext2/3/4"));
     }
 
     #[test]
@@ -85,10 +591,10 @@ mod tests {
         let drive = crate::builder::drive::DriveBuilder::new()
             .with_drive_id("rootfs".to_string())
             .try_build();
-        assert_eq!(drive.is_err(), true);
+        assert!(drive.is_err());
         assert_eq!(
             drive.err().unwrap(),
-            BuilderError::MissingRequiredField(stringify!(self.path_on_host).to_string())
+            BuilderError::missing("DriveBuilder", "path_on_host", "with_path_on_host")
         );
     }
 
@@ -97,10 +603,10 @@ mod tests {
         let drive = crate::builder::drive::DriveBuilder::new()
             .with_path_on_host("/path/to/rootfs".into())
             .try_build();
-        assert_eq!(drive.is_err(), true);
+        assert!(drive.is_err());
         assert_eq!(
             drive.err().unwrap(),
-            BuilderError::MissingRequiredField(stringify!(self.drive_id).to_string())
+            BuilderError::missing("DriveBuilder", "drive_id", "with_drive_id")
         );
     }
 }
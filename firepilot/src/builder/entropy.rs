@@ -0,0 +1,54 @@
+use firepilot_models::models::{EntropyDevice, RateLimiter};
+
+use super::{Builder, BuilderError};
+
+/// Configure the virtio-rng entropy device of the microVM, which feeds the
+/// guest with random bytes from the host, available since firecracker 1.4
+#[derive(Debug, Default)]
+pub struct EntropyBuilder {
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl EntropyBuilder {
+    pub fn new() -> EntropyBuilder {
+        EntropyBuilder::default()
+    }
+
+    /// Limit the bytes the guest can draw from the device, see
+    /// [RateLimiterBuilder](super::rate_limiter::RateLimiterBuilder)
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> EntropyBuilder {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+}
+
+impl Builder<EntropyDevice> for EntropyBuilder {
+    fn try_build(self) -> Result<EntropyDevice, BuilderError> {
+        Ok(EntropyDevice {
+            rate_limiter: self.rate_limiter.map(Box::new),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::rate_limiter::RateLimiterBuilder;
+
+    #[test]
+    fn entropy_rate_limited() {
+        let rate_limiter = RateLimiterBuilder::new()
+            .with_bandwidth("1MB/s")
+            .try_build()
+            .unwrap();
+        let entropy = EntropyBuilder::new()
+            .with_rate_limiter(rate_limiter.clone())
+            .try_build()
+            .unwrap();
+        assert_eq!(entropy.rate_limiter, Some(Box::new(rate_limiter)));
+        assert_eq!(
+            EntropyBuilder::new().try_build().unwrap(),
+            EntropyDevice::new()
+        );
+    }
+}
@@ -1,11 +1,16 @@
 use std::{
     env::{split_paths, var_os},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
 };
 
 use crate::{
     builder::{Builder, BuilderError},
-    executor::{Executor, FirecrackerExecutor},
+    cgroup::CgroupConfig,
+    executor::{BootMode, Executor, FirecrackerExecutor},
+    network::netns::{resolve_netns, NetnsExecutor},
+    version::{VmmVersion, MODELS_VERSION},
 };
 
 use super::assert_not_none;
@@ -14,6 +19,20 @@ use super::assert_not_none;
 pub struct FirecrackerExecutorBuilder {
     chroot: Option<String>,
     exec_binary: Option<PathBuf>,
+    check_version: bool,
+    min_version: Option<VmmVersion>,
+    console_log: bool,
+    cgroup: Option<CgroupConfig>,
+    netns: Option<PathBuf>,
+    detached_spawn: bool,
+    kill_grace_period: Option<Duration>,
+    boot_mode: BootMode,
+}
+
+impl Default for FirecrackerExecutorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FirecrackerExecutorBuilder {
@@ -21,6 +40,14 @@ impl FirecrackerExecutorBuilder {
         FirecrackerExecutorBuilder {
             chroot: None,
             exec_binary: None,
+            check_version: false,
+            min_version: None,
+            console_log: false,
+            cgroup: None,
+            netns: None,
+            detached_spawn: false,
+            kill_grace_period: None,
+            boot_mode: BootMode::Api,
         }
     }
 
@@ -79,7 +106,7 @@ impl FirecrackerExecutorBuilder {
         Self::find_binary_from_env_location()
             .or_else(Self::find_binary_from_path)
             .or_else(Self::find_binary_from_current_directory)
-            .map(|p| Ok(p))
+            .map(Ok)
             .unwrap_or(Err(BuilderError::BinaryNotFound("Check if FIRECRACKER_LOCATION environment variable is correctly set. For more information check https://docs.rs/firepilot/ ".to_string())))
     }
 
@@ -115,17 +142,165 @@ impl FirecrackerExecutorBuilder {
         self.exec_binary = Some(exec_binary);
         self
     }
+
+    /// Run `firecracker --version` when building the executor and check the
+    /// binary speaks the API version of the models: same major version, and
+    /// at least as recent, see [MODELS_VERSION]
+    pub fn with_version_check(mut self) -> FirecrackerExecutorBuilder {
+        self.check_version = true;
+        self
+    }
+
+    /// Same as [FirecrackerExecutorBuilder::with_version_check], but the
+    /// binary must also be at least the given version
+    pub fn with_min_version(mut self, min_version: VmmVersion) -> FirecrackerExecutorBuilder {
+        self.check_version = true;
+        self.min_version = Some(min_version);
+        self
+    }
+
+    /// Keep the serial console of the guest in the workspace, see
+    /// [Executor::with_console_log]
+    pub fn with_console_log(mut self) -> FirecrackerExecutorBuilder {
+        self.console_log = true;
+        self
+    }
+
+    /// Run firecracker in a dedicated cgroup with the given limits, see
+    /// [crate::cgroup]
+    pub fn with_cgroup(mut self, cgroup: CgroupConfig) -> FirecrackerExecutorBuilder {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// Start firecracker in an existing network namespace, given as a path or
+    /// as a name created with `ip netns add`, see [crate::network::netns]
+    pub fn with_netns<S: AsRef<str>>(mut self, path_or_name: S) -> FirecrackerExecutorBuilder {
+        self.netns = Some(resolve_netns(path_or_name.as_ref()));
+        self
+    }
+
+    /// Spawn firecracker in a new session so it survives this process, see
+    /// [Executor::with_detached_spawn]
+    pub fn with_detached_spawn(mut self) -> FirecrackerExecutorBuilder {
+        self.detached_spawn = true;
+        self
+    }
+
+    /// Time given to firecracker to exit after SIGTERM before it is killed,
+    /// see [Executor::with_kill_grace_period]
+    pub fn with_kill_grace_period(mut self, grace_period: Duration) -> FirecrackerExecutorBuilder {
+        self.kill_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Boot firecracker from a configuration file instead of configuring it
+    /// through its API, see [BootMode]
+    pub fn with_boot_mode(mut self, boot_mode: BootMode) -> FirecrackerExecutorBuilder {
+        self.boot_mode = boot_mode;
+        self
+    }
+
+    /// Version printed by `firecracker --version`
+    fn binary_version(exec_binary: &Path) -> Result<VmmVersion, BuilderError> {
+        let output = Command::new(exec_binary)
+            .arg("--version")
+            .output()
+            .map_err(|e| {
+                BuilderError::IncompatibleBinary(format!(
+                    "Could not run {}: {}",
+                    exec_binary.display(),
+                    e
+                ))
+            })?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Following lines list the snapshot versions, which are not relevant
+        let first_line = stdout.lines().next().unwrap_or_default();
+        first_line.parse().map_err(|e| {
+            BuilderError::IncompatibleBinary(format!(
+                "Could not read the version of {}: {}",
+                exec_binary.display(),
+                e
+            ))
+        })
+    }
+
+    /// Check the binary version against the version of the models and the
+    /// given minimum: the binary must have the same major version as the
+    /// models, and be at least as recent
+    fn check_compatibility(
+        version: VmmVersion,
+        models_version: VmmVersion,
+        min_version: Option<VmmVersion>,
+    ) -> Result<(), BuilderError> {
+        if version.major != models_version.major || version < models_version {
+            return Err(BuilderError::IncompatibleBinary(format!(
+                "firecracker {} is not compatible with the API version {} used by firepilot",
+                version, models_version
+            )));
+        }
+        match min_version {
+            Some(min_version) if version < min_version => {
+                Err(BuilderError::IncompatibleBinary(format!(
+                    "firecracker {} is older than the required version {}",
+                    version, min_version
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Builder<Executor> for FirecrackerExecutorBuilder {
     fn try_build(self) -> Result<Executor, BuilderError> {
-        assert_not_none(stringify!(self.chroot), &self.chroot)?;
-        assert_not_none(stringify!(self.exec_binary), &self.exec_binary)?;
+        assert_not_none(
+            "FirecrackerExecutorBuilder",
+            "chroot",
+            "with_chroot",
+            &self.chroot,
+        )?;
+        assert_not_none(
+            "FirecrackerExecutorBuilder",
+            "exec_binary",
+            "with_exec_binary or auto",
+            &self.exec_binary,
+        )?;
+        if self.check_version {
+            let version = Self::binary_version(self.exec_binary.as_ref().unwrap())?;
+            Self::check_compatibility(version, MODELS_VERSION, self.min_version)?;
+        }
         let executor = FirecrackerExecutor {
             chroot: self.chroot.unwrap(),
             exec_binary: self.exec_binary.unwrap(),
         };
-        Ok(Executor::new_with_firecracker(executor))
+        let mut executor = match self.netns {
+            Some(netns) => {
+                if !netns.exists() {
+                    return Err(BuilderError::InvalidValue(format!(
+                        "Network namespace {} doesn't exist",
+                        netns.display()
+                    )));
+                }
+                Executor::new_with_executor(NetnsExecutor {
+                    firecracker: executor,
+                    netns,
+                })
+            }
+            None => Executor::new_with_firecracker(executor),
+        };
+        if self.console_log {
+            executor = executor.with_console_log();
+        }
+        if let Some(cgroup) = self.cgroup {
+            executor = executor.with_cgroup(cgroup);
+        }
+        if self.detached_spawn {
+            executor = executor.with_detached_spawn();
+        }
+        if let Some(grace_period) = self.kill_grace_period {
+            executor = executor.with_kill_grace_period(grace_period);
+        }
+        Ok(executor.with_boot_mode(self.boot_mode))
     }
 }
 
@@ -138,6 +313,9 @@ mod tests {
     use tempfile::tempdir;
 
     use crate::builder::executor::FirecrackerExecutorBuilder;
+    use crate::builder::BuilderError;
+    use crate::version::VmmVersion;
+
     #[test]
     fn test_firecracker_executor_builder() {
         use super::FirecrackerExecutorBuilder;
@@ -151,6 +329,19 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_firecracker_executor_missing_netns() {
+        use crate::builder::Builder;
+        use std::path::PathBuf;
+
+        let result = FirecrackerExecutorBuilder::new()
+            .with_chroot("/".to_string())
+            .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
+            .with_netns("firepilot-missing-netns")
+            .try_build();
+        assert!(matches!(result, Err(BuilderError::InvalidValue(_))));
+    }
+
     #[test]
     fn test_firecracker_executor_required_fields() {
         use super::FirecrackerExecutorBuilder;
@@ -168,6 +359,61 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_binary_compatibility() {
+        let check = FirecrackerExecutorBuilder::check_compatibility;
+        let models = VmmVersion::new(1, 3, 0);
+        assert!(check(VmmVersion::new(1, 4, 1), models, None).is_ok());
+        assert!(check(
+            VmmVersion::new(1, 4, 1),
+            models,
+            Some(VmmVersion::new(1, 4, 0))
+        )
+        .is_ok());
+        assert!(matches!(
+            check(
+                VmmVersion::new(1, 3, 0),
+                models,
+                Some(VmmVersion::new(1, 4, 0))
+            ),
+            Err(BuilderError::IncompatibleBinary(_))
+        ));
+        assert!(matches!(
+            check(VmmVersion::new(0, 25, 2), models, None),
+            Err(BuilderError::IncompatibleBinary(_))
+        ));
+        assert!(matches!(
+            check(VmmVersion::new(2, 0, 0), models, None),
+            Err(BuilderError::IncompatibleBinary(_))
+        ));
+    }
+
+    #[test]
+    fn test_binary_older_than_models() {
+        let check = FirecrackerExecutorBuilder::check_compatibility;
+        let models = VmmVersion::new(1, 7, 0);
+        assert!(matches!(
+            check(VmmVersion::new(1, 4, 0), models, None),
+            Err(BuilderError::IncompatibleBinary(_))
+        ));
+        assert!(check(VmmVersion::new(1, 7, 0), models, None).is_ok());
+        assert!(check(VmmVersion::new(1, 8, 2), models, None).is_ok());
+    }
+
+    #[test]
+    fn test_version_check_unreadable_binary() {
+        use crate::builder::Builder;
+
+        for binary in ["/bin/echo", "/invalid/firecracker"] {
+            let result = FirecrackerExecutorBuilder::new()
+                .with_chroot("/".to_string())
+                .with_exec_binary(binary.into())
+                .with_version_check()
+                .try_build();
+            assert!(matches!(result, Err(BuilderError::IncompatibleBinary(_))));
+        }
+    }
+
     #[test]
     #[serial]
     fn test_can_determine_binary_location_from_env() {
@@ -190,21 +436,35 @@ mod tests {
     }
 
     #[test]
+    #[serial]
     fn test_can_determine_binary_location_from_path() {
         let dir = tempdir().expect("failed to create temporary directory");
         let file_path = dir.path().join("firecracker");
         let _file = File::create(file_path.clone()).expect("failed to create temporary file");
 
+        let path = var_os("PATH");
         std::env::set_var("PATH", file_path.parent().unwrap());
         println!("{:?}", var_os("PATH"));
         let result = FirecrackerExecutorBuilder::determine_binary_location();
+        restore_path(path);
         assert!(result.is_ok())
     }
 
     #[test]
+    #[serial]
     fn test_cant_determine_binary_location_from_path() {
+        let path = var_os("PATH");
         std::env::set_var("PATH", "/tmp/invalid_path");
         let result = FirecrackerExecutorBuilder::determine_binary_location();
+        restore_path(path);
         assert!(result.is_err())
     }
+
+    /// Other tests spawn binaries looked up in the PATH
+    fn restore_path(path: Option<std::ffi::OsString>) {
+        match path {
+            Some(path) => std::env::set_var("PATH", path),
+            None => std::env::remove_var("PATH"),
+        }
+    }
 }
@@ -0,0 +1,99 @@
+//! Stable hash of a [Configuration], used to key templates and snapshots
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::Configuration;
+
+impl Configuration {
+    /// Hash of the configuration which only changes when the microVM it
+    /// describes changes, e.g. to detect when a cached template or snapshot
+    /// must be rebuilt
+    ///
+    /// The microVM id, the executor and paths which only depend on where the
+    /// machine runs (staging directories, vsock socket) are left out. Drives
+    /// and interfaces are sorted by id so their order doesn't matter.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use firepilot::builder::Configuration;
+    ///
+    /// let a = Configuration::new("vm-a".to_string());
+    /// let b = Configuration::new("vm-b".to_string());
+    /// assert_eq!(a.fingerprint(), b.fingerprint());
+    /// ```
+    pub fn fingerprint(&self) -> String {
+        let mut storage: Vec<Value> = self
+            .storage
+            .iter()
+            .map(|drive| {
+                let thin = self
+                    .staging
+                    .get(&drive.drive_id)
+                    .and_then(|staging| staging.thin.as_ref())
+                    .map(|thin| json!([thin.pool, thin.origin_id, thin.size]));
+                json!({ "drive": drive, "thin": thin })
+            })
+            .collect();
+        storage.sort_by_key(|drive| drive["drive"]["drive_id"].to_string());
+        let mut interfaces: Vec<&_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|iface| &iface.iface_id);
+
+        let normalized = json!({
+            "kernel": self.kernel,
+            "storage": storage,
+            "interfaces": interfaces,
+            "vsock": self.vsock.as_ref().map(|vsock| vsock.guest_cid),
+            "machine_config": self.machine_config,
+            "cpu_config": self.cpu_config,
+            "balloon": self.balloon,
+            "mmds": self.mmds,
+        });
+        format!("{:x}", Sha256::digest(normalized.to_string().as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firepilot_models::models::{BootSource, Drive, NetworkInterface};
+
+    use super::*;
+
+    fn drive(drive_id: &str) -> Drive {
+        Drive::new(
+            drive_id.to_string(),
+            false,
+            false,
+            format!("{}.ext4", drive_id),
+        )
+    }
+
+    #[test]
+    fn test_fingerprint_stable() {
+        let a = Configuration::new("vm-a".to_string())
+            .with_kernel(BootSource::new("vmlinux".to_string()))
+            .with_drive(drive("rootfs"))
+            .with_drive(drive("data"))
+            .with_interface(NetworkInterface::new(
+                "tap0".to_string(),
+                "eth0".to_string(),
+            ));
+        let b = Configuration::new("vm-b".to_string())
+            .with_kernel(BootSource::new("vmlinux".to_string()))
+            .with_drive(drive("data"))
+            .with_drive(drive("rootfs"))
+            .with_interface(NetworkInterface::new(
+                "tap0".to_string(),
+                "eth0".to_string(),
+            ));
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_eq!(a.fingerprint().len(), 64);
+
+        let c = Configuration::new("vm-a".to_string())
+            .with_kernel(BootSource::new("vmlinux-5.10".to_string()))
+            .with_drive(drive("rootfs"))
+            .with_drive(drive("data"));
+        assert_ne!(a.fingerprint(), c.fingerprint());
+    }
+}
@@ -1,13 +1,129 @@
-use crate::builder::{Builder, BuilderError};
+use std::{fs::File, io::Read, path::Path};
+
+use crate::builder::{boot_args::BootArgsBuilder, Builder, BuilderError};
+use crate::host::Arch;
 use firepilot_models::models::BootSource;
 
 use super::assert_not_none;
 
+/// Default IPv4 address on which MMDS is reachable from the guest, used by
+/// firecracker when no `ipv4_address` is given in the MMDS configuration
+pub const DEFAULT_MMDS_ADDRESS: &str = "169.254.169.254";
+
+/// Kernel argument which makes cloud-init use MMDS as its NoCloud datasource,
+/// the seed (`meta-data`, `user-data`) is fetched from the data store root.
+///
+/// When `ipv4_address` is none, [DEFAULT_MMDS_ADDRESS] is used.
+pub fn mmds_datasource_arg(ipv4_address: Option<&str>) -> String {
+    format!(
+        "ds=nocloud-net;s=http://{}/",
+        ipv4_address.unwrap_or(DEFAULT_MMDS_ADDRESS)
+    )
+}
+
+/// Command to run in the guest so the MMDS address is routed through the
+/// given interface, it is needed when the guest has no default route on the
+/// interface MMDS is attached to.
+pub fn mmds_route_command(guest_iface: &str, ipv4_address: Option<&str>) -> String {
+    format!(
+        "ip route add {} dev {}",
+        ipv4_address.unwrap_or(DEFAULT_MMDS_ADDRESS),
+        guest_iface
+    )
+}
+
+/// `e_machine` values of ELF headers for the supported architectures
+const ELF_MACHINE_X86_64: u16 = 62;
+const ELF_MACHINE_AARCH64: u16 = 183;
+
+/// Offset of the `HdrS` signature of the x86 boot protocol, found in bzImages
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+
+/// Offset of the `ARM\x64` signature of arm64 kernel images
+const ARM64_MAGIC_OFFSET: usize = 56;
+
+/// Check the kernel file looks like an image firecracker can boot on the given
+/// architecture: an uncompressed ELF `vmlinux` on x86_64, an ELF or a PE
+/// `Image` on aarch64
+///
+/// Firecracker doesn't decompress kernels, a compressed image or a bzImage
+/// is loaded without error but the guest never boots.
+pub fn validate_kernel_image(path: &Path, arch: Arch) -> Result<(), BuilderError> {
+    let invalid = |reason: &str| {
+        BuilderError::InvalidValue(format!("Kernel image {} {}", path.display(), reason))
+    };
+    let mut header = Vec::with_capacity(BZIMAGE_MAGIC_OFFSET + 4);
+    File::open(path)
+        .and_then(|file| {
+            file.take(BZIMAGE_MAGIC_OFFSET as u64 + 4)
+                .read_to_end(&mut header)
+        })
+        .map_err(|e| invalid(&format!("can't be read: {}", e)))?;
+    let magic_at =
+        |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if magic_at(0, b"\x7fELF") {
+        let machine = header
+            .get(18..20)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| invalid("has a truncated ELF header"))?;
+        let image_arch = match machine {
+            ELF_MACHINE_X86_64 => Arch::X86_64,
+            ELF_MACHINE_AARCH64 => Arch::Aarch64,
+            other => return Err(invalid(&format!("is an ELF for machine {}", other))),
+        };
+        if image_arch != arch {
+            return Err(BuilderError::IncompatibleConfiguration(format!(
+                "Kernel image {} is built for {}, not {}",
+                path.display(),
+                image_arch,
+                arch
+            )));
+        }
+        return Ok(());
+    }
+    if magic_at(BZIMAGE_MAGIC_OFFSET, b"HdrS") {
+        return Err(invalid(
+            "is a compressed bzImage, extract the vmlinux with scripts/extract-vmlinux",
+        ));
+    }
+    if magic_at(ARM64_MAGIC_OFFSET, b"ARM\x64") {
+        return match arch {
+            Arch::Aarch64 => Ok(()),
+            _ => Err(BuilderError::IncompatibleConfiguration(format!(
+                "Kernel image {} is an arm64 Image, not a {} kernel",
+                path.display(),
+                arch
+            ))),
+        };
+    }
+    let compressed = [
+        (&b"\x1f\x8b"[..], "gzip"),
+        (&b"\x28\xb5\x2f\xfd"[..], "zstd"),
+        (&b"\xfd7zXZ\x00"[..], "xz"),
+        (&b"BZh"[..], "bzip2"),
+    ];
+    if let Some((_, format)) = compressed.iter().find(|(magic, _)| magic_at(0, magic)) {
+        return Err(invalid(&format!(
+            "is {} compressed, firecracker needs an uncompressed kernel",
+            format
+        )));
+    }
+    Err(invalid("is neither an ELF vmlinux nor an arm64 Image"))
+}
+
 #[derive(Debug)]
 pub struct KernelBuilder {
     pub boot_args: Option<String>,
     pub initrd_path: Option<String>,
     pub kernel_image_path: Option<String>,
+    pub validate_image: bool,
+}
+
+impl Default for KernelBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KernelBuilder {
@@ -16,6 +132,7 @@ impl KernelBuilder {
             boot_args: None,
             initrd_path: None,
             kernel_image_path: None,
+            validate_image: false,
         }
     }
 
@@ -24,6 +141,28 @@ impl KernelBuilder {
         self
     }
 
+    /// Use the boot args recommended for the host architecture, see
+    /// [BootArgsBuilder::defaults]
+    pub fn with_default_boot_args(self) -> KernelBuilder {
+        self.with_boot_args(BootArgsBuilder::defaults().build())
+    }
+
+    /// Append a kernel argument to the existing boot args
+    pub fn with_boot_arg(mut self, arg: String) -> KernelBuilder {
+        self.boot_args = Some(match self.boot_args {
+            Some(boot_args) if !boot_args.is_empty() => format!("{} {}", boot_args, arg),
+            _ => arg,
+        });
+        self
+    }
+
+    /// Append the kernel argument which makes cloud-init fetch its seed from
+    /// MMDS, see [mmds_datasource_arg]. `ipv4_address` must match the one given
+    /// in the MMDS configuration, if any.
+    pub fn with_mmds_datasource(self, ipv4_address: Option<&str>) -> KernelBuilder {
+        self.with_boot_arg(mmds_datasource_arg(ipv4_address))
+    }
+
     pub fn with_initrd_path(mut self, initrd_path: String) -> KernelBuilder {
         self.initrd_path = Some(initrd_path);
         self
@@ -33,11 +172,29 @@ impl KernelBuilder {
         self.kernel_image_path = Some(kernel_image_path);
         self
     }
+
+    /// Check the kernel image can be booted on the host when building, see
+    /// [validate_kernel_image]
+    pub fn with_image_validation(mut self) -> KernelBuilder {
+        self.validate_image = true;
+        self
+    }
 }
 
 impl Builder<BootSource> for KernelBuilder {
     fn try_build(self) -> Result<BootSource, BuilderError> {
-        assert_not_none(stringify!(self.kernel_image_path), &self.kernel_image_path)?;
+        assert_not_none(
+            "KernelBuilder",
+            "kernel_image_path",
+            "with_kernel_image_path",
+            &self.kernel_image_path,
+        )?;
+        if self.validate_image {
+            validate_kernel_image(
+                Path::new(self.kernel_image_path.as_ref().unwrap()),
+                Arch::host(),
+            )?;
+        }
         Ok(BootSource {
             kernel_image_path: self.kernel_image_path.unwrap(),
             initrd_path: self.initrd_path,
@@ -48,8 +205,85 @@ impl Builder<BootSource> for KernelBuilder {
 
 #[cfg(test)]
 mod tests {
-    use crate::builder::kernel::KernelBuilder;
-    use crate::builder::Builder;
+    use crate::builder::kernel::{mmds_route_command, validate_kernel_image, KernelBuilder};
+    use crate::builder::{Builder, BuilderError};
+    use crate::host::Arch;
+
+    fn image(content: &[(usize, &[u8])]) -> tempfile::NamedTempFile {
+        let mut bytes = vec![0; 1024];
+        for (offset, magic) in content {
+            bytes[*offset..offset + magic.len()].copy_from_slice(magic);
+        }
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), bytes).unwrap();
+        file
+    }
+
+    #[test]
+    fn kernel_image_formats() {
+        let vmlinux = image(&[(0, b"\x7fELF\x02"), (18, &62u16.to_le_bytes())]);
+        assert_eq!(validate_kernel_image(vmlinux.path(), Arch::X86_64), Ok(()));
+        assert!(matches!(
+            validate_kernel_image(vmlinux.path(), Arch::Aarch64),
+            Err(BuilderError::IncompatibleConfiguration(_))
+        ));
+
+        let arm_image = image(&[(0, b"MZ"), (56, b"ARM\x64")]);
+        assert_eq!(
+            validate_kernel_image(arm_image.path(), Arch::Aarch64),
+            Ok(())
+        );
+
+        let bzimage = image(&[(0, b"MZ"), (0x202, b"HdrS")]);
+        let gzip = image(&[(0, b"\x1f\x8b")]);
+        for invalid in [bzimage, gzip] {
+            assert!(matches!(
+                validate_kernel_image(invalid.path(), Arch::X86_64),
+                Err(BuilderError::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn kernel_image_validation_missing_file() {
+        let result = KernelBuilder::new()
+            .with_kernel_image_path("/invalid/vmlinux".to_string())
+            .with_image_validation()
+            .try_build();
+        assert!(matches!(result, Err(BuilderError::InvalidValue(_))));
+    }
+
+    #[test]
+    fn kernel_with_mmds_datasource() {
+        let kernel = KernelBuilder::new()
+            .with_kernel_image_path("path/to/kernel".to_string())
+            .with_boot_args("console=ttyS0".to_string())
+            .with_mmds_datasource(None)
+            .try_build()
+            .unwrap();
+        assert_eq!(
+            kernel.boot_args.unwrap(),
+            "console=ttyS0 ds=nocloud-net;s=http://169.254.169.254/"
+        );
+
+        let kernel = KernelBuilder::new()
+            .with_kernel_image_path("path/to/kernel".to_string())
+            .with_mmds_datasource(Some("169.254.170.2"))
+            .try_build()
+            .unwrap();
+        assert_eq!(
+            kernel.boot_args.unwrap(),
+            "ds=nocloud-net;s=http://169.254.170.2/"
+        );
+    }
+
+    #[test]
+    fn mmds_route() {
+        assert_eq!(
+            mmds_route_command("eth0", None),
+            "ip route add 169.254.169.254 dev eth0"
+        );
+    }
 
     #[test]
     fn full_kernel() {
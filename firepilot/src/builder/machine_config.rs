@@ -0,0 +1,325 @@
+use crate::builder::units::parse_size;
+use crate::builder::{assert_not_none, Builder, BuilderError};
+use crate::host::Arch;
+#[cfg(feature = "firecracker-1-7")]
+use firepilot_models::models::HugePages;
+use firepilot_models::models::{CpuTemplate, MachineConfiguration};
+
+/// Maximum number of vCPUs firecracker accepts for a microVM
+pub const MAX_VCPU_COUNT: i32 = 32;
+
+/// Configure the vCPUs and memory of the microVM
+///
+/// The configuration is validated against the target architecture, which is
+/// the host one by default: CPU templates and SMT are only supported on x86_64.
+#[derive(Debug)]
+pub struct MachineConfigurationBuilder {
+    pub vcpu_count: Option<i32>,
+    pub mem_size_mib: Option<i32>,
+    pub mem_size: Option<String>,
+    pub smt: Option<bool>,
+    pub cpu_template: Option<CpuTemplate>,
+    pub track_dirty_pages: Option<bool>,
+    #[cfg(feature = "firecracker-1-7")]
+    pub huge_pages: Option<HugePages>,
+    pub arch: Arch,
+}
+
+impl Default for MachineConfigurationBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MachineConfigurationBuilder {
+    pub fn new() -> MachineConfigurationBuilder {
+        MachineConfigurationBuilder {
+            vcpu_count: None,
+            mem_size_mib: None,
+            mem_size: None,
+            smt: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            #[cfg(feature = "firecracker-1-7")]
+            huge_pages: None,
+            arch: Arch::host(),
+        }
+    }
+
+    pub fn with_vcpu_count(mut self, vcpu_count: i32) -> MachineConfigurationBuilder {
+        self.vcpu_count = Some(vcpu_count);
+        self
+    }
+
+    pub fn with_mem_size_mib(mut self, mem_size_mib: i32) -> MachineConfigurationBuilder {
+        self.mem_size_mib = Some(mem_size_mib);
+        self.mem_size = None;
+        self
+    }
+
+    /// Set the memory size from a human-friendly value such as `"512MiB"` or
+    /// `"2G"`, it must be a whole number of MiB
+    pub fn with_mem_size<S: Into<String>>(mut self, mem_size: S) -> MachineConfigurationBuilder {
+        self.mem_size = Some(mem_size.into());
+        self.mem_size_mib = None;
+        self
+    }
+
+    pub fn with_smt(mut self, smt: bool) -> MachineConfigurationBuilder {
+        self.smt = Some(smt);
+        self
+    }
+
+    pub fn with_cpu_template(mut self, cpu_template: CpuTemplate) -> MachineConfigurationBuilder {
+        self.cpu_template = Some(cpu_template);
+        self
+    }
+
+    pub fn with_track_dirty_pages(
+        mut self,
+        track_dirty_pages: bool,
+    ) -> MachineConfigurationBuilder {
+        self.track_dirty_pages = Some(track_dirty_pages);
+        self
+    }
+
+    /// Back the guest memory with huge pages, since firecracker 1.7
+    #[cfg(feature = "firecracker-1-7")]
+    pub fn with_huge_pages(mut self, huge_pages: HugePages) -> MachineConfigurationBuilder {
+        self.huge_pages = Some(huge_pages);
+        self
+    }
+
+    /// Validate the configuration for another architecture than the host one
+    pub fn for_arch(mut self, arch: Arch) -> MachineConfigurationBuilder {
+        self.arch = arch;
+        self
+    }
+
+    /// Memory size in MiB, from whichever setter was used last
+    fn resolve_mem_size_mib(&self) -> Result<Option<i32>, BuilderError> {
+        let mem_size = match &self.mem_size {
+            Some(mem_size) => mem_size,
+            None => return Ok(self.mem_size_mib),
+        };
+        let bytes = parse_size(mem_size)?;
+        if bytes % (1 << 20) != 0 {
+            return Err(BuilderError::InvalidValue(format!(
+                "Memory size must be a whole number of MiB, got {:?}",
+                mem_size
+            )));
+        }
+        let mib = i32::try_from(bytes >> 20).map_err(|_| {
+            BuilderError::InvalidValue(format!("Memory size {:?} is too large", mem_size))
+        })?;
+        Ok(Some(mib))
+    }
+
+    /// Reject combinations which firecracker refuses on the target architecture
+    fn validate_arch(&self) -> Result<(), BuilderError> {
+        if self.arch != Arch::X86_64 {
+            if let Some(template) = self.cpu_template.filter(|t| *t != CpuTemplate::None) {
+                return Err(BuilderError::IncompatibleConfiguration(format!(
+                    "CPU template {} is only available on x86_64, not on {}",
+                    template.to_string(),
+                    self.arch
+                )));
+            }
+            if self.smt == Some(true) {
+                return Err(BuilderError::IncompatibleConfiguration(format!(
+                    "SMT can only be enabled on x86_64, not on {}",
+                    self.arch
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Builder<MachineConfiguration> for MachineConfigurationBuilder {
+    fn try_build(mut self) -> Result<MachineConfiguration, BuilderError> {
+        self.mem_size_mib = self.resolve_mem_size_mib()?;
+        assert_not_none(
+            "MachineConfigurationBuilder",
+            "vcpu_count",
+            "with_vcpu_count",
+            &self.vcpu_count,
+        )?;
+        assert_not_none(
+            "MachineConfigurationBuilder",
+            "mem_size_mib",
+            "with_mem_size_mib or with_mem_size",
+            &self.mem_size_mib,
+        )?;
+        let vcpu_count = self.vcpu_count.unwrap();
+        let mem_size_mib = self.mem_size_mib.unwrap();
+        if !(1..=MAX_VCPU_COUNT).contains(&vcpu_count) {
+            return Err(BuilderError::InvalidValue(format!(
+                "vcpu_count must be between 1 and {}, got {}",
+                MAX_VCPU_COUNT, vcpu_count
+            )));
+        }
+        // Hyperthreads come in pairs
+        if self.smt == Some(true) && vcpu_count > 1 && vcpu_count % 2 != 0 {
+            return Err(BuilderError::InvalidValue(format!(
+                "vcpu_count must be 1 or an even number with SMT enabled, got {}",
+                vcpu_count
+            )));
+        }
+        if mem_size_mib < 1 {
+            return Err(BuilderError::InvalidValue(format!(
+                "mem_size_mib must be positive, got {}",
+                mem_size_mib
+            )));
+        }
+        self.validate_arch()?;
+        let mut machine_config = MachineConfiguration::new(mem_size_mib, vcpu_count);
+        machine_config.cpu_template = self.cpu_template;
+        machine_config.smt = self.smt;
+        machine_config.track_dirty_pages = self.track_dirty_pages;
+        #[cfg(feature = "firecracker-1-7")]
+        {
+            machine_config.huge_pages = self.huge_pages;
+        }
+        Ok(machine_config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_config_full() {
+        let config = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size_mib(1024)
+            .with_track_dirty_pages(true)
+            .try_build()
+            .unwrap();
+        assert_eq!(config.vcpu_count, 2);
+        assert_eq!(config.mem_size_mib, 1024);
+        assert_eq!(config.track_dirty_pages, Some(true));
+    }
+
+    #[test]
+    fn machine_config_human_mem_size() {
+        let config = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size("2GiB")
+            .try_build()
+            .unwrap();
+        assert_eq!(config.mem_size_mib, 2048);
+
+        let config = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size("2GiB")
+            .with_mem_size_mib(256)
+            .try_build()
+            .unwrap();
+        assert_eq!(config.mem_size_mib, 256);
+
+        for mem_size in ["1.5MiB", "1GB", "lots"] {
+            let result = MachineConfigurationBuilder::new()
+                .with_vcpu_count(2)
+                .with_mem_size(mem_size)
+                .try_build();
+            assert!(matches!(result, Err(BuilderError::InvalidValue(_))));
+        }
+    }
+
+    #[test]
+    fn machine_config_missing_fields() {
+        let result = MachineConfigurationBuilder::new()
+            .with_vcpu_count(1)
+            .try_build();
+        assert_eq!(
+            result.unwrap_err(),
+            BuilderError::missing(
+                "MachineConfigurationBuilder",
+                "mem_size_mib",
+                "with_mem_size_mib or with_mem_size"
+            )
+        );
+    }
+
+    #[test]
+    fn machine_config_invalid_vcpu_count() {
+        for vcpu_count in [0, 34] {
+            let result = MachineConfigurationBuilder::new()
+                .with_vcpu_count(vcpu_count)
+                .with_mem_size_mib(128)
+                .try_build();
+            assert!(matches!(result, Err(BuilderError::InvalidValue(_))));
+        }
+        let builder = || {
+            MachineConfigurationBuilder::new()
+                .with_vcpu_count(3)
+                .with_mem_size_mib(128)
+                .for_arch(Arch::X86_64)
+        };
+        assert!(builder().try_build().is_ok());
+        assert!(matches!(
+            builder().with_smt(true).try_build(),
+            Err(BuilderError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn machine_config_x86_template() {
+        let config = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size_mib(1024)
+            .with_cpu_template(CpuTemplate::T2)
+            .with_smt(true)
+            .for_arch(Arch::X86_64)
+            .try_build();
+        assert!(config.is_ok());
+    }
+
+    #[test]
+    fn machine_config_aarch64_incompatible() {
+        let template = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size_mib(1024)
+            .with_cpu_template(CpuTemplate::C3)
+            .for_arch(Arch::Aarch64)
+            .try_build();
+        assert!(matches!(
+            template,
+            Err(BuilderError::IncompatibleConfiguration(_))
+        ));
+
+        let smt = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size_mib(1024)
+            .with_smt(true)
+            .for_arch(Arch::Aarch64)
+            .try_build();
+        assert!(matches!(
+            smt,
+            Err(BuilderError::IncompatibleConfiguration(_))
+        ));
+
+        let no_template = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size_mib(1024)
+            .with_cpu_template(CpuTemplate::None)
+            .for_arch(Arch::Aarch64)
+            .try_build();
+        assert!(no_template.is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "firecracker-1-7")]
+    fn machine_config_huge_pages() {
+        let config = MachineConfigurationBuilder::new()
+            .with_vcpu_count(2)
+            .with_mem_size_mib(1024)
+            .with_huge_pages(HugePages::Variant2M)
+            .try_build()
+            .unwrap();
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["huge_pages"], "2M");
+    }
+}
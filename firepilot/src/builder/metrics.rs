@@ -0,0 +1,73 @@
+use firepilot_models::models::Metrics;
+
+use super::{Builder, BuilderError};
+
+/// Name of the metrics FIFO, relative to the machine workspace, when none is
+/// given
+pub const DEFAULT_METRICS_FIFO: &str = "metrics.fifo";
+
+/// Configure where firecracker writes its metrics, which cover the devices,
+/// the API and seccomp
+///
+/// A relative `metrics_path` is resolved in the machine workspace when the
+/// machine is created, and a FIFO is created there if the path doesn't exist.
+/// Metrics are flushed every minute as one JSON object per line, the FIFO must
+/// be read or firecracker drops them.
+#[derive(Debug)]
+pub struct MetricsBuilder {
+    pub metrics_path: String,
+}
+
+impl Default for MetricsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetricsBuilder {
+    pub fn new() -> MetricsBuilder {
+        MetricsBuilder {
+            metrics_path: DEFAULT_METRICS_FIFO.to_string(),
+        }
+    }
+
+    pub fn with_metrics_path(mut self, metrics_path: String) -> MetricsBuilder {
+        self.metrics_path = metrics_path;
+        self
+    }
+}
+
+impl Builder<Metrics> for MetricsBuilder {
+    fn try_build(self) -> Result<Metrics, BuilderError> {
+        if self.metrics_path.is_empty() {
+            return Err(BuilderError::missing(
+                "MetricsBuilder",
+                "metrics_path",
+                "with_metrics_path",
+            ));
+        }
+        Ok(Metrics::new(self.metrics_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_default() {
+        let metrics = MetricsBuilder::new().try_build().unwrap();
+        assert_eq!(metrics.metrics_path, DEFAULT_METRICS_FIFO);
+    }
+
+    #[test]
+    fn metrics_empty_path() {
+        let metrics = MetricsBuilder::new()
+            .with_metrics_path(String::new())
+            .try_build();
+        assert!(matches!(
+            metrics,
+            Err(BuilderError::MissingRequiredField { .. })
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+use std::net::Ipv4Addr;
+
+pub use firepilot_models::models::mmds_config::Version as MmdsVersion;
+use firepilot_models::models::MmdsConfig;
+
+use super::{Builder, BuilderError};
+
+/// Configure the microVM metadata service (MMDS), a data store the guest
+/// reads over HTTP through one of its network interfaces
+///
+/// The data store itself is filled once the microVM runs, see
+/// [Machine::put_metadata](crate::machine::Machine::put_metadata).
+#[derive(Debug, Default)]
+pub struct MmdsBuilder {
+    pub version: Option<MmdsVersion>,
+    /// Ids of the network interfaces from which MMDS is reachable
+    pub network_interfaces: Vec<String>,
+    pub ipv4_address: Option<String>,
+}
+
+impl MmdsBuilder {
+    pub fn new() -> MmdsBuilder {
+        MmdsBuilder::default()
+    }
+
+    /// Protocol of the service, firecracker defaults to V1. V2 requires the
+    /// guest to get a session token before reading metadata.
+    pub fn with_version(mut self, version: MmdsVersion) -> MmdsBuilder {
+        self.version = Some(version);
+        self
+    }
+
+    /// Make MMDS reachable from the interface with the given id, it must be
+    /// configured on the microVM as well
+    pub fn with_network_interface(mut self, iface_id: String) -> MmdsBuilder {
+        self.network_interfaces.push(iface_id);
+        self
+    }
+
+    /// Address on which the guest reaches MMDS, defaults to
+    /// [DEFAULT_MMDS_ADDRESS](crate::builder::kernel::DEFAULT_MMDS_ADDRESS)
+    pub fn with_ipv4_address(mut self, ipv4_address: String) -> MmdsBuilder {
+        self.ipv4_address = Some(ipv4_address);
+        self
+    }
+}
+
+impl Builder<MmdsConfig> for MmdsBuilder {
+    fn try_build(self) -> Result<MmdsConfig, BuilderError> {
+        if self.network_interfaces.is_empty() {
+            return Err(BuilderError::missing(
+                "MmdsBuilder",
+                "network_interfaces",
+                "with_network_interface",
+            ));
+        }
+        if let Some(address) = &self.ipv4_address {
+            match address.parse::<Ipv4Addr>() {
+                Ok(ip) if ip.is_link_local() => {}
+                _ => {
+                    return Err(BuilderError::InvalidValue(format!(
+                        "ipv4_address must be a link-local IPv4 address, got {}",
+                        address
+                    )))
+                }
+            }
+        }
+        Ok(MmdsConfig {
+            version: self.version,
+            network_interfaces: self.network_interfaces,
+            ipv4_address: self.ipv4_address,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmds_full() {
+        let mmds = MmdsBuilder::new()
+            .with_version(MmdsVersion::V2)
+            .with_network_interface("eth0".to_string())
+            .with_ipv4_address("169.254.170.2".to_string())
+            .try_build()
+            .unwrap();
+        assert_eq!(mmds.version, Some(MmdsVersion::V2));
+        assert_eq!(mmds.network_interfaces, vec!["eth0".to_string()]);
+        assert_eq!(mmds.ipv4_address.as_deref(), Some("169.254.170.2"));
+    }
+
+    #[test]
+    fn mmds_invalid() {
+        assert!(matches!(
+            MmdsBuilder::new().try_build(),
+            Err(BuilderError::MissingRequiredField { .. })
+        ));
+        let mmds = MmdsBuilder::new()
+            .with_network_interface("eth0".to_string())
+            .with_ipv4_address("10.0.0.1".to_string())
+            .try_build();
+        assert!(matches!(mmds, Err(BuilderError::InvalidValue(_))));
+    }
+}
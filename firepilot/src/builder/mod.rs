@@ -49,28 +49,96 @@
 //!     .with_executor(executor)
 //!     .with_drive(drive);
 //! ```
+use std::collections::HashMap;
+
+use tokio::sync::mpsc::UnboundedSender;
+
 use crate::executor::Executor;
+use crate::network::{ipam::IpPool, tap::TapConfig};
+use crate::staging::{CopyStrategy, StagedArtifact};
 
-use firepilot_models::models::{BootSource, Drive, NetworkInterface};
+use self::drive::{DriveStaging, StagedDrive};
+use self::network_interface::TapInterface;
+#[cfg(feature = "firecracker-1-4")]
+use firepilot_models::models::EntropyDevice;
+use firepilot_models::models::{
+    Balloon, BootSource, Drive, MachineConfiguration, Metrics, MmdsConfig, NetworkInterface, Vsock,
+};
 
+pub mod balloon;
+pub mod boot_args;
+mod config_file;
+pub mod cpu_config;
 pub mod drive;
+#[cfg(feature = "firecracker-1-4")]
+pub mod entropy;
 pub mod executor;
+mod fingerprint;
 pub mod kernel;
+pub mod machine_config;
+pub mod metrics;
+pub mod mmds;
 pub mod network_interface;
+mod preboot;
+pub mod rate_limiter;
+pub mod units;
+mod validate;
+pub mod vsock;
 
-fn assert_not_none<T>(key: &str, value: &Option<T>) -> Result<(), BuilderError> {
+/// Longest microVM id accepted, the id is also used in the names of the
+/// workspace and of the devices created for the machine
+pub const MAX_VM_ID_LEN: usize = 64;
+
+fn assert_not_none<T>(
+    component: &'static str,
+    field: &'static str,
+    hint: &'static str,
+    value: &Option<T>,
+) -> Result<(), BuilderError> {
     match value {
         Some(_) => Ok(()),
-        None => return Err(BuilderError::MissingRequiredField(key.to_string())),
+        None => Err(BuilderError::missing(component, field, hint)),
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
 pub enum BuilderError {
     /// The field is required but was not provided in the builder object
-    MissingRequiredField(String),
+    #[error("{component}: {field} is required, set it with {hint}")]
+    MissingRequiredField {
+        /// Builder, or [Configuration], missing the field
+        component: &'static str,
+        field: &'static str,
+        /// Method setting the field
+        hint: &'static str,
+    },
     /// Happens when using auto methods to detect firecracker /jailer binary
+    #[error("Binary not found: {0}")]
     BinaryNotFound(String),
+    /// A field was provided but its value is not accepted by firecracker
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    /// Combination of fields which is not supported on the target host, e.g.
+    /// CPU templates on aarch64
+    #[error("Incompatible configuration: {0}")]
+    IncompatibleConfiguration(String),
+    /// The firecracker binary doesn't meet the version requirements
+    #[error("Incompatible binary: {0}")]
+    IncompatibleBinary(String),
+}
+
+impl BuilderError {
+    pub(crate) const fn missing(
+        component: &'static str,
+        field: &'static str,
+        hint: &'static str,
+    ) -> BuilderError {
+        BuilderError::MissingRequiredField {
+            component,
+            field,
+            hint,
+        }
+    }
 }
 
 /// Generic trait which all builder componenet must implement in order to be
@@ -101,7 +169,33 @@ pub struct Configuration {
     pub executor: Option<Executor>,
     pub kernel: Option<BootSource>,
     pub storage: Vec<Drive>,
+    /// Staging options of drives, indexed by drive id. Drives without an entry
+    /// are copied in the machine workspace.
+    pub staging: HashMap<String, DriveStaging>,
+    /// How the kernel, the initrd and the drives are staged in the workspace
+    pub copy_strategy: CopyStrategy,
+    /// Where each staged artifact is reported, see [crate::staging]
+    pub staging_progress: Option<UnboundedSender<StagedArtifact>>,
     pub interfaces: Vec<NetworkInterface>,
+    /// TAP devices created along with the machine, indexed by interface id
+    pub taps: HashMap<String, TapConfig>,
+    /// Pool the address of the guest is leased from, see [IpPool]
+    pub auto_ip: Option<IpPool>,
+    /// Interface of the guest configured with the leased address through the
+    /// `ip=` kernel parameter
+    pub ip_boot_arg: Option<String>,
+    pub vsock: Option<Vsock>,
+    /// vCPUs and memory of the microVM, firecracker defaults to 1 vCPU and
+    /// 128 MiB when none is given
+    pub machine_config: Option<MachineConfiguration>,
+    /// Custom CPU template, see [cpu_config::CpuConfigBuilder]
+    pub cpu_config: Option<serde_json::Value>,
+    pub balloon: Option<Balloon>,
+    pub mmds: Option<MmdsConfig>,
+    pub metrics: Option<Metrics>,
+    /// virtio-rng device, see [entropy::EntropyBuilder]
+    #[cfg(feature = "firecracker-1-4")]
+    pub entropy: Option<EntropyDevice>,
 
     pub vm_id: String,
 }
@@ -112,7 +206,21 @@ impl Configuration {
             kernel: None,
             executor: None,
             storage: Vec::new(),
+            staging: HashMap::new(),
+            copy_strategy: CopyStrategy::default(),
+            staging_progress: None,
             interfaces: Vec::new(),
+            taps: HashMap::new(),
+            auto_ip: None,
+            ip_boot_arg: None,
+            vsock: None,
+            machine_config: None,
+            cpu_config: None,
+            balloon: None,
+            mmds: None,
+            metrics: None,
+            #[cfg(feature = "firecracker-1-4")]
+            entropy: None,
             vm_id,
         }
     }
@@ -133,10 +241,93 @@ impl Configuration {
         self
     }
 
+    /// Add a drive along with its staging options
+    pub fn with_staged_drive(mut self, staged: StagedDrive) -> Configuration {
+        self.staging
+            .insert(staged.drive.drive_id.clone(), staged.staging);
+        self.with_drive(staged.drive)
+    }
+
+    /// Stage the artifacts with the given strategy instead of copying them,
+    /// see [crate::staging]
+    pub fn with_copy_strategy(mut self, copy_strategy: CopyStrategy) -> Configuration {
+        self.copy_strategy = copy_strategy;
+        self
+    }
+
+    /// Report each artifact once staged in the workspace, e.g. to show the
+    /// progress of the creation of a machine with large drives
+    pub fn with_staging_progress(
+        mut self,
+        progress: UnboundedSender<StagedArtifact>,
+    ) -> Configuration {
+        self.staging_progress = Some(progress);
+        self
+    }
+
     pub fn with_interface(mut self, iface: NetworkInterface) -> Configuration {
         self.interfaces.push(iface);
         self
     }
+
+    /// Add an interface whose TAP device is created along with the machine
+    pub fn with_tap_interface(mut self, iface: TapInterface) -> Configuration {
+        self.taps
+            .insert(iface.interface.iface_id.clone(), iface.tap);
+        self.interfaces.push(iface.interface);
+        self
+    }
+
+    /// Lease an address to the guest from the pool when the machine is created
+    pub fn with_auto_ip(mut self, pool: IpPool) -> Configuration {
+        self.auto_ip = Some(pool);
+        self
+    }
+
+    /// Append the `ip=` kernel parameter matching the address leased from the
+    /// pool given to [Configuration::with_auto_ip], so the guest is configured
+    /// before init runs. The interface is the name of the device in the guest,
+    /// e.g. `eth0`; the gateway is the one of the pool.
+    pub fn with_ip_boot_arg(mut self, guest_interface: String) -> Configuration {
+        self.ip_boot_arg = Some(guest_interface);
+        self
+    }
+
+    pub fn with_vsock(mut self, vsock: Vsock) -> Configuration {
+        self.vsock = Some(vsock);
+        self
+    }
+
+    pub fn with_machine_config(mut self, machine_config: MachineConfiguration) -> Configuration {
+        self.machine_config = Some(machine_config);
+        self
+    }
+
+    pub fn with_cpu_config(mut self, cpu_config: serde_json::Value) -> Configuration {
+        self.cpu_config = Some(cpu_config);
+        self
+    }
+
+    pub fn with_balloon(mut self, balloon: Balloon) -> Configuration {
+        self.balloon = Some(balloon);
+        self
+    }
+
+    pub fn with_mmds(mut self, mmds: MmdsConfig) -> Configuration {
+        self.mmds = Some(mmds);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Configuration {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    #[cfg(feature = "firecracker-1-4")]
+    pub fn with_entropy(mut self, entropy: EntropyDevice) -> Configuration {
+        self.entropy = Some(entropy);
+        self
+    }
 }
 
 #[cfg(test)]
@@ -147,23 +338,16 @@ mod tests {
     fn macro_assert_not_none() {
         let x = Some(1);
         let y: Option<String> = None;
-        assert_eq!(assert_not_none("x", &x), Ok(()));
+        assert_eq!(assert_not_none("Test", "x", "with_x", &x), Ok(()));
+        let err = assert_not_none("Test", "y", "with_y", &y).unwrap_err();
         assert_eq!(
-            assert_not_none("y", &y),
-            Err(BuilderError::MissingRequiredField("y".to_string()))
+            err,
+            BuilderError::MissingRequiredField {
+                component: "Test",
+                field: "y",
+                hint: "with_y"
+            }
         );
-    }
-
-    struct TestStruct {
-        #[allow(dead_code)]
-        some_field: Option<String>,
-    }
-
-    #[test]
-    fn stringify_from_struct() {
-        let _str = TestStruct {
-            some_field: Some("some value".to_string()),
-        };
-        assert_eq!(stringify!(_str.some_field), "_str.some_field");
+        assert_eq!(err.to_string(), "Test: y is required, set it with with_y");
     }
 }
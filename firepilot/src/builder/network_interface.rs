@@ -1,7 +1,85 @@
+use std::path::Path;
+
 use firepilot_models::models::{NetworkInterface, RateLimiter};
 
+use crate::network::tap::{TapConfig, MAX_IFNAME_LEN};
+
 use super::{assert_not_none, Builder, BuilderError};
 
+/// Where the kernel exposes the network devices of the host
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Flag of `tun_flags` set on TAP devices, as opposed to TUN devices
+const IFF_TAP: u32 = 0x0002;
+
+/// Check the device exists on the host and is a TAP device, which is the only
+/// kind of device firecracker can attach to a microVM
+pub fn check_tap_device(host_dev_name: &str) -> Result<(), BuilderError> {
+    check_tap_device_in(Path::new(SYS_CLASS_NET), host_dev_name)
+}
+
+fn check_tap_device_in(sys_class_net: &Path, host_dev_name: &str) -> Result<(), BuilderError> {
+    let device = sys_class_net.join(host_dev_name);
+    if !device.exists() {
+        return Err(BuilderError::InvalidValue(format!(
+            "Host device {} doesn't exist",
+            host_dev_name
+        )));
+    }
+    let flags = std::fs::read_to_string(device.join("tun_flags"))
+        .ok()
+        .and_then(|flags| u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok());
+    match flags {
+        Some(flags) if flags & IFF_TAP != 0 => Ok(()),
+        Some(_) => Err(BuilderError::InvalidValue(format!(
+            "Host device {} is a TUN device, a TAP device is expected",
+            host_dev_name
+        ))),
+        None => Err(BuilderError::InvalidValue(format!(
+            "Host device {} is not a TAP device",
+            host_dev_name
+        ))),
+    }
+}
+
+/// Check the syntax of a MAC address and normalize it to lowercase, colon
+/// separated octets, as in `06:00:ac:10:00:02`
+///
+/// Octets may be separated by `:` or `-`. Multicast addresses are rejected as
+/// they can't be assigned to an interface.
+pub fn normalize_mac(mac: &str) -> Result<String, BuilderError> {
+    let invalid = || BuilderError::InvalidValue(format!("Invalid MAC address {}", mac));
+    let octets = mac
+        .split([':', '-'])
+        .map(|octet| match octet.len() {
+            2 => u8::from_str_radix(octet, 16).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<u8>, BuilderError>>()?;
+    if octets.len() != 6 {
+        return Err(invalid());
+    }
+    if octets[0] & 0x01 != 0 {
+        return Err(BuilderError::InvalidValue(format!(
+            "MAC address {} is a multicast address",
+            mac
+        )));
+    }
+    Ok(octets
+        .iter()
+        .map(|octet| format!("{:02x}", octet))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+/// Network interface along with the TAP device to create for it, built by
+/// [NetworkInterfaceBuilder::try_build_with_tap]
+#[derive(Debug, Clone)]
+pub struct TapInterface {
+    pub interface: NetworkInterface,
+    pub tap: TapConfig,
+}
+
 #[derive(Debug)]
 pub struct NetworkInterfaceBuilder {
     guest_mac: Option<String>,
@@ -9,6 +87,14 @@ pub struct NetworkInterfaceBuilder {
     iface_id: Option<String>,
     rx_rate_limiter: Option<Box<RateLimiter>>,
     tx_rate_limiter: Option<Box<RateLimiter>>,
+    check_host_dev: bool,
+    tap: Option<TapConfig>,
+}
+
+impl Default for NetworkInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkInterfaceBuilder {
@@ -19,9 +105,13 @@ impl NetworkInterfaceBuilder {
             iface_id: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            check_host_dev: false,
+            tap: None,
         }
     }
 
+    /// MAC address of the interface in the guest, it is validated and
+    /// normalized when building, see [normalize_mac]
     pub fn with_guest_mac(mut self, guest_mac: String) -> NetworkInterfaceBuilder {
         self.guest_mac = Some(guest_mac);
         self
@@ -52,14 +142,77 @@ impl NetworkInterfaceBuilder {
         self.tx_rate_limiter = Some(tx_rate_limiter);
         self
     }
+
+    /// Check the host device is an existing TAP device when building, see
+    /// [check_tap_device]. The device must then be created beforehand.
+    pub fn with_host_dev_check(mut self) -> NetworkInterfaceBuilder {
+        self.check_host_dev = true;
+        self
+    }
+
+    /// Have the host device created when the machine is created, and deleted
+    /// when it is killed, see [crate::network::tap]
+    pub fn with_tap(mut self, tap: TapConfig) -> NetworkInterfaceBuilder {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Build the interface along with the TAP device to create for it
+    pub fn try_build_with_tap(mut self) -> Result<TapInterface, BuilderError> {
+        let tap = self.tap.take().unwrap_or_default();
+        if let Some(name) = &self.host_dev_name {
+            if name.is_empty() || name.len() > MAX_IFNAME_LEN || name.contains(['/', ' ']) {
+                return Err(BuilderError::InvalidValue(format!(
+                    "Invalid TAP device name {}, it must be 1 to {} characters without '/' or spaces",
+                    name, MAX_IFNAME_LEN
+                )));
+            }
+        }
+        if let Some(bridge) = tap.bridge.as_ref().filter(|b| !b.has_valid_prefix()) {
+            return Err(BuilderError::InvalidValue(format!(
+                "Invalid prefix length for the address of bridge {}",
+                bridge.name
+            )));
+        }
+        if tap
+            .nat
+            .as_ref()
+            .filter(|nat| !nat.has_valid_prefix())
+            .is_some()
+        {
+            return Err(BuilderError::InvalidValue(
+                "Invalid prefix length for the source of the NAT".to_string(),
+            ));
+        }
+        // The device doesn't exist yet, it can't be checked
+        self.check_host_dev = false;
+        Ok(TapInterface {
+            interface: self.try_build()?,
+            tap,
+        })
+    }
 }
 
 impl Builder<NetworkInterface> for NetworkInterfaceBuilder {
     fn try_build(self) -> Result<NetworkInterface, BuilderError> {
-        assert_not_none(stringify!(self.host_dev_name), &self.host_dev_name)?;
-        assert_not_none(stringify!(self.iface_id), &self.iface_id)?;
+        assert_not_none(
+            "NetworkInterfaceBuilder",
+            "host_dev_name",
+            "with_host_dev_name",
+            &self.host_dev_name,
+        )?;
+        assert_not_none(
+            "NetworkInterfaceBuilder",
+            "iface_id",
+            "with_iface_id",
+            &self.iface_id,
+        )?;
+        if self.check_host_dev {
+            check_tap_device(self.host_dev_name.as_ref().unwrap())?;
+        }
+        let guest_mac = self.guest_mac.as_deref().map(normalize_mac).transpose()?;
         Ok(NetworkInterface {
-            guest_mac: self.guest_mac,
+            guest_mac,
             host_dev_name: self.host_dev_name.unwrap(),
             iface_id: self.iface_id.unwrap(),
             rx_rate_limiter: self.rx_rate_limiter,
@@ -71,6 +224,7 @@ impl Builder<NetworkInterface> for NetworkInterfaceBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::bridge::Bridge;
 
     #[test]
     fn test_iface_builder() {
@@ -83,6 +237,95 @@ mod tests {
         assert_eq!(iface.iface_id, "net0");
     }
 
+    #[test]
+    fn test_guest_mac() {
+        assert_eq!(
+            normalize_mac("AA-FC-00-00-00-01"),
+            Ok("aa:fc:00:00:00:01".to_string())
+        );
+        assert_eq!(
+            normalize_mac("06:00:ac:10:00:02"),
+            Ok("06:00:ac:10:00:02".to_string())
+        );
+        for mac in [
+            "01:00:5e:00:00:01",
+            "aa:fc:00:00:01",
+            "aa:fc:00:00:00:zz",
+            "aafc00000001",
+            "a:fc:00:00:00:01",
+        ] {
+            assert!(
+                matches!(normalize_mac(mac), Err(BuilderError::InvalidValue(_))),
+                "{}",
+                mac
+            );
+        }
+
+        let iface = NetworkInterfaceBuilder::new()
+            .with_host_dev_name("tap0".to_string())
+            .with_iface_id("net0".to_string())
+            .with_guest_mac("AA:FC:00:00:00:01".to_string())
+            .try_build()
+            .unwrap();
+        assert_eq!(iface.guest_mac.unwrap(), "aa:fc:00:00:00:01");
+    }
+
+    #[test]
+    fn test_check_tap_device() {
+        let sys = tempfile::tempdir().unwrap();
+        for (device, flags) in [
+            ("tap0", Some("0x1002")),
+            ("tun0", Some("0x1001")),
+            ("eth0", None),
+        ] {
+            std::fs::create_dir(sys.path().join(device)).unwrap();
+            if let Some(flags) = flags {
+                std::fs::write(sys.path().join(device).join("tun_flags"), flags).unwrap();
+            }
+        }
+        assert_eq!(check_tap_device_in(sys.path(), "tap0"), Ok(()));
+        for device in ["tun0", "eth0", "missing"] {
+            assert!(matches!(
+                check_tap_device_in(sys.path(), device),
+                Err(BuilderError::InvalidValue(_))
+            ));
+        }
+
+        let iface = NetworkInterfaceBuilder::new()
+            .with_host_dev_name("firepilot-missing".to_string())
+            .with_iface_id("net0".to_string())
+            .with_host_dev_check()
+            .try_build();
+        assert!(iface.is_err());
+    }
+
+    #[test]
+    fn test_iface_with_tap() {
+        let iface = NetworkInterfaceBuilder::new()
+            .with_host_dev_name("fc-vm-0".to_string())
+            .with_iface_id("net0".to_string())
+            .with_host_dev_check()
+            .with_tap(TapConfig::new().with_mtu(1400))
+            .try_build_with_tap()
+            .unwrap();
+        assert_eq!(iface.interface.host_dev_name, "fc-vm-0");
+        assert_eq!(iface.tap.mtu, Some(1400));
+
+        let iface = NetworkInterfaceBuilder::new()
+            .with_host_dev_name("firepilot-vm-eth0".to_string())
+            .with_iface_id("net0".to_string())
+            .try_build_with_tap();
+        assert!(matches!(iface, Err(BuilderError::InvalidValue(_))));
+        let iface = NetworkInterfaceBuilder::new()
+            .with_host_dev_name("fc-vm-0".to_string())
+            .with_iface_id("net0".to_string())
+            .with_tap(TapConfig::new().with_bridge(
+                Bridge::new("br0".to_string()).with_address("172.16.0.1".parse().unwrap(), 33),
+            ))
+            .try_build_with_tap();
+        assert!(matches!(iface, Err(BuilderError::InvalidValue(_))));
+    }
+
     #[test]
     #[should_panic]
     fn test_iface_incomplete() {
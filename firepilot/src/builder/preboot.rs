@@ -0,0 +1,169 @@
+//! Checks of a [Configuration] against the host, before any process is spawned
+
+use std::{fs::File, path::Path};
+
+use super::{
+    drive::{check_block_device_access, is_block_device},
+    network_interface::{check_tap_device, normalize_mac},
+    vsock::MIN_GUEST_CID,
+    BuilderError, Configuration,
+};
+
+impl Configuration {
+    /// Every problem which would make firecracker reject the configuration,
+    /// or fail to boot the microVM, found without spawning it: missing or
+    /// unreadable kernel, initrd and drive files, missing TAP devices,
+    /// malformed MAC addresses and vsock context identifiers
+    ///
+    /// Drives created by firepilot (thin snapshots, overlays and scratch
+    /// drives) and TAP devices created along with the machine aren't looked
+    /// for, as they don't exist yet. Neither are the TAP devices of an
+    /// executor running in a network namespace, they aren't visible from the
+    /// host, see [crate::network::netns].
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use firepilot::builder::Configuration;
+    /// use firepilot::builder::{Builder, kernel::KernelBuilder};
+    ///
+    /// let kernel = KernelBuilder::new()
+    ///     .with_kernel_image_path("/nonexistent/vmlinux".to_string())
+    ///     .try_build()
+    ///     .unwrap();
+    /// let config = Configuration::new("vm".to_string()).with_kernel(kernel);
+    /// assert_eq!(config.check_resources().len(), 1);
+    /// ```
+    pub fn check_resources(&self) -> Vec<BuilderError> {
+        let mut problems = Vec::new();
+
+        if let Some(kernel) = &self.kernel {
+            problems.extend(check_readable("Kernel image", &kernel.kernel_image_path));
+            if let Some(initrd) = &kernel.initrd_path {
+                problems.extend(check_readable("Initrd", initrd));
+            }
+        }
+
+        for drive in &self.storage {
+            let created = self.staging.get(&drive.drive_id).map_or(false, |staging| {
+                staging.thin.is_some() || staging.overlay.is_some() || staging.scratch.is_some()
+            });
+            if created {
+                continue;
+            }
+            let path = Path::new(&drive.path_on_host);
+            if is_block_device(path) {
+                if let Err(e) = check_block_device_access(path, drive.is_read_only) {
+                    problems.push(BuilderError::InvalidValue(format!(
+                        "Drive {}: block device {} can't be opened: {}",
+                        drive.drive_id, drive.path_on_host, e
+                    )));
+                }
+            } else {
+                problems.extend(check_readable(
+                    &format!("Drive {}: file", drive.drive_id),
+                    &drive.path_on_host,
+                ));
+            }
+        }
+
+        let in_netns = self
+            .executor
+            .as_ref()
+            .map_or(false, |executor| executor.netns().is_some());
+        for iface in &self.interfaces {
+            if !in_netns && !self.taps.contains_key(&iface.iface_id) {
+                if let Err(e) = check_tap_device(&iface.host_dev_name) {
+                    problems.push(e);
+                }
+            }
+            if let Some(mac) = &iface.guest_mac {
+                if let Err(e) = normalize_mac(mac) {
+                    problems.push(e);
+                }
+            }
+        }
+
+        if let Some(vsock) = &self.vsock {
+            if vsock.guest_cid < MIN_GUEST_CID {
+                problems.push(BuilderError::InvalidValue(format!(
+                    "guest_cid must be at least {}, got {}",
+                    MIN_GUEST_CID, vsock.guest_cid
+                )));
+            }
+        }
+        problems
+    }
+}
+
+fn check_readable(what: &str, path: &str) -> Option<BuilderError> {
+    File::open(path)
+        .err()
+        .map(|e| BuilderError::InvalidValue(format!("{} {} can't be read: {}", what, path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use firepilot_models::models::{BootSource, Drive, NetworkInterface, Vsock};
+
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::executor::{Executor, FirecrackerExecutor};
+    use crate::network::{
+        netns::{resolve_netns, NetnsExecutor},
+        tap::TapConfig,
+    };
+
+    #[test]
+    fn test_check_resources() {
+        let dir = tempfile::tempdir().unwrap();
+        let kernel = dir.path().join("vmlinux");
+        std::fs::write(&kernel, "").unwrap();
+        let mut boot_source = BootSource::new(kernel.to_string_lossy().into_owned());
+        boot_source.initrd_path = Some("/nonexistent/initrd".to_string());
+
+        let mut tapped = NetworkInterface::new("fp-tap0".to_string(), "eth1".to_string());
+        tapped.guest_mac = Some("06:00:ac:10:00:02".to_string());
+        let mut config = Configuration::new("preboot".to_string())
+            .with_kernel(boot_source)
+            .with_drive(Drive::new(
+                "rootfs".to_string(),
+                false,
+                true,
+                "/nonexistent/rootfs.ext4".to_string(),
+            ))
+            .with_interface(tapped)
+            .with_vsock(Vsock::new(2, "vsock.sock".to_string()));
+        config.taps.insert("eth1".to_string(), TapConfig::new());
+        let mut missing = NetworkInterface::new("fp-missing0".to_string(), "eth0".to_string());
+        missing.guest_mac = Some("01:00:5e:00:00:01".to_string());
+        config.interfaces.push(missing);
+
+        // Initrd, rootfs, missing TAP device, multicast MAC and reserved CID
+        let problems = config.check_resources();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .all(|problem| matches!(problem, BuilderError::InvalidValue(_))));
+    }
+
+    #[test]
+    fn test_check_resources_in_netns() {
+        let executor = Executor::new_with_executor(NetnsExecutor {
+            firecracker: FirecrackerExecutor {
+                chroot: "/tmp/firepilot".to_string(),
+                exec_binary: PathBuf::from("/usr/bin/firecracker"),
+            },
+            netns: resolve_netns("tenant-a"),
+        });
+        let config = Configuration::new("preboot".to_string())
+            .with_executor(executor)
+            .with_interface(NetworkInterface::new(
+                "fp-missing0".to_string(),
+                "eth0".to_string(),
+            ));
+        // The TAP device may exist in the namespace
+        assert_eq!(config.check_resources(), Vec::new());
+    }
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use firepilot_models::models::{RateLimiter, TokenBucket};
+
+use super::units::{parse_bandwidth, parse_size};
+use super::{Builder, BuilderError};
+
+/// Interval used to refill the token buckets when none is given, the bucket
+/// size is then equal to the rate per second
+pub const DEFAULT_REFILL_TIME: Duration = Duration::from_secs(1);
+
+/// Build a [RateLimiter] from rates instead of raw token buckets
+///
+/// Firecracker limits IO with token buckets described by a size and a refill
+/// time, this builder computes them from a bandwidth and a number of
+/// operations per second.
+///
+/// ## Example
+///
+/// ```rust
+/// use firepilot::builder::Builder;
+/// use firepilot::builder::rate_limiter::RateLimiterBuilder;
+///
+/// let limiter = RateLimiterBuilder::new()
+///     .with_bandwidth("50MB/s")
+///     .with_ops(1000)
+///     .try_build()
+///     .unwrap();
+/// assert_eq!(limiter.bandwidth.unwrap().size, 50_000_000);
+/// ```
+#[derive(Debug)]
+pub struct RateLimiterBuilder {
+    bandwidth: Option<String>,
+    bandwidth_burst: Option<String>,
+    ops: Option<u64>,
+    ops_burst: Option<u64>,
+    refill_time: Duration,
+}
+
+impl Default for RateLimiterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiterBuilder {
+    pub fn new() -> RateLimiterBuilder {
+        RateLimiterBuilder {
+            bandwidth: None,
+            bandwidth_burst: None,
+            ops: None,
+            ops_burst: None,
+            refill_time: DEFAULT_REFILL_TIME,
+        }
+    }
+
+    /// Limit the bandwidth, e.g. `"50MB/s"`, `"1GiB/s"` or `"100Mbps"`
+    pub fn with_bandwidth<S: Into<String>>(mut self, bandwidth: S) -> RateLimiterBuilder {
+        self.bandwidth = Some(bandwidth.into());
+        self
+    }
+
+    /// Allow an initial burst of the given size, e.g. `"1GiB"`, before the
+    /// bandwidth limit applies
+    pub fn with_bandwidth_burst<S: Into<String>>(mut self, burst: S) -> RateLimiterBuilder {
+        self.bandwidth_burst = Some(burst.into());
+        self
+    }
+
+    /// Limit the number of operations per second
+    pub fn with_ops(mut self, ops: u64) -> RateLimiterBuilder {
+        self.ops = Some(ops);
+        self
+    }
+
+    /// Allow an initial burst of operations before the ops limit applies
+    pub fn with_ops_burst(mut self, burst: u64) -> RateLimiterBuilder {
+        self.ops_burst = Some(burst);
+        self
+    }
+
+    /// Change the refill interval of the buckets, a shorter interval smooths
+    /// the traffic but allows smaller bursts
+    pub fn with_refill_time(mut self, refill_time: Duration) -> RateLimiterBuilder {
+        self.refill_time = refill_time;
+        self
+    }
+
+    /// Token bucket which lets `rate` tokens per second go through
+    fn bucket(
+        &self,
+        name: &str,
+        rate: u64,
+        burst: Option<u64>,
+    ) -> Result<TokenBucket, BuilderError> {
+        let refill_ms = self.refill_time.as_millis();
+        let size = u128::from(rate) * refill_ms / 1000;
+        if size == 0 {
+            return Err(BuilderError::InvalidValue(format!(
+                "{} limit of {}/s is too low for a refill time of {}ms",
+                name, rate, refill_ms
+            )));
+        }
+        let to_i64 = |value: u128| {
+            i64::try_from(value)
+                .map_err(|_| BuilderError::InvalidValue(format!("{} limit is too large", name)))
+        };
+        Ok(TokenBucket {
+            one_time_burst: burst.map(|b| to_i64(b.into())).transpose()?,
+            refill_time: to_i64(refill_ms)?,
+            size: to_i64(size)?,
+        })
+    }
+}
+
+impl Builder<RateLimiter> for RateLimiterBuilder {
+    fn try_build(self) -> Result<RateLimiter, BuilderError> {
+        if self.bandwidth.is_none() && self.ops.is_none() {
+            return Err(BuilderError::missing(
+                "RateLimiterBuilder",
+                "bandwidth or ops",
+                "with_bandwidth or with_ops",
+            ));
+        }
+        let bandwidth = match &self.bandwidth {
+            Some(bandwidth) => {
+                let rate = parse_bandwidth(bandwidth)?;
+                let burst = self
+                    .bandwidth_burst
+                    .as_deref()
+                    .map(parse_size)
+                    .transpose()?;
+                Some(Box::new(self.bucket("bandwidth", rate, burst)?))
+            }
+            None => None,
+        };
+        let ops = match self.ops {
+            Some(rate) => Some(Box::new(self.bucket("ops", rate, self.ops_burst)?)),
+            None => None,
+        };
+        Ok(RateLimiter { bandwidth, ops })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_buckets() {
+        let limiter = RateLimiterBuilder::new()
+            .with_bandwidth("100Mbps")
+            .with_bandwidth_burst("1GiB")
+            .with_ops(1000)
+            .with_refill_time(Duration::from_millis(100))
+            .try_build()
+            .unwrap();
+        let bandwidth = limiter.bandwidth.unwrap();
+        assert_eq!(bandwidth.size, 1_250_000);
+        assert_eq!(bandwidth.refill_time, 100);
+        assert_eq!(bandwidth.one_time_burst, Some(1 << 30));
+        let ops = limiter.ops.unwrap();
+        assert_eq!(ops.size, 100);
+        assert_eq!(ops.one_time_burst, None);
+    }
+
+    #[test]
+    fn rate_limiter_invalid() {
+        assert!(matches!(
+            RateLimiterBuilder::new().try_build(),
+            Err(BuilderError::MissingRequiredField { .. })
+        ));
+        assert!(matches!(
+            RateLimiterBuilder::new()
+                .with_ops(5)
+                .with_refill_time(Duration::from_millis(100))
+                .try_build(),
+            Err(BuilderError::InvalidValue(_))
+        ));
+        assert!(RateLimiterBuilder::new()
+            .with_bandwidth("fast")
+            .try_build()
+            .is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! Parsing of human-friendly sizes and bandwidths, e.g. `512MiB` or `50MB/s`
+//!
+//! Suffixes follow the usual conventions:
+//!
+//! - `KB`, `MB`, `GB`, `TB` are decimal (powers of 1000)
+//! - `KiB`, `MiB`, `GiB`, `TiB` are binary (powers of 1024)
+//! - `K`, `M`, `G`, `T` alone are binary, like most virtualization tools
+//! - no suffix or `B` is a number of bytes
+//!
+//! Bandwidths can also be given in bits per second with `bps`, `Kbps`,
+//! `Mbps` or `Gbps` (decimal), and accept an optional `/s` suffix.
+
+use super::BuilderError;
+
+fn multiplier(unit: &str) -> Option<u64> {
+    let value = match unit {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Split `"50 MB"` into its number and its unit
+fn split(value: &str) -> Result<(f64, &str), BuilderError> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(end);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| BuilderError::InvalidValue(format!("Invalid quantity {:?}", value)))?;
+    Ok((number, unit.trim()))
+}
+
+fn scale(number: f64, multiplier: u64, value: &str) -> Result<u64, BuilderError> {
+    let bytes = (number * multiplier as f64).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(BuilderError::InvalidValue(format!(
+            "Quantity {:?} is too large",
+            value
+        )));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parse a size into a number of bytes, e.g. `"2GiB"` or `"512M"`
+pub fn parse_size(value: &str) -> Result<u64, BuilderError> {
+    let (number, unit) = split(value)?;
+    let multiplier = multiplier(unit).ok_or_else(|| {
+        BuilderError::InvalidValue(format!("Unknown size unit {:?} in {:?}", unit, value))
+    })?;
+    scale(number, multiplier, value)
+}
+
+/// Parse a bandwidth into a number of bytes per second, e.g. `"50MB/s"` or
+/// `"100Mbps"`
+pub fn parse_bandwidth(value: &str) -> Result<u64, BuilderError> {
+    let (number, unit) = split(value)?;
+    let unit = unit.strip_suffix("/s").unwrap_or(unit);
+    let bits = match unit {
+        "bps" => Some(1),
+        "Kbps" => Some(1_000),
+        "Mbps" => Some(1_000_000),
+        "Gbps" => Some(1_000_000_000),
+        _ => None,
+    };
+    if let Some(bits) = bits {
+        return scale(number / 8.0, bits, value);
+    }
+    let multiplier = multiplier(unit).ok_or_else(|| {
+        BuilderError::InvalidValue(format!("Unknown bandwidth unit {:?} in {:?}", unit, value))
+    })?;
+    scale(number, multiplier, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("1.5 GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("12 parsecs").is_err());
+        assert!(parse_size("MiB").is_err());
+    }
+
+    #[test]
+    fn test_parse_bandwidth() {
+        assert_eq!(parse_bandwidth("50MB/s"), Ok(50_000_000));
+        assert_eq!(parse_bandwidth("1 MiB/s"), Ok(1 << 20));
+        assert_eq!(parse_bandwidth("100Mbps"), Ok(12_500_000));
+        assert!(parse_bandwidth("1 furlong/s").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Cross-field validation of a [Configuration], which its individual builders
+//! can't do
+
+use std::collections::HashSet;
+
+use super::{BuilderError, Configuration, MAX_VM_ID_LEN};
+
+impl Configuration {
+    /// Every problem making the configuration unusable on its own: a missing
+    /// kernel, an empty or invalid vm id, duplicate drive or interface ids,
+    /// and a number of root devices other than one
+    ///
+    /// A configuration without any root device is accepted when the kernel
+    /// boots from an initrd. The executor is optional, the one of the machine
+    /// is used without it, see [Machine::create](crate::machine::Machine::create).
+    /// Files and devices on the host are checked by
+    /// [Configuration::check_resources].
+    pub fn validate(&self) -> Vec<BuilderError> {
+        let mut problems = Vec::new();
+        if self.kernel.is_none() {
+            problems.push(BuilderError::missing(
+                "Configuration",
+                "kernel",
+                "with_kernel",
+            ));
+        }
+
+        let valid_id = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.vm_id.is_empty() {
+            problems.push(BuilderError::missing(
+                "Configuration",
+                "vm_id",
+                "Configuration::new",
+            ));
+        } else if self.vm_id.len() > MAX_VM_ID_LEN || !self.vm_id.chars().all(valid_id) {
+            problems.push(BuilderError::InvalidValue(format!(
+                "vm_id {:?} must be at most {} ASCII letters, digits, '-' or '_'",
+                self.vm_id, MAX_VM_ID_LEN
+            )));
+        }
+
+        let mut drive_ids = HashSet::new();
+        for drive in &self.storage {
+            if !drive_ids.insert(&drive.drive_id) {
+                problems.push(BuilderError::InvalidValue(format!(
+                    "Drive id {} is used more than once",
+                    drive.drive_id
+                )));
+            }
+        }
+        let root_devices = self
+            .storage
+            .iter()
+            .filter(|drive| drive.is_root_device)
+            .count();
+        let initrd = self
+            .kernel
+            .as_ref()
+            .map_or(false, |kernel| kernel.initrd_path.is_some());
+        if root_devices > 1 || (root_devices == 0 && !initrd) {
+            problems.push(BuilderError::IncompatibleConfiguration(format!(
+                "Exactly one root device is required, {} found",
+                root_devices
+            )));
+        }
+
+        let mut iface_ids = HashSet::new();
+        for iface in &self.interfaces {
+            if !iface_ids.insert(&iface.iface_id) {
+                problems.push(BuilderError::InvalidValue(format!(
+                    "Interface id {} is used more than once",
+                    iface.iface_id
+                )));
+            }
+        }
+        problems
+    }
+
+    /// The configuration if [Configuration::validate] found no problem, all
+    /// the problems otherwise
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use firepilot::builder::{BuilderError, Configuration};
+    ///
+    /// let problems = Configuration::new("vm/1".to_string()).try_build().unwrap_err();
+    /// assert!(problems.iter().any(|problem| matches!(
+    ///     problem,
+    ///     BuilderError::MissingRequiredField { field: "kernel", .. }
+    /// )));
+    /// ```
+    pub fn try_build(self) -> Result<Configuration, Vec<BuilderError>> {
+        let problems = self.validate();
+        match problems.is_empty() {
+            true => Ok(self),
+            false => Err(problems),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firepilot_models::models::{BootSource, Drive, NetworkInterface};
+
+    use super::*;
+    use crate::executor::Executor;
+
+    fn drive(id: &str, is_root_device: bool) -> Drive {
+        Drive::new(
+            id.to_string(),
+            true,
+            is_root_device,
+            format!("/srv/{}.ext4", id),
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Configuration::new("vm-1".to_string())
+            .with_executor(Executor::new())
+            .with_kernel(BootSource::new("/srv/vmlinux".to_string()))
+            .with_drive(drive("rootfs", true))
+            .with_drive(drive("data", false))
+            .with_interface(NetworkInterface::new(
+                "tap0".to_string(),
+                "eth0".to_string(),
+            ));
+        let config = config.try_build().unwrap();
+
+        let problems = config
+            .with_drive(drive("data", true))
+            .with_interface(NetworkInterface::new(
+                "tap1".to_string(),
+                "eth0".to_string(),
+            ))
+            .validate();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .any(|problem| matches!(problem, BuilderError::IncompatibleConfiguration(_))));
+
+        let problems = Configuration::new(String::new()).validate();
+        assert_eq!(
+            problems,
+            vec![
+                BuilderError::missing("Configuration", "kernel", "with_kernel"),
+                BuilderError::missing("Configuration", "vm_id", "Configuration::new"),
+                BuilderError::IncompatibleConfiguration(
+                    "Exactly one root device is required, 0 found".to_string()
+                ),
+            ]
+        );
+
+        // An initrd is enough to boot
+        let mut kernel = BootSource::new("/srv/vmlinux".to_string());
+        kernel.initrd_path = Some("/srv/initrd".to_string());
+        let config = Configuration::new("../vm".to_string())
+            .with_executor(Executor::new())
+            .with_kernel(kernel);
+        assert!(matches!(
+            config.validate().as_slice(),
+            [BuilderError::InvalidValue(_)]
+        ));
+    }
+}
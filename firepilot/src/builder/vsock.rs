@@ -0,0 +1,83 @@
+use firepilot_models::models::Vsock;
+
+use super::{Builder, BuilderError};
+
+/// Smallest context identifier a guest can use, 0 to 2 are reserved
+pub const MIN_GUEST_CID: i32 = 3;
+
+/// Name of the host socket proxying vsock connections, relative to the machine
+/// workspace, when none is given
+pub const DEFAULT_VSOCK_UDS: &str = "vsock.sock";
+
+/// Configure the vsock device of the microVM, which is needed to talk to guest
+/// services without networking
+///
+/// A relative `uds_path` is resolved in the machine workspace when the machine
+/// is created.
+#[derive(Debug)]
+pub struct VsockBuilder {
+    pub guest_cid: i32,
+    pub uds_path: String,
+}
+
+impl Default for VsockBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VsockBuilder {
+    pub fn new() -> VsockBuilder {
+        VsockBuilder {
+            guest_cid: MIN_GUEST_CID,
+            uds_path: DEFAULT_VSOCK_UDS.to_string(),
+        }
+    }
+
+    pub fn with_guest_cid(mut self, guest_cid: i32) -> VsockBuilder {
+        self.guest_cid = guest_cid;
+        self
+    }
+
+    pub fn with_uds_path(mut self, uds_path: String) -> VsockBuilder {
+        self.uds_path = uds_path;
+        self
+    }
+}
+
+impl Builder<Vsock> for VsockBuilder {
+    fn try_build(self) -> Result<Vsock, BuilderError> {
+        if self.guest_cid < MIN_GUEST_CID {
+            return Err(BuilderError::InvalidValue(format!(
+                "guest_cid must be at least {}, got {}",
+                MIN_GUEST_CID, self.guest_cid
+            )));
+        }
+        if self.uds_path.is_empty() {
+            return Err(BuilderError::missing(
+                "VsockBuilder",
+                "uds_path",
+                "with_uds_path",
+            ));
+        }
+        Ok(Vsock::new(self.guest_cid, self.uds_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vsock_default() {
+        let vsock = VsockBuilder::new().try_build().unwrap();
+        assert_eq!(vsock.guest_cid, MIN_GUEST_CID);
+        assert_eq!(vsock.uds_path, DEFAULT_VSOCK_UDS);
+    }
+
+    #[test]
+    fn vsock_reserved_cid() {
+        let vsock = VsockBuilder::new().with_guest_cid(2).try_build();
+        assert!(matches!(vsock, Err(BuilderError::InvalidValue(_))));
+    }
+}
@@ -0,0 +1,286 @@
+//! # cgroup limits of the firecracker process
+//!
+//! Without the jailer, nothing stops a microVM from using all the CPU or
+//! memory of the host. An [Executor](crate::executor::Executor) given a
+//! [CgroupConfig] creates a dedicated cgroup for each microVM, named after its
+//! id, moves the firecracker process into it once spawned and removes it when
+//! the process is destroyed.
+//!
+//! Both cgroup v2 (unified hierarchy) and v1 (one hierarchy per controller)
+//! are supported, the version is detected from the mounted hierarchy.
+//!
+//! ```no_run
+//! use firepilot::cgroup::CgroupConfig;
+//! use firepilot::executor::{Executor, FirecrackerExecutor};
+//! use std::path::PathBuf;
+//!
+//! let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+//!     chroot: "/tmp/firepilot".to_string(),
+//!     exec_binary: PathBuf::from("/usr/bin/firecracker"),
+//! })
+//! .with_cgroup(
+//!     CgroupConfig::new()
+//!         .with_cpu_max(50_000, 100_000)
+//!         .with_memory_max(512 << 20)
+//!         .with_pids_max(64),
+//! );
+//! ```
+//!
+//! The process is moved once it runs, it isn't limited during the few
+//! instructions before.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// Mount point of the cgroup hierarchies
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Parent cgroup of the microVM cgroups when none is given
+pub const DEFAULT_CGROUP_PARENT: &str = "firepilot";
+
+/// Version of the cgroup hierarchy mounted on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+impl CgroupVersion {
+    /// Version mounted at the given root, v2 exposes its controllers at the
+    /// top of the unified hierarchy
+    pub fn detect(root: &Path) -> CgroupVersion {
+        match root.join("cgroup.controllers").exists() {
+            true => CgroupVersion::V2,
+            false => CgroupVersion::V1,
+        }
+    }
+}
+
+/// Limits applied to the cgroup of each microVM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupConfig {
+    /// CPU time the microVM can use in each period, both in microseconds
+    pub cpu_max: Option<(u64, u64)>,
+    /// Memory of the process in bytes, guest memory included
+    pub memory_max: Option<u64>,
+    /// Number of processes and threads, firecracker uses one thread per vCPU
+    /// plus a few for the VMM and the API
+    pub pids_max: Option<u64>,
+    /// Cgroup under which the cgroups of the microVMs are created
+    pub parent: String,
+    /// Mount point of the cgroup hierarchies
+    pub root: PathBuf,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CgroupConfig {
+    pub fn new() -> CgroupConfig {
+        CgroupConfig {
+            cpu_max: None,
+            memory_max: None,
+            pids_max: None,
+            parent: DEFAULT_CGROUP_PARENT.to_string(),
+            root: PathBuf::from(CGROUP_ROOT),
+        }
+    }
+
+    /// Let the microVM use `quota_us` of CPU time every `period_us`, e.g.
+    /// `(50_000, 100_000)` for half a CPU
+    pub fn with_cpu_max(mut self, quota_us: u64, period_us: u64) -> CgroupConfig {
+        self.cpu_max = Some((quota_us, period_us));
+        self
+    }
+
+    pub fn with_memory_max(mut self, bytes: u64) -> CgroupConfig {
+        self.memory_max = Some(bytes);
+        self
+    }
+
+    pub fn with_pids_max(mut self, pids: u64) -> CgroupConfig {
+        self.pids_max = Some(pids);
+        self
+    }
+
+    pub fn with_parent(mut self, parent: String) -> CgroupConfig {
+        self.parent = parent;
+        self
+    }
+
+    /// Use the hierarchies mounted somewhere else than [CGROUP_ROOT]
+    pub fn with_root(mut self, root: PathBuf) -> CgroupConfig {
+        self.root = root;
+        self
+    }
+
+    /// Files to write in each controller, for the given version
+    fn limits(&self, version: CgroupVersion) -> Vec<(&'static str, &'static str, String)> {
+        let mut limits = Vec::new();
+        match version {
+            CgroupVersion::V2 => {
+                if let Some((quota, period)) = self.cpu_max {
+                    limits.push(("cpu", "cpu.max", format!("{} {}", quota, period)));
+                }
+                if let Some(bytes) = self.memory_max {
+                    limits.push(("memory", "memory.max", bytes.to_string()));
+                }
+            }
+            CgroupVersion::V1 => {
+                if let Some((quota, period)) = self.cpu_max {
+                    // The period must be set first, the quota is checked against it
+                    limits.push(("cpu", "cpu.cfs_period_us", period.to_string()));
+                    limits.push(("cpu", "cpu.cfs_quota_us", quota.to_string()));
+                }
+                if let Some(bytes) = self.memory_max {
+                    limits.push(("memory", "memory.limit_in_bytes", bytes.to_string()));
+                }
+            }
+        }
+        if let Some(pids) = self.pids_max {
+            limits.push(("pids", "pids.max", pids.to_string()));
+        }
+        limits
+    }
+
+    /// Create the cgroup of a microVM with the limits applied
+    pub fn create(&self, id: &str) -> io::Result<Cgroup> {
+        let version = CgroupVersion::detect(&self.root);
+        let limits = self.limits(version);
+        let mut controllers: Vec<&str> = limits.iter().map(|(c, _, _)| *c).collect();
+        controllers.dedup();
+
+        let dirs: Vec<(&str, PathBuf)> = match version {
+            CgroupVersion::V2 => {
+                let parent = self.root.join(&self.parent);
+                fs::create_dir_all(&parent)?;
+                let enable: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+                if !enable.is_empty() {
+                    // Controllers must be enabled on each level down to the cgroup
+                    for level in [&self.root, &parent] {
+                        fs::write(level.join("cgroup.subtree_control"), enable.join(" "))?;
+                    }
+                }
+                vec![("", parent.join(id))]
+            }
+            CgroupVersion::V1 => controllers
+                .iter()
+                .map(|c| (*c, self.root.join(c).join(&self.parent).join(id)))
+                .collect(),
+        };
+        let mut cgroup = Cgroup { paths: Vec::new() };
+        for (_, dir) in dirs.iter() {
+            fs::create_dir_all(dir)?;
+            cgroup.paths.push(dir.clone());
+        }
+        for (controller, file, value) in limits {
+            let dir = dirs
+                .iter()
+                .find(|(c, _)| c.is_empty() || *c == controller)
+                .map(|(_, dir)| dir)
+                .unwrap();
+            fs::write(dir.join(file), value).map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to write {}: {}", file, e))
+            })?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// Cgroup created for a microVM, in each hierarchy it is part of
+#[derive(Debug)]
+pub struct Cgroup {
+    paths: Vec<PathBuf>,
+}
+
+impl Cgroup {
+    /// Directories of the cgroup, one per hierarchy
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Move a process into the cgroup
+    pub fn add_process(&self, pid: u32) -> io::Result<()> {
+        for path in self.paths.iter() {
+            fs::write(path.join("cgroup.procs"), pid.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Remove the cgroup, it must not contain any process anymore
+    pub fn remove(&self) -> io::Result<()> {
+        for path in self.paths.iter() {
+            match fs::remove_dir(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(root: &Path) -> CgroupConfig {
+        CgroupConfig::new()
+            .with_root(root.to_path_buf())
+            .with_cpu_max(50_000, 100_000)
+            .with_memory_max(512 << 20)
+            .with_pids_max(64)
+    }
+
+    #[test]
+    fn test_cgroup_v2() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("cgroup.controllers"), "cpu memory pids").unwrap();
+        let cgroup = config(root.path()).create("vm").unwrap();
+
+        let dir = root.path().join(DEFAULT_CGROUP_PARENT).join("vm");
+        assert_eq!(cgroup.paths(), std::slice::from_ref(&dir));
+        assert_eq!(
+            fs::read_to_string(root.path().join("cgroup.subtree_control")).unwrap(),
+            "+cpu +memory +pids"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("cpu.max")).unwrap(),
+            "50000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("memory.max")).unwrap(),
+            "536870912"
+        );
+        assert_eq!(fs::read_to_string(dir.join("pids.max")).unwrap(), "64");
+
+        cgroup.add_process(42).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("cgroup.procs")).unwrap(), "42");
+    }
+
+    #[test]
+    fn test_cgroup_v1() {
+        let root = tempfile::tempdir().unwrap();
+        let cgroup = config(root.path()).create("vm").unwrap();
+
+        let dir = |controller: &str| root.path().join(controller).join("firepilot/vm");
+        assert_eq!(cgroup.paths(), &[dir("cpu"), dir("memory"), dir("pids")]);
+        assert_eq!(
+            fs::read_to_string(dir("cpu").join("cpu.cfs_quota_us")).unwrap(),
+            "50000"
+        );
+        assert_eq!(
+            fs::read_to_string(dir("memory").join("memory.limit_in_bytes")).unwrap(),
+            "536870912"
+        );
+        assert_eq!(
+            fs::read_to_string(dir("pids").join("pids.max")).unwrap(),
+            "64"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! # Remote management daemon
+//!
+//! Only available with the `daemon` feature. The daemon exposes the lifecycle
+//! of microVMs over a local HTTP API, so tools which are not written in Rust,
+//! or remote control planes, can manage microVMs through firepilot without
+//! linking the library. It is shipped as the `firepilot-daemon` binary.
+//!
+//! Every request must carry the token of the daemon in an
+//! `Authorization: Bearer <token>` header.
+//!
+//! | Method   | Path                      | Action                                     |
+//! |----------|---------------------------|--------------------------------------------|
+//! | `GET`    | `/machines`               | List the ids of the machines               |
+//! | `POST`   | `/machines/{id}`          | Create a machine from a firecracker config |
+//! | `PUT`    | `/machines/{id}/start`    | Start the machine                          |
+//! | `PUT`    | `/machines/{id}/stop`     | Send CtrlAltDel to the guest               |
+//! | `PUT`    | `/machines/{id}/pause`    | Pause the machine                          |
+//! | `PUT`    | `/machines/{id}/resume`   | Resume the machine                         |
+//! | `PUT`    | `/machines/{id}/snapshot` | Pause the machine and snapshot it          |
+//! | `DELETE` | `/machines/{id}`          | Kill the machine and forget about it       |
+//!
+//! Machines are created from the JSON accepted by firecracker with
+//! `--config-file`, see [Configuration::from_firecracker_json]. Errors are
+//! returned as `{"error": "..."}`.
+//!
+//! Snapshots are saved in `.snapshots/{id}` under the chroot of the daemon,
+//! a full one unless the body is `{"snapshot_type": "Diff"}`. The machine is
+//! left paused, see [Machine::snapshot](crate::machine::Machine::snapshot).
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use firepilot_models::models::FullVmConfiguration;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    builder::Configuration,
+    executor::{Executor, FirecrackerExecutor},
+    machine::FirepilotError,
+    registry::MachineRegistry,
+    snapshot::SnapshotType,
+};
+
+/// Directory of the snapshots in the chroot, hidden so it isn't taken for the
+/// workspace of a machine
+const SNAPSHOTS_DIR: &str = ".snapshots";
+
+/// Body of `PUT /machines/{id}/snapshot`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SnapshotRequest {
+    snapshot_type: SnapshotType,
+}
+
+/// Settings of the daemon
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// Address the API listens on, it should be a local one
+    pub addr: SocketAddr,
+    /// Token expected in the `Authorization` header of every request
+    pub token: String,
+    /// Chroot of the executors of the machines
+    pub chroot: String,
+    /// Firecracker binary used by the machines
+    pub exec_binary: PathBuf,
+}
+
+/// Daemon holding the machines created through its API
+#[derive(Debug)]
+pub struct Daemon {
+    config: DaemonConfig,
+    machines: MachineRegistry,
+}
+
+impl Daemon {
+    pub fn new(config: DaemonConfig) -> Arc<Daemon> {
+        Arc::new(Daemon {
+            machines: MachineRegistry::new(&config.chroot),
+            config,
+        })
+    }
+
+    /// Serve the API until the server fails
+    pub async fn serve(self: Arc<Self>) -> Result<(), hyper::Error> {
+        let addr = self.config.addr;
+        let make_service = make_service_fn(move |_| {
+            let daemon = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let daemon = daemon.clone();
+                    async move { Ok::<_, Infallible>(daemon.handle(request).await) }
+                }))
+            }
+        });
+        info!("firepilot daemon listening on {}", addr);
+        Server::bind(&addr).serve(make_service).await
+    }
+
+    /// Answer a request of the API
+    pub async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if !self.is_authorized(&request) {
+            return error(StatusCode::UNAUTHORIZED, "Invalid or missing token");
+        }
+        let method = request.method().clone();
+        let segments: Vec<String> = request
+            .uri()
+            .path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        match (method, segments.as_slice()) {
+            (Method::GET, ["machines"]) => {
+                respond(StatusCode::OK, json!(self.machines.list().await))
+            }
+            (Method::POST, ["machines", id]) => self.create(id, request.into_body()).await,
+            (Method::DELETE, ["machines", id]) => self.delete(id).await,
+            (Method::PUT, ["machines", id, "snapshot"]) => {
+                self.snapshot(id, request.into_body()).await
+            }
+            (Method::PUT, ["machines", id, action]) => self.action(id, action).await,
+            _ => error(StatusCode::NOT_FOUND, "Unknown endpoint"),
+        }
+    }
+
+    fn is_authorized(&self, request: &Request<Body>) -> bool {
+        let expected = format!("Bearer {}", self.config.token);
+        match request.headers().get(AUTHORIZATION) {
+            // Compare every byte so the time taken doesn't leak the token
+            Some(header) => {
+                header.as_bytes().len() == expected.len()
+                    && header
+                        .as_bytes()
+                        .iter()
+                        .zip(expected.as_bytes())
+                        .fold(0, |acc, (a, b)| acc | (a ^ b))
+                        == 0
+            }
+            None => false,
+        }
+    }
+
+    async fn create(&self, id: &str, body: Body) -> Response<Body> {
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let vm_config: FullVmConfiguration = match serde_json::from_slice(&body) {
+            Ok(vm_config) => vm_config,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: self.config.chroot.clone(),
+            exec_binary: self.config.exec_binary.clone(),
+        });
+        let config =
+            Configuration::from_full_vm_config(id.to_string(), vm_config).with_executor(executor);
+        match self.machines.create(config).await {
+            Ok(_) => respond(StatusCode::CREATED, json!({ "id": id })),
+            Err(e) => failure(e),
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Response<Body> {
+        let machine = match self.machines.remove(id).await {
+            Ok(machine) => machine,
+            Err(e) => return failure(e),
+        };
+        let result = machine.kill().await;
+        match result {
+            Ok(()) => respond(StatusCode::OK, json!({ "id": id })),
+            Err(e) => failure(e),
+        }
+    }
+
+    async fn snapshot(&self, id: &str, body: Body) -> Response<Body> {
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let request = match body.is_empty() {
+            true => SnapshotRequest::default(),
+            false => match serde_json::from_slice(&body) {
+                Ok(request) => request,
+                Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+            },
+        };
+        let machine = match self.machines.get(id).await {
+            Ok(machine) => machine,
+            Err(e) => return failure(e),
+        };
+        let dir = Path::new(&self.config.chroot).join(SNAPSHOTS_DIR).join(id);
+        match machine.snapshot(dir, request.snapshot_type).await {
+            Ok(snapshot) => respond(
+                StatusCode::OK,
+                json!({
+                    "id": id,
+                    "snapshot_path": snapshot.snapshot_path,
+                    "mem_file_path": snapshot.mem_file_path,
+                }),
+            ),
+            Err(e) => failure(e),
+        }
+    }
+
+    async fn action(&self, id: &str, action: &str) -> Response<Body> {
+        let machine = match self.machines.get(id).await {
+            Ok(machine) => machine,
+            Err(e) => return failure(e),
+        };
+        let result = match action {
+            "start" => machine.start().await,
+            "stop" => machine.stop().await,
+            "pause" => machine.pause().await,
+            "resume" => machine.resume().await,
+            _ => return error(StatusCode::NOT_FOUND, "Unknown action"),
+        };
+        match result {
+            Ok(()) => respond(StatusCode::OK, json!({ "id": id })),
+            Err(e) => failure(e),
+        }
+    }
+}
+
+fn respond(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    respond(status, json!({ "error": message }))
+}
+
+fn failure(e: FirepilotError) -> Response<Body> {
+    let status = match e {
+        FirepilotError::Setup { .. }
+        | FirepilotError::Configure { .. }
+        | FirepilotError::InvalidConfiguration { .. } => StatusCode::BAD_REQUEST,
+        FirepilotError::InvalidTransition { .. } | FirepilotError::AlreadyExists { .. } => {
+            StatusCode::CONFLICT
+        }
+        FirepilotError::UnknownMachine { .. } => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    // The cause isn't part of the message of the error
+    let message = match std::error::Error::source(&e) {
+        Some(cause) => format!("{}: {}", e, cause),
+        None => e.to_string(),
+    };
+    error(status, &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::testing::MockTransport, machine::testing::running_machine};
+
+    fn daemon() -> Arc<Daemon> {
+        Daemon::new(DaemonConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            token: "secret".to_string(),
+            chroot: "/tmp/firepilot-daemon".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+    }
+
+    fn request(method: Method, path: &str, token: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_daemon_auth() {
+        let daemon = daemon();
+        let response = daemon
+            .handle(request(Method::GET, "/machines", "wrong", ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = daemon
+            .handle(request(Method::GET, "/machines", "secret", ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[tokio::test]
+    async fn test_daemon_errors() {
+        let daemon = daemon();
+        let response = daemon
+            .handle(request(Method::PUT, "/machines/vm/start", "secret", ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = daemon
+            .handle(request(
+                Method::POST,
+                "/machines/vm",
+                "secret",
+                "{\"drives\": 1}",
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = daemon
+            .handle(request(Method::DELETE, "/machines/vm", "secret", ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_daemon_snapshot() {
+        let chroot = tempfile::tempdir().unwrap();
+        let daemon = Daemon::new(DaemonConfig {
+            chroot: chroot.path().to_string_lossy().into_owned(),
+            ..daemon().config.clone()
+        });
+        let transport = MockTransport::new();
+        daemon
+            .machines
+            .insert("vm", running_machine("vm", &transport))
+            .await
+            .unwrap();
+
+        let response = daemon
+            .handle(request(
+                Method::PUT,
+                "/machines/vm/snapshot",
+                "secret",
+                r#"{"snapshot_type": "Diff"}"#,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let dir = chroot.path().join(SNAPSHOTS_DIR).join("vm");
+        assert_eq!(body["mem_file_path"], json!(dir.join("memory")));
+        assert!(dir.is_dir());
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].path, "/vm");
+        assert_eq!(requests[1].path, "/snapshot/create");
+        assert!(requests[1].body.contains("\"Diff\""));
+
+        // Already paused, it is snapshotted again
+        let response = daemon
+            .handle(request(Method::PUT, "/machines/vm/snapshot", "secret", ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = daemon
+            .handle(request(
+                Method::PUT,
+                "/machines/vm/snapshot",
+                "secret",
+                "[]",
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,51 @@
+//! # Lifecycle events of a machine
+//!
+//! A [Machine](crate::machine::Machine) publishes a [MachineEvent] at each
+//! step of its lifecycle, so orchestrators can react to them instead of
+//! polling the machine.
+//!
+//! ```no_run
+//! # async fn example(machine: firepilot::machine::Machine) {
+//! use firepilot::events::MachineEvent;
+//!
+//! let mut events = machine.events();
+//! tokio::spawn(async move {
+//!     while let Ok(event) = events.recv().await {
+//!         if let MachineEvent::GuestExited(status) = event {
+//!             println!("Guest exited with {:?}", status);
+//!         }
+//!     }
+//! });
+//! # }
+//! ```
+//!
+//! Only the events published after subscribing are received, subscribe
+//! before [Machine::create](crate::machine::Machine::create) to get them all.
+
+use std::process::ExitStatus;
+
+/// Number of events kept for receivers which are lagging behind
+pub(crate) const MACHINE_EVENTS_CAPACITY: usize = 32;
+
+/// Step reached by a machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MachineEvent {
+    /// The workspace of the machine exists and is locked
+    WorkspaceCreated,
+    /// firecracker is running and answers on its API socket
+    SocketReady,
+    /// The configuration was applied, the guest can be started
+    Configured,
+    /// The guest is running
+    Started,
+    /// firecracker exited by itself, with its exit status when it is known
+    GuestExited(Option<ExitStatus>),
+    /// firecracker was killed
+    Killed,
+    /// firecracker exited unexpectedly with the given status, the machine is
+    /// [Crashed](crate::machine::MachineState::Crashed)
+    Crashed(ExitStatus),
+    /// An operation failed and left the machine
+    /// [Failed](crate::machine::MachineState::Failed)
+    Errored(String),
+}
@@ -17,59 +17,163 @@
 //! [FirecrackerExecutor] or you could decide to be safer and run with a
 //! JailerExecutor. Be aware that the JailerExecutor is not yet implemented, but
 //! we welcome contributions.
-use std::{path::PathBuf, process::Stdio};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::File,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
 
-use tokio::process::{Child, Command};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 
-use hyper::{Body, Client, Method, Request};
-use hyperlocal::{UnixClientExt, UnixConnector, Uri};
-use tracing::{debug, error, info, instrument, trace};
+use std::sync::Arc;
 
-use crate::machine::FirepilotError;
-use firepilot_models::models::vm::Vm;
-use firepilot_models::models::{BootSource, Drive, NetworkInterface};
+use hyper::Client;
+use hyperlocal::UnixClientExt;
+use nix::sys::signal::{self, Signal};
+use nix::sys::stat::Mode;
+use nix::unistd::{mkfifo, Pid};
+use tracing::{debug, info, trace, warn};
+
+use crate::api::{FirecrackerClient, RetryPolicy, Transport, TransportError};
+use crate::audit::{AuditLog, AUDIT_LOG_FILE};
+use crate::boot::{capture_console, BootEvent, BOOT_EVENTS_CAPACITY, CONSOLE_LOG_FILE};
+use crate::cgroup::{Cgroup, CgroupConfig};
+use crate::health::{DirWatcher, HealthCheck, HealthStrategy};
+use crate::host;
+use crate::machine::{FirepilotError, IntoFirepilotError};
+use crate::telemetry;
+use crate::version::{VmmFeature, VmmVersion};
+use crate::workspace::{self, WorkspaceLock, CONFIG_FILE, PID_FILE, SOCKET_FILE};
+use firepilot_models::models::drive::IoEngine;
+use firepilot_models::models::instance_action_info::ActionType;
+use firepilot_models::models::mmds_config::Version as MmdsVersion;
+use firepilot_models::models::vm::{State, Vm};
+use firepilot_models::models::{
+    Balloon, BalloonStats, BalloonUpdate, BootSource, Drive, FirecrackerVersion,
+    FullVmConfiguration, InstanceActionInfo, InstanceInfo, MachineConfiguration, Metrics,
+    MmdsConfig, NetworkInterface, PartialDrive, PartialNetworkInterface, SnapshotLoadParams, Vsock,
+};
+use serde::{de::DeserializeOwned, Serialize};
 
 /// Interface to determine how to execute commands on the socket and where to do it
-pub trait Execute {
+///
+/// [FirecrackerExecutor] runs the firecracker binary directly, other
+/// implementations (e.g. wrapping the jailer, or a test double) can be given
+/// to [Executor::new_with_executor].
+pub trait Execute: std::fmt::Debug + Send + Sync {
     /// Define where all the drives, rootfs, kernel and socket will be created
     fn chroot(&self) -> PathBuf;
     /// Execute a command onto the binary behind the executor
     ///
     /// It is only used to spawn the executor process, not to send commands to it
-    fn spawn_binary_child(&self, args: &Vec<String>) -> Result<Child, ExecuteError>;
+    fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError>;
+    /// Spawn the binary in a new session with its output appended to
+    /// `output`, so it survives this process, see
+    /// [Executor::with_detached_spawn]. It isn't supported unless implemented.
+    fn spawn_detached_child(&self, args: &[String], output: File) -> Result<Child, ExecuteError> {
+        let _ = (args, output);
+        Err(ExecuteError::CommandExecution(
+            "Detached spawn isn't supported by this executor".to_string(),
+        ))
+    }
+    /// Binary spawned by [Execute::spawn_binary_child], when it is known, so a
+    /// reattached machine can spawn it again, see [crate::persist]
+    fn exec_binary(&self) -> Option<PathBuf> {
+        None
+    }
+    /// Network namespace the binary runs in, where its TAP devices must be,
+    /// none for the one of the host, see [crate::network::netns]
+    fn netns(&self) -> Option<PathBuf> {
+        None
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ExecuteError {
     #[error("Could not initate worksapce for machine, reason: {0}")]
     WorkspaceCreation(String),
+    #[error("Workspace is locked, reason: {0}")]
+    WorkspaceLocked(String),
     #[error("Could not delete worksapce for machine, reason: {0}")]
     WorkspaceDeletion(String),
     #[error("Could not execute command, reason: {0}")]
     CommandExecution(String),
+    #[error("Failed to manage the cgroup of the process, reason: {0}")]
+    Cgroup(String),
     #[error("Failed to manage socket, reason: {0}")]
     Socket(String),
     #[error("Could not send request on uri {0}, reason: {1}")]
-    Request(hyper::Uri, String),
+    Request(hyper::Uri, #[source] TransportError),
     #[error("Could not serialize request, reason: {0}")]
     Serialize(#[from] serde_json::Error),
+    #[error("Unexpected content type from uri {0}: {1}")]
+    ContentType(hyper::Uri, String),
+    #[error("Could not deserialize response from uri {0}, reason: {1}")]
+    Deserialize(hyper::Uri, #[source] serde_json::Error),
+    #[error("Failed to send request to {uri}, status: {status}, reason: {fault}")]
+    Api {
+        uri: hyper::Uri,
+        status: hyper::StatusCode,
+        fault: String,
+    },
+    #[error("Guest is not ready to handle {0}")]
+    GuestNotReady(String),
+    #[error("Process exited with code {code:?} before the socket was available: {stderr}")]
+    Exited { code: Option<i32>, stderr: String },
     #[error("Socket didn't start on time")]
     Unhealthy,
+    #[error("No implementation is configured on the executor, see Executor::new_with_executor")]
+    NoExecutor,
+    #[error("Not supported by the host, reason: {0}")]
+    UnsupportedByHost(String),
+    #[error("{feature} is not supported by firecracker {version}, it requires at least {}", feature.minimum_version())]
+    UnsupportedByVmm {
+        feature: VmmFeature,
+        version: VmmVersion,
+    },
 }
 
-impl From<ExecuteError> for FirepilotError {
-    fn from(e: ExecuteError) -> FirepilotError {
-        match e {
-            ExecuteError::CommandExecution(e) => FirepilotError::Setup(e),
-            ExecuteError::Request(url, e) => FirepilotError::Configure(format!("{}: {}", url, e)),
-            ExecuteError::Serialize(e) => FirepilotError::Configure(e.to_string()),
-            ExecuteError::Socket(e) => FirepilotError::Configure(e),
-            ExecuteError::WorkspaceCreation(e) => FirepilotError::Setup(e),
-            ExecuteError::WorkspaceDeletion(e) => FirepilotError::Setup(e),
-            ExecuteError::Unhealthy => {
-                FirepilotError::Configure("Socket didn't start on time".to_string())
+impl IntoFirepilotError for ExecuteError {
+    fn into_firepilot_error(self, vm_id: &str) -> FirepilotError {
+        // The error itself is kept as the source, the message only tells
+        // which step failed
+        let error = match &self {
+            ExecuteError::CommandExecution(_)
+            | ExecuteError::Cgroup(_)
+            | ExecuteError::WorkspaceCreation(_)
+            | ExecuteError::WorkspaceLocked(_)
+            | ExecuteError::WorkspaceDeletion(_)
+            | ExecuteError::NoExecutor => {
+                FirepilotError::setup(vm_id, "Failed to prepare the firecracker process")
             }
-        }
+            ExecuteError::GuestNotReady(_) => FirepilotError::GuestNotReady {
+                vm_id: vm_id.to_string(),
+                message: "Guest is not ready".to_string(),
+                source: None,
+            },
+            ExecuteError::Exited { .. } => {
+                FirepilotError::execute(vm_id, "Firecracker process exited")
+            }
+            ExecuteError::Request(..)
+            | ExecuteError::Serialize(_)
+            | ExecuteError::ContentType(..)
+            | ExecuteError::Deserialize(..)
+            | ExecuteError::Socket(_)
+            | ExecuteError::Unhealthy
+            | ExecuteError::UnsupportedByVmm { .. }
+            | ExecuteError::UnsupportedByHost(_)
+            | ExecuteError::Api { .. } => {
+                FirepilotError::configure(vm_id, "Request to the firecracker API failed")
+            }
+        };
+        error.with_source(self)
     }
 }
 
@@ -81,225 +185,968 @@ pub enum Action {
     SendCtrlAltDel,
 }
 
+impl From<Action> for ActionType {
+    fn from(action: Action) -> ActionType {
+        match action {
+            Action::InstanceStart => ActionType::InstanceStart,
+            Action::SendCtrlAltDel => ActionType::SendCtrlAltDel,
+        }
+    }
+}
+
 /// Contains an instance of the microVM, this low-level implementation hold the
 /// process and is able to talk to the socket in order to configure the microVM.
 #[derive(Debug)]
 pub struct Executor {
-    /// Optional executor, if none is provided, it will crash as no other
-    /// executor is available
-    ///
-    /// It is not a [Box<dyn Execute>] because we didn't want to use Boxes
-    /// everywhere. We could have been using an enum, but due to the small
-    /// number of implementation we judged it was not worth it.
-    firecracker: Option<FirecrackerExecutor>,
+    /// Optional executor, if none is provided, it will crash when spawning
+    /// the process or locating the workspace
+    execute: Option<Box<dyn Execute>>,
     /// Holds the process of the executor when it is running
-    socket_process: Option<Child>,
-    /// A RPC client to talk to the socket
-    client: Client<UnixConnector>,
+    socket_process: Option<ChildProcess>,
+    /// How requests are sent to the socket, HTTP over Unix sockets by default
+    transport: Arc<dyn Transport>,
     /// ID given when creating the executor, it doesn't need to be unique, but
     /// we really encourage to make it unique and it might collapse if you run
     /// two VM with the same ID at the same time (file system issues).
     id: String,
+    /// Version of the running firecracker process, known once the socket
+    /// started and [Executor::negotiate_version] was called
+    vmm_version: Option<VmmVersion>,
+    /// Lock on the workspace, taken by [Executor::create_workspace] and held
+    /// until the executor is dropped
+    workspace_lock: Option<WorkspaceLock>,
+    /// Pid of a process started by someone else and adopted with
+    /// [Executor::adopt], it can't be waited for as it isn't our child
+    adopted_pid: Option<Pid>,
+    /// Audit log of the workspace, available once it is created
+    audit: Option<AuditLog>,
+    /// Milestones read on the console of the guest, see [crate::boot]
+    boot_events: broadcast::Sender<BootEvent>,
+    /// Whether the output of the process is kept in [CONSOLE_LOG_FILE]
+    console_log: bool,
+    /// Limits of the cgroup the process is moved into, see [crate::cgroup]
+    cgroup_config: Option<CgroupConfig>,
+    /// Cgroup of the running process
+    cgroup: Option<Cgroup>,
+    /// Whether the process is left running when the executor is dropped, see
+    /// [Executor::detach]
+    detached: bool,
+    /// Whether the workspace is left when the executor is dropped while its
+    /// process runs, see [Executor::with_kept_workspace]
+    keep_workspace: bool,
+    /// How the process is found healthy once spawned, see [crate::health]
+    health_check: HealthCheck,
+    /// How requests are sent again after a transient error of the transport
+    retry: RetryPolicy,
+    /// Whether the process is spawned in a new session, see
+    /// [Executor::with_detached_spawn]
+    detached_spawn: bool,
+    /// Time given to the process to exit after SIGTERM before it is killed,
+    /// see [Executor::with_kill_grace_period]
+    kill_grace_period: Duration,
+    /// How the configuration reaches firecracker, see [BootMode]
+    boot_mode: BootMode,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Executor {
     /// Create a new Executor with no implementation, and with id "default"
     pub fn new() -> Executor {
         Executor {
-            firecracker: None,
+            execute: None,
             socket_process: None,
             id: "default".to_string(),
-            client: Client::unix(),
+            transport: Arc::new(Client::unix()),
+            vmm_version: None,
+            workspace_lock: None,
+            adopted_pid: None,
+            audit: None,
+            boot_events: broadcast::channel(BOOT_EVENTS_CAPACITY).0,
+            console_log: false,
+            cgroup_config: None,
+            cgroup: None,
+            detached: false,
+            keep_workspace: false,
+            health_check: HealthCheck::new(),
+            retry: RetryPolicy::new(),
+            detached_spawn: false,
+            kill_grace_period: DEFAULT_KILL_GRACE_PERIOD,
+            boot_mode: BootMode::Api,
         }
     }
     /// Create a new Executor with the firecracker binary
     pub fn new_with_firecracker(firecracker: FirecrackerExecutor) -> Executor {
-        Executor {
-            firecracker: Some(firecracker),
-            socket_process: None,
-            id: "default".to_string(),
-            client: Client::unix(),
-        }
+        Executor::new_with_executor(firecracker)
+    }
+
+    /// Create a new Executor spawning the process with a custom [Execute]
+    /// implementation
+    pub fn new_with_executor<E: Execute + 'static>(execute: E) -> Executor {
+        // Executor implements Drop, so it can't be built with `..Executor::new()`
+        let mut executor = Executor::new();
+        executor.execute = Some(Box::new(execute));
+        executor
     }
 
     /// Mutate the executor to have a new id
-    pub fn with_id(self, id: String) -> Executor {
-        Executor { id, ..self }
+    pub fn with_id(mut self, id: String) -> Executor {
+        self.id = id;
+        self
+    }
+
+    /// Mutate the executor to send its requests through the given [Transport]
+    /// instead of the default HTTP client over Unix sockets
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Executor {
+        self.transport = transport;
+        self
     }
 
-    /// Tells whether the mVM is running or not
+    /// Mutate the executor to keep the serial console of the guest, and the
+    /// logs of firecracker, in [CONSOLE_LOG_FILE] in the workspace
+    ///
+    /// The output is copied while it is read for the boot milestones, so it
+    /// works with any [Execute] implementation, see
+    /// [FirecrackerExecutorBuilder::with_console_log](crate::builder::executor::FirecrackerExecutorBuilder::with_console_log).
+    pub fn with_console_log(mut self) -> Executor {
+        self.console_log = true;
+        self
+    }
+
+    /// Mutate the executor to run the process in a dedicated cgroup with the
+    /// given limits, see [crate::cgroup]
+    pub fn with_cgroup(mut self, cgroup: CgroupConfig) -> Executor {
+        self.cgroup_config = Some(cgroup);
+        self
+    }
+
+    /// Mutate the executor to wait for the process to be healthy with the
+    /// given settings, see [crate::health]
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Executor {
+        self.health_check = health_check;
+        self
+    }
+
+    /// Mutate the executor to send its requests again after a transient error
+    /// with the given policy, see [RetryPolicy]
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Executor {
+        self.retry = retry;
+        self
+    }
+
+    /// Mutate the executor to give its process `grace_period` to exit after
+    /// SIGTERM before killing it, see [Executor::destroy_socket]
+    ///
+    /// A zero grace period kills the process right away.
+    pub fn with_kill_grace_period(mut self, grace_period: Duration) -> Executor {
+        self.kill_grace_period = grace_period;
+        self
+    }
+
+    /// Mutate the executor to boot firecracker from a configuration file, see
+    /// [BootMode]
+    pub fn with_boot_mode(mut self, boot_mode: BootMode) -> Executor {
+        self.boot_mode = boot_mode;
+        self
+    }
+
+    pub fn boot_mode(&self) -> BootMode {
+        self.boot_mode
+    }
+
+    /// Path of the configuration file firecracker boots from, unless it is
+    /// configured through its API, see [BootMode]
+    pub fn config_file_path(&self) -> PathBuf {
+        self.chroot().join(CONFIG_FILE)
+    }
+
+    /// Mutate the executor to spawn the process in a new session, so it
+    /// survives this process exiting and can be taken over later with
+    /// [Machine::reattach](crate::machine::Machine::reattach)
+    ///
+    /// The process isn't killed when the executor is dropped, see
+    /// [Executor::detach]. Its output, the serial console of the guest
+    /// included, is appended to [CONSOLE_LOG_FILE] rather than read through
+    /// pipes which would break when this process exits, so no boot events
+    /// are published.
+    pub fn with_detached_spawn(mut self) -> Executor {
+        self.detached_spawn = true;
+        self.detached = true;
+        self.console_log = true;
+        self
+    }
+
+    /// Leave the process running when the executor is dropped, so the microVM
+    /// outlives this process and can be adopted later, see [Executor::adopt]
+    ///
+    /// By default a dropped executor kills its process, as nothing else could
+    /// stop it.
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
+    /// Kill the process when the executor is dropped, the opposite of
+    /// [Executor::detach], e.g. once an adopted process is owned by this
+    /// process
+    pub fn attach(&mut self) {
+        self.detached = false;
+    }
+
+    /// Leave the workspace, drives and logs included, when the executor is
+    /// dropped while its process runs, e.g. to find out what happened
+    ///
+    /// By default it is removed along with the process, as after
+    /// [Executor::destroy_socket] and [Executor::delete_workspace].
+    pub fn with_kept_workspace(mut self) -> Executor {
+        self.keep_workspace = true;
+        self
+    }
+
+    /// Path of the log of the console, when it is kept
+    pub fn console_log_path(&self) -> Option<PathBuf> {
+        match self.console_log {
+            true => Some(self.chroot().join(CONSOLE_LOG_FILE)),
+            false => None,
+        }
+    }
+
+    /// Subscribe to the boot milestones of the guest, only the ones reached
+    /// after subscribing are received
+    pub fn boot_events(&self) -> broadcast::Receiver<BootEvent> {
+        self.boot_events.subscribe()
+    }
+
+    /// Id of the executor, which is the name of its workspace
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Binary spawned by the executor, when it is known
+    pub fn exec_binary(&self) -> Option<PathBuf> {
+        self.execute
+            .as_ref()
+            .and_then(|execute| execute.exec_binary())
+    }
+
+    /// Network namespace of the process, see [Execute::netns]
+    pub fn netns(&self) -> Option<PathBuf> {
+        self.execute.as_ref().and_then(|execute| execute.netns())
+    }
+
+    /// Pid of the process, spawned or adopted, until it is destroyed or waited
+    /// for. It is also written to [PID_FILE] in the workspace.
+    pub fn pid(&self) -> Option<u32> {
+        let pid = match &self.socket_process {
+            Some(process) => process.pid,
+            None => self.adopted_pid?,
+        };
+        Some(pid.as_raw() as u32)
+    }
+
+    /// Path of the file holding the pid of the process
+    pub fn pid_path(&self) -> PathBuf {
+        self.chroot().join(PID_FILE)
+    }
+
+    fn write_pid_file(&self) {
+        if let Some(pid) = self.pid() {
+            if let Err(e) = std::fs::write(self.pid_path(), format!("{}\n", pid)) {
+                warn!(
+                    "Failed to write the pid file of executor {}: {}",
+                    self.id, e
+                );
+            }
+        }
+    }
+
+    /// Tells whether the mVM is running or not, it isn't anymore as soon as
+    /// the process exited, even if nobody waited for it
     pub fn is_running(&self) -> bool {
-        self.socket_process.is_some()
+        match &self.socket_process {
+            Some(process) => process.exit_status().is_none(),
+            None => self.adopted_pid.is_some(),
+        }
+    }
+
+    /// Receive the exit status of the spawned process as soon as it exits,
+    /// without waiting for it like [Executor::wait]. There is none for an
+    /// adopted process, which isn't a child of this process.
+    pub fn subscribe_exit(&self) -> Option<watch::Receiver<Option<ExitStatus>>> {
+        self.socket_process
+            .as_ref()
+            .map(|process| process.exit.clone())
+    }
+
+    /// Whether an [Execute] implementation is configured, the executor
+    /// can't spawn nor manage a workspace without one
+    pub fn has_implementation(&self) -> bool {
+        self.execute.is_some()
     }
 
-    /// Return the configured executor, or panic if none is configured
-    fn executor(&self) -> &dyn Execute {
-        match &self.firecracker {
-            Some(firecracker) => return firecracker,
-            None => panic!("No executor found"),
+    /// Return the configured executor, see [Executor::has_implementation]
+    fn executor(&self) -> Result<&dyn Execute, ExecuteError> {
+        match &self.execute {
+            Some(execute) => Ok(execute.as_ref()),
+            None => Err(ExecuteError::NoExecutor),
         }
     }
 
-    #[instrument(skip(self), fields(id = %self.id))]
-    fn wait_healthy(&self) -> Result<(), ExecuteError> {
-        debug!("Waiting for socket to be healthy");
-        let sock = self.chroot().join("firecracker.socket");
-        let mut retries = 0;
-        while retries < 10 {
-            let res = std::fs::metadata(&sock);
-            if res.is_ok() {
+    /// Wait for the socket of the given process to be created, returns the
+    /// exit status instead if the process died before
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    async fn wait_healthy(&self, child: &mut Child) -> Result<Option<ExitStatus>, ExecuteError> {
+        let check = &self.health_check;
+        if self.boot_mode == BootMode::ConfigFileNoApi {
+            // Without a socket, the process is only expected not to reject
+            // its configuration file right away
+            sleep(check.interval).await;
+            return child
+                .try_wait()
+                .map_err(|e| ExecuteError::Socket(e.to_string()));
+        }
+        debug!("Waiting for socket to be healthy ({:?})", check.strategy);
+        let deadline = Instant::now() + check.max_wait;
+        let watcher = match DirWatcher::new(&self.chroot()) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                debug!("Can't watch the workspace, polling it instead: {}", e);
+                None
+            }
+        };
+        loop {
+            if let Some(status) = child
+                .try_wait()
+                .map_err(|e| ExecuteError::Socket(e.to_string()))?
+            {
+                debug!("Process exited before the socket was healthy");
+                return Ok(Some(status));
+            }
+            if self.is_healthy(check.strategy).await {
                 debug!("Socket is now healthy");
-                return Ok(());
+                return Ok(None);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let wait = check.interval.min(deadline - now);
+            match &watcher {
+                // Check again as soon as the socket is created
+                Some(watcher) if !self.socket_path().exists() => watcher.created(wait).await,
+                _ => sleep(wait).await,
             }
-            retries += 1;
-            std::thread::sleep(std::time::Duration::from_millis(50));
         }
-        debug!("Socket is not healthy");
+        debug!("Socket is not healthy after {:?}", check.max_wait);
         Err(ExecuteError::Unhealthy)
     }
 
-    #[instrument(skip_all, fields(id = %self.id))]
-    async fn send_request(
-        &self,
-        url: hyper::Uri,
-        method: Method,
-        body: String,
-    ) -> Result<(), ExecuteError> {
-        debug!("Send request to socket: {}", url);
-        trace!("Sent body to socket [{}]: {}", url, body);
-        let request = Request::builder()
-            .method(method)
-            .uri(url.clone())
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(Body::from(body))
-            .map_err(|e| ExecuteError::Request(url.clone(), e.to_string()))?;
-
-        let response = self
-            .client
-            .request(request)
+    async fn is_healthy(&self, strategy: HealthStrategy) -> bool {
+        match strategy {
+            HealthStrategy::SocketFile => self.socket_path().exists(),
+            // The socket file exists before firecracker accepts requests on it
+            HealthStrategy::Api => match timeout(HEALTH_PING_TIMEOUT, self.ping()).await {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    trace!("Socket is not healthy yet: {}", e);
+                    false
+                }
+                Err(_) => {
+                    trace!("Socket didn't answer within {:?}", HEALTH_PING_TIMEOUT);
+                    false
+                }
+            },
+        }
+    }
+
+    /// Check that firecracker answers on its API socket: `GET /` must return
+    /// `200 OK`. Pings aren't recorded in the audit log.
+    pub async fn ping(&self) -> Result<(), ExecuteError> {
+        // The health check already retries
+        FirecrackerClient::with_transport(self.socket_path(), self.transport.clone())
+            .with_retry(RetryPolicy::none())
+            .ping()
             .await
-            .map_err(|e| ExecuteError::Request(url.clone(), e.to_string()))?;
-
-        trace!("Response status: {:#?}", response.status());
-        let status = response.status();
-        if !status.is_success() {
-            error!("Request to socket failed [{}]: {:#?}", url, status);
-            // body stream to string
-            let body = hyper::body::to_bytes(response.into_body())
-                .await
-                .map_err(|e| ExecuteError::Request(url.clone(), e.to_string()))?;
-            error!(
-                "Request [{}] body: {}",
-                url,
-                String::from_utf8(body.to_vec()).unwrap()
-            );
-            return Err(ExecuteError::CommandExecution(format!(
-                "Failed to send request to {}, status: {}",
-                url, status
-            )));
+    }
+
+    /// Path to the API socket of the firecracker process
+    pub fn socket_path(&self) -> PathBuf {
+        self.chroot().join(SOCKET_FILE)
+    }
+
+    /// Typed client to send requests to the API socket of the microVM, it can
+    /// be used to reach endpoints which are not wrapped by the executor
+    pub fn api(&self) -> FirecrackerClient {
+        let client = FirecrackerClient::with_transport(self.socket_path(), self.transport.clone())
+            .with_retry(self.retry);
+        match &self.audit {
+            Some(audit) => client.with_audit(audit.clone()),
+            None => client,
+        }
+    }
+
+    /// Append a record to the audit log of the workspace, if it exists
+    fn audit<T, E: std::fmt::Display>(
+        &self,
+        operation: &str,
+        params: Option<&[u8]>,
+        outcome: &Result<T, E>,
+    ) {
+        if let Some(audit) = &self.audit {
+            audit.record(operation, params, outcome);
         }
+    }
+
+    /// Send a `GET` request to the socket on the given path and deserialize
+    /// the response into the expected type
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ExecuteError> {
+        self.api().get(path).await
+    }
+
+    /// Send a `PUT` request to the socket on the given path with the
+    /// serialized body
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, body), fields(id = %self.id)))]
+    pub async fn put<B: Serialize>(&self, path: &str, body: &B) -> Result<(), ExecuteError> {
+        self.api().put(path, body).await
+    }
+
+    /// Send a `PATCH` request to the socket on the given path with the
+    /// serialized body, it is used for updates of already configured
+    /// resources: VM state, drives, balloon, MMDS, etc...
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, body), fields(id = %self.id)))]
+    pub async fn patch<B: Serialize>(&self, path: &str, body: &B) -> Result<(), ExecuteError> {
+        self.api().patch(path, body).await
+    }
+
+    /// Fetch general information about the microVM, including its state
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn describe_instance(&self) -> Result<InstanceInfo, ExecuteError> {
+        debug!("Describe instance");
+        self.get("/").await
+    }
 
+    /// Fetch the version of the firecracker process behind the socket
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn version(&self) -> Result<FirecrackerVersion, ExecuteError> {
+        debug!("Fetch firecracker version");
+        self.get("/version").await
+    }
+
+    /// Fetch the machine configuration applied by firecracker, defaults
+    /// included
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn describe_machine_config(&self) -> Result<MachineConfiguration, ExecuteError> {
+        debug!("Describe machine configuration");
+        self.api().get_machine_configuration().await
+    }
+
+    /// Fetch the whole configuration applied by firecracker, in the format
+    /// of its `--config-file`, e.g. to detect a drift from the [Configuration](crate::builder::Configuration)
+    /// the microVM was created with
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn describe_vm_config(&self) -> Result<FullVmConfiguration, ExecuteError> {
+        debug!("Describe VM configuration");
+        self.api().get_export_vm_config().await
+    }
+
+    /// Query the version of the running firecracker process and keep it, so
+    /// optional features can be gated with [Executor::require]
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn negotiate_version(&mut self) -> Result<VmmVersion, ExecuteError> {
+        let version = self.version().await?;
+        let version = version
+            .firecracker_version
+            .parse::<VmmVersion>()
+            .map_err(ExecuteError::CommandExecution)?;
+        info!("Socket runs firecracker {}", version);
+        self.vmm_version = Some(version);
+        Ok(version)
+    }
+
+    /// Version of the running firecracker process, if it was negotiated
+    pub fn vmm_version(&self) -> Option<VmmVersion> {
+        self.vmm_version
+    }
+
+    /// Ensure the running firecracker process supports the given feature,
+    /// when the version is unknown the feature is considered available and
+    /// the VMM will reject the request itself if it doesn't.
+    pub fn require(&self, feature: VmmFeature) -> Result<(), ExecuteError> {
+        match self.vmm_version {
+            Some(version) if !version.supports(feature) => {
+                Err(ExecuteError::UnsupportedByVmm { feature, version })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Ensure both the VMM and the host kernel support the `Async` io engine,
+    /// otherwise the drive configuration fails deep inside firecracker
+    fn require_async_io(&self) -> Result<(), ExecuteError> {
+        self.require(VmmFeature::AsyncIoEngine)?;
+        let kernel = host::kernel_version().map_err(ExecuteError::UnsupportedByHost)?;
+        if kernel < host::ASYNC_IO_MIN_KERNEL {
+            return Err(ExecuteError::UnsupportedByHost(format!(
+                "Async io engine requires a host kernel >= {}, running {}",
+                host::ASYNC_IO_MIN_KERNEL,
+                kernel
+            )));
+        }
         Ok(())
     }
 
     /// Sends a specific [Action] to the microVM
-    #[instrument(skip_all, fields(id = %self.id))]
+    ///
+    /// CtrlAltDel is rejected by firecracker until the guest initialized its
+    /// keyboard controller, this is reported as [ExecuteError::GuestNotReady].
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
     pub async fn send_action(&self, action: Action) -> Result<(), ExecuteError> {
         debug!("Send action to socket: {:#?}", action);
-        let json = serde_json::to_string(&action).map_err(ExecuteError::Serialize)?;
-
-        let url: hyper::Uri = Uri::new(self.chroot().join("firecracker.socket"), "/actions").into();
-        self.send_request(url, Method::PUT, json).await?;
-        Ok(())
+        let ctrl_alt_del = matches!(action, Action::SendCtrlAltDel);
+        match self
+            .api()
+            .create_sync_action(&InstanceActionInfo::new(action.into()))
+            .await
+        {
+            Err(ExecuteError::Api { fault, .. })
+                if ctrl_alt_del && fault.to_lowercase().contains("i8042") =>
+            {
+                Err(ExecuteError::GuestNotReady(format!(
+                    "CtrlAltDel: {}",
+                    fault
+                )))
+            }
+            result => result,
+        }
     }
 
     /// Sets the microVM the to the specified state
-    #[instrument(skip_all, fields(id = %self.id))]
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
     pub async fn set_vm_state(&self, state: Vm) -> Result<(), ExecuteError> {
         debug!("Change VM state: {:#?}", state);
-        let json = serde_json::to_string(&state).map_err(ExecuteError::Serialize)?;
+        self.patch("/vm", &state).await
+    }
 
-        let url: hyper::Uri = Uri::new(self.chroot().join("firecracker.socket"), "/vm").into();
-        self.send_request(url, Method::PATCH, json).await?;
-        Ok(())
+    /// Change the backing file or the rate limiter of a drive, only after boot
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id, drive_id = %drive.drive_id)))]
+    pub async fn update_drive(&self, drive: PartialDrive) -> Result<(), ExecuteError> {
+        debug!("Update drive {}", drive.drive_id);
+        trace!("Drive update: {:#?}", drive);
+        self.api().patch_guest_drive_by_id(&drive).await
+    }
+
+    /// Change the rate limiters of a network interface, only after boot
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id, iface_id = %iface.iface_id)))]
+    pub async fn update_network_interface(
+        &self,
+        iface: PartialNetworkInterface,
+    ) -> Result<(), ExecuteError> {
+        debug!("Update network interface {}", iface.iface_id);
+        trace!("Network interface update: {:#?}", iface);
+        self.api().patch_guest_network_interface_by_id(&iface).await
+    }
+
+    /// Fetch the latest statistics of the balloon device
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn balloon_stats(&self) -> Result<BalloonStats, ExecuteError> {
+        debug!("Describe balloon statistics");
+        self.api().describe_balloon_stats().await
+    }
+
+    /// Change the target size of the balloon, only after boot
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn resize_balloon(&self, amount_mib: i32) -> Result<(), ExecuteError> {
+        debug!("Resize balloon to {} MiB", amount_mib);
+        self.api()
+            .patch_balloon(&BalloonUpdate::new(amount_mib))
+            .await
+    }
+
+    /// Pause the vCPUs of a running microVM, its memory is kept as is
+    pub async fn pause(&self) -> Result<(), ExecuteError> {
+        self.set_vm_state(Vm::new(State::Paused)).await
+    }
+
+    /// Resume the vCPUs of a paused microVM
+    pub async fn resume(&self) -> Result<(), ExecuteError> {
+        self.set_vm_state(Vm::new(State::Resumed)).await
     }
 
     /// Full path to the chroot of the machine which contains the socket, drives, kernel, etc...
+    ///
+    /// Without an implementation, there is no directory the workspaces are
+    /// in and the id alone is returned. The workspace can't be created nor
+    /// deleted then, see [ExecuteError::NoExecutor].
     pub fn chroot(&self) -> PathBuf {
-        self.executor().chroot().join(&self.id)
+        match &self.execute {
+            Some(execute) => execute.chroot().join(&self.id),
+            None => PathBuf::from(&self.id),
+        }
     }
 
     /// Tries to spawn the executor process, the workspace for the machine should
     /// already exist ([create_workspace] should have been called)
-    #[instrument(skip(self), fields(id = %self.id))]
-    pub fn run_socket(&mut self) -> Result<(), ExecuteError> {
+    ///
+    /// If the process exits before its socket is available, e.g. because of
+    /// invalid arguments or a missing `/dev/kvm`, [ExecuteError::Exited] is
+    /// returned with its exit code and the end of its standard error.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn run_socket(&mut self) -> Result<(), ExecuteError> {
+        self.executor()?;
         info!("Running the socket");
-        let executor = self.executor();
-        let sock = self.chroot().join("firecracker.socket");
-
-        let child = executor.spawn_binary_child(&vec![
-            "--api-sock".to_string(),
-            sock.into_os_string().into_string().unwrap(),
-        ])?;
-        self.wait_healthy()?;
-        self.socket_process = Some(child);
+        let socket_path = self.socket_path().into_os_string().into_string().unwrap();
+        let config_path = self
+            .config_file_path()
+            .into_os_string()
+            .into_string()
+            .unwrap();
+        let args = match self.boot_mode {
+            BootMode::Api => vec!["--api-sock".to_string(), socket_path],
+            BootMode::ConfigFile => vec![
+                "--api-sock".to_string(),
+                socket_path,
+                "--config-file".to_string(),
+                config_path,
+            ],
+            BootMode::ConfigFileNoApi => vec![
+                "--no-api".to_string(),
+                "--config-file".to_string(),
+                config_path,
+            ],
+        };
+        let result = self.spawn_socket(&args).await;
+        self.audit("spawn", Some(args.join(" ").as_bytes()), &result);
+        result
+    }
+
+    /// Move the spawned process into its cgroup, when limits are configured
+    fn limit_process(&mut self, child: &Child) -> Result<(), ExecuteError> {
+        let config = match &self.cgroup_config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let pid = child
+            .id()
+            .ok_or_else(|| ExecuteError::Cgroup("Process already exited".to_string()))?;
+        let cgroup = config
+            .create(&self.id)
+            .map_err(|e| ExecuteError::Cgroup(e.to_string()))?;
+        debug!("Move process {} into cgroup {:?}", pid, cgroup.paths());
+        let placed = cgroup.add_process(pid);
+        self.cgroup = Some(cgroup);
+        placed.map_err(|e| ExecuteError::Cgroup(e.to_string()))
+    }
+
+    /// Remove the cgroup of the process once it is gone
+    fn release_cgroup(&mut self) {
+        if let Some(cgroup) = self.cgroup.take() {
+            if let Err(e) = cgroup.remove() {
+                warn!("Failed to remove cgroup {:?}: {}", cgroup.paths(), e);
+            }
+        }
+    }
+
+    /// Open the log of the console for appending, when it is kept
+    async fn open_console_log(&self) -> Result<Option<tokio::fs::File>, ExecuteError> {
+        let path = match self.console_log_path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                ExecuteError::WorkspaceCreation(format!("Failed to open {:?}: {}", path, e))
+            })?;
+        Ok(Some(file))
+    }
+
+    /// Last lines of the console log, when it is kept
+    fn console_log_tail(&self) -> String {
+        let log = match self.console_log_path() {
+            Some(path) => std::fs::read_to_string(path).unwrap_or_default(),
+            None => return String::new(),
+        };
+        let lines: Vec<&str> = log.lines().collect();
+        lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+    }
+
+    async fn spawn_socket(&mut self, args: &[String]) -> Result<(), ExecuteError> {
+        let spawned = Instant::now();
+        let mut child = match self.detached_spawn {
+            true => {
+                let path = self.chroot().join(CONSOLE_LOG_FILE);
+                let output = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| {
+                        ExecuteError::WorkspaceCreation(format!("Failed to open {:?}: {}", path, e))
+                    })?;
+                self.executor()?.spawn_detached_child(args, output)?
+            }
+            false => self.executor()?.spawn_binary_child(args)?,
+        };
+        if let Some(stdout) = child.stdout.take() {
+            capture_console(
+                stdout,
+                self.id.clone(),
+                self.boot_events.clone(),
+                spawned,
+                self.open_console_log().await?,
+            );
+        }
+        if let Err(e) = self.limit_process(&child) {
+            let _ = child.kill().await;
+            self.release_cgroup();
+            return Err(e);
+        }
+        let stderr = match child.stderr.take() {
+            Some(stderr) => Some(capture_stderr(
+                stderr,
+                self.id.clone(),
+                self.open_console_log().await?,
+            )),
+            None => None,
+        };
+        match self.wait_healthy(&mut child).await {
+            Ok(None) => {}
+            Ok(Some(status)) => {
+                let stderr = match stderr {
+                    Some(task) => timeout(STDERR_DRAIN_TIMEOUT, task)
+                        .await
+                        .ok()
+                        .and_then(Result::ok)
+                        .unwrap_or_default(),
+                    // The output of a detached process only is in the console log
+                    None => self.console_log_tail(),
+                };
+                self.release_cgroup();
+                return Err(ExecuteError::Exited {
+                    code: status.code(),
+                    stderr,
+                });
+            }
+            Err(e) => {
+                let _ = child.kill().await;
+                self.release_cgroup();
+                return Err(e);
+            }
+        }
+        self.socket_process = Some(ChildProcess::monitor(child, self.id.clone())?);
+        self.write_pid_file();
+        telemetry::vm_spawned();
         debug!("Socket is now running");
         Ok(())
     }
 
-    /// Shutdown abruptly the socket process, if the VM was running it will stop it
-    #[instrument(skip(self), fields(id = %self.id))]
+    /// Shutdown the socket process, if the VM was running it will stop it
+    ///
+    /// The process is sent SIGTERM first so firecracker can flush its devices,
+    /// and SIGKILL if it is still running after the grace period, see
+    /// [Executor::with_kill_grace_period].
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
     pub async fn destroy_socket(&mut self) -> Result<(), ExecuteError> {
         info!("Destroying the socket");
-        let sock_path = self.chroot().join("firecracker.socket");
+        let result = self.kill_socket().await;
+        self.audit("kill", None, &result);
+        result
+    }
+
+    async fn kill_socket(&mut self) -> Result<(), ExecuteError> {
+        let sock_path = self.socket_path();
 
+        if let Some(pid) = self.adopted_pid {
+            terminate_adopted(pid, self.kill_grace_period).await?;
+            self.release_socket(sock_path)?;
+            return Ok(());
+        }
         let socket = self.socket_process.as_mut().ok_or_else(|| {
             ExecuteError::Socket(
                 "Socket hasn't been spawned, you must spawn it before destroying it".to_string(),
             )
         })?;
-        socket
-            .kill()
-            .await
-            .map_err(|e| ExecuteError::Socket(e.to_string()))?;
-        std::fs::remove_file(sock_path).map_err(|e| ExecuteError::Socket(e.to_string()))?;
+        socket.terminate(self.kill_grace_period).await?;
+        self.release_socket(sock_path)?;
         debug!("Socket is now destroyed and the socket file doesn't exist anymore");
+        Ok(())
+    }
+
+    /// Wait for the executor process to exit by itself, e.g. after the guest
+    /// shut down, for at most `max_wait`. Returns whether it exited.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub async fn wait_exit(&mut self, max_wait: Duration) -> Result<bool, ExecuteError> {
+        match timeout(max_wait, self.wait()).await {
+            Ok(result) => result.map(|_| true),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Wait, without any time limit, for the executor process to exit by
+    /// itself, and release its socket once it did
+    ///
+    /// Returns the exit status of the process, or `None` when it isn't known:
+    /// an adopted process can only be polled until it disappears, and there is
+    /// nothing to wait for when no process runs.
+    pub async fn wait(&mut self) -> Result<Option<ExitStatus>, ExecuteError> {
+        let sock_path = self.socket_path();
+        if let Some(pid) = self.adopted_pid {
+            // Not our child, it can only be polled until it disappears
+            while signal::kill(pid, None).is_ok() {
+                sleep(ADOPTED_POLL_INTERVAL).await;
+            }
+            info!("Adopted executor process {} exited", pid);
+            self.audit::<(), ExecuteError>("exit", None, &Ok(()));
+            self.release_socket(sock_path)?;
+            return Ok(None);
+        }
+        let socket = match self.socket_process.as_mut() {
+            Some(socket) => socket,
+            None => return Ok(None),
+        };
+        let status = socket.wait().await?;
+        info!("Executor process exited with {}", status);
+        self.audit::<(), String>("exit", Some(status.to_string().as_bytes()), &Ok(()));
+        self.release_socket(sock_path)?;
+        Ok(Some(status))
+    }
+
+    /// Take over the firecracker process of an existing workspace, e.g. after
+    /// the process which spawned it crashed, see [crate::workspace::list]
+    ///
+    /// The workspace is locked and the process is found from its API socket.
+    /// As it isn't a child of this process, its standard error isn't captured.
+    ///
+    /// As it was started elsewhere, the process is left running when the
+    /// executor is dropped unless [Executor::attach] is called.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub fn adopt(&mut self) -> Result<(), ExecuteError> {
+        self.executor()?;
+        if self.is_running() {
+            return Err(ExecuteError::Socket(
+                "Executor already has a running process".to_string(),
+            ));
+        }
+        let lock = WorkspaceLock::acquire(&self.chroot())?;
+        let sock_path = self.socket_path();
+        let pid = workspace::find_process(&sock_path).ok_or_else(|| {
+            ExecuteError::Socket(format!("No process is serving {}", sock_path.display()))
+        })?;
+        info!("Adopted executor process {}", pid);
+        self.audit = Some(AuditLog::new(&self.chroot()));
+        self.audit::<(), ExecuteError>("adopt", Some(pid.to_string().as_bytes()), &Ok(()));
+        self.workspace_lock = Some(lock);
+        self.adopted_pid = Some(Pid::from_raw(pid));
+        self.detached = true;
+        self.write_pid_file();
+        telemetry::vm_spawned();
+        Ok(())
+    }
+
+    /// Forget about the executor process once it exited, and remove its socket
+    fn release_socket(&mut self, sock_path: PathBuf) -> Result<(), ExecuteError> {
+        match std::fs::remove_file(sock_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ExecuteError::Socket(e.to_string()))
+            }
+            _ => {}
+        }
+        let _ = std::fs::remove_file(self.pid_path());
         self.socket_process = None;
+        self.adopted_pid = None;
+        self.release_cgroup();
+        telemetry::vm_destroyed();
         Ok(())
     }
 
     /// Apply the boot source configuration to the VM
-    #[instrument(skip_all, fields(id = %self.id))]
+    ///
+    /// Nothing is sent if firecracker already has this boot source, so it can
+    /// be applied again on a VM which isn't booted yet, see
+    /// [Executor::describe_vm_config]
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
     pub async fn configure_boot_source(&self, boot_source: BootSource) -> Result<(), ExecuteError> {
         debug!("Configure boot source");
         trace!("Boot source: {:#?}", boot_source);
-        let json = serde_json::to_string(&boot_source).map_err(ExecuteError::Serialize)?;
-
-        let url: hyper::Uri =
-            Uri::new(self.chroot().join("firecracker.socket"), "/boot-source").into();
-        self.send_request(url, Method::PUT, json).await?;
-        Ok(())
+        let current = self.current_vm_config().await;
+        let applied = current.and_then(|config| config.boot_source);
+        if matches!(&applied, Some(applied) if is_applied(&boot_source, applied)) {
+            debug!("Boot source is already configured");
+            return Ok(());
+        }
+        self.api().put_guest_boot_source(&boot_source).await
     }
 
     /// Apply all drives configuration on the VM
-    #[instrument(skip_all, fields(id = %self.id))]
+    ///
+    /// Drives firecracker already has with the same configuration are
+    /// skipped, so they can be applied again on a VM which isn't booted yet
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
     pub async fn configure_drives(&self, drives: Vec<Drive>) -> Result<(), ExecuteError> {
         debug!("Configure drives");
+        if drives.is_empty() {
+            return Ok(());
+        }
+        // Before any drive reaches the VMM
+        if drives
+            .iter()
+            .any(|drive| drive.io_engine == Some(IoEngine::Async))
+        {
+            self.require_async_io()?;
+        }
+        let applied = self
+            .current_vm_config()
+            .await
+            .and_then(|config| config.drives)
+            .unwrap_or_default();
         for drive in drives {
+            let unchanged = applied
+                .iter()
+                .any(|current| current.drive_id == drive.drive_id && is_applied(&drive, current));
+            if unchanged {
+                debug!("Drive {} is already configured", drive.drive_id);
+                continue;
+            }
             debug!("Configure drive {}", drive.drive_id);
             trace!("Drive: {:#?}", drive);
-            let json = serde_json::to_string(&drive).map_err(ExecuteError::Serialize)?;
-
-            let path = format!("/drives/{}", drive.drive_id);
-            let url: hyper::Uri = Uri::new(self.chroot().join("firecracker.socket"), &path).into();
-            self.send_request(url, Method::PUT, json).await?;
+            self.api().put_guest_drive_by_id(&drive).await?;
         }
         Ok(())
     }
 
+    /// Apply the configuration of a single drive, e.g. one attached to a VM
+    /// which is already configured, without comparing it with the drives
+    /// firecracker has
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_drive(&self, drive: Drive) -> Result<(), ExecuteError> {
+        debug!("Configure drive {}", drive.drive_id);
+        if drive.io_engine == Some(IoEngine::Async) {
+            self.require_async_io()?;
+        }
+        trace!("Drive: {:#?}", drive);
+        self.api().put_guest_drive_by_id(&drive).await
+    }
+
+    /// Configuration applied by firecracker, if it can tell: otherwise all
+    /// the resources are configured again
+    async fn current_vm_config(&self) -> Option<FullVmConfiguration> {
+        match self.describe_vm_config().await {
+            Ok(config) => Some(config),
+            Err(e) => {
+                debug!("Can't compare with the applied configuration: {}", e);
+                None
+            }
+        }
+    }
+
     /// Apply network configuration on the VM
-    #[instrument(skip_all, fields(id = %self.id))]
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
     pub async fn configure_network(
         &self,
         network_interfaces: Vec<NetworkInterface>,
@@ -308,24 +1155,481 @@ impl Executor {
         for network_interface in network_interfaces {
             debug!("Configure network interface {}", network_interface.iface_id);
             trace!("Network interface: {:#?}", network_interface);
-            let json =
-                serde_json::to_string(&network_interface).map_err(ExecuteError::Serialize)?;
-
-            let path = format!("/network-interfaces/{}", network_interface.iface_id);
-            let url: hyper::Uri = Uri::new(self.chroot().join("firecracker.socket"), &path).into();
-            self.send_request(url, Method::PUT, json).await?;
+            self.api()
+                .put_guest_network_interface_by_id(&network_interface)
+                .await?;
         }
         Ok(())
     }
 
-    /// Create needed folders where the VM will be configured
-    #[instrument(skip(self), fields(id = %self.id))]
-    pub fn create_workspace(&self) -> Result<(), ExecuteError> {
+    /// Apply the vsock configuration on the VM
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_vsock(&self, vsock: Vsock) -> Result<(), ExecuteError> {
+        debug!("Configure vsock");
+        trace!("Vsock: {:#?}", vsock);
+        self.api().put_guest_vsock(&vsock).await
+    }
+
+    /// Apply the vCPUs and memory configuration on the VM, only before boot
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_machine_config(
+        &self,
+        machine_config: MachineConfiguration,
+    ) -> Result<(), ExecuteError> {
+        debug!("Configure machine");
+        trace!("Machine configuration: {:#?}", machine_config);
+        self.api().put_machine_configuration(&machine_config).await
+    }
+
+    /// Apply a custom CPU template on the VM, only before boot, see
+    /// [CpuConfigBuilder](crate::builder::cpu_config::CpuConfigBuilder)
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_cpu_config(
+        &self,
+        cpu_config: &serde_json::Value,
+    ) -> Result<(), ExecuteError> {
+        debug!("Configure custom CPU template");
+        trace!("CPU template: {:#}", cpu_config);
+        self.require(VmmFeature::CpuConfig)?;
+        self.api().put("/cpu-config", cpu_config).await
+    }
+
+    /// Add the balloon device to the VM, only before boot
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_balloon(&self, balloon: Balloon) -> Result<(), ExecuteError> {
+        debug!("Configure balloon");
+        trace!("Balloon: {:#?}", balloon);
+        self.api().put_balloon(&balloon).await
+    }
+
+    /// Add the entropy device to the VM, only before boot
+    #[cfg(feature = "firecracker-1-4")]
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_entropy(
+        &self,
+        entropy: firepilot_models::models::EntropyDevice,
+    ) -> Result<(), ExecuteError> {
+        debug!("Configure entropy device");
+        trace!("Entropy device: {:#?}", entropy);
+        self.require(VmmFeature::Entropy)?;
+        self.api().put_entropy_device(&entropy).await
+    }
+
+    /// Make firecracker write its metrics to the given path, only before boot.
+    /// A FIFO is created at the path if nothing exists there yet.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_metrics(&self, metrics: Metrics) -> Result<(), ExecuteError> {
+        debug!("Configure metrics");
+        trace!("Metrics: {:#?}", metrics);
+        let path = Path::new(&metrics.metrics_path);
+        if !path.exists() {
+            mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(|e| {
+                ExecuteError::WorkspaceCreation(format!(
+                    "Failed to create metrics FIFO {:?}: {}",
+                    path, e
+                ))
+            })?;
+        }
+        self.api().put_metrics(&metrics).await
+    }
+
+    /// Enable MMDS on the VM, only before boot and once the network interfaces
+    /// it uses are configured
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn configure_mmds(&self, mmds: MmdsConfig) -> Result<(), ExecuteError> {
+        debug!("Configure MMDS");
+        trace!("MMDS: {:#?}", mmds);
+        if mmds.version == Some(MmdsVersion::V2) {
+            self.require(VmmFeature::MmdsV2)?;
+        }
+        self.api().put_mmds_config(&mmds).await
+    }
+
+    /// Restore the microVM from a snapshot, only on a firecracker process
+    /// which wasn't configured
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(id = %self.id)))]
+    pub async fn load_snapshot(&self, params: SnapshotLoadParams) -> Result<(), ExecuteError> {
+        debug!("Load snapshot {}", params.snapshot_path);
+        trace!("Snapshot: {:#?}", params);
+        self.api().load_snapshot(&params).await
+    }
+
+    /// Create needed folders where the VM will be configured, and lock the
+    /// workspace so no other executor can use it, see [crate::workspace]
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub fn create_workspace(&mut self) -> Result<(), ExecuteError> {
+        self.executor()?;
         debug!("Creating workspace at {}", self.chroot().display());
         std::fs::create_dir_all(self.chroot())
             .map_err(|e| ExecuteError::WorkspaceCreation(e.to_string()))?;
+        if self.workspace_lock.is_none() {
+            self.workspace_lock = Some(WorkspaceLock::acquire(&self.chroot())?);
+            self.audit = Some(AuditLog::new(&self.chroot()));
+            self.audit::<(), ExecuteError>("create_workspace", None, &Ok(()));
+        }
         Ok(())
     }
+
+    /// Remove the workspace once the process is gone: drives, kernel, socket
+    /// and logs, and release the addresses leased to the microVM
+    ///
+    /// With `keep_logs`, the console and audit logs are left in the workspace
+    /// for inspection. The workspace is unlocked either way.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self), fields(id = %self.id)))]
+    pub fn delete_workspace(&mut self, keep_logs: bool) -> Result<(), ExecuteError> {
+        let root = self.executor()?.chroot();
+        if self.is_running() {
+            return Err(ExecuteError::WorkspaceDeletion(
+                "The process is still running, it must be stopped first".to_string(),
+            ));
+        }
+        if self.socket_process.is_some() {
+            // It exited without anybody waiting for it
+            self.release_socket(self.socket_path())?;
+        }
+        self.remove_workspace(&root, keep_logs)
+    }
+
+    /// Remove the workspace, whether the process is gone or not, see
+    /// [Executor::delete_workspace]
+    fn remove_workspace(&mut self, root: &Path, keep_logs: bool) -> Result<(), ExecuteError> {
+        let chroot = self.chroot();
+        debug!("Deleting workspace at {}", chroot.display());
+        crate::network::ipam::release_all(root, &self.id)
+            .map_err(|e| ExecuteError::WorkspaceDeletion(e.to_string()))?;
+        self.workspace_lock = None;
+        let result = match keep_logs {
+            true => remove_all_but(&chroot, &[CONSOLE_LOG_FILE, AUDIT_LOG_FILE]),
+            false => match std::fs::remove_dir_all(&chroot) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+        }
+        .map_err(|e| ExecuteError::WorkspaceDeletion(e.to_string()));
+        match keep_logs {
+            true => self.audit("delete_workspace", None, &result),
+            false => self.audit = None,
+        }
+        result
+    }
+}
+
+/// Remove everything in a directory except the given files
+fn remove_all_but(dir: &Path, keep: &[&str]) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if keep.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        match entry.file_type()?.is_dir() {
+            true => std::fs::remove_dir_all(entry.path())?,
+            false => std::fs::remove_file(entry.path())?,
+        }
+    }
+    Ok(())
+}
+
+/// Whether firecracker applied the resource as requested: the fields left
+/// unset in the request are filled with defaults by firecracker, only the
+/// fields which are set are compared
+fn is_applied<T: Serialize>(requested: &T, applied: &T) -> bool {
+    let (requested, applied) = match (
+        serde_json::to_value(requested),
+        serde_json::to_value(applied),
+    ) {
+        (Ok(requested), Ok(applied)) => (requested, applied),
+        _ => return false,
+    };
+    match (requested.as_object(), applied.as_object()) {
+        (Some(requested), Some(applied)) => requested
+            .iter()
+            .all(|(field, value)| value.is_null() || applied.get(field) == Some(value)),
+        _ => requested == applied,
+    }
+}
+
+impl Drop for Executor {
+    /// Kill the process and remove its socket and its workspace, unless the
+    /// executor was detached, see [Executor::detach] and
+    /// [Executor::with_kept_workspace]. Adopted processes are detached, see
+    /// [Executor::adopt].
+    ///
+    /// The process isn't waited for, its cgroup is removed in the background
+    /// once it exited.
+    fn drop(&mut self) {
+        let pid = match (&self.socket_process, self.adopted_pid) {
+            (Some(process), _) => process.pid,
+            (None, Some(pid)) => pid,
+            (None, None) => return,
+        };
+        if self.detached {
+            return;
+        }
+        let running = match &self.socket_process {
+            Some(process) => process.exit_status().is_none(),
+            // The pid may have been reused by another process since
+            None => !has_exited(pid) && workspace::serves(pid.as_raw(), &self.socket_path()),
+        };
+        if running {
+            warn!(
+                "Executor {} dropped while its process runs, killing it",
+                self.id
+            );
+            let killed = match &self.socket_process {
+                Some(process) => process.kill(),
+                None => signal::kill(pid, Signal::SIGKILL)
+                    .map_err(|e| ExecuteError::Socket(e.to_string())),
+            };
+            self.audit("kill", None, &killed);
+            if let Err(e) = killed {
+                warn!("Failed to kill the process of executor {}: {}", self.id, e);
+                return;
+            }
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            release_cgroup_once_exited(pid, cgroup);
+        }
+        if let Err(e) = self.release_socket(self.socket_path()) {
+            warn!(
+                "Failed to release the socket of executor {}: {}",
+                self.id, e
+            );
+        }
+        let root = match (&self.execute, running && !self.keep_workspace) {
+            (Some(execute), true) => execute.chroot(),
+            _ => return,
+        };
+        if let Err(e) = self.remove_workspace(&root, false) {
+            warn!(
+                "Failed to remove the workspace of executor {}: {}",
+                self.id, e
+            );
+        }
+    }
+}
+
+/// Remove the cgroup of a killed process once it is gone, from another thread
+/// as an executor can't wait for it when it is dropped
+fn release_cgroup_once_exited(pid: Pid, cgroup: Cgroup) {
+    std::thread::spawn(move || {
+        let deadline = Instant::now() + DROP_REAP_TIMEOUT;
+        while !has_exited(pid) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        if let Err(e) = cgroup.remove() {
+            warn!("Failed to remove cgroup {:?}: {}", cgroup.paths(), e);
+        }
+    });
+}
+
+/// Process spawned by the executor. A background task waits for it, so its
+/// exit is noticed even when nobody waits for it.
+#[derive(Debug)]
+struct ChildProcess {
+    pid: Pid,
+    exit: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl ChildProcess {
+    fn monitor(mut child: Child, id: String) -> Result<ChildProcess, ExecuteError> {
+        let pid = child
+            .id()
+            .ok_or_else(|| ExecuteError::Socket("Process already exited".to_string()))?;
+        let (sender, exit) = watch::channel(None);
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) => {
+                    debug!("Process of executor {} exited with {}", id, status);
+                    let _ = sender.send(Some(status));
+                }
+                Err(e) => warn!("Failed to wait for the process of executor {}: {}", id, e),
+            }
+        });
+        Ok(ChildProcess {
+            pid: Pid::from_raw(pid as i32),
+            exit,
+        })
+    }
+
+    /// Exit status of the process, once it exited
+    fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit.borrow()
+    }
+
+    async fn wait(&mut self) -> Result<ExitStatus, ExecuteError> {
+        loop {
+            if let Some(status) = self.exit_status() {
+                return Ok(status);
+            }
+            self.exit
+                .changed()
+                .await
+                .map_err(|_| ExecuteError::Socket("The process can't be waited for".to_string()))?;
+        }
+    }
+
+    /// Send the signal to the process, unless it already exited and may have
+    /// been reaped, so the signal can't reach a process reusing its pid
+    fn signal(&self, signal: Signal) -> Result<(), ExecuteError> {
+        match self.exit_status() {
+            Some(_) => Ok(()),
+            None => signal::kill(self.pid, signal).map_err(|e| ExecuteError::Socket(e.to_string())),
+        }
+    }
+
+    fn kill(&self) -> Result<(), ExecuteError> {
+        self.signal(Signal::SIGKILL)
+    }
+
+    /// Send SIGTERM to the process, then SIGKILL if it didn't exit within the
+    /// grace period, and wait for it to exit
+    async fn terminate(&mut self, grace_period: Duration) -> Result<ExitStatus, ExecuteError> {
+        if !grace_period.is_zero() {
+            self.signal(Signal::SIGTERM)?;
+            if let Ok(result) = timeout(grace_period, self.wait()).await {
+                return result;
+            }
+            warn!(
+                "Process {} still running {:?} after SIGTERM, killing it",
+                self.pid, grace_period
+            );
+        }
+        self.kill()?;
+        self.wait().await
+    }
+}
+
+/// Same as [ChildProcess::terminate] for a process which isn't our child, it
+/// is polled until it disappears
+async fn terminate_adopted(pid: Pid, grace_period: Duration) -> Result<(), ExecuteError> {
+    if !grace_period.is_zero() {
+        signal::kill(pid, Signal::SIGTERM).map_err(|e| ExecuteError::Socket(e.to_string()))?;
+        let deadline = Instant::now() + grace_period;
+        while Instant::now() < deadline {
+            if has_exited(pid) {
+                return Ok(());
+            }
+            sleep(ADOPTED_POLL_INTERVAL).await;
+        }
+        warn!(
+            "Process {} still running {:?} after SIGTERM, killing it",
+            pid, grace_period
+        );
+    }
+    match signal::kill(pid, Signal::SIGKILL) {
+        // Exited since the last check
+        Err(nix::errno::Errno::ESRCH) => Ok(()),
+        result => result.map_err(|e| ExecuteError::Socket(e.to_string())),
+    }
+}
+
+/// Whether a process is gone, or is a zombie waiting to be reaped
+fn has_exited(pid: Pid) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state follows the command name, which is between parentheses
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .map_or(false, |(_, rest)| rest.trim_start().starts_with('Z')),
+        Err(_) => true,
+    }
+}
+
+/// Time given to firecracker to answer a health check, see [Executor::ping]
+const HEALTH_PING_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Time given to the killed process of a dropped executor to exit before its
+/// cgroup is removed
+const DROP_REAP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Interval between two checks of an adopted process, see [Executor::wait_exit]
+const ADOPTED_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Number of lines of the standard error of the process kept to explain an
+/// early exit
+const STDERR_TAIL_LINES: usize = 32;
+
+/// Time given to read the remaining standard error once the process exited
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Forward the standard error of the process to the logs until it is closed,
+/// the task returns its last lines
+fn capture_stderr(
+    stderr: ChildStderr,
+    id: String,
+    mut log: Option<tokio::fs::File>,
+) -> JoinHandle<String> {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        while let Ok(Some(line)) = lines.next_line().await {
+            debug!("[{}] {}", id, line);
+            if let Some(file) = &mut log {
+                if file
+                    .write_all(format!("{}\n", line).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    log = None;
+                }
+            }
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        Vec::from(tail).join("\n")
+    })
+}
+
+/// How the configuration of the microVM reaches firecracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// firecracker is spawned with its API socket, then the microVM is
+    /// configured and started with one request per resource
+    Api,
+    /// The whole configuration is written to [CONFIG_FILE] and firecracker
+    /// boots the guest as soon as it is spawned with `--config-file`, saving
+    /// the configuration round trips. The API socket stays available to
+    /// control the microVM.
+    ///
+    /// Firecracker is only spawned when the machine is started: until then,
+    /// drives and interfaces can't be attached nor metadata written.
+    ConfigFile,
+    /// Same as [BootMode::ConfigFile] with `--no-api`, the microVM can't be
+    /// controlled once booted: it can only be killed, or stop by itself
+    ConfigFileNoApi,
+}
+
+/// Time given to the process to exit after SIGTERM, see
+/// [Executor::with_kill_grace_period]
+pub const DEFAULT_KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Binary used to spawn firecracker in a new session, see
+/// [Executor::with_detached_spawn]
+pub const SETSID_BINARY: &str = "setsid";
+
+/// Spawn the command line in a new session through [SETSID_BINARY], which
+/// runs it in place so the pid is the one of the command. Its output is
+/// appended to the given file.
+pub(crate) fn spawn_in_new_session(
+    command_line: Vec<OsString>,
+    output: File,
+) -> Result<Child, ExecuteError> {
+    let stderr = output
+        .try_clone()
+        .map_err(|e| ExecuteError::CommandExecution(e.to_string()))?;
+    Command::new(SETSID_BINARY)
+        .args(command_line)
+        .stdin(Stdio::null())
+        .stdout(output)
+        .stderr(stderr)
+        .spawn()
+        .map_err(|e| ExecuteError::CommandExecution(format!("{}: {}", SETSID_BINARY, e)))
 }
 
 /// Implementation of Executor for Firecracker, it will spawn the microVM using
@@ -344,23 +1648,39 @@ impl Execute for FirecrackerExecutor {
         PathBuf::from(&self.chroot)
     }
 
-    fn spawn_binary_child(&self, args: &Vec<String>) -> Result<Child, ExecuteError> {
+    fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError> {
         let command = Command::new(&self.exec_binary)
             .args(args)
-            // FIXME: Implement logging
             .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
+            // Serial console of the guest, read for boot milestones
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .map_err(|e| ExecuteError::CommandExecution(e.to_string()))?;
         Ok(command)
     }
+
+    fn spawn_detached_child(&self, args: &[String], output: File) -> Result<Child, ExecuteError> {
+        let mut command_line = vec![self.exec_binary.clone().into_os_string()];
+        command_line.extend(args.iter().map(OsString::from));
+        spawn_in_new_session(command_line, output)
+    }
+
+    fn exec_binary(&self) -> Option<PathBuf> {
+        Some(self.exec_binary.clone())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::testing::MockTransport;
+    use crate::audit::AUDIT_LOG_FILE;
+
+    use hyper::StatusCode;
+    use serial_test::serial;
 
+    use std::os::unix::process::ExitStatusExt;
     use std::path::PathBuf;
 
     #[tokio::test]
@@ -371,7 +1691,7 @@ mod tests {
         };
         let mut machine = Executor::new_with_firecracker(executor);
         machine.create_workspace().unwrap();
-        machine.run_socket().expect("Failed to run socket");
+        machine.run_socket().await.expect("Failed to run socket");
 
         // expect socket to exist
         let socket = machine.chroot().join("firecracker.socket");
@@ -381,6 +1701,330 @@ mod tests {
         assert!(!socket.exists());
     }
 
+    #[tokio::test]
+    async fn test_get_through_transport() {
+        let transport = MockTransport::new();
+        transport.respond(StatusCode::OK, r#"{"firecracker_version": "1.3.1"}"#);
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+
+        let version = executor.version().await.unwrap();
+        assert_eq!(version.firecracker_version, "1.3.1");
+        assert_eq!(transport.requests()[0].path, "/version");
+    }
+
+    #[tokio::test]
+    async fn test_configure_machine_config() {
+        let transport = MockTransport::new();
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+
+        executor
+            .configure_machine_config(MachineConfiguration::new(1024, 2))
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PUT);
+        assert_eq!(requests[0].path, "/machine-config");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["vcpu_count"], 2);
+        assert_eq!(body["mem_size_mib"], 1024);
+    }
+
+    #[tokio::test]
+    async fn test_describe_applied_config() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                StatusCode::OK,
+                r#"{"vcpu_count": 2, "mem_size_mib": 1024, "smt": false}"#,
+            )
+            .respond(
+                StatusCode::OK,
+                r#"{"machine-config": {"vcpu_count": 2, "mem_size_mib": 1024}, "drives": []}"#,
+            );
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+
+        let machine_config = executor.describe_machine_config().await.unwrap();
+        assert_eq!(machine_config.vcpu_count, 2);
+        assert_eq!(machine_config.smt, Some(false));
+        let vm_config = executor.describe_vm_config().await.unwrap();
+        assert_eq!(vm_config.machine_config.unwrap().mem_size_mib, 1024);
+        assert_eq!(vm_config.drives, Some(Vec::new()));
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].path, "/machine-config");
+        assert_eq!(requests[1].path, "/vm/config");
+    }
+
+    #[tokio::test]
+    async fn test_configure_metrics_creates_fifo() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let transport = MockTransport::new();
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.fifo");
+
+        executor
+            .configure_metrics(Metrics::new(path.to_string_lossy().into_owned()))
+            .await
+            .unwrap();
+        assert!(std::fs::metadata(&path).unwrap().file_type().is_fifo());
+        let requests = transport.requests();
+        assert_eq!(requests[0].path, "/metrics");
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_version_gates_features() {
+        let transport = MockTransport::new();
+        transport.respond(StatusCode::OK, r#"{"firecracker_version": "1.3.0"}"#);
+        let mut executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+
+        assert!(executor.require(VmmFeature::CpuConfig).is_ok());
+        let version = executor.negotiate_version().await.unwrap();
+        assert_eq!(version, VmmVersion::new(1, 3, 0));
+        assert!(executor.require(VmmFeature::MmdsV2).is_ok());
+        assert!(matches!(
+            executor.require(VmmFeature::CpuConfig),
+            Err(ExecuteError::UnsupportedByVmm {
+                feature: VmmFeature::CpuConfig,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_async_io_engine_unsupported_by_vmm() {
+        let transport = MockTransport::new();
+        transport.respond(StatusCode::OK, r#"{"firecracker_version": "0.25.2"}"#);
+        let mut executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+        executor.negotiate_version().await.unwrap();
+
+        let mut drive = Drive::new("rootfs".to_string(), false, true, "/rootfs".to_string());
+        drive.io_engine = Some(IoEngine::Async);
+        let err = executor.configure_drives(vec![drive]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::UnsupportedByVmm {
+                feature: VmmFeature::AsyncIoEngine,
+                ..
+            }
+        ));
+        // Only the version was requested, the drive never reached the VMM
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_configure_skips_applied_resources() {
+        let applied = r#"{
+            "boot-source": {"kernel_image_path": "/vmlinux", "boot_args": "console=ttyS0"},
+            "drives": [{
+                "drive_id": "rootfs", "path_on_host": "/rootfs", "is_root_device": true,
+                "is_read_only": false, "cache_type": "Unsafe", "io_engine": "Sync"
+            }]
+        }"#;
+        let transport = MockTransport::new();
+        transport
+            .respond(StatusCode::OK, applied)
+            .respond(StatusCode::OK, applied)
+            .respond(StatusCode::OK, applied);
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+
+        let rootfs = Drive::new("rootfs".to_string(), false, true, "/rootfs".to_string());
+        let data = Drive::new("data".to_string(), false, false, "/data".to_string());
+        // Only the drive which isn't configured yet is sent
+        executor
+            .configure_drives(vec![rootfs, data.clone()])
+            .await
+            .unwrap();
+        let mut boot_source = BootSource::new("/vmlinux".to_string());
+        boot_source.boot_args = Some("console=ttyS0".to_string());
+        executor.configure_boot_source(boot_source).await.unwrap();
+        executor.configure_drives(vec![data]).await.unwrap();
+
+        let requests = transport.requests();
+        let paths: Vec<_> = requests.iter().map(|r| (&r.method, &*r.path)).collect();
+        assert_eq!(
+            paths,
+            vec![
+                (&hyper::Method::GET, "/vm/config"),
+                (&hyper::Method::PUT, "/drives/data"),
+                (&hyper::Method::GET, "/vm/config"),
+                (&hyper::Method::GET, "/vm/config"),
+                (&hyper::Method::PUT, "/drives/data"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patch_through_transport() {
+        let transport = MockTransport::new();
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+
+        executor
+            .patch("/mmds", &serde_json::json!({"key": "value"}))
+            .await
+            .unwrap();
+        executor
+            .set_vm_state(Vm::new(firepilot_models::models::vm::State::Resumed))
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PATCH);
+        assert_eq!(requests[0].path, "/mmds");
+        assert_eq!(requests[0].body, r#"{"key":"value"}"#);
+        assert_eq!(requests[1].method, hyper::Method::PATCH);
+        assert_eq!(requests[1].path, "/vm");
+    }
+
+    #[tokio::test]
+    async fn test_run_socket_early_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        // Any binary rejecting `--api-sock` exits right away with an error
+        let mut executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: dir.path().to_string_lossy().into_owned(),
+            exec_binary: PathBuf::from("/bin/ls"),
+        })
+        .with_console_log();
+        executor.create_workspace().unwrap();
+
+        match executor.run_socket().await.unwrap_err() {
+            ExecuteError::Exited { code, stderr } => {
+                assert!(matches!(code, Some(code) if code != 0));
+                assert!(stderr.contains("api-sock"));
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(!executor.is_running());
+
+        let events = std::fs::read_to_string(executor.chroot().join(AUDIT_LOG_FILE)).unwrap();
+        let spawn = events
+            .lines()
+            .find(|line| line.contains("\"spawn\""))
+            .unwrap();
+        assert!(spawn.contains("\"outcome\":\"error: "));
+
+        let console = std::fs::read_to_string(executor.console_log_path().unwrap()).unwrap();
+        assert!(console.contains("api-sock"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_exit() {
+        let mut executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_id("wait_exit".to_string());
+
+        let spawn = |command: &str| {
+            let child = Command::new(command).arg("5").spawn().unwrap();
+            Some(ChildProcess::monitor(child, "wait_exit".to_string()).unwrap())
+        };
+        executor.socket_process = spawn("/bin/sleep");
+        assert!(!executor.wait_exit(Duration::from_millis(50)).await.unwrap());
+        assert!(executor.is_running());
+        executor.destroy_socket().await.unwrap();
+
+        executor.socket_process = spawn("/bin/true");
+        assert!(executor.wait_exit(Duration::from_secs(5)).await.unwrap());
+        assert!(!executor.is_running());
+
+        // The exit is noticed without waiting for the process
+        executor.socket_process = spawn("/bin/false");
+        let mut exit = executor.subscribe_exit().unwrap();
+        exit.changed().await.unwrap();
+        assert_eq!(exit.borrow().unwrap().code(), Some(1));
+        assert!(!executor.is_running());
+        executor.destroy_socket().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_destroy_sigterm_then_sigkill() {
+        let mut executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_id("destroy_sigterm".to_string())
+        .with_kill_grace_period(Duration::from_millis(200));
+
+        let spawn = |script: &str| {
+            let child = Command::new("/bin/sh")
+                .arg("-c")
+                .arg(script)
+                .spawn()
+                .unwrap();
+            Some(ChildProcess::monitor(child, "destroy_sigterm".to_string()).unwrap())
+        };
+        // Exits on SIGTERM
+        executor.socket_process = spawn("exec sleep 5");
+        let exit = executor.subscribe_exit().unwrap();
+        executor.destroy_socket().await.unwrap();
+        assert_eq!(
+            exit.borrow().unwrap().signal(),
+            Some(Signal::SIGTERM as i32)
+        );
+
+        // Ignores SIGTERM, killed once the grace period is over
+        executor.socket_process = spawn("trap '' TERM; exec sleep 5");
+        let exit = executor.subscribe_exit().unwrap();
+        // Leave time to the shell to set the trap
+        sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        executor.destroy_socket().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(
+            exit.borrow().unwrap().signal(),
+            Some(Signal::SIGKILL as i32)
+        );
+    }
+
+    #[test]
+    fn test_adopt_without_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: dir.path().to_string_lossy().into_owned(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_id("adopt".to_string());
+        std::fs::create_dir(executor.chroot()).unwrap();
+
+        assert!(matches!(executor.adopt(), Err(ExecuteError::Socket(_))));
+        assert!(!executor.is_running());
+        // The lock isn't kept when nothing was adopted
+        assert!(!workspace::is_locked(&executor.chroot()));
+    }
+
     #[tokio::test]
     #[should_panic]
     async fn test_destroy_when_no_init() {
@@ -393,15 +2037,332 @@ mod tests {
         machine.destroy_socket().await.expect("fail to kill");
     }
 
+    /// Test double which creates the API socket file itself and runs a
+    /// process standing for firecracker
+    #[derive(Debug)]
+    struct FakeExecute {
+        chroot: PathBuf,
+    }
+
+    impl Execute for FakeExecute {
+        fn chroot(&self) -> PathBuf {
+            self.chroot.clone()
+        }
+
+        fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError> {
+            std::fs::write(&args[1], "").unwrap();
+            Command::new("/bin/sleep")
+                .arg("30")
+                .spawn()
+                .map_err(|e| ExecuteError::CommandExecution(e.to_string()))
+        }
+
+        fn spawn_detached_child(
+            &self,
+            args: &[String],
+            output: File,
+        ) -> Result<Child, ExecuteError> {
+            std::fs::write(&args[1], "").unwrap();
+            let command_line = ["/bin/sh", "-c", "echo started; exec sleep 30"];
+            spawn_in_new_session(command_line.iter().map(OsString::from).collect(), output)
+        }
+    }
+
+    /// Transport answering the health check of the next spawned process
+    fn healthy() -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        transport.respond(StatusCode::OK, "");
+        Arc::new(transport)
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let transport = MockTransport::new();
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+        transport.respond(StatusCode::OK, "");
+        executor.ping().await.unwrap();
+        // Only a 200 means firecracker is ready
+        assert!(matches!(
+            executor.ping().await,
+            Err(ExecuteError::Api {
+                status: StatusCode::NO_CONTENT,
+                ..
+            })
+        ));
+        assert_eq!(transport.requests()[0].path, "/");
+    }
+
+    #[tokio::test]
+    async fn test_run_socket_unhealthy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new_with_executor(FakeExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("unhealthy".to_string())
+        .with_transport(Arc::new(MockTransport::new()))
+        .with_health_check(HealthCheck::new().with_max_wait(Duration::from_millis(100)));
+        executor.create_workspace().unwrap();
+        assert!(matches!(
+            executor.run_socket().await,
+            Err(ExecuteError::Unhealthy)
+        ));
+        assert!(!executor.is_running());
+
+        // The socket file is enough when the API isn't checked
+        let mut executor = Executor::new_with_executor(FakeExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("socket_file".to_string())
+        .with_transport(Arc::new(MockTransport::new()))
+        .with_health_check(HealthCheck::new().with_strategy(HealthStrategy::SocketFile));
+        executor.create_workspace().unwrap();
+        executor.run_socket().await.unwrap();
+        assert!(executor.is_running());
+        executor.destroy_socket().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_custom_execute() {
+        let dir = tempfile::tempdir().unwrap();
+        let cgroup_root = tempfile::tempdir().unwrap();
+        std::fs::write(cgroup_root.path().join("cgroup.controllers"), "pids").unwrap();
+        let mut executor = Executor::new_with_executor(FakeExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("custom".to_string())
+        .with_transport(healthy())
+        .with_cgroup(
+            CgroupConfig::new()
+                .with_root(cgroup_root.path().to_path_buf())
+                .with_pids_max(8),
+        );
+        executor.create_workspace().unwrap();
+        assert_eq!(executor.chroot(), dir.path().join("custom"));
+
+        executor.run_socket().await.unwrap();
+        assert!(executor.is_running());
+        let pid = executor.pid().unwrap();
+        let procs = cgroup_root.path().join("firepilot/custom/cgroup.procs");
+        assert_eq!(std::fs::read_to_string(procs).unwrap(), pid.to_string());
+        let pid_file = std::fs::read_to_string(executor.pid_path()).unwrap();
+        assert_eq!(pid_file.trim(), pid.to_string());
+        executor.destroy_socket().await.unwrap();
+        assert!(!executor.is_running());
+        assert_eq!(executor.pid(), None);
+        assert!(!executor.pid_path().exists());
+        assert!(!executor.socket_path().exists());
+    }
+
+    #[tokio::test]
+    // Other tests change the PATH in which setsid is looked up
+    #[serial]
+    async fn test_detached_spawn() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new_with_executor(FakeExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("detached".to_string())
+        .with_health_check(HealthCheck::new().with_strategy(HealthStrategy::SocketFile))
+        .with_detached_spawn();
+        executor.create_workspace().unwrap();
+        executor.run_socket().await.unwrap();
+        let pid = executor.pid().unwrap();
+        let log = executor.console_log_path().unwrap();
+
+        // Leader of its own session
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+        let (_, fields) = stat.rsplit_once(')').unwrap();
+        let session = fields.split_whitespace().nth(3).unwrap();
+        assert_eq!(session, pid.to_string());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !std::fs::read_to_string(&log).unwrap().contains("started") {
+            assert!(Instant::now() < deadline);
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        drop(executor);
+        assert!(is_alive(pid));
+        signal::kill(Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new_with_executor(FakeExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("deleted".to_string())
+        .with_transport(healthy())
+        .with_console_log();
+        executor.create_workspace().unwrap();
+        let chroot = executor.chroot();
+        std::fs::write(chroot.join("rootfs.ext4"), "").unwrap();
+        std::fs::write(chroot.join(CONSOLE_LOG_FILE), "login:").unwrap();
+
+        executor.run_socket().await.unwrap();
+        assert!(matches!(
+            executor.delete_workspace(true),
+            Err(ExecuteError::WorkspaceDeletion(_))
+        ));
+        executor.destroy_socket().await.unwrap();
+
+        executor.delete_workspace(true).unwrap();
+        let mut kept: Vec<_> = std::fs::read_dir(&chroot)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![CONSOLE_LOG_FILE, AUDIT_LOG_FILE]);
+        assert!(!workspace::is_locked(&chroot));
+
+        executor.delete_workspace(false).unwrap();
+        assert!(!chroot.exists());
+    }
+
+    fn is_alive(pid: u32) -> bool {
+        // A killed child stays a zombie until it is reaped
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.contains(") Z "),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_kills_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let spawn = |id: &str| {
+            Executor::new_with_executor(FakeExecute {
+                chroot: dir.path().to_path_buf(),
+            })
+            .with_id(id.to_string())
+            .with_transport(healthy())
+        };
+
+        let mut executor = spawn("dropped");
+        executor.create_workspace().unwrap();
+        executor.run_socket().await.unwrap();
+        let pid = executor.pid().unwrap();
+        let chroot = executor.chroot();
+        drop(executor);
+        assert!(!chroot.exists());
+        // The process is killed but not waited for
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while is_alive(pid) {
+            assert!(Instant::now() < deadline);
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut executor = spawn("kept").with_kept_workspace();
+        executor.create_workspace().unwrap();
+        executor.run_socket().await.unwrap();
+        let socket = executor.socket_path();
+        drop(executor);
+        assert!(!socket.exists());
+        assert!(dir.path().join("kept").exists());
+
+        let mut executor = spawn("detached");
+        executor.create_workspace().unwrap();
+        executor.run_socket().await.unwrap();
+        let pid = executor.pid().unwrap();
+        let socket = executor.socket_path();
+        executor.detach();
+        drop(executor);
+        assert!(is_alive(pid));
+        assert!(socket.exists());
+        signal::kill(Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+    }
+
+    #[test]
+    fn test_drop_adopted_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let adopt = || {
+            let mut executor = Executor::new_with_executor(FakeExecute {
+                chroot: dir.path().to_path_buf(),
+            })
+            .with_id("adopted".to_string());
+            executor.adopt().unwrap();
+            executor
+        };
+        let socket = dir.path().join("adopted").join(SOCKET_FILE);
+        std::fs::create_dir(socket.parent().unwrap()).unwrap();
+        // The socket is on the command line, as for firecracker
+        let mut process = std::process::Command::new("/bin/sh")
+            .args(["-c", "sleep 30; :"])
+            .arg(&socket)
+            .spawn()
+            .unwrap();
+        let mut other = std::process::Command::new("/bin/sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+
+        // Adopted processes are left running
+        drop(adopt());
+        assert!(process.try_wait().unwrap().is_none());
+
+        // Nor is a process which took the pid over killed
+        let mut executor = adopt();
+        executor.adopted_pid = Some(Pid::from_raw(other.id() as i32));
+        executor.attach();
+        drop(executor);
+        assert!(other.try_wait().unwrap().is_none());
+        other.kill().unwrap();
+
+        let mut executor = adopt();
+        executor.attach();
+        drop(executor);
+        assert_eq!(
+            process.wait().unwrap().signal(),
+            Some(Signal::SIGKILL as i32)
+        );
+    }
+
+    /// Test double whose process exits by itself with a failure
+    #[derive(Debug)]
+    struct ExitingExecute {
+        chroot: PathBuf,
+    }
+
+    impl Execute for ExitingExecute {
+        fn chroot(&self) -> PathBuf {
+            self.chroot.clone()
+        }
+
+        fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError> {
+            std::fs::write(&args[1], "").unwrap();
+            Command::new("/bin/sh")
+                .args(["-c", "sleep 0.1; exit 3"])
+                .spawn()
+                .map_err(|e| ExecuteError::CommandExecution(e.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new_with_executor(ExitingExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("exiting".to_string())
+        .with_transport(healthy());
+        executor.create_workspace().unwrap();
+        assert_eq!(executor.wait().await.unwrap(), None);
+
+        executor.run_socket().await.unwrap();
+        let status = executor.wait().await.unwrap().unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert!(!executor.is_running());
+        assert!(!executor.socket_path().exists());
+    }
+
     #[test]
     #[should_panic]
     fn test_no_executor_fails() {
-        let machine = Executor {
-            firecracker: None,
-            socket_process: None,
-            id: "default".to_string(),
-            client: Client::unix(),
-        };
+        let mut machine = Executor::new();
         machine.create_workspace().unwrap();
     }
 }
@@ -0,0 +1,172 @@
+//! # Sharing a machine between tasks
+//!
+//! Most operations of a [Machine] borrow it, some mutably, so a machine can't
+//! be used from several tasks as is. A [MachineHandle] wraps it behind a lock
+//! and can be cloned into as many tasks as needed, e.g. one waiting for the
+//! guest to exit while another one controls it.
+//!
+//! ```no_run
+//! use firepilot::handle::MachineHandle;
+//!
+//! # async fn example(machine: firepilot::machine::Machine) {
+//! let handle = MachineHandle::new(machine);
+//! handle.start().await.unwrap();
+//!
+//! let monitor = handle.clone();
+//! let exited = tokio::spawn(async move { monitor.wait().await });
+//! handle.stop().await.unwrap();
+//! println!("Guest exited with {:?}", exited.await.unwrap());
+//! # }
+//! ```
+//!
+//! Each operation holds the lock until it completes, except [MachineHandle::wait]
+//! which releases it regularly so the machine can be controlled meanwhile.
+
+use std::{path::Path, process::ExitStatus, sync::Arc, time::Duration};
+
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::timeout;
+
+use crate::{
+    machine::{FirepilotError, InstanceState, Machine, MachineState},
+    snapshot::{Snapshot, SnapshotType},
+};
+
+/// How long [MachineHandle::wait] holds the lock before letting other tasks
+/// use the machine
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Cloneable handle on a [Machine], see [crate::handle]
+#[derive(Debug, Clone)]
+pub struct MachineHandle {
+    machine: Arc<Mutex<Machine>>,
+}
+
+impl MachineHandle {
+    pub fn new(machine: Machine) -> MachineHandle {
+        MachineHandle {
+            machine: Arc::new(Mutex::new(machine)),
+        }
+    }
+
+    /// Lock the machine for the operations which aren't forwarded by the
+    /// handle, the other tasks wait until the guard is dropped
+    pub async fn lock(&self) -> MutexGuard<'_, Machine> {
+        self.machine.lock().await
+    }
+
+    /// Whether both handles share the same machine
+    pub fn ptr_eq(&self, other: &MachineHandle) -> bool {
+        Arc::ptr_eq(&self.machine, &other.machine)
+    }
+
+    /// See [Machine::lifecycle]
+    pub async fn lifecycle(&self) -> MachineState {
+        self.lock().await.lifecycle()
+    }
+
+    /// See [Machine::state]
+    pub async fn state(&self) -> Result<InstanceState, FirepilotError> {
+        self.lock().await.state().await
+    }
+
+    /// See [Machine::start]
+    pub async fn start(&self) -> Result<(), FirepilotError> {
+        self.lock().await.start().await
+    }
+
+    /// See [Machine::stop]
+    pub async fn stop(&self) -> Result<(), FirepilotError> {
+        self.lock().await.stop().await
+    }
+
+    /// See [Machine::stop_and_wait]
+    pub async fn stop_and_wait(&self, max_wait: Duration) -> Result<(), FirepilotError> {
+        self.lock().await.stop_and_wait(max_wait).await
+    }
+
+    /// See [Machine::pause]
+    pub async fn pause(&self) -> Result<(), FirepilotError> {
+        self.lock().await.pause().await
+    }
+
+    /// See [Machine::resume]
+    pub async fn resume(&self) -> Result<(), FirepilotError> {
+        self.lock().await.resume().await
+    }
+
+    /// See [Machine::reboot]
+    pub async fn reboot(&self, max_wait: Duration) -> Result<(), FirepilotError> {
+        self.lock().await.reboot(max_wait).await
+    }
+
+    /// See [Machine::snapshot]
+    pub async fn snapshot<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        snapshot_type: SnapshotType,
+    ) -> Result<Snapshot, FirepilotError> {
+        self.lock().await.snapshot(dir, snapshot_type).await
+    }
+
+    /// See [Machine::kill]
+    pub async fn kill(&self) -> Result<(), FirepilotError> {
+        self.lock().await.kill().await
+    }
+
+    /// See [Machine::purge]
+    pub async fn purge(&self, keep_logs: bool) -> Result<(), FirepilotError> {
+        self.lock().await.purge(keep_logs).await
+    }
+
+    /// Wait until firecracker exits, see [Machine::wait]. Unlike the other
+    /// operations, the machine can be used by other tasks meanwhile.
+    pub async fn wait(&self) -> Result<Option<ExitStatus>, FirepilotError> {
+        loop {
+            let mut machine = self.lock().await;
+            if let Ok(result) = timeout(WAIT_POLL_INTERVAL, machine.wait()).await {
+                return result;
+            }
+        }
+    }
+}
+
+impl From<Machine> for MachineHandle {
+    fn from(machine: Machine) -> Self {
+        MachineHandle::new(machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handle_across_tasks() {
+        let handle = MachineHandle::new(crate::machine::testing::idle_machine("handle"));
+        let other = handle.clone();
+        assert!(handle.ptr_eq(&other));
+
+        let lifecycle = tokio::spawn(async move { other.lifecycle().await });
+        assert_eq!(lifecycle.await.unwrap(), MachineState::Created);
+        assert!(matches!(
+            handle.wait().await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handle_forwards_operations() {
+        let transport = crate::api::testing::MockTransport::new();
+        let handle = MachineHandle::new(crate::machine::testing::running_machine(
+            "handle", &transport,
+        ));
+        let other = handle.clone();
+        tokio::spawn(async move { other.pause().await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(handle.lifecycle().await, MachineState::Paused);
+        assert_eq!(transport.requests()[0].path, "/vm");
+    }
+}
@@ -0,0 +1,173 @@
+//! # Health check of the firecracker process
+//!
+//! Once spawned, an [Executor](crate::executor::Executor) waits for
+//! firecracker to be healthy before it sends the configuration. By default,
+//! the API is asked every 50ms for at most 500ms, which may not be enough on
+//! a loaded host:
+//!
+//! ```no_run
+//! use firepilot::executor::{Executor, FirecrackerExecutor};
+//! use firepilot::health::HealthCheck;
+//! use std::{path::PathBuf, time::Duration};
+//!
+//! let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+//!     chroot: "/tmp/firepilot".to_string(),
+//!     exec_binary: PathBuf::from("/usr/bin/firecracker"),
+//! })
+//! .with_health_check(
+//!     HealthCheck::new()
+//!         .with_max_wait(Duration::from_secs(5))
+//!         .with_interval(Duration::from_millis(100)),
+//! );
+//! ```
+//!
+//! A process which exits during the health check fails it right away, without
+//! waiting for the end of the delay. The workspace is watched with inotify
+//! while the socket doesn't exist, so the check runs as soon as firecracker
+//! creates it rather than at the next interval.
+
+use std::{io, os::unix::io::AsRawFd, path::Path, time::Duration};
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use tokio::io::unix::AsyncFd;
+use tokio::time::timeout;
+
+/// Time given to firecracker to be healthy when none is given
+pub const DEFAULT_HEALTH_MAX_WAIT: Duration = Duration::from_millis(500);
+
+/// Interval between two checks when none is given
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What tells that firecracker is healthy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthStrategy {
+    /// firecracker answers `GET /` with `200 OK`, see
+    /// [Executor::ping](crate::executor::Executor::ping)
+    Api,
+    /// The API socket file exists, firecracker may not accept requests on it
+    /// yet. It is meant for processes which don't serve the API themselves.
+    SocketFile,
+}
+
+/// How an executor waits for firecracker to be healthy, see [crate::health]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Time after which the process is considered unhealthy and killed
+    pub max_wait: Duration,
+    pub interval: Duration,
+    pub strategy: HealthStrategy,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HealthCheck {
+    pub fn new() -> HealthCheck {
+        HealthCheck {
+            max_wait: DEFAULT_HEALTH_MAX_WAIT,
+            interval: DEFAULT_HEALTH_INTERVAL,
+            strategy: HealthStrategy::Api,
+        }
+    }
+
+    pub fn with_max_wait(mut self, max_wait: Duration) -> HealthCheck {
+        self.max_wait = max_wait;
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> HealthCheck {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: HealthStrategy) -> HealthCheck {
+        self.strategy = strategy;
+        self
+    }
+}
+
+/// Wakes up when a file is created in a directory
+#[derive(Debug)]
+pub(crate) struct DirWatcher {
+    /// Always set, it is only taken to close the descriptor on drop
+    inotify: Option<AsyncFd<Inotify>>,
+}
+
+impl DirWatcher {
+    /// Watch the given directory, it fails when the runtime has no IO driver
+    pub(crate) fn new(dir: &Path) -> io::Result<DirWatcher> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let watched = inotify
+            .add_watch(dir, AddWatchFlags::IN_CREATE)
+            .map(|_| ())
+            .map_err(io::Error::from)
+            .and_then(|_| AsyncFd::new(inotify));
+        match watched {
+            Ok(inotify) => Ok(DirWatcher {
+                inotify: Some(inotify),
+            }),
+            Err(e) => {
+                let _ = nix::unistd::close(inotify.as_raw_fd());
+                Err(e)
+            }
+        }
+    }
+
+    /// Wait until a file is created in the directory since the last call, or
+    /// for at most `max_wait`
+    pub(crate) async fn created(&self, max_wait: Duration) {
+        let inotify = match &self.inotify {
+            Some(inotify) => inotify,
+            None => return,
+        };
+        let _ = timeout(max_wait, async {
+            loop {
+                let mut guard = match inotify.readable().await {
+                    Ok(guard) => guard,
+                    Err(_) => return,
+                };
+                match guard
+                    .try_io(|inotify| inotify.get_ref().read_events().map_err(io::Error::from))
+                {
+                    Ok(Ok(events)) if !events.is_empty() => return,
+                    Ok(Ok(_)) => {}
+                    Ok(Err(_)) => return,
+                    // Spurious wake up, the readiness was cleared
+                    Err(_) => {}
+                }
+            }
+        })
+        .await;
+    }
+}
+
+impl Drop for DirWatcher {
+    fn drop(&mut self) {
+        // Inotify doesn't close its descriptor itself
+        if let Some(inotify) = self.inotify.take() {
+            let _ = nix::unistd::close(inotify.into_inner().as_raw_fd());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dir_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = DirWatcher::new(dir.path()).unwrap();
+        let created = std::time::Instant::now();
+        watcher.created(Duration::from_millis(50)).await;
+        assert!(created.elapsed() >= Duration::from_millis(50));
+
+        std::fs::write(dir.path().join("firecracker.socket"), "").unwrap();
+        let created = std::time::Instant::now();
+        watcher.created(Duration::from_secs(5)).await;
+        assert!(created.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,206 @@
+//! # Information about the host running the microVMs
+//!
+//! Firecracker behaves differently depending on the host it runs on, e.g.
+//! CPU templates are only available on x86_64. Helpers in this module are used
+//! to validate configurations before they reach the VMM.
+use std::{fmt, str::FromStr};
+
+/// CPU architecture supported by firecracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    /// Architecture of the host firepilot was compiled for
+    pub fn host() -> Arch {
+        if cfg!(target_arch = "aarch64") {
+            Arch::Aarch64
+        } else {
+            Arch::X86_64
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arch::X86_64 => write!(f, "x86_64"),
+            Arch::Aarch64 => write!(f, "aarch64"),
+        }
+    }
+}
+
+/// Version of the Linux kernel of the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl KernelVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> KernelVersion {
+        KernelVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for KernelVersion {
+    type Err = String;
+
+    /// Parse a kernel release as given by `uname -r`, e.g.
+    /// `5.10.186-179.751.amzn2.x86_64`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, '.');
+        let mut next = |name: &str| -> Result<u32, String> {
+            let part = parts.next().unwrap_or("0");
+            let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits
+                .parse()
+                .map_err(|_| format!("Invalid {} in kernel release {:?}", name, s))
+        };
+        Ok(KernelVersion::new(
+            next("major")?,
+            next("minor")?,
+            next("patch")?,
+        ))
+    }
+}
+
+/// Where the release of the running kernel is read
+pub const KERNEL_RELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+
+/// Version of the running kernel, read from [KERNEL_RELEASE_PATH]
+pub fn kernel_version() -> Result<KernelVersion, String> {
+    std::fs::read_to_string(KERNEL_RELEASE_PATH)
+        .map_err(|e| format!("Could not read kernel release: {}", e))?
+        .parse()
+}
+
+/// First host kernel release on which firecracker supports the `Async`
+/// (io_uring) block io engine
+pub const ASYNC_IO_MIN_KERNEL: KernelVersion = KernelVersion::new(5, 10, 51);
+
+/// Tells whether the host kernel is recent enough to use the `Async` io engine
+/// for block devices
+pub fn supports_async_io() -> bool {
+    kernel_version()
+        .map(|version| version >= ASYNC_IO_MIN_KERNEL)
+        .unwrap_or(false)
+}
+
+/// Resources of the host, used to decide where microVMs can be placed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HostStats {
+    /// Number of CPUs available to this process
+    pub cpus: usize,
+    pub mem_total_mib: u64,
+    /// Memory which can be given to new processes without swapping
+    pub mem_available_mib: u64,
+    /// Load average over the last minute
+    pub load_average: f64,
+}
+
+/// Current resources of the host, read from `/proc`
+pub fn stats() -> Result<HostStats, String> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")
+        .map_err(|e| format!("Could not read memory information: {}", e))?;
+    let loadavg = std::fs::read_to_string("/proc/loadavg")
+        .map_err(|e| format!("Could not read load average: {}", e))?;
+    let cpus = std::thread::available_parallelism()
+        .map(|cpus| cpus.get())
+        .unwrap_or(1);
+    parse_stats(&meminfo, &loadavg, cpus)
+}
+
+fn parse_stats(meminfo: &str, loadavg: &str, cpus: usize) -> Result<HostStats, String> {
+    let field_mib = |name: &str| -> Result<u64, String> {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kib| kib / 1024)
+            .ok_or_else(|| format!("Missing {} in memory information", name))
+    };
+    let load_average = loadavg
+        .split_whitespace()
+        .next()
+        .and_then(|load| load.parse().ok())
+        .ok_or_else(|| format!("Invalid load average {:?}", loadavg))?;
+    Ok(HostStats {
+        cpus,
+        mem_total_mib: field_mib("MemTotal")?,
+        mem_available_mib: field_mib("MemAvailable")?,
+        load_average,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(
+            "5.10.186-179.751.amzn2.x86_64"
+                .parse::<KernelVersion>()
+                .unwrap(),
+            KernelVersion::new(5, 10, 186)
+        );
+        assert_eq!(
+            "6.1.0-13-amd64\n".parse::<KernelVersion>().unwrap(),
+            KernelVersion::new(6, 1, 0)
+        );
+        assert_eq!(
+            "6.8-rc1".parse::<KernelVersion>().unwrap(),
+            KernelVersion::new(6, 8, 0)
+        );
+        assert!("linux".parse::<KernelVersion>().is_err());
+        assert!(KernelVersion::new(5, 10, 50) < ASYNC_IO_MIN_KERNEL);
+    }
+
+    #[test]
+    fn test_kernel_version_of_host() {
+        assert!(kernel_version().is_ok());
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let meminfo = "MemTotal:       16310112 kB\nMemFree:         1119180 kB\nMemAvailable:    8355940 kB\n";
+        let parsed = parse_stats(meminfo, "0.52 0.58 0.59 1/1227 12345\n", 8).unwrap();
+        assert_eq!(
+            parsed,
+            HostStats {
+                cpus: 8,
+                mem_total_mib: 15927,
+                mem_available_mib: 8160,
+                load_average: 0.52,
+            }
+        );
+        assert!(parse_stats("MemTotal: 1024 kB\n", "0.52", 8).is_err());
+        assert!(stats().is_ok());
+    }
+
+    #[test]
+    fn test_host_arch() {
+        assert_eq!(Arch::host().to_string(), std::env::consts::ARCH);
+    }
+}
@@ -10,6 +10,32 @@ extern crate serde;
 extern crate serde_json;
 extern crate url;
 
+pub mod api;
+pub mod audit;
+pub mod boot;
 pub mod builder;
+pub mod cgroup;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod events;
 pub mod executor;
+pub mod handle;
+pub mod health;
+pub mod host;
 pub mod machine;
+pub mod metrics;
+pub mod network;
+pub mod overlay;
+pub mod persist;
+pub mod pool;
+pub mod preflight;
+pub mod registry;
+pub mod scheduler;
+pub mod shutdown;
+pub mod snapshot;
+pub mod staging;
+pub mod telemetry;
+pub mod thin;
+pub mod version;
+pub mod vsock;
+pub mod workspace;
@@ -9,11 +9,16 @@
 //! ```ignore
 //! use tokio::time::{sleep, Duration};
 //! use firepilot::builder::Configuration;
+//! use firepilot::executor::{Executor, FirecrackerExecutor};
 //! use firepilot::machine::Machine;
+//! let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+//!     chroot: "/tmp/firepilot".to_string(),
+//!     exec_binary: std::path::PathBuf::from("/usr/bin/firecracker"),
+//! });
 //! // This configuration is not enough to run a microVM
 //! let config = Configuration::new("simple_vm".to_string());
 //!
-//! let mut machine = Machine::new();
+//! let mut machine = Machine::new(executor).unwrap();
 //! // Apply configuration to the machine
 //! machine.create(config).await.unwrap();
 //!     
@@ -26,56 +31,416 @@
 //! machine.kill().await.unwrap();
 //! ```
 
-use std::{fs::copy, path::Path};
+use std::{
+    fs::create_dir_all,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use tracing::{debug, info, instrument};
+use nix::{sys::signal, unistd::Pid};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
 
 use crate::{
-    builder::Configuration,
-    executor::{Action, Executor},
+    boot::BootEvent,
+    builder::{
+        boot_args::BootArgsBuilder,
+        drive::{
+            check_block_device_access, create_scratch_image, is_block_device, DriveStaging,
+            StagedDrive,
+        },
+        metrics::DEFAULT_METRICS_FIFO,
+        vsock::DEFAULT_VSOCK_UDS,
+        BuilderError, Configuration,
+    },
+    events::{MachineEvent, MACHINE_EVENTS_CAPACITY},
+    executor::{Action, BootMode, ExecuteError, Executor, FirecrackerExecutor},
+    metrics::{FirecrackerMetrics, MetricsReader},
+    network::{
+        guest::GuestNetworkConfig,
+        nat::NatRules,
+        neighbor::{find_neighbor, read_neighbors, GuestLink},
+        tap::TapDevice,
+    },
+    overlay::OverlayDevice,
+    persist::{PersistedMachine, DEFAULT_EXEC_BINARY, STATE_FILE},
+    preflight::{self, PreflightReport},
+    shutdown::{ShutdownPolicy, ShutdownStep},
+    snapshot::{RestoreOptions, Snapshot, SnapshotType},
+    staging::{self, CopyStrategy, StagedArtifact, StagingJob},
+    telemetry,
+    thin::ThinDevice,
+    vsock,
+};
+
+use firepilot_models::models::{
+    Balloon, BalloonStats, BootSource, Drive, FullVmConfiguration, MachineConfiguration, Metrics,
+    MmdsConfig, NetworkInterface, PartialDrive, PartialNetworkInterface, RateLimiter, Vsock,
 };
 
-use firepilot_models::models::vm::{State, Vm};
+/// State of the microVM as reported by firecracker, see [Machine::state]
+pub use firepilot_models::models::instance_info::State as InstanceState;
 
-#[derive(Debug)]
+/// Underlying error of a [FirepilotError], e.g. an [std::io::Error] or an
+/// [ExecuteError]
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync>;
+
+/// Error of an operation on a machine, every variant tells which machine with
+/// `vm_id` so the errors of several machines can be told apart
+#[derive(thiserror::Error, Debug)]
 pub enum FirepilotError {
     /// Mostly problems related to directories error or unavailable files
-    Setup(String),
+    #[error("Machine {vm_id}: {message}")]
+    Setup {
+        vm_id: String,
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
     /// Related to communication with the socket to configure the microVM which failed
-    Configure(String),
+    #[error("Machine {vm_id}: {message}")]
+    Configure {
+        vm_id: String,
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
     /// The process didn't start properly or an error occurred while trying to run it
-    Execute(String),
+    #[error("Machine {vm_id}: {message}")]
+    Execute {
+        vm_id: String,
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    /// An operation on the microVM didn't complete on time
+    #[error("Machine {vm_id}: {message}")]
+    Timeout { vm_id: String, message: String },
+    /// The guest is still booting and can't handle the request yet
+    #[error("Machine {vm_id}: {message}")]
+    GuestNotReady {
+        vm_id: String,
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    /// The microVM didn't reach the running state after being started
+    #[error("Machine {vm_id}: {message}")]
+    StartFailed {
+        vm_id: String,
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    /// The operation isn't possible in the current state of the machine, e.g.
+    /// starting it before it is created
+    #[error("Machine {vm_id}: can't {action} while {state:?}")]
+    InvalidTransition {
+        vm_id: String,
+        action: &'static str,
+        state: MachineState,
+    },
+    /// No machine with this id is known, see [crate::registry]
+    #[error("No machine with id {vm_id}")]
+    UnknownMachine { vm_id: String },
+    /// The id of the machine is already used, see [crate::registry]
+    #[error("A machine with id {vm_id} already exists")]
+    AlreadyExists { vm_id: String },
+    /// The scheduler refused to create the machine on this host, see
+    /// [crate::scheduler]
+    #[error("Machine {vm_id}: rejected by the scheduler, {reason}")]
+    Rejected { vm_id: String, reason: String },
+    /// The host lacks something firecracker needs, e.g. access to `/dev/kvm`,
+    /// see [crate::preflight]
+    #[error("Machine {vm_id}: the host can't run firecracker\n{report}")]
+    Preflight {
+        vm_id: String,
+        report: Box<PreflightReport>,
+    },
+    /// The configuration is incomplete or inconsistent, see
+    /// [Configuration::validate], or it refers to files or devices which
+    /// can't be used, see [Configuration::check_resources]
+    #[error(
+        "Machine {vm_id}: invalid configuration, {}",
+        .problems.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    )]
+    InvalidConfiguration {
+        vm_id: String,
+        problems: Vec<BuilderError>,
+    },
 }
 
+impl FirepilotError {
+    pub(crate) fn setup<M: Into<String>>(vm_id: &str, message: M) -> FirepilotError {
+        FirepilotError::Setup {
+            vm_id: vm_id.to_string(),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn configure<M: Into<String>>(vm_id: &str, message: M) -> FirepilotError {
+        FirepilotError::Configure {
+            vm_id: vm_id.to_string(),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn execute<M: Into<String>>(vm_id: &str, message: M) -> FirepilotError {
+        FirepilotError::Execute {
+            vm_id: vm_id.to_string(),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn timeout<M: Into<String>>(vm_id: &str, message: M) -> FirepilotError {
+        FirepilotError::Timeout {
+            vm_id: vm_id.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Keep the error which caused this one, variants without a source are
+    /// returned as they are
+    pub(crate) fn with_source<E: Into<ErrorSource>>(mut self, cause: E) -> FirepilotError {
+        match &mut self {
+            FirepilotError::Setup { source, .. }
+            | FirepilotError::Configure { source, .. }
+            | FirepilotError::Execute { source, .. }
+            | FirepilotError::GuestNotReady { source, .. }
+            | FirepilotError::StartFailed { source, .. } => *source = Some(cause.into()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Id of the machine the error is about
+    pub fn vm_id(&self) -> &str {
+        match self {
+            FirepilotError::Setup { vm_id, .. }
+            | FirepilotError::Configure { vm_id, .. }
+            | FirepilotError::Execute { vm_id, .. }
+            | FirepilotError::Timeout { vm_id, .. }
+            | FirepilotError::GuestNotReady { vm_id, .. }
+            | FirepilotError::StartFailed { vm_id, .. }
+            | FirepilotError::InvalidTransition { vm_id, .. }
+            | FirepilotError::UnknownMachine { vm_id }
+            | FirepilotError::AlreadyExists { vm_id }
+            | FirepilotError::Rejected { vm_id, .. }
+            | FirepilotError::Preflight { vm_id, .. }
+            | FirepilotError::InvalidConfiguration { vm_id, .. } => vm_id,
+        }
+    }
+}
+
+/// Errors of the lower-level modules, which don't know the machine they are
+/// about, turned into a [FirepilotError] once it is known
+pub trait IntoFirepilotError {
+    fn into_firepilot_error(self, vm_id: &str) -> FirepilotError;
+}
+
+/// Turn the error of a result into a [FirepilotError] about the given machine,
+/// see [IntoFirepilotError]
+pub trait ForVm<T> {
+    fn for_vm(self, vm_id: &str) -> Result<T, FirepilotError>;
+}
+
+impl<T, E: IntoFirepilotError> ForVm<T> for Result<T, E> {
+    fn for_vm(self, vm_id: &str) -> Result<T, FirepilotError> {
+        self.map_err(|e| e.into_firepilot_error(vm_id))
+    }
+}
+
+/// Lifecycle of a [Machine] as tracked by firepilot, see [Machine::lifecycle]
+///
+/// Unlike [InstanceState], it is known without asking firecracker, and it
+/// tells apart a machine which wasn't created yet from one which stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MachineState {
+    /// Nothing is provisioned yet, see [Machine::create]
+    Created,
+    /// firecracker runs with the configuration applied, the guest isn't
+    /// started yet
+    Configured,
+    Running,
+    Paused,
+    /// firecracker exited, or was killed
+    Stopped,
+    /// An operation failed midway, the machine can only be killed or purged,
+    /// or created again when configuring firecracker failed, see
+    /// [Machine::create]
+    Failed,
+    /// firecracker exited unexpectedly, it crashed or was killed by someone
+    /// else. The machine can only be waited for, killed or purged.
+    Crashed,
+}
+
+/// Top-level key in the MMDS data store used for the readiness handshake
+pub const READINESS_MMDS_KEY: &str = "firepilot";
+
+/// Time given to firecracker to report the instance as running once started
+const START_CONFIRM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Interval between two reads of the instance state after starting it
+const START_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Name of the custom CPU template referenced by the configuration file, in
+/// the workspace, see [BootMode]
+const CPU_CONFIG_FILE: &str = "cpu_config.json";
+
+/// Number of times CtrlAltDel is sent again while the guest is still booting
+const CTRL_ALT_DEL_RETRIES: u32 = 5;
+
+/// Delay before the first CtrlAltDel retry, doubled on each attempt
+const CTRL_ALT_DEL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Interval between two reads of the MMDS data store while waiting for the
+/// guest to be ready
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// An instance of microVM which can be created and deployed easily
 #[derive(Debug)]
 pub struct Machine {
     /// Current microVM executor with applied configuration
     executor: Executor,
+    /// Host socket proxying vsock connections, when a vsock device is configured
+    vsock_uds: Option<PathBuf>,
+    /// Thin snapshots created for the drives, removed when the machine is killed
+    thin_devices: Vec<ThinDevice>,
+    /// Copy-on-write overlays created for the drives, removed when the machine
+    /// is killed
+    overlay_devices: Vec<OverlayDevice>,
+    /// Copies of the drives staged outside of the workspace, see
+    /// [DriveStaging::dir](crate::builder::drive::DriveStaging::dir), removed
+    /// when the machine is purged
+    staged_copies: Vec<PathBuf>,
+    /// Strategy staging the drives, kept for the drives attached after
+    /// [Machine::create], see [Configuration::with_copy_strategy]
+    copy_strategy: CopyStrategy,
+    /// Where firecracker writes its metrics, when they are configured
+    metrics_path: Option<PathBuf>,
+    /// Opened on the first [Machine::read_metrics], kept so firecracker
+    /// always finds a reader
+    metrics_reader: Option<MetricsReader>,
+    /// TAP devices created for the interfaces, removed when the machine is killed
+    tap_devices: Vec<TapDevice>,
+    /// Firewall rules installed for the TAP devices, removed when the machine
+    /// is killed
+    nat_rules: Vec<NatRules>,
+    /// Address leased to the guest, when it is taken from a pool
+    allocated_ip: Option<Ipv4Addr>,
+    /// Configuration applied to firecracker, kept to apply it again when the
+    /// machine is rebooted
+    applied: Option<AppliedConfig>,
+    /// Where the machine is in its lifecycle, behind a lock as most
+    /// operations only borrow the machine, shared with the exit monitor
+    lifecycle: Arc<Mutex<MachineState>>,
+    /// Lifecycle events published to the subscribers, see [crate::events]
+    events: broadcast::Sender<MachineEvent>,
+    /// Set once firecracker is told to exit, so the exit monitor doesn't take
+    /// it for a crash. Each process gets its own flag, see
+    /// [Machine::monitor_exit]
+    exit_expected: Arc<AtomicBool>,
+}
+
+/// Configuration sent to firecracker once the artifacts are staged, with the
+/// paths in the workspace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AppliedConfig {
+    machine_config: Option<MachineConfiguration>,
+    cpu_config: Option<serde_json::Value>,
+    balloon: Option<Balloon>,
+    drives: Vec<Drive>,
+    boot_source: BootSource,
+    interfaces: Vec<NetworkInterface>,
+    mmds: Option<MmdsConfig>,
+    vsock: Option<Vsock>,
+    metrics: Option<Metrics>,
+    #[cfg(feature = "firecracker-1-4")]
+    #[serde(default)]
+    entropy: Option<firepilot_models::models::EntropyDevice>,
+}
+
+impl AppliedConfig {
+    /// Configuration without executor nor staging options, its CPU template
+    /// left out as it is written to its own file, see
+    /// [Configuration::to_firecracker_json]
+    fn to_configuration(&self, vm_id: &str) -> Configuration {
+        let mut config = Configuration::new(vm_id.to_string());
+        config.kernel = Some(self.boot_source.clone());
+        config.storage = self.drives.clone();
+        config.interfaces = self.interfaces.clone();
+        config.machine_config = self.machine_config.clone();
+        config.balloon = self.balloon.clone();
+        config.mmds = self.mmds.clone();
+        config.vsock = self.vsock.clone();
+        config.metrics = self.metrics.clone();
+        #[cfg(feature = "firecracker-1-4")]
+        {
+            config.entropy = self.entropy.clone();
+        }
+        config
+    }
 }
 
 impl Machine {
-    pub fn new() -> Self {
-        Machine {
-            executor: Executor::new(),
+    /// Machine running on the given executor, which must have an [Execute]
+    /// implementation, see [Executor::has_implementation]
+    ///
+    /// The executor of the configuration given to [Machine::create], when
+    /// there is one, replaces it.
+    ///
+    /// [Execute]: crate::executor::Execute
+    pub fn new(executor: Executor) -> Result<Machine, FirepilotError> {
+        if !executor.has_implementation() {
+            return Err(ExecuteError::NoExecutor.into_firepilot_error(executor.id()));
         }
+        Ok(Machine {
+            executor,
+            vsock_uds: None,
+            thin_devices: Vec::new(),
+            overlay_devices: Vec::new(),
+            staged_copies: Vec::new(),
+            copy_strategy: CopyStrategy::default(),
+            tap_devices: Vec::new(),
+            nat_rules: Vec::new(),
+            allocated_ip: None,
+            applied: None,
+            metrics_path: None,
+            metrics_reader: None,
+            lifecycle: Arc::new(Mutex::new(MachineState::Created)),
+            events: broadcast::channel(MACHINE_EVENTS_CAPACITY).0,
+            exit_expected: Arc::new(AtomicBool::new(false)),
+        })
     }
 
-    fn copy<P, Q>(from: P, to: Q) -> Result<(), FirepilotError>
-    where
-        P: AsRef<Path>,
-        Q: AsRef<Path>,
-    {
-        copy(&from, &to).map_err(|e| {
-            let msg = format!(
-                "Failed to copy {:?} to {:?}: {}",
-                from.as_ref(),
-                to.as_ref(),
-                e
-            );
-            FirepilotError::Setup(msg)
-        })?;
-        Ok(())
+    /// Machine running on the executor of the configuration, taken out of it
+    /// so the configuration can be given to [Machine::create]
+    pub(crate) fn for_config(config: &mut Configuration) -> Result<Machine, FirepilotError> {
+        let executor =
+            config
+                .executor
+                .take()
+                .ok_or_else(|| FirepilotError::InvalidConfiguration {
+                    vm_id: config.vm_id.clone(),
+                    problems: vec![BuilderError::missing(
+                        "Configuration",
+                        "executor",
+                        "with_executor",
+                    )],
+                })?;
+        Machine::new(executor)
     }
 
     /// Setup an initial workspace to be working and to have the microVM
@@ -83,86 +448,2798 @@ impl Machine {
     /// configured when you are creating the executor object.
     ///
     /// 1. Setup the machine workspace from the executor
-    /// 2. Copy drives into the machine workspace (rootfs included)
-    /// 3. Copy the kernel in the system workspace
+    /// 2. Copy drives into the machine workspace (rootfs included), or in their
+    ///    own staging directory when one is configured, see
+    ///    [Configuration::with_copy_strategy] for cheaper ways than a copy.
+    ///    Drives backed by a block device are used in place, after checking
+    ///    they can be opened, drives staged in a thin pool are snapshotted, and
+    ///    scratch drives are created empty in the workspace. Overlay drives get a
+    ///    copy-on-write device over their shared base image. Drives built
+    ///    [without staging](crate::builder::drive::DriveBuilder::without_staging)
+    ///    are used in place
+    /// 3. Copy the kernel in the system workspace, and create the TAP devices
+    ///    of the interfaces configured with one, along with their NAT rules
     /// 4. Spawn the socket process
     /// 5. Configure the socket with given informations from the configuration
-    #[instrument(skip(self, config), fields(id = %config.vm_id))]
+    ///
+    /// When configuring firecracker failed, e.g. on a transient error of its
+    /// API, the [MachineState::Failed] machine can be created again with the
+    /// same configuration while firecracker still runs: the artifacts and
+    /// devices already provisioned are reused and only the resources
+    /// firecracker doesn't have yet are sent.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, config), fields(id = %config.vm_id)))]
     pub async fn create(&mut self, mut config: Configuration) -> Result<(), FirepilotError> {
-        self.executor = match config.executor {
-            Some(executor) => Ok(executor),
-            None => Err(FirepilotError::Setup(
-                "No executor was provided in the configuration".to_string(),
-            )),
-        }?;
+        if self.lifecycle() == MachineState::Failed {
+            return self.configure_again(&config).await;
+        }
+        self.require("create", &[MachineState::Created])?;
+        // Id of the executor of the machine, when the configuration runs on it
+        let mut own_id = None;
+        if config.executor.is_none() {
+            let executor = std::mem::take(&mut self.executor);
+            own_id = Some(executor.id().to_string());
+            config.executor = Some(executor.with_id(config.vm_id.clone()));
+        }
+        let checked = Machine::check(&config);
+        if let Some(executor) = config.executor.take() {
+            // A rejected configuration leaves the executor of the machine as
+            // it was, it can be created again
+            match (checked.is_ok(), own_id) {
+                (true, _) => self.executor = executor,
+                (false, Some(id)) => self.executor = executor.with_id(id),
+                (false, None) => {}
+            }
+        }
+        checked?;
+        let started = Instant::now();
+        let result = self.provision(config).await;
+        telemetry::record_create(started.elapsed(), result.is_ok());
+        self.settle(result, MachineState::Configured)
+    }
+
+    /// Send the configuration again to the process left running by a failed
+    /// creation, see [Machine::create]
+    async fn configure_again(&mut self, config: &Configuration) -> Result<(), FirepilotError> {
+        let applied = match &self.applied {
+            Some(applied)
+                if config.vm_id == self.vm_id()
+                    && self.executor.boot_mode() == BootMode::Api
+                    && self.executor.is_running() =>
+            {
+                applied.clone()
+            }
+            _ => {
+                return Err(FirepilotError::InvalidTransition {
+                    vm_id: self.vm_id().to_string(),
+                    action: "create",
+                    state: MachineState::Failed,
+                })
+            }
+        };
+        info!("Configure microVM again");
+        let result = self.configure_process(&applied).await;
+        if result.is_ok() {
+            self.save_state();
+        }
+        self.settle(result, MachineState::Configured)
+    }
+
+    /// Everything making the configuration fail before anything is
+    /// provisioned, see [Configuration::validate], [preflight::check] and
+    /// [Configuration::check_resources]
+    fn check(config: &Configuration) -> Result<(), FirepilotError> {
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(FirepilotError::InvalidConfiguration {
+                vm_id: config.vm_id.clone(),
+                problems,
+            });
+        }
+        if let Some(executor) = config.executor.as_ref().filter(|e| !e.has_implementation()) {
+            return Err(ExecuteError::NoExecutor.into_firepilot_error(executor.id()));
+        }
+        // Firecracker would exit right after being spawned
+        let report = preflight::check();
+        if !report.blocking(config).is_empty() {
+            warn!("The host can't run machine {}:\n{}", config.vm_id, report);
+            return Err(FirepilotError::Preflight {
+                vm_id: config.vm_id.clone(),
+                report: Box::new(report),
+            });
+        }
+        // Rather than a bad request from firecracker once it is spawned
+        let problems = config.check_resources();
+        if !problems.is_empty() {
+            return Err(FirepilotError::InvalidConfiguration {
+                vm_id: config.vm_id.clone(),
+                problems,
+            });
+        }
+        Ok(())
+    }
+
+    async fn provision(&mut self, mut config: Configuration) -> Result<(), FirepilotError> {
+        if config.ip_boot_arg.is_some() && config.auto_ip.is_none() {
+            return Err(FirepilotError::configure(
+                &config.vm_id,
+                "The ip= boot argument requires an address pool, see with_auto_ip",
+            ));
+        }
 
         // Step 1. Setup the machine workspace from the executor
-        self.executor.create_workspace()?;
+        self.executor.create_workspace().for_vm(self.vm_id())?;
+        self.emit(MachineEvent::WorkspaceCreated);
+        let mut kernel = config.kernel.unwrap();
+        if let Some(pool) = &config.auto_ip {
+            let address = pool.allocate(&config.vm_id).for_vm(self.vm_id())?;
+            info!("Leased {} to the guest", address);
+            self.allocated_ip = Some(address);
+            if let Some(interface) = config.ip_boot_arg.take() {
+                let guest = GuestNetworkConfig::new(interface, address, pool.prefix_len())
+                    .with_gateway(pool.gateway());
+                let cmdline = kernel.boot_args.take().unwrap_or_default();
+                kernel.boot_args = Some(
+                    BootArgsBuilder::from_cmdline(&cmdline)
+                        .with_ip(&guest)
+                        .build(),
+                );
+            }
+        }
 
         // Step 3. Copy drives into the machine workspace
-        let kernel = config.kernel.unwrap();
-        for drive in config.storage.iter_mut() {
-            let new_drive_path = self.executor.chroot().join(&drive.drive_id);
-            info!("Copy drive {} in the workspace", drive.drive_id);
-            debug!(
-                "Drive from {:?} to {:?}",
-                drive.path_on_host, new_drive_path
-            );
-            Machine::copy(&drive.path_on_host, &new_drive_path)?;
-            drive.path_on_host = new_drive_path.into_os_string().into_string().unwrap();
+        let workspace = self.executor.chroot();
+        self.copy_strategy = config.copy_strategy;
+        let mut jobs = Vec::new();
+        // Index of the drive of each job, the kernel and initrd jobs come last
+        let mut staged_drives = Vec::new();
+        for (index, drive) in config.storage.iter_mut().enumerate() {
+            let staging = config.staging.remove(&drive.drive_id).unwrap_or_default();
+            if let Some(job) = self.prepare_drive(drive, &staging).await? {
+                staged_drives.push(index);
+                jobs.push(job);
+            }
         }
 
-        // Step 4. Copy the kernel in the system workspace
-        let kernel_path = self.executor.chroot().join("vmlinux");
+        // Step 4. Copy the kernel in the system workspace, along with the drives
+        let strategy = config.copy_strategy.for_artifact(false);
         info!("Copy kernel in the workspace");
-        debug!(
-            "Kernel from {:?} to {:?}",
-            kernel.kernel_image_path, kernel_path
-        );
-        Machine::copy(kernel.kernel_image_path.clone(), kernel_path)?;
+        jobs.push(StagingJob {
+            name: "kernel".to_string(),
+            source: PathBuf::from(&kernel.kernel_image_path),
+            target: workspace.join("vmlinux"),
+            strategy,
+        });
+        if let Some(initrd) = &kernel.initrd_path {
+            jobs.push(StagingJob {
+                name: "initrd".to_string(),
+                source: PathBuf::from(initrd),
+                target: workspace.join("initrd"),
+                strategy,
+            });
+        }
+        let staged = staging::stage_all(jobs, config.staging_progress.as_ref())
+            .await
+            .map_err(|e| {
+                FirepilotError::setup(&config.vm_id, "Failed to stage the drives").with_source(e)
+            })?;
+        for (index, artifact) in staged_drives.into_iter().zip(staged) {
+            self.apply_staged(&mut config.storage[index], artifact);
+        }
 
-        if let Some(initrd) = kernel.initrd_path.clone() {
-            Machine::copy(initrd, self.executor.chroot().join("initrd"))?;
+        // Where firecracker opens the TAP devices
+        let netns = self.executor.netns();
+        for iface in config.interfaces.iter() {
+            if let Some(tap) = config.taps.remove(&iface.iface_id) {
+                info!("Create TAP device {}", iface.host_dev_name);
+                let device = tap
+                    .create_in(&iface.host_dev_name, netns.as_deref())
+                    .await
+                    .for_vm(self.vm_id())?;
+                self.tap_devices.push(device.clone());
+                if let Some(nat) = &tap.nat {
+                    self.nat_rules.push(
+                        nat.install(&config.vm_id, &device)
+                            .await
+                            .for_vm(self.vm_id())?,
+                    );
+                }
+            }
         }
 
+        let mut applied = AppliedConfig {
+            machine_config: config.machine_config,
+            cpu_config: config.cpu_config,
+            balloon: config.balloon,
+            drives: config.storage,
+            boot_source: kernel,
+            interfaces: config.interfaces,
+            mmds: config.mmds,
+            vsock: config.vsock,
+            metrics: config.metrics,
+            #[cfg(feature = "firecracker-1-4")]
+            entropy: config.entropy,
+        };
+        if let Some(vsock) = &mut applied.vsock {
+            let uds_path = workspace.join(&vsock.uds_path);
+            vsock.uds_path = uds_path.to_string_lossy().into_owned();
+            self.vsock_uds = Some(uds_path);
+        }
+        if let Some(metrics) = &mut applied.metrics {
+            let metrics_path = workspace.join(&metrics.metrics_path);
+            metrics.metrics_path = metrics_path.to_string_lossy().into_owned();
+            self.metrics_path = Some(metrics_path);
+        }
+        // Kept on failure, for the creation to be retried
+        self.applied = Some(applied.clone());
+        self.spawn_configured(&applied).await?;
+        self.save_state();
+        Ok(())
+    }
+
+    /// Prepare a drive on the host before it is handed to firecracker
+    ///
+    /// Thin snapshots, overlays and scratch images are created right away and
+    /// block devices are used in place. Other drives give the job staging
+    /// them, its artifact is then given to [Machine::apply_staged].
+    async fn prepare_drive(
+        &mut self,
+        drive: &mut Drive,
+        staging: &DriveStaging,
+    ) -> Result<Option<StagingJob>, FirepilotError> {
+        let vm_id = self.vm_id().to_string();
+        let workspace = self.executor.chroot();
+        // Files in the workspace are named after the drive
+        staging::check_file_name(&drive.drive_id).map_err(|e| {
+            FirepilotError::configure(&vm_id, format!("Invalid drive id {:?}", drive.drive_id))
+                .with_source(e)
+        })?;
+        if let Some(origin) = &staging.thin {
+            let name = format!("firepilot-{}-{}", vm_id, drive.drive_id);
+            let device = origin.snapshot(&name).await.for_vm(&vm_id)?;
+            drive.path_on_host = device.path().to_string_lossy().into_owned();
+            self.thin_devices.push(device);
+            return Ok(None);
+        }
+        if let Some(origin) = &staging.overlay {
+            let name = format!("firepilot-{}-{}", vm_id, drive.drive_id);
+            let cow_path = workspace.join(format!("{}.cow", drive.drive_id));
+            let device = origin.create(&name, &cow_path).await.for_vm(&vm_id)?;
+            drive.path_on_host = device.path().to_string_lossy().into_owned();
+            self.overlay_devices.push(device);
+            return Ok(None);
+        }
+        if let Some(size_mib) = staging.scratch {
+            let image = workspace.join(&drive.drive_id);
+            info!("Create scratch drive {}", drive.drive_id);
+            create_scratch_image(&image, size_mib).await.map_err(|e| {
+                FirepilotError::setup(
+                    &vm_id,
+                    format!("Failed to create scratch drive {:?}", image),
+                )
+                .with_source(e)
+            })?;
+            drive.path_on_host = image.to_string_lossy().into_owned();
+            return Ok(None);
+        }
+        let device = Path::new(&drive.path_on_host);
+        if is_block_device(device) {
+            info!(
+                "Drive {} is a block device, using it in place",
+                drive.drive_id
+            );
+            check_block_device_access(device, drive.is_read_only).map_err(|e| {
+                FirepilotError::setup(&vm_id, format!("Block device {:?} can't be opened", device))
+                    .with_source(e)
+            })?;
+            return Ok(None);
+        }
+        let source = PathBuf::from(&drive.path_on_host);
+        let (target, strategy) = match staging.no_stage {
+            true => {
+                info!("Drive {} is not staged, using it in place", drive.drive_id);
+                (source.clone(), CopyStrategy::InPlace)
+            }
+            false => {
+                let new_drive_path = staging.location(&workspace, &vm_id, &drive.drive_id);
+                if let Some(parent) = new_drive_path.parent() {
+                    create_dir_all(parent).map_err(|e| {
+                        FirepilotError::setup(&vm_id, format!("Failed to create {:?}", parent))
+                            .with_source(e)
+                    })?;
+                }
+                info!("Copy drive {} in the workspace", drive.drive_id);
+                debug!(
+                    "Drive from {:?} to {:?}",
+                    drive.path_on_host, new_drive_path
+                );
+                let strategy = self.copy_strategy.for_artifact(!drive.is_read_only);
+                (new_drive_path, strategy)
+            }
+        };
+        Ok(Some(StagingJob {
+            name: drive.drive_id.clone(),
+            source,
+            target,
+            strategy,
+        }))
+    }
+
+    /// Point the drive to its staged copy, copies outside of the workspace
+    /// are tracked to be removed when the machine is purged
+    fn apply_staged(&mut self, drive: &mut Drive, artifact: StagedArtifact) {
+        if artifact.strategy != CopyStrategy::InPlace {
+            if !artifact.target.starts_with(self.executor.chroot()) {
+                self.staged_copies.push(artifact.target.clone());
+            }
+            drive.path_on_host = artifact.target.into_os_string().into_string().unwrap();
+        }
+    }
+
+    /// Spawn the socket process and send it the configuration
+    ///
+    /// When firecracker boots from a configuration file, the file is only
+    /// written: the guest boots as soon as the process is spawned, which is
+    /// left to [Machine::start].
+    async fn spawn_configured(&mut self, applied: &AppliedConfig) -> Result<(), FirepilotError> {
+        if self.executor.boot_mode() != BootMode::Api {
+            self.write_config_file(applied)?;
+            self.emit(MachineEvent::Configured);
+            return Ok(());
+        }
         // Step 5. Spawn the socket process
-        self.executor.run_socket()?;
+        self.executor.run_socket().await.for_vm(self.vm_id())?;
+        self.monitor_exit();
+        self.executor
+            .negotiate_version()
+            .await
+            .for_vm(self.vm_id())?;
+        self.emit(MachineEvent::SocketReady);
 
         // Step 6. Configure the socket with given informations from the configuration
         info!("Configure microVM");
-        self.executor.configure_drives(config.storage).await?;
-        self.executor.configure_boot_source(kernel).await?;
-        self.executor.configure_network(config.interfaces).await?;
+        self.configure_process(applied).await
+    }
+
+    /// Send the configuration to the running firecracker process
+    async fn configure_process(&self, applied: &AppliedConfig) -> Result<(), FirepilotError> {
+        if let Some(machine_config) = &applied.machine_config {
+            self.executor
+                .configure_machine_config(machine_config.clone())
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        if let Some(cpu_config) = &applied.cpu_config {
+            self.executor
+                .configure_cpu_config(cpu_config)
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        if let Some(balloon) = &applied.balloon {
+            self.executor
+                .configure_balloon(balloon.clone())
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        self.executor
+            .configure_drives(applied.drives.clone())
+            .await
+            .for_vm(self.vm_id())?;
+        self.executor
+            .configure_boot_source(applied.boot_source.clone())
+            .await
+            .for_vm(self.vm_id())?;
+        self.executor
+            .configure_network(applied.interfaces.clone())
+            .await
+            .for_vm(self.vm_id())?;
+        if let Some(mmds) = &applied.mmds {
+            self.executor
+                .configure_mmds(mmds.clone())
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        if let Some(vsock) = &applied.vsock {
+            self.executor
+                .configure_vsock(vsock.clone())
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        if let Some(metrics) = &applied.metrics {
+            self.executor
+                .configure_metrics(metrics.clone())
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        #[cfg(feature = "firecracker-1-4")]
+        if let Some(entropy) = &applied.entropy {
+            self.executor
+                .configure_entropy(entropy.clone())
+                .await
+                .for_vm(self.vm_id())?;
+        }
+        self.emit(MachineEvent::Configured);
         Ok(())
     }
 
-    /// Shutdown abruptly the socket process, if the VM was running it will stop it
+    /// Write the configuration firecracker boots from, see [BootMode]
+    fn write_config_file(&self, applied: &AppliedConfig) -> Result<(), FirepilotError> {
+        let vm_config = applied.to_configuration(self.vm_id()).to_full_vm_config();
+        let mut content = serde_json::to_value(vm_config).map_err(|e| {
+            FirepilotError::configure(self.vm_id(), "Failed to serialize the configuration")
+                .with_source(e)
+        })?;
+        // The CPU template is referenced by path
+        if let Some(cpu_config) = &applied.cpu_config {
+            let path = self.executor.chroot().join(CPU_CONFIG_FILE);
+            write_json(self.vm_id(), &path, cpu_config)?;
+            content["cpu-config"] = serde_json::Value::from(path.to_string_lossy().into_owned());
+        }
+        let path = self.executor.config_file_path();
+        debug!("Write the configuration to {:?}", path);
+        write_json(self.vm_id(), &path, &content)
+    }
+
+    /// Restart the guest: stop it gracefully, waiting for at most `max_wait`,
+    /// spawn firecracker again with the configuration given at creation and
+    /// start it
+    ///
+    /// The staged artifacts, TAP devices and leased address are reused as they
+    /// are, writable drives keep what the guest wrote. The MMDS data store is
+    /// empty after the reboot, it must be filled again.
+    ///
+    /// ```no_run
+    /// # async fn example(mut machine: firepilot::machine::Machine) {
+    /// use std::time::Duration;
+    ///
+    /// machine.reboot(Duration::from_secs(10)).await.unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn reboot(&mut self, max_wait: Duration) -> Result<(), FirepilotError> {
+        let applied = self.applied.clone().ok_or_else(|| {
+            FirepilotError::configure(
+                self.vm_id(),
+                "Only machines created with Machine::create can be rebooted",
+            )
+        })?;
+        self.require("reboot", &[MachineState::Running])?;
+        info!("Rebooting the machine");
+        self.stop_and_wait(max_wait).await?;
+        // The new process can't bind the vsock socket left by the previous one
+        if let Some(uds_path) = &self.vsock_uds {
+            let _ = std::fs::remove_file(uds_path);
+        }
+        let result = self.spawn_configured(&applied).await;
+        self.settle(result, MachineState::Configured)?;
+        self.start().await
+    }
+
+    /// Adopt the microVM still running in the workspace of the executor, e.g.
+    /// one listed as [WorkspaceStatus::Running](crate::workspace::WorkspaceStatus::Running)
+    /// after the controller crashed
+    ///
+    /// The executor must be given the id of the workspace, see
+    /// [Executor::adopt]. Thin snapshots of the drives aren't known anymore
+    /// and are not removed when the machine is killed.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use std::path::{Path, PathBuf};
+    /// use firepilot::executor::{Executor, FirecrackerExecutor};
+    /// use firepilot::machine::Machine;
+    /// use firepilot::workspace::{self, WorkspaceStatus};
+    ///
+    /// # async fn example() {
+    /// for workspace in workspace::list(Path::new("/tmp/firepilot")).unwrap() {
+    ///     if workspace.status == WorkspaceStatus::Running {
+    ///         let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+    ///             chroot: "/tmp/firepilot".to_string(),
+    ///             exec_binary: PathBuf::from("/usr/bin/firecracker"),
+    ///         })
+    ///         .with_id(workspace.id);
+    ///         let machine = Machine::from_existing(executor).await.unwrap();
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub async fn from_existing(mut executor: Executor) -> Result<Machine, FirepilotError> {
+        executor.adopt().for_vm(executor.id())?;
+        executor.negotiate_version().await.for_vm(executor.id())?;
+        let lifecycle = match executor
+            .describe_instance()
+            .await
+            .for_vm(executor.id())?
+            .state
+        {
+            InstanceState::NotStarted => MachineState::Configured,
+            InstanceState::Running => MachineState::Running,
+            InstanceState::Paused => MachineState::Paused,
+        };
+        let vsock_uds = executor.chroot().join(DEFAULT_VSOCK_UDS);
+        let metrics_path = executor.chroot().join(DEFAULT_METRICS_FIFO);
+        Ok(Machine {
+            vsock_uds: match vsock_uds.exists() {
+                true => Some(vsock_uds),
+                false => None,
+            },
+            metrics_path: match metrics_path.exists() {
+                true => Some(metrics_path),
+                false => None,
+            },
+            metrics_reader: None,
+            executor,
+            thin_devices: Vec::new(),
+            overlay_devices: Vec::new(),
+            staged_copies: Vec::new(),
+            copy_strategy: CopyStrategy::default(),
+            tap_devices: Vec::new(),
+            nat_rules: Vec::new(),
+            allocated_ip: None,
+            applied: None,
+            lifecycle: Arc::new(Mutex::new(lifecycle)),
+            events: broadcast::channel(MACHINE_EVENTS_CAPACITY).0,
+            exit_expected: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Take over a microVM from the state persisted by another process, e.g.
+    /// before the controller restarted, see [crate::persist]
+    ///
+    /// Unlike [Machine::from_existing], the configuration of the microVM is
+    /// known again so it can be rebooted, and the devices created on the host
+    /// for it are removed when it is killed.
+    pub async fn reattach(state_path: &Path) -> Result<Machine, FirepilotError> {
+        let state = PersistedMachine::load(state_path)?;
+        if let Some(pid) = state.pid {
+            if signal::kill(Pid::from_raw(pid), None).is_err() {
+                return Err(FirepilotError::execute(
+                    &state.vm_id,
+                    format!("Process {} isn't running anymore", pid),
+                ));
+            }
+        }
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: state.chroot.to_string_lossy().into_owned(),
+            exec_binary: state
+                .exec_binary
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_EXEC_BINARY)),
+        })
+        .with_id(state.vm_id.clone());
+        if executor.socket_path() != state.socket_path {
+            warn!(
+                "Socket of machine {} moved from {}",
+                state.vm_id,
+                state.socket_path.display()
+            );
+        }
+        let mut machine = Machine::from_existing(executor).await?;
+        machine.applied = state.config;
+        machine.vsock_uds = state.vsock_uds.or(machine.vsock_uds);
+        machine.metrics_path = state.metrics_path.or(machine.metrics_path);
+        machine.allocated_ip = state.allocated_ip;
+        machine.tap_devices = state.tap_devices;
+        machine.nat_rules = state.nat_rules;
+        machine.thin_devices = state.thin_devices;
+        machine.overlay_devices = state.overlay_devices;
+        machine.staged_copies = state.staged_copies;
+        info!("Reattached to machine {}", state.vm_id);
+        Ok(machine)
+    }
+
+    /// Path of the state persisted in the workspace, see [crate::persist]
+    pub fn state_path(&self) -> PathBuf {
+        self.executor.chroot().join(STATE_FILE)
+    }
+
+    /// Write what another process needs to take the machine over with
+    /// [Machine::reattach] to the given path. It is kept up to date at
+    /// [Machine::state_path] as the machine is configured.
+    pub fn persist(&self, path: &Path) -> Result<(), FirepilotError> {
+        let chroot = self.executor.chroot();
+        PersistedMachine {
+            vm_id: self.executor.id().to_string(),
+            chroot: chroot.parent().map(Path::to_path_buf).unwrap_or_default(),
+            socket_path: self.executor.socket_path(),
+            pid: self.executor.pid().map(|pid| pid as i32),
+            exec_binary: self.executor.exec_binary(),
+            vsock_uds: self.vsock_uds.clone(),
+            metrics_path: self.metrics_path.clone(),
+            allocated_ip: self.allocated_ip,
+            config: self.applied.clone(),
+            tap_devices: self.tap_devices.clone(),
+            nat_rules: self.nat_rules.clone(),
+            thin_devices: self.thin_devices.clone(),
+            overlay_devices: self.overlay_devices.clone(),
+            staged_copies: self.staged_copies.clone(),
+        }
+        .save(path)
+    }
+
+    /// Keep the persisted state up to date, a failure doesn't fail the
+    /// operation which changed the machine
+    fn save_state(&self) {
+        if let Err(e) = self.persist(&self.state_path()) {
+            warn!("Failed to persist the state of the machine: {:?}", e);
+        }
+    }
+
+    /// Restore a microVM from a snapshot in the workspace of the executor,
+    /// instead of configuring and booting a new one
+    ///
+    /// The drives, network interfaces and vsock of the snapshotted microVM
+    /// are restored as they were, so the host paths and TAP devices they use
+    /// must still exist. The vsock socket of the restored microVM isn't
+    /// known, [Machine::vsock_connect] isn't available on it.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use std::path::{Path, PathBuf};
+    /// use firepilot::executor::{Executor, FirecrackerExecutor};
+    /// use firepilot::machine::Machine;
+    /// use firepilot::snapshot::{RestoreOptions, Snapshot, SnapshotType};
+    ///
+    /// # async fn example() {
+    /// let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+    ///     chroot: "/tmp/firepilot".to_string(),
+    ///     exec_binary: PathBuf::from("/usr/bin/firecracker"),
+    /// })
+    /// .with_id("web-restored".to_string());
+    /// let snapshot = Snapshot::in_dir(Path::new("/srv/snapshots/web"), SnapshotType::Full);
+    /// let options = RestoreOptions {
+    ///     resume_vm: true,
+    ///     ..Default::default()
+    /// };
+    /// let machine = Machine::from_snapshot(executor, &snapshot, options)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all, fields(snapshot = ?snapshot.snapshot_path)))]
+    pub async fn from_snapshot(
+        executor: Executor,
+        snapshot: &Snapshot,
+        options: RestoreOptions,
+    ) -> Result<Machine, FirepilotError> {
+        // A snapshot can only be loaded through the API
+        let mut executor = executor.with_boot_mode(BootMode::Api);
+        executor.create_workspace().for_vm(executor.id())?;
+        executor.run_socket().await.for_vm(executor.id())?;
+        executor.negotiate_version().await.for_vm(executor.id())?;
+        if let (Some(created), Some(running)) = (snapshot.vmm_version, executor.vmm_version()) {
+            if created != running {
+                warn!(
+                    "Snapshot was created by firecracker {} but is loaded by {}",
+                    created, running
+                );
+            }
+        }
+        info!("Restore microVM from snapshot");
+        let lifecycle = match options.resume_vm {
+            true => MachineState::Running,
+            false => MachineState::Paused,
+        };
+        executor
+            .load_snapshot(snapshot.load_params(options))
+            .await
+            .for_vm(executor.id())?;
+        let mut machine = Machine {
+            executor,
+            vsock_uds: None,
+            thin_devices: Vec::new(),
+            overlay_devices: Vec::new(),
+            staged_copies: Vec::new(),
+            copy_strategy: CopyStrategy::default(),
+            tap_devices: Vec::new(),
+            nat_rules: Vec::new(),
+            allocated_ip: None,
+            applied: None,
+            metrics_path: None,
+            metrics_reader: None,
+            lifecycle: Arc::new(Mutex::new(lifecycle)),
+            events: broadcast::channel(MACHINE_EVENTS_CAPACITY).0,
+            exit_expected: Arc::new(AtomicBool::new(false)),
+        };
+        machine.monitor_exit();
+        Ok(machine)
+    }
+
+    /// Shutdown the socket process without asking the guest, if the VM was
+    /// running it will stop it, see [Executor::destroy_socket]
     pub async fn kill(&mut self) -> Result<(), FirepilotError> {
-        self.executor.destroy_socket().await?;
+        use MachineState::*;
+        self.require("kill", &[Configured, Running, Paused, Failed, Crashed])?;
+        self.exit_expected.store(true, Ordering::SeqCst);
+        // A machine which failed to be created, or which boots from a
+        // configuration file and isn't started, may have no process
+        if self.executor.is_running() {
+            self.executor.destroy_socket().await.for_vm(self.vm_id())?;
+        }
+        self.release_host_devices().await?;
+        self.set_lifecycle(Stopped);
+        self.emit(MachineEvent::Killed);
         Ok(())
     }
 
-    /// Send a InstanceStart signal to the VM
-    pub async fn start(&self) -> Result<(), FirepilotError> {
-        self.executor.send_action(Action::InstanceStart).await?;
+    /// Kill the microVM if it still runs and remove everything it used: the
+    /// devices created on the host, its leased address and its workspace,
+    /// see [Executor::delete_workspace]
+    ///
+    /// With `keep_logs`, the console and audit logs stay in the workspace.
+    pub async fn purge(&mut self, keep_logs: bool) -> Result<(), FirepilotError> {
+        use MachineState::*;
+        self.require(
+            "purge",
+            &[Configured, Running, Paused, Stopped, Failed, Crashed],
+        )?;
+        match self.executor.is_running() {
+            true => self.kill().await?,
+            false => self.release_host_devices().await?,
+        }
+        self.remove_staged_copies()?;
+        self.executor
+            .delete_workspace(keep_logs)
+            .for_vm(self.vm_id())?;
+        self.allocated_ip = None;
+        self.metrics_path = None;
+        self.metrics_reader = None;
+        self.set_lifecycle(Stopped);
         Ok(())
     }
 
+    /// Remove the devices created on the host for the microVM
+    ///
+    /// Every device is removed even if some fail, the ones which failed are
+    /// kept so a later [Machine::kill] or [Machine::purge] tries again, and
+    /// the first error is returned.
+    async fn release_host_devices(&mut self) -> Result<(), FirepilotError> {
+        let mut errors = Vec::new();
+        for device in std::mem::take(&mut self.thin_devices) {
+            if let Err(e) = device.remove().await.for_vm(self.vm_id()) {
+                errors.push(e);
+                self.thin_devices.push(device);
+            }
+        }
+        for device in std::mem::take(&mut self.overlay_devices) {
+            if let Err(e) = device.remove().await.for_vm(self.vm_id()) {
+                errors.push(e);
+                self.overlay_devices.push(device);
+            }
+        }
+        for mut rules in std::mem::take(&mut self.nat_rules) {
+            if let Err(e) = rules.remove().await.for_vm(self.vm_id()) {
+                errors.push(e);
+                self.nat_rules.push(rules);
+            }
+        }
+        for device in std::mem::take(&mut self.tap_devices) {
+            if let Err(e) = device.remove().await.for_vm(self.vm_id()) {
+                errors.push(e);
+                self.tap_devices.push(device);
+            }
+        }
+        first_of(errors)
+    }
+
+    /// Remove the copies of the drives staged outside of the workspace, along
+    /// with the directory of the machine when it is left empty
+    ///
+    /// As for the devices, the copies which can't be removed are kept to be
+    /// tried again.
+    fn remove_staged_copies(&mut self) -> Result<(), FirepilotError> {
+        let mut errors = Vec::new();
+        for path in std::mem::take(&mut self.staged_copies) {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    if let Some(parent) = path.parent() {
+                        // Other copies of the machine may still be there
+                        let _ = std::fs::remove_dir(parent);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    errors.push(
+                        FirepilotError::setup(
+                            self.vm_id(),
+                            format!("Failed to remove staged drive {:?}", path),
+                        )
+                        .with_source(e),
+                    );
+                    self.staged_copies.push(path);
+                }
+            }
+        }
+        first_of(errors)
+    }
+
+    /// Id of the machine, which is the id of its executor
+    pub fn vm_id(&self) -> &str {
+        self.executor.id()
+    }
+
+    /// Pid of the firecracker process while it runs, see [Executor::pid]
+    pub fn pid(&self) -> Option<u32> {
+        self.executor.pid()
+    }
+
+    /// Where the machine is in its lifecycle, as tracked by firepilot without
+    /// asking firecracker
+    pub fn lifecycle(&self) -> MachineState {
+        *self.lifecycle.lock().unwrap()
+    }
+
+    fn set_lifecycle(&self, state: MachineState) {
+        debug!("Machine is now {:?}", state);
+        *self.lifecycle.lock().unwrap() = state;
+    }
+
+    /// Fail with [FirepilotError::InvalidTransition] unless the machine is in
+    /// one of the given states
+    fn require(
+        &self,
+        action: &'static str,
+        allowed: &[MachineState],
+    ) -> Result<(), FirepilotError> {
+        let state = self.lifecycle();
+        match allowed.contains(&state) {
+            true => Ok(()),
+            false => Err(FirepilotError::InvalidTransition {
+                vm_id: self.vm_id().to_string(),
+                action,
+                state,
+            }),
+        }
+    }
+
+    /// Fail with [FirepilotError::InvalidTransition] while firecracker isn't
+    /// spawned yet, which is the case of a [MachineState::Configured] machine
+    /// booting from a configuration file, see [BootMode]
+    fn require_process(&self, action: &'static str) -> Result<(), FirepilotError> {
+        let state = self.lifecycle();
+        match self.executor.boot_mode() != BootMode::Api && state == MachineState::Configured {
+            true => Err(FirepilotError::InvalidTransition {
+                vm_id: self.vm_id().to_string(),
+                action,
+                state,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Move to the state reached by an operation, or to
+    /// [MachineState::Failed] when it failed
+    fn settle<T>(
+        &self,
+        result: Result<T, FirepilotError>,
+        reached: MachineState,
+    ) -> Result<T, FirepilotError> {
+        match &result {
+            Ok(_) => self.set_lifecycle(reached),
+            Err(e) => {
+                self.set_lifecycle(MachineState::Failed);
+                self.emit(MachineEvent::Errored(format!("{:?}", e)));
+            }
+        }
+        result
+    }
+
+    /// Watch the process of firecracker in the background, so the machine
+    /// becomes [MachineState::Crashed] as soon as it exits unexpectedly. An
+    /// exit with a success status without being asked for, e.g. when the
+    /// guest shut itself down, only stops the machine.
+    fn monitor_exit(&mut self) {
+        let mut exit = match self.executor.subscribe_exit() {
+            Some(exit) => exit,
+            None => return,
+        };
+        // A new flag, the monitor of a previous process keeps its own
+        self.exit_expected = Arc::new(AtomicBool::new(false));
+        let expected = self.exit_expected.clone();
+        let lifecycle = self.lifecycle.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let status = loop {
+                let status = *exit.borrow();
+                if let Some(status) = status {
+                    break status;
+                }
+                if exit.changed().await.is_err() {
+                    return;
+                }
+            };
+            if expected.load(Ordering::SeqCst) {
+                return;
+            }
+            let event = {
+                let mut state = lifecycle.lock().unwrap();
+                if matches!(*state, MachineState::Stopped | MachineState::Failed) {
+                    return;
+                }
+                match status.success() {
+                    true => {
+                        *state = MachineState::Stopped;
+                        MachineEvent::GuestExited(Some(status))
+                    }
+                    false => {
+                        warn!("firecracker exited unexpectedly with {}", status);
+                        *state = MachineState::Crashed;
+                        MachineEvent::Crashed(status)
+                    }
+                }
+            };
+            let _ = events.send(event);
+        });
+    }
+
+    /// Publish an event to the subscribers, see [Machine::events]
+    fn emit(&self, event: MachineEvent) {
+        // No receiver is not an error, nobody is interested yet
+        let _ = self.events.send(event);
+    }
+
+    /// Subscribe to the lifecycle events of the machine, see [crate::events]
+    pub fn events(&self) -> broadcast::Receiver<MachineEvent> {
+        self.events.subscribe()
+    }
+
+    /// State of the microVM as reported by firecracker, unlike
+    /// [Executor::is_running] which only tells whether the process is alive
+    ///
+    /// Fails when the VMM doesn't answer, e.g. it exited or was never spawned.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # async fn example(machine: firepilot::machine::Machine) {
+    /// use firepilot::machine::InstanceState;
+    ///
+    /// if machine.state().await.unwrap() == InstanceState::Paused {
+    ///     machine.resume().await.unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub async fn state(&self) -> Result<InstanceState, FirepilotError> {
+        Ok(self
+            .executor
+            .describe_instance()
+            .await
+            .for_vm(self.vm_id())?
+            .state)
+    }
+
+    /// Configuration applied by firecracker, see [Executor::describe_vm_config]
+    pub async fn applied_config(&self) -> Result<FullVmConfiguration, FirepilotError> {
+        self.executor
+            .describe_vm_config()
+            .await
+            .for_vm(self.vm_id())
+    }
+
+    /// Send a InstanceStart signal to the VM, and wait for firecracker to
+    /// report it as running
+    ///
+    /// If the VMM stops answering (e.g. it exited because the guest panicked)
+    /// or the instance isn't running within a short delay,
+    /// [FirepilotError::StartFailed] is returned with the last known state.
+    ///
+    /// When firecracker boots from a configuration file, see [BootMode], it
+    /// is spawned instead. Without its API, the machine is running as soon as
+    /// the process accepted its configuration.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn start(&mut self) -> Result<(), FirepilotError> {
+        self.require("start", &[MachineState::Configured])?;
+        let result = match self.executor.boot_mode() {
+            BootMode::Api => self.confirm_start().await,
+            _ => self.boot_from_config_file().await,
+        };
+        self.settle(result, MachineState::Running)?;
+        self.emit(MachineEvent::Started);
+        Ok(())
+    }
+
+    async fn boot_from_config_file(&mut self) -> Result<(), FirepilotError> {
+        self.executor.run_socket().await.for_vm(self.vm_id())?;
+        self.monitor_exit();
+        if self.executor.boot_mode() == BootMode::ConfigFileNoApi {
+            return Ok(());
+        }
+        self.executor
+            .negotiate_version()
+            .await
+            .for_vm(self.vm_id())?;
+        self.emit(MachineEvent::SocketReady);
+        self.wait_running().await
+    }
+
+    async fn confirm_start(&self) -> Result<(), FirepilotError> {
+        self.executor
+            .send_action(Action::InstanceStart)
+            .await
+            .for_vm(self.vm_id())?;
+        self.wait_running().await
+    }
+
+    async fn wait_running(&self) -> Result<(), FirepilotError> {
+        let started = Instant::now();
+        loop {
+            let state = match self.executor.describe_instance().await {
+                Ok(info) => info.state,
+                Err(e) => {
+                    return Err(FirepilotError::StartFailed {
+                        vm_id: self.vm_id().to_string(),
+                        message: format!("VMM stopped answering after InstanceStart: {}", e),
+                        source: Some(Box::new(e)),
+                    })
+                }
+            };
+            if state == InstanceState::Running {
+                debug!("Instance is running");
+                return Ok(());
+            }
+            if started.elapsed() >= START_CONFIRM_TIMEOUT {
+                return Err(FirepilotError::StartFailed {
+                    vm_id: self.vm_id().to_string(),
+                    message: format!(
+                        "Instance is still {:?} {:?} after InstanceStart",
+                        state, START_CONFIRM_TIMEOUT
+                    ),
+                    source: None,
+                });
+            }
+            sleep(START_POLL_INTERVAL).await;
+        }
+    }
+
     /// Send a CtrlAltDel signal so it will shutdown gracefully
+    ///
+    /// When called right after boot, the guest may not be able to receive it
+    /// yet: it is retried with backoff for a few seconds before giving up with
+    /// [FirepilotError::GuestNotReady].
     pub async fn stop(&self) -> Result<(), FirepilotError> {
-        self.executor.send_action(Action::SendCtrlAltDel).await?;
+        self.require("stop", &[MachineState::Running])?;
+        let mut backoff = CTRL_ALT_DEL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            match self.executor.send_action(Action::SendCtrlAltDel).await {
+                Err(ExecuteError::GuestNotReady(reason)) if retries < CTRL_ALT_DEL_RETRIES => {
+                    debug!("{}, retrying in {:?}", reason, backoff);
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    retries += 1;
+                }
+                result => {
+                    result.for_vm(self.vm_id())?;
+                    self.exit_expected.store(true, Ordering::SeqCst);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Send CtrlAltDel and wait for at most `max_wait` for firecracker to
+    /// exit, its socket is removed once it did
+    ///
+    /// The guest must be booted with `reboot=k` so it exits instead of
+    /// rebooting, see [ShutdownPolicy] to kill it when it doesn't stop.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn stop_and_wait(&mut self, max_wait: Duration) -> Result<(), FirepilotError> {
+        let started = Instant::now();
+        self.stop().await?;
+        let remaining = max_wait.saturating_sub(started.elapsed());
+        match self
+            .executor
+            .wait_exit(remaining)
+            .await
+            .for_vm(self.vm_id())?
+        {
+            true => {
+                self.set_lifecycle(MachineState::Stopped);
+                self.emit(MachineEvent::GuestExited(None));
+                Ok(())
+            }
+            false => Err(FirepilotError::timeout(
+                self.vm_id(),
+                format!("Machine didn't stop within {:?}", max_wait),
+            )),
+        }
+    }
+
+    /// Wait until firecracker exits, e.g. once the guest shut down, and return
+    /// its exit status. Its socket is removed once it did.
+    ///
+    /// The status is `None` for a machine which isn't running or whose process
+    /// was adopted, as only the parent of a process gets its status.
+    ///
+    /// ```no_run
+    /// # async fn example(mut machine: firepilot::machine::Machine) {
+    /// machine.start().await.unwrap();
+    /// let status = machine.wait().await.unwrap();
+    /// println!("Guest exited with {:?}", status);
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn wait(&mut self) -> Result<Option<ExitStatus>, FirepilotError> {
+        use MachineState::*;
+        self.require(
+            "wait",
+            &[Configured, Running, Paused, Stopped, Failed, Crashed],
+        )?;
+        let status = self.executor.wait().await.for_vm(self.vm_id())?;
+        // Already published when it stopped or crashed
+        if !matches!(self.lifecycle(), Stopped | Crashed) {
+            self.set_lifecycle(Stopped);
+            self.emit(MachineEvent::GuestExited(status));
+        }
+        Ok(status)
+    }
+
+    /// Stop the microVM by running the steps of the given policy in order,
+    /// until one of them succeeds. Returns the step which stopped the microVM.
+    ///
+    /// A step fails when it returns an error or when firecracker is still
+    /// running once its timeout elapsed, the next step is then tried.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all))]
+    pub async fn shutdown(
+        &mut self,
+        policy: &ShutdownPolicy,
+    ) -> Result<ShutdownStep, FirepilotError> {
+        use MachineState::*;
+        self.require("shut down", &[Configured, Running, Paused])?;
+        for step in &policy.steps {
+            info!("Trying to shut down the machine with step {}", step.name());
+            match self.shutdown_step(step).await {
+                Ok(true) => return Ok(step.clone()),
+                Ok(false) => warn!("Machine still running after step {}", step.name()),
+                Err(e) => warn!("Shutdown step {} failed: {:?}", step.name(), e),
+            }
+        }
+        Err(FirepilotError::timeout(
+            self.vm_id(),
+            "Machine is still running after all shutdown steps",
+        ))
+    }
+
+    /// Run a single shutdown step, and tell whether the microVM is stopped
+    async fn shutdown_step(&mut self, step: &ShutdownStep) -> Result<bool, FirepilotError> {
+        match step {
+            ShutdownStep::Agent { port, timeout } => {
+                let mut stream = self.vsock_connect(*port).await?;
+                stream
+                    .write_all(crate::shutdown::AGENT_SHUTDOWN_COMMAND)
+                    .await
+                    .map_err(vsock::VsockError::from)
+                    .for_vm(self.vm_id())?;
+                self.exit_expected.store(true, Ordering::SeqCst);
+                let stopped = self
+                    .executor
+                    .wait_exit(*timeout)
+                    .await
+                    .for_vm(self.vm_id())?;
+                if stopped {
+                    self.set_lifecycle(MachineState::Stopped);
+                    self.emit(MachineEvent::GuestExited(None));
+                }
+                Ok(stopped)
+            }
+            ShutdownStep::CtrlAltDel { timeout } => match self.stop_and_wait(*timeout).await {
+                Err(FirepilotError::Timeout { .. }) => Ok(false),
+                result => result.map(|_| true),
+            },
+            ShutdownStep::Snapshot {
+                snapshot_path,
+                mem_file_path,
+                timeout: max_wait,
+            } => {
+                let snapshot = Snapshot {
+                    snapshot_path: snapshot_path.clone(),
+                    mem_file_path: mem_file_path.clone(),
+                    snapshot_type: SnapshotType::Full,
+                    vmm_version: None,
+                };
+                let snapshot = self.create_snapshot(snapshot);
+                timeout(*max_wait, snapshot).await.map_err(|_| {
+                    FirepilotError::timeout(
+                        self.vm_id(),
+                        format!("Snapshot not created within {:?}", max_wait),
+                    )
+                })??;
+                self.kill().await?;
+                Ok(true)
+            }
+            ShutdownStep::Kill => {
+                self.kill().await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Pause the VM and save a snapshot of it in the given directory, see
+    /// [crate::snapshot]. The VM is left paused, call [Machine::resume] to
+    /// keep it running.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # async fn example(machine: firepilot::machine::Machine) {
+    /// use firepilot::snapshot::SnapshotType;
+    ///
+    /// let snapshot = machine
+    ///     .snapshot("/srv/snapshots/web", SnapshotType::Full)
+    ///     .await
+    ///     .unwrap();
+    /// println!("Memory saved in {}", snapshot.mem_file_path.display());
+    /// machine.resume().await.unwrap();
+    /// # }
+    /// ```
+    pub async fn snapshot<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        snapshot_type: SnapshotType,
+    ) -> Result<Snapshot, FirepilotError> {
+        let dir = dir.as_ref();
+        create_dir_all(dir).map_err(|e| {
+            FirepilotError::setup(self.vm_id(), format!("Failed to create {:?}", dir))
+                .with_source(e)
+        })?;
+        self.create_snapshot(Snapshot::in_dir(dir, snapshot_type))
+            .await
+    }
+
+    async fn create_snapshot(&self, mut snapshot: Snapshot) -> Result<Snapshot, FirepilotError> {
+        if self.lifecycle() != MachineState::Paused {
+            self.pause().await?;
+        }
+        self.executor
+            .api()
+            .create_snapshot(&snapshot.create_params())
+            .await
+            .for_vm(self.vm_id())?;
+        snapshot.vmm_version = self.executor.vmm_version();
+        info!("Snapshot saved to {:?}", snapshot.snapshot_path);
+        Ok(snapshot)
+    }
+
+    /// Add a drive to the machine between [Machine::create] and
+    /// [Machine::start]
+    ///
+    /// The drive is staged like the drives given at creation, with its own
+    /// staging options and the copy strategy of the machine, see
+    /// [DriveBuilder::try_build_staged](crate::builder::drive::DriveBuilder::try_build_staged).
+    #[cfg_attr(
+        feature = "instrument",
+        tracing::instrument(skip(self, staged), fields(drive_id = %staged.drive.drive_id))
+    )]
+    pub async fn attach_drive(&mut self, staged: StagedDrive) -> Result<(), FirepilotError> {
+        self.require("attach a drive", &[MachineState::Configured])?;
+        self.require_process("attach a drive")?;
+        let mut drive = staged.drive;
+        if let Some(job) = self.prepare_drive(&mut drive, &staged.staging).await? {
+            let artifact = staging::stage_all(vec![job], None)
+                .await
+                .map_err(|e| {
+                    FirepilotError::setup(
+                        self.vm_id(),
+                        format!("Failed to stage drive {}", drive.drive_id),
+                    )
+                    .with_source(e)
+                })?
+                .remove(0);
+            self.apply_staged(&mut drive, artifact);
+        }
+        self.executor
+            .configure_drive(drive.clone())
+            .await
+            .for_vm(self.vm_id())?;
+        if let Some(applied) = &mut self.applied {
+            applied.drives.push(drive);
+        }
+        self.save_state();
+        Ok(())
+    }
+
+    /// Add a network interface to the machine between [Machine::create] and
+    /// [Machine::start], its host device must already exist
+    #[cfg_attr(
+        feature = "instrument",
+        tracing::instrument(skip(self, iface), fields(iface_id = %iface.iface_id))
+    )]
+    pub async fn attach_interface(
+        &mut self,
+        iface: NetworkInterface,
+    ) -> Result<(), FirepilotError> {
+        self.require("attach a network interface", &[MachineState::Configured])?;
+        self.require_process("attach a network interface")?;
+        self.executor
+            .configure_network(vec![iface.clone()])
+            .await
+            .for_vm(self.vm_id())?;
+        if let Some(applied) = &mut self.applied {
+            applied.interfaces.push(iface);
+        }
+        self.save_state();
+        Ok(())
+    }
+
+    /// Swap the backing file of a drive, or change its rate limiter, while
+    /// the VM is running
+    ///
+    /// The new file is used in place, it isn't copied in the workspace like
+    /// the drives given at creation. The guest must not be using the drive
+    /// when its file is swapped, e.g. it should be unmounted first.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # async fn example(machine: firepilot::machine::Machine) {
+    /// use firepilot::builder::Builder;
+    /// use firepilot::builder::rate_limiter::RateLimiterBuilder;
+    ///
+    /// let limiter = RateLimiterBuilder::new()
+    ///     .with_bandwidth("10MB/s")
+    ///     .try_build()
+    ///     .unwrap();
+    /// machine
+    ///     .update_drive::<&str>("data", None, Some(limiter))
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(
+        feature = "instrument",
+        tracing::instrument(skip(self, path_on_host, rate_limiter))
+    )]
+    pub async fn update_drive<P: AsRef<Path>>(
+        &self,
+        drive_id: &str,
+        path_on_host: Option<P>,
+        rate_limiter: Option<RateLimiter>,
+    ) -> Result<(), FirepilotError> {
+        self.require(
+            "update a drive",
+            &[MachineState::Running, MachineState::Paused],
+        )?;
+        if path_on_host.is_none() && rate_limiter.is_none() {
+            return Err(FirepilotError::configure(
+                self.vm_id(),
+                format!("Nothing to update on drive {}", drive_id),
+            ));
+        }
+        let path_on_host = match path_on_host {
+            Some(path) => {
+                let path = path.as_ref();
+                if !path.exists() {
+                    return Err(FirepilotError::setup(
+                        self.vm_id(),
+                        format!("Drive file {:?} doesn't exist", path),
+                    ));
+                }
+                Some(path.to_string_lossy().into_owned())
+            }
+            None => None,
+        };
+        self.executor
+            .update_drive(PartialDrive {
+                path_on_host,
+                rate_limiter: rate_limiter.map(Box::new),
+                ..PartialDrive::new(drive_id.to_string())
+            })
+            .await
+            .for_vm(self.vm_id())?;
+        Ok(())
+    }
+
+    /// Change the rate limiters of a network interface while the VM is
+    /// running, e.g. to throttle a noisy guest
+    ///
+    /// A direction given as `None` keeps its current limiter, a limiter
+    /// without bucket removes the limit in that direction.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, rx, tx)))]
+    pub async fn update_network_rate_limiters(
+        &self,
+        iface_id: &str,
+        rx: Option<RateLimiter>,
+        tx: Option<RateLimiter>,
+    ) -> Result<(), FirepilotError> {
+        self.require(
+            "update the rate limiters of an interface",
+            &[MachineState::Running, MachineState::Paused],
+        )?;
+        self.executor
+            .update_network_interface(PartialNetworkInterface {
+                rx_rate_limiter: rx.map(Box::new),
+                tx_rate_limiter: tx.map(Box::new),
+                ..PartialNetworkInterface::new(iface_id.to_string())
+            })
+            .await
+            .for_vm(self.vm_id())?;
+        Ok(())
+    }
+
+    /// Latest statistics of the balloon device, they are only available when
+    /// a polling interval was set, see
+    /// [BalloonBuilder::with_stats_polling_interval_s](crate::builder::balloon::BalloonBuilder::with_stats_polling_interval_s)
+    pub async fn balloon_stats(&self) -> Result<BalloonStats, FirepilotError> {
+        self.require(
+            "read the balloon statistics",
+            &[MachineState::Running, MachineState::Paused],
+        )?;
+        self.executor.balloon_stats().await.for_vm(self.vm_id())
+    }
+
+    /// Inflate or deflate the balloon to the given size, to reclaim memory
+    /// from the guest or give it back
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # async fn example(machine: firepilot::machine::Machine) {
+    /// let stats = machine.balloon_stats().await.unwrap();
+    /// if stats.available_memory.unwrap_or(0) > 512 << 20 {
+    ///     machine.resize_balloon(stats.target_mib + 256).await.unwrap();
+    /// }
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn resize_balloon(&self, amount_mib: i32) -> Result<(), FirepilotError> {
+        self.require(
+            "resize the balloon",
+            &[MachineState::Running, MachineState::Paused],
+        )?;
+        if amount_mib < 0 {
+            return Err(FirepilotError::configure(
+                self.vm_id(),
+                format!("Balloon size can't be negative, got {}", amount_mib),
+            ));
+        }
+        self.executor
+            .resize_balloon(amount_mib)
+            .await
+            .for_vm(self.vm_id())?;
         Ok(())
     }
 
-    /// Pause a running VM
+    /// Pause a running VM, e.g. to freeze an idle one or before taking a
+    /// snapshot
     pub async fn pause(&self) -> Result<(), FirepilotError> {
-        self.executor.set_vm_state(Vm::new(State::Paused)).await?;
+        self.require("pause", &[MachineState::Running])?;
+        self.executor.pause().await.for_vm(self.vm_id())?;
+        self.set_lifecycle(MachineState::Paused);
         Ok(())
     }
 
     /// Resume a paused VM
     pub async fn resume(&self) -> Result<(), FirepilotError> {
-        self.executor.set_vm_state(Vm::new(State::Resumed)).await?;
+        self.require("resume", &[MachineState::Paused])?;
+        self.executor.resume().await.for_vm(self.vm_id())?;
+        self.set_lifecycle(MachineState::Running);
         Ok(())
     }
+
+    /// Publish a new readiness token in the MMDS data store and return it
+    ///
+    /// The token is available to the guest at `/firepilot/ready_token` on the
+    /// MMDS address. Once the guest userspace is up, it must acknowledge it by
+    /// having `/firepilot/ready` set to the same token. The guest can't write
+    /// into MMDS by itself, so the acknowledgement is relayed by an agent or
+    /// any host-side component which patches the data store.
+    ///
+    /// MMDS must be configured on the microVM for this to work.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn publish_ready_token(&self) -> Result<String, FirepilotError> {
+        let token = uuid::Uuid::new_v4().to_string();
+        debug!("Publish readiness token {}", token);
+        self.patch_metadata(&json!({ READINESS_MMDS_KEY: { "ready_token": token } }))
+            .await?;
+        Ok(token)
+    }
+
+    /// Replace the whole content of the MMDS data store, MMDS must be
+    /// configured on the microVM, see [MmdsBuilder](crate::builder::mmds::MmdsBuilder)
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # async fn example(machine: firepilot::machine::Machine) {
+    /// use serde_json::json;
+    ///
+    /// machine
+    ///     .put_metadata(&json!({ "instance": { "hostname": "web-1" } }))
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all))]
+    pub async fn put_metadata(&self, metadata: &serde_json::Value) -> Result<(), FirepilotError> {
+        self.require_process("put metadata")?;
+        self.executor
+            .api()
+            .put_mmds(metadata)
+            .await
+            .for_vm(self.vm_id())?;
+        Ok(())
+    }
+
+    /// Merge the given JSON into the MMDS data store, keys set to `null` are
+    /// removed
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip_all))]
+    pub async fn patch_metadata(&self, metadata: &serde_json::Value) -> Result<(), FirepilotError> {
+        self.require_process("patch metadata")?;
+        self.executor
+            .api()
+            .patch_mmds(metadata)
+            .await
+            .for_vm(self.vm_id())?;
+        Ok(())
+    }
+
+    /// Leave firecracker running when the machine is dropped, e.g. for a
+    /// microVM which must outlive this process. It can be taken over later
+    /// with [Executor::adopt].
+    ///
+    /// A machine dropped without being detached kills firecracker and removes
+    /// its socket and workspace, see [Executor::with_kept_workspace]. The
+    /// devices created on the host, such as TAP devices, are only removed by
+    /// [Machine::kill].
+    pub fn detach(&mut self) {
+        self.executor.detach();
+    }
+
+    /// Address leased to the guest from the pool given to
+    /// [Configuration::with_auto_ip]
+    pub fn allocated_ip(&self) -> Option<Ipv4Addr> {
+        self.allocated_ip
+    }
+
+    /// Address of the guest, polled until `max_wait` is elapsed
+    ///
+    /// It is the one leased from a pool when there is one, see
+    /// [Configuration::with_auto_ip]. Otherwise it is looked up in the neighbor
+    /// table for the devices of the network interfaces, which requires the
+    /// guest to have sent some traffic, or read from `/firepilot/guest_ip` in
+    /// MMDS, where an agent can publish it.
+    ///
+    /// When firecracker runs in a network namespace, the neighbor table of the
+    /// namespace is read through
+    /// [NSENTER_BINARY](crate::network::netns::NSENTER_BINARY), which requires
+    /// `CAP_SYS_ADMIN`. If it can't be read, only MMDS is polled.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn guest_ip(&self, max_wait: Duration) -> Result<IpAddr, FirepilotError> {
+        if let Some(address) = self.allocated_ip {
+            return Ok(IpAddr::V4(address));
+        }
+        let links: Vec<GuestLink> = self
+            .applied_config()
+            .await?
+            .network_interfaces
+            .unwrap_or_default()
+            .into_iter()
+            .map(|iface| GuestLink {
+                host_dev_name: iface.host_dev_name,
+                guest_mac: iface.guest_mac,
+            })
+            .collect();
+        let netns = self.executor.netns();
+        let poll = async {
+            let mut read_table = true;
+            loop {
+                if read_table {
+                    match read_neighbors(netns.as_deref()).await {
+                        Ok(table) => {
+                            if let Some(address) = find_neighbor(&table, &links) {
+                                return IpAddr::V4(address);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Neighbor table unreadable, only polling MMDS: {}", e);
+                            read_table = false;
+                        }
+                    }
+                }
+                // MMDS may not be configured, it is only one of the sources
+                if let Ok(content) = self.executor.api().get_mmds().await {
+                    let published = content[READINESS_MMDS_KEY]["guest_ip"].as_str();
+                    if let Some(address) = published.and_then(|a| a.parse().ok()) {
+                        return address;
+                    }
+                }
+                sleep(READINESS_POLL_INTERVAL).await;
+            }
+        };
+        let address = timeout(max_wait, poll).await.map_err(|_| {
+            FirepilotError::timeout(
+                self.vm_id(),
+                format!("Address of the guest not found within {:?}", max_wait),
+            )
+        })?;
+        debug!("Guest address is {}", address);
+        Ok(address)
+    }
+
+    /// Path of the FIFO, or file, where firecracker writes its metrics, see
+    /// [MetricsBuilder](crate::builder::metrics::MetricsBuilder)
+    pub fn metrics_path(&self) -> Option<&Path> {
+        self.metrics_path.as_deref()
+    }
+
+    /// Metrics flushed by firecracker since the previous call, see
+    /// [crate::metrics]
+    ///
+    /// Firecracker flushes its metrics every minute, the list is empty until
+    /// the first flush.
+    pub fn read_metrics(&mut self) -> Result<Vec<FirecrackerMetrics>, FirepilotError> {
+        let reader = match &mut self.metrics_reader {
+            Some(reader) => reader,
+            None => {
+                let path = self.metrics_path.as_ref().ok_or_else(|| {
+                    FirepilotError::configure(
+                        self.executor.id(),
+                        "Metrics aren't configured, see Configuration::with_metrics",
+                    )
+                })?;
+                let reader = MetricsReader::open(path).for_vm(self.executor.id())?;
+                self.metrics_reader.insert(reader)
+            }
+        };
+        reader.read().for_vm(self.executor.id())
+    }
+
+    /// Path of the log of the serial console, when the executor keeps it,
+    /// see [Executor::with_console_log]
+    pub fn console_log_path(&self) -> Option<PathBuf> {
+        self.executor.console_log_path()
+    }
+
+    /// Subscribe to the boot milestones read on the serial console of the
+    /// guest, see [crate::boot]. Subscribe before [Machine::start] to receive
+    /// all of them.
+    pub fn boot_events(&self) -> tokio::sync::broadcast::Receiver<BootEvent> {
+        self.executor.boot_events()
+    }
+
+    fn vsock_uds(&self) -> Result<&Path, FirepilotError> {
+        self.vsock_uds.as_deref().ok_or_else(|| {
+            FirepilotError::configure(self.vm_id(), "No vsock device is configured on the machine")
+        })
+    }
+
+    /// Open a stream to a service listening on the given guest vsock port, the
+    /// machine must have a vsock device configured
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # async fn example(machine: firepilot::machine::Machine) {
+    /// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    ///
+    /// let mut stream = machine.vsock_connect(52).await.unwrap();
+    /// stream.write_all(b"ping\n").await.unwrap();
+    /// let mut answer = String::new();
+    /// stream.read_to_string(&mut answer).await.unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn vsock_connect(&self, port: u32) -> Result<vsock::VsockStream, FirepilotError> {
+        vsock::connect(self.vsock_uds()?, port)
+            .await
+            .for_vm(self.vm_id())
+    }
+
+    /// Copy a local file into the guest at `remote`, returning the number of
+    /// bytes sent
+    ///
+    /// The transfer is chunked and checksummed, it requires a vsock device and
+    /// a guest service implementing the protocol described in [vsock].
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, local)))]
+    pub async fn push_file<P: AsRef<Path>>(
+        &self,
+        local: P,
+        remote: &str,
+    ) -> Result<u64, FirepilotError> {
+        let stream = vsock::connect(self.vsock_uds()?, vsock::FILE_TRANSFER_PORT)
+            .await
+            .for_vm(self.vm_id())?;
+        vsock::push_file(stream, local.as_ref(), remote)
+            .await
+            .for_vm(self.vm_id())
+    }
+
+    /// Copy the guest file `remote` to `local`, returning the number of bytes
+    /// received. The local file is only replaced once the checksum matched.
+    ///
+    /// See [Machine::push_file] for the requirements.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, local)))]
+    pub async fn pull_file<P: AsRef<Path>>(
+        &self,
+        remote: &str,
+        local: P,
+    ) -> Result<u64, FirepilotError> {
+        let stream = vsock::connect(self.vsock_uds()?, vsock::FILE_TRANSFER_PORT)
+            .await
+            .for_vm(self.vm_id())?;
+        vsock::pull_file(stream, remote, local.as_ref())
+            .await
+            .for_vm(self.vm_id())
+    }
+
+    /// Wait until the guest acknowledged the readiness token given by
+    /// [Machine::publish_ready_token], polling the MMDS data store until
+    /// `max_wait` is elapsed.
+    ///
+    /// This gives a network-free and image-agnostic signal that the guest
+    /// userspace is up.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, token)))]
+    pub async fn wait_ready(&self, token: &str, max_wait: Duration) -> Result<(), FirepilotError> {
+        debug!("Waiting for the guest to acknowledge readiness token");
+        let poll = async {
+            loop {
+                let content = self.executor.api().get_mmds().await.for_vm(self.vm_id())?;
+                if content[READINESS_MMDS_KEY]["ready"].as_str() == Some(token) {
+                    info!("Guest is ready");
+                    return Ok::<(), FirepilotError>(());
+                }
+                sleep(READINESS_POLL_INTERVAL).await;
+            }
+        };
+        timeout(max_wait, poll).await.map_err(|_| {
+            FirepilotError::timeout(
+                self.vm_id(),
+                format!(
+                    "Guest didn't acknowledge readiness token within {:?}",
+                    max_wait
+                ),
+            )
+        })?
+    }
+}
+
+/// First of the errors of a cleanup which went on after a failure, the
+/// others are only logged
+fn first_of(errors: Vec<FirepilotError>) -> Result<(), FirepilotError> {
+    let mut errors = errors.into_iter();
+    let first = errors.next();
+    for e in errors {
+        warn!("Cleanup failed: {}", e);
+    }
+    match first {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn write_json<T: serde::Serialize>(
+    vm_id: &str,
+    path: &Path,
+    value: &T,
+) -> Result<(), FirepilotError> {
+    let content = serde_json::to_vec_pretty(value).map_err(|e| {
+        FirepilotError::configure(vm_id, format!("Failed to serialize {:?}", path)).with_source(e)
+    })?;
+    std::fs::write(path, content).map_err(|e| {
+        FirepilotError::setup(vm_id, format!("Failed to write {:?}", path)).with_source(e)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::path::PathBuf;
+
+    use super::{Machine, MachineState};
+    use crate::{
+        api::testing::MockTransport,
+        executor::{Executor, FirecrackerExecutor},
+    };
+
+    /// Machine with the given id which was never created
+    pub(crate) fn idle_machine(id: &str) -> Machine {
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_id(id.to_string());
+        Machine::new(executor).unwrap()
+    }
+
+    /// Running machine with the given id, talking to firecracker through the
+    /// given transport
+    pub(crate) fn running_machine(id: &str, transport: &MockTransport) -> Machine {
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_id(id.to_string())
+        .with_transport(std::sync::Arc::new(transport.clone()));
+        let machine = Machine::new(executor).unwrap();
+        machine.set_lifecycle(MachineState::Running);
+        machine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{
+        api::testing::MockTransport,
+        builder::{
+            drive::DriveBuilder, network_interface::NetworkInterfaceBuilder,
+            rate_limiter::RateLimiterBuilder, Builder,
+        },
+        executor::{Execute, FirecrackerExecutor},
+        network::netns::NetnsExecutor,
+    };
+    use tokio::process::{Child, Command};
+
+    fn machine(transport: &MockTransport) -> Machine {
+        let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp/firepilot".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+        Machine {
+            executor,
+            vsock_uds: None,
+            thin_devices: Vec::new(),
+            overlay_devices: Vec::new(),
+            staged_copies: Vec::new(),
+            copy_strategy: CopyStrategy::default(),
+            tap_devices: Vec::new(),
+            nat_rules: Vec::new(),
+            allocated_ip: None,
+            applied: None,
+            metrics_path: None,
+            metrics_reader: None,
+            lifecycle: Arc::new(Mutex::new(MachineState::Running)),
+            events: broadcast::channel(MACHINE_EVENTS_CAPACITY).0,
+            exit_expected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn test_error_vm_id_and_source() {
+        use std::error::Error;
+
+        let cause = serde_json::from_str::<Vsock>("{").unwrap_err();
+        let result: Result<(), ExecuteError> = Err(ExecuteError::Deserialize(
+            "http://localhost/vsock".parse().unwrap(),
+            cause,
+        ));
+        let err = result.for_vm("web-1").unwrap_err();
+        assert!(matches!(err, FirepilotError::Configure { .. }));
+        assert_eq!(err.vm_id(), "web-1");
+        assert_eq!(
+            err.to_string(),
+            "Machine web-1: Request to the firecracker API failed"
+        );
+        // Configure -> ExecuteError -> serde_json::Error
+        let execute = err.source().unwrap();
+        assert!(execute.downcast_ref::<ExecuteError>().is_some());
+        assert!(execute
+            .source()
+            .unwrap()
+            .downcast_ref::<serde_json::Error>()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        machine.pause().await.unwrap();
+        machine.resume().await.unwrap();
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PATCH);
+        assert_eq!(requests[0].path, "/vm");
+        assert_eq!(requests[0].body, r#"{"state":"Paused"}"#);
+        assert_eq!(requests[1].body, r#"{"state":"Resumed"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = machine
+            .snapshot(dir.path().join("web"), SnapshotType::Diff)
+            .await
+            .unwrap();
+        assert_eq!(snapshot.snapshot_path, dir.path().join("web/vmstate"));
+        assert_eq!(snapshot.snapshot_type, SnapshotType::Diff);
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].path, "/vm");
+        assert_eq!(requests[1].method, hyper::Method::PUT);
+        assert_eq!(requests[1].path, "/snapshot/create");
+        assert!(requests[1].body.contains(r#""snapshot_type":"Diff""#));
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        machine
+            .put_metadata(&json!({ "instance": { "hostname": "web-1" } }))
+            .await
+            .unwrap();
+        machine
+            .patch_metadata(&json!({ "instance": { "hostname": null } }))
+            .await
+            .unwrap();
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PUT);
+        assert_eq!(requests[0].path, "/mmds");
+        assert_eq!(requests[1].method, hyper::Method::PATCH);
+        assert_eq!(requests[1].body, r#"{"instance":{"hostname":null}}"#);
+    }
+
+    #[tokio::test]
+    async fn test_guest_ip() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        transport.respond(
+            StatusCode::OK,
+            r#"{"network-interfaces": [{"iface_id": "eth0", "host_dev_name": "firepilot-none"}]}"#,
+        );
+        transport.respond(StatusCode::BAD_REQUEST, r#"{"fault_message": "No MMDS"}"#);
+        transport.respond(
+            StatusCode::OK,
+            r#"{"firepilot": {"guest_ip": "172.16.0.2"}}"#,
+        );
+        let address = machine.guest_ip(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(address, IpAddr::V4(Ipv4Addr::new(172, 16, 0, 2)));
+
+        machine.allocated_ip = Some(Ipv4Addr::new(172, 16, 0, 3));
+        let address = machine.guest_ip(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(address, IpAddr::V4(Ipv4Addr::new(172, 16, 0, 3)));
+    }
+
+    #[tokio::test]
+    async fn test_guest_ip_in_netns() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine.executor = Executor::new_with_executor(NetnsExecutor {
+            firecracker: FirecrackerExecutor {
+                chroot: "/tmp/firepilot".to_string(),
+                exec_binary: PathBuf::from("/usr/bin/firecracker"),
+            },
+            netns: PathBuf::from("/invalid/netns"),
+        })
+        .with_transport(Arc::new(transport.clone()));
+        transport.respond(
+            StatusCode::OK,
+            r#"{"network-interfaces": [{"iface_id": "eth0", "host_dev_name": "firepilot-none"}]}"#,
+        );
+        // The table of the namespace can't be read, MMDS is polled instead
+        transport.respond(
+            StatusCode::OK,
+            r#"{"firepilot": {"guest_ip": "172.16.0.2"}}"#,
+        );
+        let address = machine.guest_ip(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(address, IpAddr::V4(Ipv4Addr::new(172, 16, 0, 2)));
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready_acknowledged() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        let token = machine.publish_ready_token().await.unwrap();
+
+        transport.respond(StatusCode::OK, r#"{"firepilot": {"ready_token": "x"}}"#);
+        transport.respond(
+            StatusCode::OK,
+            &json!({ "firepilot": { "ready_token": token, "ready": token } }).to_string(),
+        );
+        machine
+            .wait_ready(&token, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PATCH);
+        assert!(requests[0].body.contains(&token));
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_escalates() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        transport.respond(
+            StatusCode::BAD_REQUEST,
+            r#"{"fault_message": "Operation not supported before starting the microVM"}"#,
+        );
+        let policy = ShutdownPolicy::new()
+            .then(ShutdownStep::Agent {
+                port: 52,
+                timeout: Duration::from_secs(1),
+            })
+            .then(ShutdownStep::CtrlAltDel {
+                timeout: Duration::from_secs(1),
+            })
+            .then(ShutdownStep::Snapshot {
+                snapshot_path: PathBuf::from("/tmp/vm.snap"),
+                mem_file_path: PathBuf::from("/tmp/vm.mem"),
+                timeout: Duration::from_secs(1),
+            });
+
+        // No vsock device, CtrlAltDel rejected, the snapshot is taken and
+        // there is no process left to kill afterwards
+        let step = machine.shutdown(&policy).await.unwrap();
+        assert!(matches!(step, ShutdownStep::Snapshot { .. }));
+        assert_eq!(machine.lifecycle(), MachineState::Stopped);
+        let paths: Vec<_> = transport
+            .requests()
+            .into_iter()
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(paths, vec!["/actions", "/vm", "/snapshot/create"]);
+    }
+
+    fn instance(state: &str) -> String {
+        json!({ "app_name": "Firecracker", "id": "vm", "state": state, "vmm_version": "1.3.0" })
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_attach() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        create_dir_all(machine.executor.chroot()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("data.ext4"), [0; 512]).unwrap();
+
+        machine.set_lifecycle(MachineState::Configured);
+        let drive = DriveBuilder::new()
+            .with_drive_id("data".to_string())
+            .with_path_on_host(dir.path().join("data.ext4"))
+            .try_build_staged()
+            .unwrap();
+        machine.attach_drive(drive).await.unwrap();
+        let staged = machine.executor.chroot().join("data");
+        assert!(staged.exists());
+
+        // Attached drives follow their staging options and the copy strategy
+        machine.copy_strategy = CopyStrategy::Symlink;
+        let drive = DriveBuilder::new()
+            .with_drive_id("base".to_string())
+            .with_path_on_host(dir.path().join("data.ext4"))
+            .as_read_only()
+            .try_build_staged()
+            .unwrap();
+        machine.attach_drive(drive).await.unwrap();
+        let base = machine.executor.chroot().join("base");
+        assert!(std::fs::symlink_metadata(&base)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let drive = DriveBuilder::new()
+            .with_drive_id("shared".to_string())
+            .with_path_on_host(dir.path().join("data.ext4"))
+            .without_staging()
+            .try_build_staged()
+            .unwrap();
+        machine.attach_drive(drive).await.unwrap();
+
+        // The workspace can't be escaped with the id of the drive
+        let drive = DriveBuilder::new()
+            .with_drive_id("../escape".to_string())
+            .with_path_on_host(dir.path().join("data.ext4"))
+            .try_build_staged()
+            .unwrap();
+        assert!(matches!(
+            machine.attach_drive(drive).await,
+            Err(FirepilotError::Configure { .. })
+        ));
+
+        machine.set_lifecycle(MachineState::Running);
+        let iface = NetworkInterfaceBuilder::new()
+            .with_iface_id("eth1".to_string())
+            .with_host_dev_name("tap1".to_string())
+            .try_build()
+            .unwrap();
+        assert!(matches!(
+            machine.attach_interface(iface).await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/drives/data");
+        assert!(requests[0].body.contains(&*staged.to_string_lossy()));
+        assert!(requests[1].body.contains(&*base.to_string_lossy()));
+        let source = dir.path().join("data.ext4");
+        assert!(requests[2].body.contains(&*source.to_string_lossy()));
+        std::fs::remove_file(staged).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_metrics() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        assert!(matches!(
+            machine.read_metrics(),
+            Err(FirepilotError::Configure { .. })
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.json");
+        std::fs::write(&path, "{\"seccomp\": {\"num_faults\": 2}}\n").unwrap();
+        machine.metrics_path = Some(path);
+        let metrics = machine.read_metrics().unwrap();
+        assert_eq!(metrics[0].seccomp.num_faults, 2);
+        assert!(machine.read_metrics().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remove_staged_copies() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        let dir = tempfile::tempdir().unwrap();
+        let machine_dir = dir.path().join("vm");
+        create_dir_all(&machine_dir).unwrap();
+        std::fs::write(machine_dir.join("rootfs"), [0; 512]).unwrap();
+        std::fs::write(machine_dir.join("data"), [0; 512]).unwrap();
+
+        machine.staged_copies = vec![machine_dir.join("rootfs"), machine_dir.join("gone")];
+        machine.remove_staged_copies().unwrap();
+        assert!(machine.staged_copies.is_empty());
+        assert!(!machine_dir.join("rootfs").exists());
+        // Another copy is still there
+        assert!(machine_dir.exists());
+
+        machine.staged_copies = vec![machine_dir.join("data")];
+        machine.remove_staged_copies().unwrap();
+        assert!(!machine_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_reboot_requires_creation() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        assert!(matches!(
+            machine.reboot(Duration::from_secs(1)).await,
+            Err(FirepilotError::Configure { .. })
+        ));
+        assert!(transport.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_drive() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        machine.set_lifecycle(MachineState::Configured);
+        assert!(matches!(
+            machine.update_drive("data", Some(file.path()), None).await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        machine.set_lifecycle(MachineState::Running);
+        machine
+            .update_drive("data", Some(file.path()), None)
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PATCH);
+        assert_eq!(requests[0].path, "/drives/data");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["path_on_host"], file.path().to_str().unwrap());
+
+        let missing = machine
+            .update_drive("data", Some("/nonexistent/data.ext4"), None)
+            .await;
+        assert!(matches!(missing, Err(FirepilotError::Setup { .. })));
+        let empty = machine.update_drive::<&str>("data", None, None).await;
+        assert!(matches!(empty, Err(FirepilotError::Configure { .. })));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_network_rate_limiters() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        let limiter = RateLimiterBuilder::new()
+            .with_bandwidth("1MB/s")
+            .try_build()
+            .unwrap();
+        machine.set_lifecycle(MachineState::Configured);
+        assert!(matches!(
+            machine
+                .update_network_rate_limiters("eth0", None, Some(limiter.clone()))
+                .await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        machine.set_lifecycle(MachineState::Paused);
+        machine
+            .update_network_rate_limiters("eth0", None, Some(limiter))
+            .await
+            .unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, hyper::Method::PATCH);
+        assert_eq!(requests[0].path, "/network-interfaces/eth0");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert!(body.get("rx_rate_limiter").is_none());
+        assert_eq!(body["tx_rate_limiter"]["bandwidth"]["size"], 1_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_balloon() {
+        let transport = MockTransport::new();
+        transport.respond(
+            StatusCode::OK,
+            r#"{"target_pages": 65536, "actual_pages": 32768, "target_mib": 256, "actual_mib": 128, "free_memory": 1048576}"#,
+        );
+        let machine = machine(&transport);
+        machine.set_lifecycle(MachineState::Stopped);
+        assert!(matches!(
+            machine.balloon_stats().await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        assert!(matches!(
+            machine.resize_balloon(512).await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        machine.set_lifecycle(MachineState::Running);
+        let stats = machine.balloon_stats().await.unwrap();
+        assert_eq!(stats.target_mib, 256);
+        assert_eq!(stats.free_memory, Some(1048576));
+
+        machine.resize_balloon(512).await.unwrap();
+        assert!(matches!(
+            machine.resize_balloon(-1).await,
+            Err(FirepilotError::Configure { .. })
+        ));
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/balloon/statistics");
+        assert_eq!(requests[1].method, hyper::Method::PATCH);
+        assert_eq!(requests[1].path, "/balloon");
+        assert_eq!(requests[1].body, r#"{"amount_mib":512}"#);
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        let transport = MockTransport::new();
+        transport
+            .respond(StatusCode::OK, &instance("Not started"))
+            .respond(StatusCode::OK, &instance("Paused"));
+        let machine = machine(&transport);
+        assert_eq!(machine.state().await.unwrap(), InstanceState::NotStarted);
+        assert_eq!(machine.state().await.unwrap(), InstanceState::Paused);
+        assert_eq!(transport.requests()[0].path, "/");
+    }
+
+    #[tokio::test]
+    async fn test_start_waits_for_running() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine.set_lifecycle(MachineState::Configured);
+        transport.respond(StatusCode::NO_CONTENT, "");
+        transport.respond(StatusCode::OK, &instance("Not started"));
+        transport.respond(StatusCode::OK, &instance("Running"));
+        machine.start().await.unwrap();
+        assert_eq!(transport.requests().len(), 3);
+        assert_eq!(machine.lifecycle(), MachineState::Running);
+    }
+
+    #[tokio::test]
+    async fn test_start_detects_crashed_vmm() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine.set_lifecycle(MachineState::Configured);
+        transport.respond(StatusCode::NO_CONTENT, "");
+        transport.respond(StatusCode::INTERNAL_SERVER_ERROR, "");
+        let err = machine.start().await.unwrap_err();
+        assert!(matches!(err, FirepilotError::StartFailed { .. }));
+        assert_eq!(machine.lifecycle(), MachineState::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine.set_lifecycle(MachineState::Configured);
+        let mut events = machine.events();
+        transport.respond(StatusCode::NO_CONTENT, "");
+        transport.respond(StatusCode::OK, &instance("Running"));
+        machine.start().await.unwrap();
+        machine.stop_and_wait(Duration::from_secs(1)).await.unwrap();
+        machine.wait().await.unwrap();
+
+        assert_eq!(events.try_recv().unwrap(), MachineEvent::Started);
+        assert_eq!(events.try_recv().unwrap(), MachineEvent::GuestExited(None));
+        assert!(events.try_recv().is_err());
+    }
+
+    /// Spawns a process which exits with an error shortly after
+    #[derive(Debug)]
+    struct CrashingExecute {
+        chroot: PathBuf,
+    }
+
+    impl Execute for CrashingExecute {
+        fn chroot(&self) -> PathBuf {
+            self.chroot.clone()
+        }
+
+        fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError> {
+            std::fs::write(&args[1], "").unwrap();
+            Command::new("/bin/sh")
+                .args(["-c", "sleep 0.1; exit 3"])
+                .spawn()
+                .map_err(|e| ExecuteError::CommandExecution(e.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crash_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new();
+        transport.respond(StatusCode::OK, "");
+        let executor = Executor::new_with_executor(CrashingExecute {
+            chroot: dir.path().to_path_buf(),
+        })
+        .with_id("crashing".to_string())
+        .with_transport(Arc::new(transport));
+        let mut machine = Machine::new(executor).unwrap();
+        machine.executor.create_workspace().unwrap();
+        let mut events = machine.events();
+        machine.executor.run_socket().await.unwrap();
+        machine.monitor_exit();
+        machine.set_lifecycle(MachineState::Running);
+
+        let event = timeout(Duration::from_secs(5), events.recv()).await;
+        match event.unwrap().unwrap() {
+            MachineEvent::Crashed(status) => assert_eq!(status.code(), Some(3)),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert_eq!(machine.lifecycle(), MachineState::Crashed);
+        assert!(!machine.executor.is_running());
+        assert!(matches!(
+            machine.start().await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        machine.purge(false).await.unwrap();
+        assert!(!dir.path().join("crashing").exists());
+    }
+
+    /// Records the arguments given to firecracker and runs a process standing
+    /// for it, which exits after the given number of seconds
+    #[derive(Debug)]
+    struct RecordingExecute {
+        chroot: PathBuf,
+        args: Arc<std::sync::Mutex<Vec<String>>>,
+        lifetime: &'static str,
+    }
+
+    impl Execute for RecordingExecute {
+        fn chroot(&self) -> PathBuf {
+            self.chroot.clone()
+        }
+
+        fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError> {
+            *self.args.lock().unwrap() = args.to_vec();
+            Command::new("/bin/sleep")
+                .arg(self.lifetime)
+                .spawn()
+                .map_err(|e| ExecuteError::CommandExecution(e.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_again_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new();
+        let executor = Executor::new_with_executor(RecordingExecute {
+            chroot: dir.path().to_path_buf(),
+            args: Arc::new(std::sync::Mutex::new(Vec::new())),
+            lifetime: "30",
+        })
+        .with_id("retry".to_string())
+        .with_transport(Arc::new(transport.clone()));
+        let mut machine = Machine::new(executor).unwrap();
+        machine.executor.create_workspace().unwrap();
+        let applied = AppliedConfig {
+            machine_config: None,
+            cpu_config: None,
+            balloon: None,
+            drives: Vec::new(),
+            boot_source: BootSource::new("/srv/vmlinux".to_string()),
+            interfaces: Vec::new(),
+            mmds: None,
+            vsock: None,
+            metrics: None,
+            #[cfg(feature = "firecracker-1-4")]
+            entropy: None,
+        };
+        transport
+            .respond(StatusCode::OK, "")
+            .respond(StatusCode::OK, r#"{"firecracker_version": "1.3.0"}"#)
+            .respond(StatusCode::OK, "{}")
+            .respond(
+                StatusCode::BAD_REQUEST,
+                r#"{"fault_message": "Kernel image not found"}"#,
+            );
+
+        // The boot source is rejected once the process runs
+        machine.applied = Some(applied.clone());
+        let result = machine.spawn_configured(&applied).await;
+        assert!(machine.settle(result, MachineState::Configured).is_err());
+        assert_eq!(machine.lifecycle(), MachineState::Failed);
+        assert!(matches!(
+            machine
+                .create(Configuration::new("other".to_string()))
+                .await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+
+        transport.respond(StatusCode::OK, "{}");
+        let sent = transport.requests().len();
+        machine
+            .create(Configuration::new("retry".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(machine.lifecycle(), MachineState::Configured);
+        let paths: Vec<_> = transport.requests()[sent..]
+            .iter()
+            .map(|request| request.path.clone())
+            .collect();
+        assert_eq!(paths, vec!["/vm/config", "/boot-source"]);
+        machine.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provision_without_staging() {
+        let dir = tempfile::tempdir().unwrap();
+        let images = tempfile::tempdir().unwrap();
+        let kernel = images.path().join("vmlinux");
+        let rootfs = images.path().join("rootfs.ext4");
+        std::fs::write(&kernel, b"kernel").unwrap();
+        std::fs::write(&rootfs, b"rootfs").unwrap();
+        let transport = MockTransport::new();
+        let executor = Executor::new_with_executor(RecordingExecute {
+            chroot: dir.path().to_path_buf(),
+            args: Arc::new(std::sync::Mutex::new(Vec::new())),
+            lifetime: "30",
+        })
+        .with_id("in-place".to_string())
+        .with_transport(Arc::new(transport.clone()));
+        let mut machine = Machine::new(executor).unwrap();
+        let staged = DriveBuilder::new()
+            .with_drive_id("rootfs".to_string())
+            .with_path_on_host(rootfs.clone())
+            .as_root_device()
+            .without_staging()
+            .try_build_staged()
+            .unwrap();
+        let (progress, mut staged_artifacts) = tokio::sync::mpsc::unbounded_channel();
+        let config = Configuration::new("in-place".to_string())
+            .with_kernel(BootSource::new(kernel.to_string_lossy().into_owned()))
+            .with_staged_drive(staged)
+            .with_staging_progress(progress);
+        transport
+            .respond(StatusCode::OK, "")
+            .respond(StatusCode::OK, r#"{"firecracker_version": "1.3.0"}"#)
+            .respond(StatusCode::OK, "{}");
+        let result = machine.provision(config).await;
+        machine.settle(result, MachineState::Configured).unwrap();
+
+        // The drive goes through staging, which leaves it where it is
+        let mut artifacts = Vec::new();
+        while let Ok(artifact) = staged_artifacts.try_recv() {
+            artifacts.push((artifact.name, artifact.strategy));
+        }
+        artifacts.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            artifacts,
+            vec![
+                ("kernel".to_string(), CopyStrategy::Copy),
+                ("rootfs".to_string(), CopyStrategy::InPlace),
+            ]
+        );
+        let applied = machine.applied.clone().unwrap();
+        assert_eq!(applied.drives[0].path_on_host, rootfs.to_string_lossy());
+        assert!(machine.staged_copies.is_empty());
+        assert!(!dir.path().join("in-place").join("rootfs").exists());
+        machine.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_boot_from_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let executor = Executor::new_with_executor(RecordingExecute {
+            chroot: dir.path().to_path_buf(),
+            args: args.clone(),
+            lifetime: "30",
+        })
+        .with_id("config_file".to_string())
+        .with_boot_mode(BootMode::ConfigFileNoApi);
+        let mut machine = Machine::new(executor).unwrap();
+        machine.executor.create_workspace().unwrap();
+        let applied = AppliedConfig {
+            machine_config: None,
+            cpu_config: Some(serde_json::json!({ "kvm_capabilities": [] })),
+            balloon: None,
+            drives: Vec::new(),
+            boot_source: BootSource::new("/srv/vmlinux".to_string()),
+            interfaces: Vec::new(),
+            mmds: None,
+            vsock: None,
+            metrics: None,
+            #[cfg(feature = "firecracker-1-4")]
+            entropy: None,
+        };
+
+        // Nothing is spawned until the machine is started
+        machine.spawn_configured(&applied).await.unwrap();
+        assert!(!machine.executor.is_running());
+        let config_path = machine.executor.config_file_path();
+        let content: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(content["boot-source"]["kernel_image_path"], "/srv/vmlinux");
+        let cpu_config = PathBuf::from(content["cpu-config"].as_str().unwrap());
+        assert!(cpu_config.exists());
+
+        machine.set_lifecycle(MachineState::Configured);
+        // Firecracker doesn't run yet to receive the metadata
+        assert!(matches!(
+            machine.put_metadata(&serde_json::json!({})).await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        machine.start().await.unwrap();
+        assert_eq!(machine.lifecycle(), MachineState::Running);
+        assert_eq!(
+            *args.lock().unwrap(),
+            vec![
+                "--no-api".to_string(),
+                "--config-file".to_string(),
+                config_path.to_string_lossy().into_owned(),
+            ]
+        );
+        machine.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reattach_exited_process() {
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine
+            .staged_copies
+            .push(PathBuf::from("/srv/staging/vm/data"));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE);
+        machine.persist(&path).unwrap();
+        let state = PersistedMachine::load(&path).unwrap();
+        assert_eq!(state.chroot, PathBuf::from("/tmp/firepilot"));
+        assert_eq!(state.staged_copies, machine.staged_copies);
+        assert_eq!(
+            state.exec_binary,
+            Some(PathBuf::from("/usr/bin/firecracker"))
+        );
+
+        let mut exited = std::process::Command::new("/bin/true").spawn().unwrap();
+        exited.wait().unwrap();
+        PersistedMachine {
+            pid: Some(exited.id() as i32),
+            ..state
+        }
+        .save(&path)
+        .unwrap();
+        assert!(matches!(
+            Machine::reattach(&path).await,
+            Err(FirepilotError::Execute { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_requires_executor() {
+        assert!(matches!(
+            Machine::new(Executor::new()),
+            Err(FirepilotError::Setup { .. })
+        ));
+        let mut executor = Executor::new();
+        assert!(matches!(
+            executor.create_workspace(),
+            Err(ExecuteError::NoExecutor)
+        ));
+        assert!(matches!(
+            executor.run_socket().await,
+            Err(ExecuteError::NoExecutor)
+        ));
+
+        // An executor without implementation in the configuration is
+        // rejected, the one of the machine is kept
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine.set_lifecycle(MachineState::Created);
+        let config = Configuration::new("no-executor".to_string())
+            .with_executor(Executor::new())
+            .with_kernel(BootSource::new("/nonexistent/vmlinux".to_string()))
+            .with_drive(Drive::new(
+                "rootfs".to_string(),
+                true,
+                true,
+                "/nonexistent/rootfs.ext4".to_string(),
+            ));
+        let err = machine.create(config).await.unwrap_err();
+        assert!(matches!(err, FirepilotError::Setup { .. }), "{:?}", err);
+        assert!(machine.executor.has_implementation());
+
+        // Without its own executor, the one of the machine keeps its id
+        let id = machine.executor.id().to_string();
+        let err = machine
+            .create(Configuration::new("no-kernel".to_string()))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, FirepilotError::InvalidConfiguration { .. }),
+            "{:?}",
+            err
+        );
+        assert_eq!(machine.executor.id(), id);
+        assert!(machine.executor.has_implementation());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transitions() {
+        let transport = MockTransport::new();
+        let mut created = machine(&transport);
+        created.set_lifecycle(MachineState::Created);
+        assert!(matches!(
+            created.start().await,
+            Err(FirepilotError::InvalidTransition {
+                action: "start",
+                state: MachineState::Created,
+                ..
+            })
+        ));
+        assert!(matches!(
+            created.kill().await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+
+        let transport = MockTransport::new();
+        let mut machine = machine(&transport);
+        machine.pause().await.unwrap();
+        assert_eq!(machine.lifecycle(), MachineState::Paused);
+        assert!(matches!(
+            machine.pause().await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        machine
+            .stop_and_wait(Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        machine.set_lifecycle(MachineState::Stopped);
+        assert!(matches!(
+            machine.kill().await,
+            Err(FirepilotError::InvalidTransition {
+                action: "kill",
+                state: MachineState::Stopped,
+                ..
+            })
+        ));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stop_retries_while_booting() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        let not_ready = r#"{"fault_message": "Failed to send CtrlAltDel: i8042 error"}"#;
+        transport.respond(StatusCode::BAD_REQUEST, not_ready);
+        transport.respond(StatusCode::BAD_REQUEST, not_ready);
+        machine.stop().await.unwrap();
+        assert_eq!(transport.requests().len(), 3);
+
+        for _ in 0..=CTRL_ALT_DEL_RETRIES {
+            transport.respond(StatusCode::BAD_REQUEST, not_ready);
+        }
+        let err = machine.stop().await.unwrap_err();
+        assert!(matches!(err, FirepilotError::GuestNotReady { .. }));
+    }
+
+    /// Running machine whose process exits by itself after `lifetime` seconds
+    async fn sleeping_machine(
+        dir: &Path,
+        id: &str,
+        transport: &MockTransport,
+        lifetime: &'static str,
+    ) -> Machine {
+        let executor = Executor::new_with_executor(RecordingExecute {
+            chroot: dir.to_path_buf(),
+            args: Arc::new(std::sync::Mutex::new(Vec::new())),
+            lifetime,
+        })
+        .with_id(id.to_string())
+        .with_transport(Arc::new(transport.clone()));
+        let mut machine = Machine::new(executor).unwrap();
+        machine.executor.create_workspace().unwrap();
+        transport.respond(StatusCode::OK, "");
+        machine.executor.run_socket().await.unwrap();
+        machine.set_lifecycle(MachineState::Running);
+        machine
+    }
+
+    #[tokio::test]
+    async fn test_stop_and_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new();
+        let mut machine = sleeping_machine(dir.path(), "stopping", &transport, "0.5").await;
+        let sent = transport.requests().len();
+        machine.stop_and_wait(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(transport.requests()[sent].path, "/actions");
+        assert_eq!(machine.lifecycle(), MachineState::Stopped);
+        assert!(!machine.executor.is_running());
+
+        // The guest ignores CtrlAltDel
+        let transport = MockTransport::new();
+        let mut machine = sleeping_machine(dir.path(), "wedged", &transport, "30").await;
+        let err = machine
+            .stop_and_wait(Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FirepilotError::Timeout { .. }));
+        assert_eq!(machine.lifecycle(), MachineState::Running);
+        assert!(machine.executor.is_running());
+        machine.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_at_first_success() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = ShutdownPolicy {
+            steps: vec![
+                ShutdownStep::CtrlAltDel {
+                    timeout: Duration::from_secs(5),
+                },
+                ShutdownStep::Kill,
+            ],
+        };
+        let transport = MockTransport::new();
+        let mut machine = sleeping_machine(dir.path(), "stopping", &transport, "0.5").await;
+        let step = machine.shutdown(&policy).await.unwrap();
+        assert_eq!(step, policy.steps[0]);
+        assert_eq!(machine.lifecycle(), MachineState::Stopped);
+        assert!(!machine.executor.is_running());
+
+        // The guest ignores CtrlAltDel, it is killed once the timeout elapsed
+        let policy = ShutdownPolicy {
+            steps: vec![
+                ShutdownStep::CtrlAltDel {
+                    timeout: Duration::from_millis(200),
+                },
+                ShutdownStep::Kill,
+            ],
+        };
+        let transport = MockTransport::new();
+        let mut machine = sleeping_machine(dir.path(), "wedged", &transport, "30").await;
+        let step = machine.shutdown(&policy).await.unwrap();
+        assert_eq!(step, ShutdownStep::Kill);
+        assert_eq!(machine.lifecycle(), MachineState::Stopped);
+        assert!(!machine.executor.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready_timeout() {
+        let transport = MockTransport::new();
+        let machine = machine(&transport);
+        for _ in 0..10 {
+            transport.respond(StatusCode::OK, r#"{}"#);
+        }
+        let err = machine
+            .wait_ready("token", Duration::from_millis(150))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FirepilotError::Timeout { .. }));
+    }
 }
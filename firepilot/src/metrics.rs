@@ -0,0 +1,270 @@
+//! # Metrics of firecracker
+//!
+//! Once metrics are configured, see
+//! [MetricsBuilder](crate::builder::metrics::MetricsBuilder), firecracker
+//! flushes them every minute as one JSON object per line. Counters hold what
+//! happened since the previous flush, not a running total.
+//!
+//! A [MetricsReader] reads the lines written since its last read, from a FIFO
+//! or a regular file, and deserializes them into [FirecrackerMetrics]. Only
+//! the counters of the API server, block and network devices, vCPUs and
+//! seccomp are typed, the other sections are kept as JSON.
+//!
+//! ```no_run
+//! # fn example(machine: &mut firepilot::machine::Machine) {
+//! for metrics in machine.read_metrics().unwrap() {
+//!     println!(
+//!         "{} bytes read from the drives, {} bytes sent",
+//!         metrics.block.read_bytes, metrics.net.tx_bytes_count
+//!     );
+//! }
+//! # }
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use nix::fcntl::OFlag;
+use serde_json::{Map, Value};
+use tracing::{trace, warn};
+
+use crate::machine::{FirepilotError, IntoFirepilotError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum MetricsError {
+    #[error("Could not read metrics from {0:?}: {1}")]
+    Read(PathBuf, #[source] io::Error),
+}
+
+impl IntoFirepilotError for MetricsError {
+    fn into_firepilot_error(self, vm_id: &str) -> FirepilotError {
+        FirepilotError::execute(vm_id, "Failed to read the metrics").with_source(self)
+    }
+}
+
+/// One flush of the metrics of firecracker, see [crate::metrics]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FirecrackerMetrics {
+    /// When the metrics were flushed, in milliseconds since the epoch
+    pub utc_timestamp_ms: u64,
+    pub api_server: ApiServerMetrics,
+    /// Aggregate of all the block devices
+    pub block: BlockMetrics,
+    /// Aggregate of all the network interfaces
+    pub net: NetMetrics,
+    pub vcpu: VcpuMetrics,
+    pub seccomp: SeccompMetrics,
+    /// Sections which aren't typed, including the metrics of each device,
+    /// see [FirecrackerMetrics::block_device] and [FirecrackerMetrics::net_device]
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl FirecrackerMetrics {
+    /// Metrics of a single block device, by drive id
+    pub fn block_device(&self, drive_id: &str) -> Option<BlockMetrics> {
+        self.section(&format!("block_{}", drive_id))
+    }
+
+    /// Metrics of a single network interface, by interface id
+    pub fn net_device(&self, iface_id: &str) -> Option<NetMetrics> {
+        self.section(&format!("net_{}", iface_id))
+    }
+
+    fn section<T: serde::de::DeserializeOwned>(&self, name: &str) -> Option<T> {
+        let section = self.other.get(name)?;
+        serde_json::from_value(section.clone()).ok()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiServerMetrics {
+    pub process_startup_time_us: u64,
+    pub process_startup_time_cpu_us: u64,
+    pub sync_response_fails: u64,
+    pub sync_vmm_send_timeout_count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockMetrics {
+    pub activate_fails: u64,
+    pub cfg_fails: u64,
+    pub no_avail_buffer: u64,
+    pub event_fails: u64,
+    pub execute_fails: u64,
+    pub invalid_reqs_count: u64,
+    pub flush_count: u64,
+    pub queue_event_count: u64,
+    pub rate_limiter_event_count: u64,
+    pub update_count: u64,
+    pub update_fails: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_count: u64,
+    pub write_count: u64,
+    pub rate_limiter_throttled_events: u64,
+    pub io_engine_throttled_events: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetMetrics {
+    pub activate_fails: u64,
+    pub cfg_fails: u64,
+    pub mac_address_updates: u64,
+    pub no_rx_avail_buffer: u64,
+    pub no_tx_avail_buffer: u64,
+    pub event_fails: u64,
+    pub rx_queue_event_count: u64,
+    pub rx_event_rate_limiter_count: u64,
+    pub rx_partial_writes: u64,
+    pub rx_rate_limiter_throttled: u64,
+    pub rx_tap_event_count: u64,
+    pub rx_bytes_count: u64,
+    pub rx_packets_count: u64,
+    pub rx_fails: u64,
+    pub rx_count: u64,
+    pub tap_read_fails: u64,
+    pub tap_write_fails: u64,
+    pub tx_bytes_count: u64,
+    pub tx_malformed_frames: u64,
+    pub tx_fails: u64,
+    pub tx_count: u64,
+    pub tx_packets_count: u64,
+    pub tx_partial_reads: u64,
+    pub tx_queue_event_count: u64,
+    pub tx_rate_limiter_event_count: u64,
+    pub tx_rate_limiter_throttled: u64,
+    pub tx_spoofed_mac_count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VcpuMetrics {
+    pub exit_io_in: u64,
+    pub exit_io_out: u64,
+    pub exit_mmio_read: u64,
+    pub exit_mmio_write: u64,
+    pub failures: u64,
+    pub filter_cpuid: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeccompMetrics {
+    /// Syscalls denied by the seccomp filters
+    pub num_faults: u64,
+}
+
+/// Reads the metrics written by firecracker to a FIFO or a file, see
+/// [crate::metrics]
+///
+/// The FIFO is opened without blocking and kept open between reads, so
+/// firecracker always finds a reader. A line only partly written is kept
+/// until the next read.
+#[derive(Debug)]
+pub struct MetricsReader {
+    path: PathBuf,
+    file: File,
+    pending: Vec<u8>,
+}
+
+impl MetricsReader {
+    pub fn open(path: &Path) -> Result<MetricsReader, MetricsError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(path)
+            .map_err(|e| MetricsError::Read(path.to_path_buf(), e))?;
+        Ok(MetricsReader {
+            path: path.to_path_buf(),
+            file,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Path of the FIFO or file being read
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Metrics flushed since the previous read, oldest first
+    ///
+    /// Lines which aren't valid metrics are skipped with a warning.
+    pub fn read(&mut self) -> Result<Vec<FirecrackerMetrics>, MetricsError> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => self.pending.extend_from_slice(&buffer[..read]),
+                // Nothing more written to the FIFO for now
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(MetricsError::Read(self.path.clone(), e)),
+            }
+        }
+        let complete = match self.pending.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => self.pending.drain(..=end).collect::<Vec<u8>>(),
+            None => return Ok(Vec::new()),
+        };
+        let metrics = complete
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(metrics) => Some(metrics),
+                Err(e) => {
+                    warn!("Skip invalid metrics in {:?}: {}", self.path, e);
+                    None
+                }
+            })
+            .collect::<Vec<FirecrackerMetrics>>();
+        trace!("Read {} metrics from {:?}", metrics.len(), self.path);
+        Ok(metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    const FLUSH: &str = r#"{"utc_timestamp_ms":1700000000000,"api_server":{"process_startup_time_us":1200,"sync_response_fails":0},"block":{"read_bytes":4096,"read_count":1},"block_rootfs":{"read_bytes":4096,"read_count":1},"net":{"tx_bytes_count":42},"vcpu":{"exit_io_out":7,"exit_io_in_agg":{"min_us":1,"max_us":3,"sum_us":4}},"seccomp":{"num_faults":0},"vmm":{"panic_count":0}}"#;
+
+    #[test]
+    fn test_parse_metrics() {
+        let metrics: FirecrackerMetrics = serde_json::from_str(FLUSH).unwrap();
+        assert_eq!(metrics.utc_timestamp_ms, 1_700_000_000_000);
+        assert_eq!(metrics.api_server.process_startup_time_us, 1200);
+        assert_eq!(metrics.block.read_bytes, 4096);
+        assert_eq!(metrics.net.tx_bytes_count, 42);
+        assert_eq!(metrics.vcpu.exit_io_out, 7);
+        assert_eq!(metrics.block_device("rootfs").unwrap().read_count, 1);
+        assert_eq!(metrics.net_device("eth0"), None);
+        assert_eq!(metrics.other["vmm"]["panic_count"], 0);
+    }
+
+    #[test]
+    fn test_reader_keeps_partial_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.json");
+        let mut file = File::create(&path).unwrap();
+        let mut reader = MetricsReader::open(&path).unwrap();
+        assert!(reader.read().unwrap().is_empty());
+
+        let (start, end) = FLUSH.split_at(100);
+        write!(file, "{}\nnot json\n{}", FLUSH, start).unwrap();
+        assert_eq!(reader.read().unwrap().len(), 1);
+        writeln!(file, "{}", end).unwrap();
+        let metrics = reader.read().unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].block.read_count, 1);
+    }
+}
@@ -0,0 +1,108 @@
+//! Attach TAP devices to a Linux bridge
+//!
+//! The most common topology puts every microVM on the same L2 network as the
+//! host, through a bridge created beforehand (e.g. `ip link add br0 type
+//! bridge`). A [Bridge] given to [TapConfig::with_bridge] attaches the TAP
+//! device to it once created, the device leaves the bridge when it is deleted.
+//!
+//! ```no_run
+//! use firepilot::network::bridge::Bridge;
+//! use firepilot::network::tap::TapConfig;
+//!
+//! let tap = TapConfig::new()
+//!     .with_bridge(Bridge::new("br0".to_string()).with_address("172.16.0.1".parse().unwrap(), 24));
+//! ```
+//!
+//! [TapConfig::with_bridge]: super::tap::TapConfig::with_bridge
+use std::net::IpAddr;
+
+use tracing::debug;
+
+use super::{ip, tap::TapDevice, NetworkError};
+
+/// Existing bridge the TAP device of an interface is attached to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bridge {
+    pub name: String,
+    /// Address assigned to the bridge with its prefix length, usually the
+    /// gateway of the guests
+    pub address: Option<(IpAddr, u8)>,
+}
+
+impl Bridge {
+    pub fn new(name: String) -> Bridge {
+        Bridge {
+            name,
+            address: None,
+        }
+    }
+
+    /// Assign an address to the bridge, it is kept if the bridge already has
+    /// it so several machines can share the bridge
+    pub fn with_address(mut self, address: IpAddr, prefix_len: u8) -> Bridge {
+        self.address = Some((address, prefix_len));
+        self
+    }
+
+    /// Whether the prefix length fits the address family
+    pub(crate) fn has_valid_prefix(&self) -> bool {
+        match self.address {
+            Some((IpAddr::V4(_), len)) => len <= 32,
+            Some((IpAddr::V6(_), len)) => len <= 128,
+            None => true,
+        }
+    }
+
+    /// Arguments of `ip` assigning the address to the bridge
+    fn address_args(&self) -> Option<Vec<String>> {
+        self.address.map(|(address, len)| {
+            vec![
+                "addr".to_string(),
+                "replace".to_string(),
+                format!("{}/{}", address, len),
+                "dev".to_string(),
+                self.name.clone(),
+            ]
+        })
+    }
+
+    /// Attach the device to the bridge, assigning the address of the bridge
+    /// and bringing it up first. The bridge is looked for in the network
+    /// namespace of the device.
+    pub async fn attach(&self, device: &TapDevice) -> Result<(), NetworkError> {
+        let netns = device.netns();
+        if let Some(args) = self.address_args() {
+            ip(netns, &args).await?;
+        }
+        ip(netns, &["link", "set", "dev", &self.name, "up"]).await?;
+        debug!("Attach {} to bridge {}", device.name(), self.name);
+        ip(
+            netns,
+            &["link", "set", "dev", device.name(), "master", &self.name],
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bridge_address() {
+        let bridge = Bridge::new("br0".to_string());
+        assert!(bridge.address_args().is_none());
+
+        let bridge = bridge.with_address("172.16.0.1".parse().unwrap(), 24);
+        assert!(bridge.has_valid_prefix());
+        assert_eq!(
+            bridge.address_args().unwrap().join(" "),
+            "addr replace 172.16.0.1/24 dev br0"
+        );
+
+        let bridge = bridge.with_address("172.16.0.1".parse().unwrap(), 64);
+        assert!(!bridge.has_valid_prefix());
+        let bridge = bridge.with_address("fd00::1".parse().unwrap(), 64);
+        assert!(bridge.has_valid_prefix());
+    }
+}
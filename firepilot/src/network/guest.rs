@@ -0,0 +1,357 @@
+//! Network configuration of the guest
+//!
+//! Once an address is chosen for the guest on the host side, the guest must be
+//! configured accordingly. [GuestNetworkConfig] renders the configuration for
+//! the most common network managers and writes it in the rootfs image before
+//! boot, or in a cloud-init seed.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::net::Ipv4Addr;
+//! use firepilot::network::guest::{GuestNetworkConfig, GuestNetworkFormat};
+//!
+//! let config = GuestNetworkConfig::new("eth0".to_string(), Ipv4Addr::new(172, 16, 0, 2), 24)
+//!     .with_gateway(Ipv4Addr::new(172, 16, 0, 1))
+//!     .with_nameserver(Ipv4Addr::new(1, 1, 1, 1));
+//! let rendered = config.render(GuestNetworkFormat::Networkd);
+//! assert!(rendered.contains("Address=172.16.0.2/24"));
+//! ```
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+use tracing::debug;
+
+use super::NetworkError;
+
+/// Network manager of the guest for which the configuration is rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestNetworkFormat {
+    /// Netplan YAML, used by Ubuntu images
+    Netplan,
+    /// `.network` unit for systemd-networkd
+    Networkd,
+    /// `/etc/network/interfaces` stanza for ifupdown, used by Debian and Alpine
+    Ifupdown,
+}
+
+impl GuestNetworkFormat {
+    /// Location of the configuration file inside the guest
+    pub fn guest_path(&self) -> &'static str {
+        match self {
+            GuestNetworkFormat::Netplan => "/etc/netplan/50-firepilot.yaml",
+            GuestNetworkFormat::Networkd => "/etc/systemd/network/50-firepilot.network",
+            GuestNetworkFormat::Ifupdown => "/etc/network/interfaces.d/firepilot",
+        }
+    }
+}
+
+/// Static IPv4 configuration of a guest interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestNetworkConfig {
+    /// Name of the interface in the guest, e.g. `eth0`
+    pub interface: String,
+    pub address: Ipv4Addr,
+    /// Length of the network prefix, e.g. `24` for `255.255.255.0`
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub nameservers: Vec<Ipv4Addr>,
+}
+
+/// Convert a prefix length into a netmask, e.g. `24` into `255.255.255.0`
+pub(crate) fn prefix_to_netmask(prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len.min(32)))
+        .unwrap_or(0);
+    Ipv4Addr::from(mask)
+}
+
+impl GuestNetworkConfig {
+    pub fn new(interface: String, address: Ipv4Addr, prefix_len: u8) -> GuestNetworkConfig {
+        GuestNetworkConfig {
+            interface,
+            address,
+            prefix_len,
+            gateway: None,
+            nameservers: Vec::new(),
+        }
+    }
+
+    pub fn with_gateway(mut self, gateway: Ipv4Addr) -> GuestNetworkConfig {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    pub fn with_nameserver(mut self, nameserver: Ipv4Addr) -> GuestNetworkConfig {
+        self.nameservers.push(nameserver);
+        self
+    }
+
+    /// Netmask of the guest network, e.g. `255.255.255.0`
+    pub fn netmask(&self) -> Ipv4Addr {
+        prefix_to_netmask(self.prefix_len)
+    }
+
+    /// Value of the `ip=` kernel parameter configuring the interface, as in
+    /// `172.16.0.2::172.16.0.1:255.255.255.0::eth0:off`. The kernel only
+    /// takes two nameservers, the others are left out.
+    ///
+    /// The guest kernel must be built with `CONFIG_IP_PNP`.
+    pub fn kernel_ip_param(&self) -> String {
+        // <client>:<server>:<gateway>:<netmask>:<hostname>:<device>:<autoconf>:<dns0>:<dns1>
+        let mut fields = vec![
+            self.address.to_string(),
+            String::new(),
+            self.gateway.map(|g| g.to_string()).unwrap_or_default(),
+            self.netmask().to_string(),
+            String::new(),
+            self.interface.clone(),
+            "off".to_string(),
+        ];
+        fields.extend(self.nameservers.iter().take(2).map(|n| n.to_string()));
+        fields.join(":")
+    }
+
+    /// Render the configuration for the given network manager
+    pub fn render(&self, format: GuestNetworkFormat) -> String {
+        match format {
+            GuestNetworkFormat::Netplan => self.render_netplan(),
+            GuestNetworkFormat::Networkd => self.render_networkd(),
+            GuestNetworkFormat::Ifupdown => self.render_ifupdown(),
+        }
+    }
+
+    fn render_netplan(&self) -> String {
+        let mut out = format!(
+            "network:\n  version: 2\n  ethernets:\n    {}:\n      dhcp4: false\n      addresses: [{}/{}]\n",
+            self.interface, self.address, self.prefix_len
+        );
+        if let Some(gateway) = self.gateway {
+            out.push_str(&format!(
+                "      routes:\n        - to: default\n          via: {}\n",
+                gateway
+            ));
+        }
+        if !self.nameservers.is_empty() {
+            let nameservers: Vec<String> = self.nameservers.iter().map(|n| n.to_string()).collect();
+            out.push_str(&format!(
+                "      nameservers:\n        addresses: [{}]\n",
+                nameservers.join(", ")
+            ));
+        }
+        out
+    }
+
+    fn render_networkd(&self) -> String {
+        let mut out = format!(
+            "[Match]\nName={}\n\n[Network]\nAddress={}/{}\n",
+            self.interface, self.address, self.prefix_len
+        );
+        if let Some(gateway) = self.gateway {
+            out.push_str(&format!("Gateway={}\n", gateway));
+        }
+        for nameserver in &self.nameservers {
+            out.push_str(&format!("DNS={}\n", nameserver));
+        }
+        out
+    }
+
+    fn render_ifupdown(&self) -> String {
+        let mut out = format!(
+            "auto {iface}\niface {iface} inet static\n    address {}\n    netmask {}\n",
+            self.address,
+            self.netmask(),
+            iface = self.interface
+        );
+        if let Some(gateway) = self.gateway {
+            out.push_str(&format!("    gateway {}\n", gateway));
+        }
+        if !self.nameservers.is_empty() {
+            let nameservers: Vec<String> = self.nameservers.iter().map(|n| n.to_string()).collect();
+            out.push_str(&format!("    dns-nameservers {}\n", nameservers.join(" ")));
+        }
+        out
+    }
+
+    /// Write the configuration in a cloud-init NoCloud seed directory, as the
+    /// `network-config` file (netplan v2 format understood by cloud-init)
+    pub fn write_cloud_init_seed(&self, seed_dir: &Path) -> Result<PathBuf, NetworkError> {
+        let path = seed_dir.join("network-config");
+        std::fs::create_dir_all(seed_dir)
+            .map_err(|e| NetworkError::Io(seed_dir.display().to_string(), e))?;
+        std::fs::write(&path, self.render(GuestNetworkFormat::Netplan))
+            .map_err(|e| NetworkError::Io(path.display().to_string(), e))?;
+        Ok(path)
+    }
+
+    /// Inject the configuration in an ext2/3/4 rootfs image, it relies on
+    /// `debugfs` (e2fsprogs) so the image doesn't need to be mounted. The image
+    /// must not be in use by a running microVM.
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self)))]
+    pub async fn inject_into_rootfs(
+        &self,
+        rootfs: &Path,
+        format: GuestNetworkFormat,
+    ) -> Result<(), NetworkError> {
+        let guest_path = format.guest_path();
+        debug!("Inject network configuration at {}", guest_path);
+        let content = self.render(format);
+        let content_path =
+            std::env::temp_dir().join(format!("firepilot-net-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&content_path, &content)
+            .map_err(|e| NetworkError::Io(content_path.display().to_string(), e))?;
+
+        // debugfs doesn't create parent directories nor overwrite files, errors
+        // of mkdir and rm are ignored when the path already exists or not.
+        let mut commands = Vec::new();
+        let parent = Path::new(guest_path).parent().unwrap_or(Path::new("/"));
+        let mut current = PathBuf::from("/");
+        for component in parent.iter().skip(1) {
+            current.push(component);
+            commands.push(format!("mkdir {}", current.display()));
+        }
+        commands.push(format!("rm {}", guest_path));
+        commands.push(format!("write {} {}", content_path.display(), guest_path));
+        let script = commands.join("\n");
+
+        let result = debugfs_script(rootfs, &script).await;
+        let _ = std::fs::remove_file(&content_path);
+        result?;
+
+        // debugfs exits successfully even when a command of the script failed,
+        // e.g. on an image which isn't ext2/3/4 or without free space
+        let written = debugfs(rootfs, &["-R", &format!("cat {}", guest_path)]).await?;
+        if written != content.as_bytes() {
+            return Err(NetworkError::Command(
+                "debugfs".to_string(),
+                format!("Failed to write {} in {}", guest_path, rootfs.display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Run a script of `debugfs` commands in write mode on the given image
+async fn debugfs_script(image: &Path, script: &str) -> Result<(), NetworkError> {
+    let script_path =
+        std::env::temp_dir().join(format!("firepilot-debugfs-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&script_path, script)
+        .map_err(|e| NetworkError::Io(script_path.display().to_string(), e))?;
+    let result = debugfs(image, &["-w", "-f", &script_path.to_string_lossy()]).await;
+    let _ = std::fs::remove_file(&script_path);
+    result.map(|_| ())
+}
+
+/// Run `debugfs` with the given arguments on the image, returns its output
+async fn debugfs(image: &Path, args: &[&str]) -> Result<Vec<u8>, NetworkError> {
+    let output = Command::new("debugfs")
+        .args(args)
+        .arg(image)
+        .output()
+        .await
+        .map_err(|e| NetworkError::Command("debugfs".to_string(), e.to_string()))?;
+    if !output.status.success() {
+        return Err(NetworkError::Command(
+            "debugfs".to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> GuestNetworkConfig {
+        GuestNetworkConfig::new("eth0".to_string(), Ipv4Addr::new(172, 16, 0, 2), 24)
+            .with_gateway(Ipv4Addr::new(172, 16, 0, 1))
+            .with_nameserver(Ipv4Addr::new(1, 1, 1, 1))
+    }
+
+    #[test]
+    fn test_prefix_to_netmask() {
+        assert_eq!(prefix_to_netmask(24), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(prefix_to_netmask(30), Ipv4Addr::new(255, 255, 255, 252));
+        assert_eq!(prefix_to_netmask(0), Ipv4Addr::new(0, 0, 0, 0));
+        assert_eq!(prefix_to_netmask(32), Ipv4Addr::new(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn test_kernel_ip_param() {
+        assert_eq!(
+            config().kernel_ip_param(),
+            "172.16.0.2::172.16.0.1:255.255.255.0::eth0:off:1.1.1.1"
+        );
+        let config = GuestNetworkConfig::new("eth1".to_string(), Ipv4Addr::new(10, 0, 0, 5), 8);
+        assert_eq!(config.kernel_ip_param(), "10.0.0.5:::255.0.0.0::eth1:off");
+    }
+
+    #[test]
+    fn test_render_netplan() {
+        let rendered = config().render(GuestNetworkFormat::Netplan);
+        assert!(rendered.contains("addresses: [172.16.0.2/24]"));
+        assert!(rendered.contains("via: 172.16.0.1"));
+        assert!(rendered.contains("addresses: [1.1.1.1]"));
+    }
+
+    #[test]
+    fn test_render_networkd() {
+        let rendered = config().render(GuestNetworkFormat::Networkd);
+        assert_eq!(
+            rendered,
+            "[Match]\nName=eth0\n\n[Network]\nAddress=172.16.0.2/24\nGateway=172.16.0.1\nDNS=1.1.1.1\n"
+        );
+    }
+
+    #[test]
+    fn test_render_ifupdown() {
+        let rendered = config().render(GuestNetworkFormat::Ifupdown);
+        assert!(rendered.starts_with("auto eth0\niface eth0 inet static\n"));
+        assert!(rendered.contains("netmask 255.255.255.0"));
+        assert!(rendered.contains("dns-nameservers 1.1.1.1"));
+    }
+
+    #[test]
+    fn test_write_cloud_init_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config().write_cloud_init_seed(dir.path()).unwrap();
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(content.starts_with("network:\n  version: 2"));
+    }
+
+    #[tokio::test]
+    async fn test_inject_into_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs.ext4");
+        if crate::builder::drive::create_scratch_image(&rootfs, 8)
+            .await
+            .is_err()
+        {
+            // e2fsprogs isn't installed
+            return;
+        }
+        let config = config();
+        config
+            .inject_into_rootfs(&rootfs, GuestNetworkFormat::Networkd)
+            .await
+            .unwrap();
+        let command = format!("cat {}", GuestNetworkFormat::Networkd.guest_path());
+        let written = debugfs(&rootfs, &["-R", &command]).await.unwrap();
+        assert_eq!(
+            written,
+            config.render(GuestNetworkFormat::Networkd).as_bytes()
+        );
+
+        // debugfs exits successfully on an image which isn't ext2/3/4
+        let broken = dir.path().join("broken.img");
+        std::fs::write(&broken, [0; 4096]).unwrap();
+        assert!(config
+            .inject_into_rootfs(&broken, GuestNetworkFormat::Networkd)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Allocation of guest addresses from a subnet
+//!
+//! An [IpPool] hands out the addresses of a subnet to the machines, one per
+//! machine id. Leases are kept as files under [IPAM_DIR] in the chroot base
+//! of the executors, so they survive the process and are shared by every
+//! process using the same base. A lease is kept when the machine is killed and
+//! released when its workspace is removed, see
+//! [Workspace::remove](crate::workspace::Workspace::remove).
+//!
+//! The first address of the subnet is left to the host, as the gateway of the
+//! guests, see [IpPool::gateway].
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use firepilot::builder::Configuration;
+//! use firepilot::network::ipam::IpPool;
+//!
+//! let pool = IpPool::new(Path::new("/tmp/firepilot"), "172.16.0.0".parse().unwrap(), 24).unwrap();
+//! let config = Configuration::new("web".to_string()).with_auto_ip(pool);
+//! ```
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+use super::NetworkError;
+
+/// Directory of the leases in the chroot base, hidden so it isn't taken for a
+/// workspace
+pub const IPAM_DIR: &str = ".ipam";
+
+/// Subnet whose addresses are leased to the machines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpPool {
+    network: Ipv4Addr,
+    prefix_len: u8,
+    /// Directory holding one file per lease, named after the address and
+    /// containing the machine id
+    dir: PathBuf,
+}
+
+impl IpPool {
+    /// Pool of the given subnet, with its leases kept under the chroot base
+    ///
+    /// The subnet must have room for the gateway and at least one guest, and
+    /// the address must be the one of the subnet, e.g. `172.16.0.0/24`.
+    pub fn new(base: &Path, network: Ipv4Addr, prefix_len: u8) -> Result<IpPool, NetworkError> {
+        if prefix_len > 30 {
+            return Err(NetworkError::AddressPool(format!(
+                "Subnet {}/{} is too small",
+                network, prefix_len
+            )));
+        }
+        if u32::from(network) & !mask(prefix_len) != 0 {
+            return Err(NetworkError::AddressPool(format!(
+                "{} is not the address of a /{} subnet",
+                network, prefix_len
+            )));
+        }
+        Ok(IpPool {
+            network,
+            prefix_len,
+            dir: base
+                .join(IPAM_DIR)
+                .join(format!("{}_{}", network, prefix_len)),
+        })
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// First address of the subnet, never leased as it is meant for the host
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1)
+    }
+
+    /// Addresses which can be leased, the gateway and broadcast excluded
+    fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let network = u32::from(self.network);
+        let broadcast = network | !mask(self.prefix_len);
+        (network + 2..broadcast).map(Ipv4Addr::from)
+    }
+
+    /// Address leased to the machine, if any
+    pub fn lease(&self, id: &str) -> Result<Option<Ipv4Addr>, NetworkError> {
+        Ok(leases(&self.dir)?
+            .into_iter()
+            .find(|(_, owner)| owner == id)
+            .and_then(|(path, _)| path.file_name()?.to_str()?.parse().ok()))
+    }
+
+    /// Lease an address to the machine, the one it already has if any
+    pub fn allocate(&self, id: &str) -> Result<Ipv4Addr, NetworkError> {
+        if let Some(address) = self.lease(id)? {
+            return Ok(address);
+        }
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| NetworkError::Io(self.dir.display().to_string(), e))?;
+        for address in self.hosts() {
+            let path = self.dir.join(address.to_string());
+            // Creating the file is atomic, a concurrent allocation of the same
+            // address fails and moves on to the next one
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(id.as_bytes())
+                        .map_err(|e| NetworkError::Io(path.display().to_string(), e))?;
+                    debug!("Leased {} to {}", address, id);
+                    return Ok(address);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(NetworkError::Io(path.display().to_string(), e)),
+            }
+        }
+        Err(NetworkError::AddressPool(format!(
+            "No address left in {}/{}",
+            self.network, self.prefix_len
+        )))
+    }
+
+    /// Release the address leased to the machine, if any
+    pub fn release(&self, id: &str) -> Result<(), NetworkError> {
+        release_in(&self.dir, id).map_err(|e| NetworkError::Io(self.dir.display().to_string(), e))
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+/// Lease files of a pool along with the machine owning them
+fn leases(dir: &Path) -> Result<Vec<(PathBuf, String)>, NetworkError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(NetworkError::Io(dir.display().to_string(), e)),
+    };
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let owner = fs::read_to_string(entry.path()).ok()?;
+            Some((entry.path(), owner))
+        })
+        .collect())
+}
+
+fn release_in(dir: &Path, id: &str) -> io::Result<()> {
+    let leases = leases(dir).map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+    for (path, _) in leases.into_iter().filter(|(_, owner)| owner == id) {
+        debug!("Release lease {:?} of {}", path.file_name(), id);
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Release the leases of the machine in every pool of the chroot base
+pub(crate) fn release_all(base: &Path, id: &str) -> io::Result<()> {
+    let pools = match fs::read_dir(base.join(IPAM_DIR)) {
+        Ok(pools) => pools,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for pool in pools {
+        release_in(&pool?.path(), id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_pool() {
+        let base = tempfile::tempdir().unwrap();
+        let pool = IpPool::new(base.path(), "172.16.0.0".parse().unwrap(), 30).unwrap();
+        assert_eq!(pool.gateway(), Ipv4Addr::new(172, 16, 0, 1));
+
+        let address = pool.allocate("vm-a").unwrap();
+        assert_eq!(address, Ipv4Addr::new(172, 16, 0, 2));
+        assert_eq!(pool.allocate("vm-a").unwrap(), address);
+        assert!(matches!(
+            pool.allocate("vm-b"),
+            Err(NetworkError::AddressPool(_))
+        ));
+
+        // Leases are shared by every pool of the same base
+        let other = IpPool::new(base.path(), "172.16.0.0".parse().unwrap(), 30).unwrap();
+        assert_eq!(other.lease("vm-a").unwrap(), Some(address));
+
+        release_all(base.path(), "vm-a").unwrap();
+        assert_eq!(pool.lease("vm-a").unwrap(), None);
+        assert_eq!(pool.allocate("vm-b").unwrap(), address);
+        pool.release("vm-b").unwrap();
+        assert_eq!(pool.lease("vm-b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_pool() {
+        let base = Path::new("/tmp/firepilot");
+        assert!(IpPool::new(base, "172.16.0.0".parse().unwrap(), 31).is_err());
+        assert!(IpPool::new(base, "172.16.0.1".parse().unwrap(), 24).is_err());
+        assert!(IpPool::new(base, "0.0.0.0".parse().unwrap(), 0).is_ok());
+    }
+}
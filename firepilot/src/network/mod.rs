@@ -0,0 +1,85 @@
+//! # Host and guest networking helpers
+//!
+//! Firecracker only attaches an existing TAP device to the microVM, everything
+//! else (creating the device, addressing, guest configuration) is left to the
+//! user. This module gathers helpers to close this gap.
+//!
+//! - [guest]: render the network configuration of the guest and inject it in
+//!   its rootfs before boot
+//! - [netns]: start firecracker in the network namespace of the microVM
+//! - [tap]: create and delete the TAP devices of the microVM
+//! - [bridge]: attach the TAP devices to a Linux bridge
+//! - [nat]: masquerade the traffic of the guests leaving the host
+//! - [ipam]: allocate the addresses of the guests from a subnet
+//! - [neighbor]: find the address of a guest from the host
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::machine::{FirepilotError, IntoFirepilotError};
+
+pub mod bridge;
+pub mod guest;
+pub mod ipam;
+pub mod nat;
+pub mod neighbor;
+pub mod netns;
+pub mod tap;
+
+/// Binary used to manage network devices of the host
+pub const IP_BINARY: &str = "ip";
+
+#[derive(thiserror::Error, Debug)]
+pub enum NetworkError {
+    #[error("Could not run command {0}, reason: {1}")]
+    Command(String, String),
+    #[error("Could not write file {0}, reason: {1}")]
+    Io(String, std::io::Error),
+    #[error("Address pool error: {0}")]
+    AddressPool(String),
+}
+
+impl IntoFirepilotError for NetworkError {
+    fn into_firepilot_error(self, vm_id: &str) -> FirepilotError {
+        FirepilotError::setup(vm_id, "Failed to set up the network").with_source(self)
+    }
+}
+
+/// Command running the program in the given network namespace, through
+/// [netns::NSENTER_BINARY], or in the one of the host
+pub(crate) fn command_in(netns: Option<&Path>, program: &str) -> Command {
+    match netns {
+        Some(netns) => {
+            let mut command = Command::new(netns::NSENTER_BINARY);
+            command
+                .arg(format!("--net={}", netns.display()))
+                .arg("--")
+                .arg(program);
+            command
+        }
+        None => Command::new(program),
+    }
+}
+
+/// Run `ip` with the given arguments, in the given network namespace
+pub(crate) async fn ip<S: AsRef<str>>(
+    netns: Option<&Path>,
+    args: &[S],
+) -> Result<(), NetworkError> {
+    let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+    debug!("Running {} {}", IP_BINARY, args.join(" "));
+    let command = format!("{} {}", IP_BINARY, args.join(" "));
+    let output = command_in(netns, IP_BINARY)
+        .args(&args)
+        .output()
+        .await
+        .map_err(|e| NetworkError::Command(command.clone(), e.to_string()))?;
+    if !output.status.success() {
+        return Err(NetworkError::Command(
+            command,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
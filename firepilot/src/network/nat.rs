@@ -0,0 +1,217 @@
+//! Egress of the guests through the network of the host
+//!
+//! A guest behind a TAP device which isn't bridged to the host network needs
+//! its traffic masqueraded to reach the internet. A [NatConfig] given to
+//! [TapConfig::with_nat] makes [Machine::create](crate::machine::Machine::create)
+//! install the `MASQUERADE` and `FORWARD` rules for the device, and
+//! [Machine::kill](crate::machine::Machine::kill) remove them.
+//!
+//! Rules are installed with [IPTABLES_BINARY] (or `ip6tables`), which drives
+//! nftables on hosts using `iptables-nft`. Each rule is tagged with a comment
+//! holding the id of the machine, so the rules of a machine can be told apart
+//! from the others. Forwarding must be enabled on the host
+//! (`net.ipv4.ip_forward=1`), it is not changed as it affects the whole host.
+//!
+//! ```no_run
+//! use firepilot::network::nat::NatConfig;
+//! use firepilot::network::tap::TapConfig;
+//!
+//! let tap = TapConfig::new().with_nat(NatConfig::new(
+//!     "eth0".to_string(),
+//!     "172.16.0.2".parse().unwrap(),
+//!     32,
+//! ));
+//! ```
+//!
+//! [TapConfig::with_nat]: super::tap::TapConfig::with_nat
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, warn};
+
+use super::{command_in, tap::TapDevice, NetworkError};
+
+/// Binary used to manage IPv4 rules, `ip6tables` is used for IPv6 guests
+pub const IPTABLES_BINARY: &str = "iptables";
+
+/// Prefix of the comment tagging the rules of a machine
+pub const RULE_COMMENT_PREFIX: &str = "firepilot:";
+
+/// Masquerading of the traffic of a guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatConfig {
+    /// Interface of the host the traffic leaves through
+    pub egress: String,
+    /// Addresses of the guest behind the TAP device, with their prefix length
+    pub source: (IpAddr, u8),
+}
+
+impl NatConfig {
+    pub fn new(egress: String, source: IpAddr, prefix_len: u8) -> NatConfig {
+        NatConfig {
+            egress,
+            source: (source, prefix_len),
+        }
+    }
+
+    /// Whether the prefix length fits the address family
+    pub(crate) fn has_valid_prefix(&self) -> bool {
+        match self.source {
+            (IpAddr::V4(_), len) => len <= 32,
+            (IpAddr::V6(_), len) => len <= 128,
+        }
+    }
+
+    /// Rules needed by the guest behind the device, as the table, the chain and
+    /// the rule specification
+    fn rules(&self, id: &str, device: &str) -> Vec<(&'static str, &'static str, Vec<String>)> {
+        let comment = [
+            "-m".to_string(),
+            "comment".to_string(),
+            "--comment".to_string(),
+            format!("{}{}", RULE_COMMENT_PREFIX, id),
+        ];
+        let rule = |args: &[&str]| -> Vec<String> {
+            args.iter()
+                .map(|arg| arg.to_string())
+                .chain(comment.iter().cloned())
+                .collect()
+        };
+        let source = format!("{}/{}", self.source.0, self.source.1);
+        vec![
+            (
+                "nat",
+                "POSTROUTING",
+                rule(&["-s", &source, "-o", &self.egress, "-j", "MASQUERADE"]),
+            ),
+            (
+                "filter",
+                "FORWARD",
+                rule(&["-i", device, "-o", &self.egress, "-j", "ACCEPT"]),
+            ),
+            (
+                "filter",
+                "FORWARD",
+                rule(&[
+                    "-i",
+                    &self.egress,
+                    "-o",
+                    device,
+                    "-m",
+                    "conntrack",
+                    "--ctstate",
+                    "RELATED,ESTABLISHED",
+                    "-j",
+                    "ACCEPT",
+                ]),
+            ),
+        ]
+    }
+
+    /// Install the rules of the machine for the device, in the network
+    /// namespace of the device. The rules already installed are removed if
+    /// one of them fails.
+    pub async fn install(&self, id: &str, device: &TapDevice) -> Result<NatRules, NetworkError> {
+        let binary = match self.source.0 {
+            IpAddr::V4(_) => IPTABLES_BINARY,
+            IpAddr::V6(_) => "ip6tables",
+        };
+        let mut installed = NatRules {
+            binary: binary.to_string(),
+            netns: device.netns().map(Path::to_path_buf),
+            rules: Vec::new(),
+        };
+        for (table, chain, spec) in self.rules(id, device.name()) {
+            debug!("Add rule to {} {}: {}", table, chain, spec.join(" "));
+            let netns = installed.netns.as_deref();
+            if let Err(e) = iptables(netns, binary, table, "-A", chain, &spec).await {
+                if let Err(e) = installed.remove().await {
+                    warn!("Failed to remove the NAT rules of {}: {}", id, e);
+                }
+                return Err(e);
+            }
+            installed
+                .rules
+                .push((table.to_string(), chain.to_string(), spec));
+        }
+        Ok(installed)
+    }
+}
+
+/// Rules installed for a machine, see [NatConfig::install]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatRules {
+    binary: String,
+    /// Network namespace the rules are installed in, none for the one of the
+    /// host
+    netns: Option<PathBuf>,
+    rules: Vec<(String, String, Vec<String>)>,
+}
+
+impl NatRules {
+    /// Remove the rules, in the reverse order of their installation
+    pub async fn remove(&mut self) -> Result<(), NetworkError> {
+        while let Some((table, chain, spec)) = self.rules.pop() {
+            debug!("Remove rule from {} {}: {}", table, chain, spec.join(" "));
+            let netns = self.netns.as_deref();
+            if let Err(e) = iptables(netns, &self.binary, &table, "-D", &chain, &spec).await {
+                self.rules.push((table, chain, spec));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn iptables(
+    netns: Option<&Path>,
+    binary: &str,
+    table: &str,
+    operation: &str,
+    chain: &str,
+    spec: &[String],
+) -> Result<(), NetworkError> {
+    let command = format!("{} -t {} {} {}", binary, table, operation, chain);
+    let output = command_in(netns, binary)
+        .args(["-w", "-t", table, operation, chain])
+        .args(spec)
+        .output()
+        .await
+        .map_err(|e| NetworkError::Command(command.clone(), e.to_string()))?;
+    if !output.status.success() {
+        return Err(NetworkError::Command(
+            command,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat_rules() {
+        let nat = NatConfig::new("eth0".to_string(), "172.16.0.2".parse().unwrap(), 32);
+        assert!(nat.has_valid_prefix());
+        let rules: Vec<String> = nat
+            .rules("vm", "fc-vm-0")
+            .into_iter()
+            .map(|(table, chain, spec)| format!("{} {} {}", table, chain, spec.join(" ")))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "nat POSTROUTING -s 172.16.0.2/32 -o eth0 -j MASQUERADE -m comment --comment firepilot:vm",
+                "filter FORWARD -i fc-vm-0 -o eth0 -j ACCEPT -m comment --comment firepilot:vm",
+                "filter FORWARD -i eth0 -o fc-vm-0 -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT -m comment --comment firepilot:vm",
+            ]
+        );
+
+        let nat = NatConfig::new("eth0".to_string(), "172.16.0.2".parse().unwrap(), 33);
+        assert!(!nat.has_valid_prefix());
+    }
+}
@@ -0,0 +1,124 @@
+//! Lookup of guest addresses in the neighbor table of the host
+//!
+//! Once the guest has sent traffic through its TAP device, the host knows its
+//! address from ARP. [find_neighbor] looks it up in [ARP_TABLE], which only
+//! holds IPv4 neighbors.
+//!
+//! The kernel exposes the table of the network namespace of the reader, so
+//! when the TAP devices live in the namespace of firecracker, see
+//! [super::netns], [read_neighbors] enters it to read the table.
+use std::{net::Ipv4Addr, path::Path};
+
+use super::{command_in, NetworkError};
+
+/// IPv4 neighbor table of the host, as exposed by the kernel
+pub const ARP_TABLE: &str = "/proc/net/arp";
+
+/// Binary used to read [ARP_TABLE] from inside a network namespace
+pub const CAT_BINARY: &str = "cat";
+
+/// Read [ARP_TABLE] in the given network namespace, or in the one of the host.
+/// Entering a namespace requires `CAP_SYS_ADMIN`.
+pub async fn read_neighbors(netns: Option<&Path>) -> Result<String, NetworkError> {
+    let command = format!("{} {}", CAT_BINARY, ARP_TABLE);
+    let netns = match netns {
+        Some(netns) => netns,
+        None => {
+            return tokio::fs::read_to_string(ARP_TABLE)
+                .await
+                .map_err(|e| NetworkError::Io(ARP_TABLE.to_string(), e))
+        }
+    };
+    let output = command_in(Some(netns), CAT_BINARY)
+        .arg(ARP_TABLE)
+        .output()
+        .await
+        .map_err(|e| NetworkError::Command(command.clone(), e.to_string()))?;
+    if !output.status.success() {
+        return Err(NetworkError::Command(
+            command,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `ATF_COM` flag, set once the hardware address of the neighbor is known
+const ATF_COM: u32 = 0x2;
+
+/// Device of the host the guest is reached through, along with the MAC address
+/// of the guest when it is known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestLink {
+    pub host_dev_name: String,
+    pub guest_mac: Option<String>,
+}
+
+/// Find the address of a guest in the content of [ARP_TABLE], i.e. a complete
+/// entry on one of its devices, with its MAC address if known
+pub fn find_neighbor(arp_table: &str, links: &[GuestLink]) -> Option<Ipv4Addr> {
+    // IP address, HW type, Flags, HW address, Mask, Device
+    arp_table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            return None;
+        }
+        let flags = u32::from_str_radix(fields[2].trim_start_matches("0x"), 16).ok()?;
+        if flags & ATF_COM == 0 {
+            return None;
+        }
+        links
+            .iter()
+            .any(|link| {
+                link.host_dev_name == fields[5]
+                    && link
+                        .guest_mac
+                        .as_ref()
+                        .map_or(true, |mac| mac.eq_ignore_ascii_case(fields[3]))
+            })
+            .then(|| fields[0].parse().ok())
+            .flatten()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_neighbor() {
+        let table =
+            "IP address       HW type     Flags       HW address            Mask     Device\n\
+            192.168.1.1      0x1         0x2         a0:b1:c2:d3:e4:f5     *        eth0\n\
+            172.16.0.3       0x1         0x0         00:00:00:00:00:00     *        fc-vm-0\n\
+            172.16.0.9       0x1         0x2         06:00:ac:10:00:09     *        fc-vm-0\n\
+            172.16.0.2       0x1         0x2         06:00:AC:10:00:02     *        fc-vm-0\n";
+        let link = |mac: Option<&str>| GuestLink {
+            host_dev_name: "fc-vm-0".to_string(),
+            guest_mac: mac.map(str::to_string),
+        };
+        assert_eq!(
+            find_neighbor(table, &[link(None)]),
+            Some(Ipv4Addr::new(172, 16, 0, 9))
+        );
+        assert_eq!(
+            find_neighbor(table, &[link(Some("06:00:ac:10:00:02"))]),
+            Some(Ipv4Addr::new(172, 16, 0, 2))
+        );
+        assert_eq!(
+            find_neighbor(table, &[link(Some("06:00:ac:10:00:05"))]),
+            None
+        );
+        assert_eq!(find_neighbor(table, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_neighbors() {
+        let table = read_neighbors(None).await.unwrap();
+        assert!(table.starts_with("IP address"));
+        assert!(matches!(
+            read_neighbors(Some(Path::new("/invalid/netns"))).await,
+            Err(NetworkError::Command(..))
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+//! Network namespaces of the firecracker process
+//!
+//! Firecracker opens the TAP devices of the microVM in its own network
+//! namespace, so multi-tenant hosts isolate each microVM, and its TAP devices,
+//! in a namespace of its own. [NetnsExecutor] starts firecracker in an existing
+//! namespace through [NSENTER_BINARY], which requires `CAP_SYS_ADMIN`.
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//! use firepilot::builder::Builder;
+//! use firepilot::builder::executor::FirecrackerExecutorBuilder;
+//!
+//! // Namespace created with `ip netns add tenant-a`
+//! let executor = FirecrackerExecutorBuilder::new()
+//!     .with_chroot("/tmp/firepilot".to_string())
+//!     .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
+//!     .with_netns("tenant-a")
+//!     .try_build()
+//!     .unwrap();
+//! ```
+use std::{
+    ffi::OsString,
+    fs::File,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::process::{Child, Command};
+
+use crate::executor::{spawn_in_new_session, Execute, ExecuteError, FirecrackerExecutor};
+
+/// Binary used to enter the namespace before running firecracker
+pub const NSENTER_BINARY: &str = "nsenter";
+
+/// Directory where `ip netns` keeps named network namespaces
+pub const NETNS_DIR: &str = "/var/run/netns";
+
+/// Path of a network namespace given either as a path, or as a name created
+/// with `ip netns add`
+pub fn resolve_netns(path_or_name: &str) -> PathBuf {
+    if path_or_name.contains('/') {
+        PathBuf::from(path_or_name)
+    } else {
+        Path::new(NETNS_DIR).join(path_or_name)
+    }
+}
+
+/// [FirecrackerExecutor] which starts firecracker in an existing network
+/// namespace
+#[derive(Debug, Clone)]
+pub struct NetnsExecutor {
+    pub firecracker: FirecrackerExecutor,
+    /// Path of the network namespace, e.g. `/var/run/netns/tenant-a`
+    pub netns: PathBuf,
+}
+
+impl NetnsExecutor {
+    fn command_line(&self, args: &[String]) -> Vec<OsString> {
+        let mut command_line = vec![
+            OsString::from(NSENTER_BINARY),
+            OsString::from(format!("--net={}", self.netns.display())),
+            OsString::from("--"),
+            self.firecracker.exec_binary.clone().into_os_string(),
+        ];
+        command_line.extend(args.iter().map(OsString::from));
+        command_line
+    }
+
+    fn command(&self, args: &[String]) -> Command {
+        let command_line = self.command_line(args);
+        let mut command = Command::new(&command_line[0]);
+        command.args(&command_line[1..]);
+        command
+    }
+}
+
+impl Execute for NetnsExecutor {
+    fn chroot(&self) -> PathBuf {
+        self.firecracker.chroot()
+    }
+
+    fn spawn_binary_child(&self, args: &[String]) -> Result<Child, ExecuteError> {
+        self.command(args)
+            .stdin(Stdio::null())
+            // Serial console of the guest, read for boot milestones
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| ExecuteError::CommandExecution(format!("{}: {}", NSENTER_BINARY, e)))
+    }
+
+    fn spawn_detached_child(&self, args: &[String], output: File) -> Result<Child, ExecuteError> {
+        spawn_in_new_session(self.command_line(args), output)
+    }
+
+    fn exec_binary(&self) -> Option<PathBuf> {
+        self.firecracker.exec_binary()
+    }
+
+    fn netns(&self) -> Option<PathBuf> {
+        Some(self.netns.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_netns() {
+        assert_eq!(
+            resolve_netns("tenant-a"),
+            PathBuf::from("/var/run/netns/tenant-a")
+        );
+        assert_eq!(
+            resolve_netns("/proc/42/ns/net"),
+            PathBuf::from("/proc/42/ns/net")
+        );
+    }
+
+    #[test]
+    fn test_netns_command() {
+        let executor = NetnsExecutor {
+            firecracker: FirecrackerExecutor {
+                chroot: "/tmp/firepilot".to_string(),
+                exec_binary: PathBuf::from("/usr/bin/firecracker"),
+            },
+            netns: resolve_netns("tenant-a"),
+        };
+        let command = executor.command(&["--api-sock".to_string(), "fc.socket".to_string()]);
+        let args: Vec<_> = command.as_std().get_args().collect();
+        assert_eq!(command.as_std().get_program(), NSENTER_BINARY);
+        assert_eq!(
+            args,
+            [
+                "--net=/var/run/netns/tenant-a",
+                "--",
+                "/usr/bin/firecracker",
+                "--api-sock",
+                "fc.socket"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_in_netns() {
+        let netns = resolve_netns("tenant-a");
+        let command = crate::network::command_in(Some(&netns), "ip");
+        let args: Vec<_> = command.as_std().get_args().collect();
+        assert_eq!(command.as_std().get_program(), NSENTER_BINARY);
+        assert_eq!(args, ["--net=/var/run/netns/tenant-a", "--", "ip"]);
+
+        let command = crate::network::command_in(None, "ip");
+        assert_eq!(command.as_std().get_program(), "ip");
+        assert_eq!(command.as_std().get_args().count(), 0);
+    }
+}
@@ -0,0 +1,186 @@
+//! Lifecycle of the TAP devices of a microVM
+//!
+//! A [TapConfig] given to
+//! [NetworkInterfaceBuilder::with_tap](crate::builder::network_interface::NetworkInterfaceBuilder::with_tap)
+//! makes [Machine::create](crate::machine::Machine::create) create the TAP
+//! device of the interface with [IP_BINARY] before spawning firecracker, and
+//! [Machine::kill](crate::machine::Machine::kill) delete it. Managing TAP
+//! devices requires `CAP_NET_ADMIN`.
+//!
+//! When firecracker runs in a network namespace, see [super::netns], the
+//! device is created in this namespace, along with its bridge and NAT rules.
+//!
+//! ```no_run
+//! use firepilot::builder::Builder;
+//! use firepilot::builder::Configuration;
+//! use firepilot::builder::network_interface::NetworkInterfaceBuilder;
+//! use firepilot::network::tap::TapConfig;
+//!
+//! let iface = NetworkInterfaceBuilder::new()
+//!     .with_iface_id("eth0".to_string())
+//!     .with_host_dev_name("fc-web-0".to_string())
+//!     .with_tap(TapConfig::new().with_mtu(1400))
+//!     .try_build_with_tap()
+//!     .unwrap();
+//! let config = Configuration::new("web".to_string()).with_tap_interface(iface);
+//! ```
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use super::{bridge::Bridge, ip, nat::NatConfig, NetworkError};
+
+/// Longest name the kernel accepts for a network device
+pub const MAX_IFNAME_LEN: usize = 15;
+
+/// How the TAP device of an interface is created
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TapConfig {
+    /// User allowed to open the device, firecracker must run as this user
+    /// when it isn't root
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    pub mtu: Option<u32>,
+    pub bridge: Option<Bridge>,
+    /// Masquerading installed by the machine for the device, see
+    /// [NatConfig::install]
+    pub nat: Option<NatConfig>,
+}
+
+impl TapConfig {
+    pub fn new() -> TapConfig {
+        TapConfig::default()
+    }
+
+    pub fn with_owner(mut self, uid: u32) -> TapConfig {
+        self.owner = Some(uid);
+        self
+    }
+
+    pub fn with_group(mut self, gid: u32) -> TapConfig {
+        self.group = Some(gid);
+        self
+    }
+
+    pub fn with_mtu(mut self, mtu: u32) -> TapConfig {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Attach the device to an existing bridge once created
+    pub fn with_bridge(mut self, bridge: Bridge) -> TapConfig {
+        self.bridge = Some(bridge);
+        self
+    }
+
+    /// Let the guest reach the outside through the network of the host
+    pub fn with_nat(mut self, nat: NatConfig) -> TapConfig {
+        self.nat = Some(nat);
+        self
+    }
+
+    /// Arguments of `ip` creating the device
+    fn add_args(&self, name: &str) -> Vec<String> {
+        let mut args: Vec<String> = ["tuntap", "add", "dev", name, "mode", "tap"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        if let Some(owner) = self.owner {
+            args.extend(["user".to_string(), owner.to_string()]);
+        }
+        if let Some(group) = self.group {
+            args.extend(["group".to_string(), group.to_string()]);
+        }
+        args
+    }
+
+    /// Arguments of `ip` configuring the device and bringing it up
+    fn up_args(&self, name: &str) -> Vec<String> {
+        let mut args: Vec<String> = ["link", "set", "dev", name]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        if let Some(mtu) = self.mtu {
+            args.extend(["mtu".to_string(), mtu.to_string()]);
+        }
+        args.push("up".to_string());
+        args
+    }
+
+    /// Create the TAP device with the given name and bring it up
+    pub async fn create(&self, name: &str) -> Result<TapDevice, NetworkError> {
+        self.create_in(name, None).await
+    }
+
+    /// Create the TAP device in the given network namespace, or in the one of
+    /// the host, and bring it up
+    pub async fn create_in(
+        &self,
+        name: &str,
+        netns: Option<&Path>,
+    ) -> Result<TapDevice, NetworkError> {
+        debug!("Create TAP device {}", name);
+        ip(netns, &self.add_args(name)).await?;
+        let device = TapDevice {
+            name: name.to_string(),
+            netns: netns.map(Path::to_path_buf),
+        };
+        let mut result = ip(netns, &self.up_args(name)).await;
+        if let (Ok(()), Some(bridge)) = (&result, &self.bridge) {
+            result = bridge.attach(&device).await;
+        }
+        if let Err(e) = result {
+            if let Err(e) = device.remove().await {
+                warn!("Failed to remove TAP device {}: {}", name, e);
+            }
+            return Err(e);
+        }
+        Ok(device)
+    }
+}
+
+/// TAP device created by firepilot, see [TapConfig::create]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TapDevice {
+    name: String,
+    netns: Option<PathBuf>,
+}
+
+impl TapDevice {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Network namespace of the device, none for the one of the host
+    pub fn netns(&self) -> Option<&Path> {
+        self.netns.as_deref()
+    }
+
+    /// Delete the device, it is detached from any bridge along the way
+    pub async fn remove(&self) -> Result<(), NetworkError> {
+        debug!("Remove TAP device {}", self.name);
+        ip(self.netns(), &["link", "del", "dev", &self.name]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tap_args() {
+        let config = TapConfig::new().with_owner(1000).with_mtu(1400);
+        assert_eq!(
+            config.add_args("tap0").join(" "),
+            "tuntap add dev tap0 mode tap user 1000"
+        );
+        assert_eq!(
+            config.up_args("tap0").join(" "),
+            "link set dev tap0 mtu 1400 up"
+        );
+        assert_eq!(
+            TapConfig::new().up_args("tap0").join(" "),
+            "link set dev tap0 up"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! # Copy-on-write overlays of a shared base image
+//!
+//! Copying the rootfs for each microVM doesn't scale to hundreds of machines.
+//! An overlay drive instead keeps the base image read-only and shared, each
+//! machine only gets a sparse copy-on-write file in its workspace holding the
+//! blocks written by the guest.
+//!
+//! The overlay is a device-mapper `snapshot` device, stacked on loop devices
+//! of the base image (read-only) and of the copy-on-write file. It is created
+//! when the machine is created, see
+//! [DriveBuilder::with_overlay](crate::builder::drive::DriveBuilder::with_overlay),
+//! and removed when the machine is killed. The base image must not be written
+//! while overlays of it exist.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::{
+    builder::drive::SECTOR_SIZE,
+    executor::ExecuteError,
+    thin::{dmsetup, DEVICE_MAPPER_DIR},
+};
+
+/// Binary used to manage loop devices
+pub const LOSETUP_BINARY: &str = "losetup";
+
+/// Chunk size of the snapshot, in sectors
+const CHUNK_SECTORS: u64 = 8;
+
+/// Copy-on-write layer of a drive over a read-only base image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverlayOrigin {
+    /// Base image shared by the overlays
+    pub base: PathBuf,
+    /// Maximum size of the copy-on-write file in bytes, the overlay becomes
+    /// invalid once the guest wrote more. The file is sparse, only written
+    /// blocks use space.
+    pub cow_size: u64,
+}
+
+/// Overlay device owned by a microVM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayDevice {
+    /// Name of the activated device, under [DEVICE_MAPPER_DIR]
+    pub name: String,
+    /// Loop devices of the base image and of the copy-on-write file
+    loops: Vec<String>,
+}
+
+impl OverlayOrigin {
+    /// Create the overlay under the given name, with its copy-on-write file at
+    /// `cow_path`
+    ///
+    /// The size of the base image must be a multiple of [SECTOR_SIZE], the
+    /// overlay would leave its last partial sector out.
+    pub(crate) async fn create(
+        &self,
+        name: &str,
+        cow_path: &Path,
+    ) -> Result<OverlayDevice, ExecuteError> {
+        let size = std::fs::metadata(&self.base)
+            .map_err(|e| ExecuteError::CommandExecution(format!("{}: {}", self.base.display(), e)))?
+            .len();
+        if size % SECTOR_SIZE != 0 {
+            return Err(ExecuteError::CommandExecution(format!(
+                "Base image {} is {} bytes, it must be a multiple of {} bytes",
+                self.base.display(),
+                size,
+                SECTOR_SIZE
+            )));
+        }
+        let sectors = size / SECTOR_SIZE;
+        File::create(cow_path)
+            .and_then(|file| file.set_len(self.cow_size))
+            .map_err(|e| {
+                ExecuteError::CommandExecution(format!("{}: {}", cow_path.display(), e))
+            })?;
+
+        let mut device = OverlayDevice {
+            name: name.to_string(),
+            loops: Vec::new(),
+        };
+        let result = async {
+            let base = attach_loop(&self.base, true).await?;
+            device.loops.push(base.clone());
+            let cow = attach_loop(cow_path, false).await?;
+            device.loops.push(cow.clone());
+            let table = snapshot_table(sectors, &base, &cow);
+            dmsetup(&["create", name, "--table", &table]).await
+        }
+        .await;
+        if let Err(e) = result {
+            detach_loops(&device.loops).await;
+            return Err(e);
+        }
+        info!("Created overlay {} of {}", name, self.base.display());
+        Ok(device)
+    }
+}
+
+impl OverlayDevice {
+    /// Path of the block device given to firecracker
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(DEVICE_MAPPER_DIR).join(&self.name)
+    }
+
+    /// Deactivate the overlay and detach its loop devices, the copy-on-write
+    /// file is left in the workspace
+    pub(crate) async fn remove(&self) -> Result<(), ExecuteError> {
+        dmsetup(&["remove", &self.name]).await?;
+        detach_loops(&self.loops).await;
+        info!("Removed overlay {}", self.name);
+        Ok(())
+    }
+}
+
+/// Table of a non-persistent snapshot of the base over the copy-on-write device
+fn snapshot_table(sectors: u64, base: &str, cow: &str) -> String {
+    format!(
+        "0 {} snapshot {} {} N {}",
+        sectors, base, cow, CHUNK_SECTORS
+    )
+}
+
+async fn attach_loop(path: &Path, read_only: bool) -> Result<String, ExecuteError> {
+    let mut command = Command::new(LOSETUP_BINARY);
+    command.args(["--find", "--show"]);
+    if read_only {
+        command.arg("--read-only");
+    }
+    let output = command
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| ExecuteError::CommandExecution(format!("{}: {}", LOSETUP_BINARY, e)))?;
+    if !output.status.success() {
+        return Err(ExecuteError::CommandExecution(format!(
+            "{} {} failed: {}",
+            LOSETUP_BINARY,
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    debug!("Attached {} to {}", path.display(), device);
+    Ok(device)
+}
+
+async fn detach_loops(loops: &[String]) {
+    for device in loops.iter().rev() {
+        let result = Command::new(LOSETUP_BINARY)
+            .args(["--detach", device])
+            .status()
+            .await;
+        if !matches!(result, Ok(status) if status.success()) {
+            warn!("Failed to detach loop device {}", device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_table() {
+        assert_eq!(
+            snapshot_table(2097152, "/dev/loop0", "/dev/loop1"),
+            "0 2097152 snapshot /dev/loop0 /dev/loop1 N 8"
+        );
+        let device = OverlayDevice {
+            name: "firepilot-vm-rootfs".to_string(),
+            loops: vec!["/dev/loop0".to_string(), "/dev/loop1".to_string()],
+        };
+        assert_eq!(
+            device.path(),
+            PathBuf::from("/dev/mapper/firepilot-vm-rootfs")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_partial_sector() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = OverlayOrigin {
+            base: dir.path().join("base.ext4"),
+            cow_size: 1 << 20,
+        };
+        std::fs::write(&origin.base, [0; 1000]).unwrap();
+        let cow_path = dir.path().join("rootfs.cow");
+        assert!(origin
+            .create("firepilot-vm-rootfs", &cow_path)
+            .await
+            .is_err());
+        assert!(!cow_path.exists());
+    }
+}
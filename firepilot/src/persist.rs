@@ -0,0 +1,156 @@
+//! # Persisted state of a machine
+//!
+//! The firecracker process of a microVM outlives the process controlling it,
+//! but everything firepilot knows about it lives in memory. A
+//! [Machine](crate::machine::Machine) keeps what is needed to take it over in
+//! [STATE_FILE] in its workspace, so a new controller can
+//! [reattach](crate::machine::Machine::reattach) to it after a restart instead
+//! of leaving it orphaned:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use firepilot::machine::Machine;
+//! use firepilot::persist::STATE_FILE;
+//!
+//! # async fn example() {
+//! let state = Path::new("/tmp/firepilot/vm").join(STATE_FILE);
+//! let machine = Machine::reattach(&state).await.unwrap();
+//! println!("Machine is {:?}", machine.lifecycle());
+//! # }
+//! ```
+//!
+//! The devices created on the host for the microVM, like TAP devices or thin
+//! snapshots, and the drives staged outside of its workspace are persisted
+//! too, so they are removed when a reattached machine is killed or purged.
+
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    machine::{AppliedConfig, FirepilotError},
+    network::{nat::NatRules, tap::TapDevice},
+    overlay::OverlayDevice,
+    thin::ThinDevice,
+};
+
+/// Name of the persisted state in each workspace
+pub const STATE_FILE: &str = "machine.json";
+
+/// Binary spawned by a reattached machine when the persisted state doesn't
+/// tell, it is looked up in the `PATH`
+pub const DEFAULT_EXEC_BINARY: &str = "firecracker";
+
+/// What a new process needs to take over a machine, see [crate::persist]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedMachine {
+    pub vm_id: String,
+    /// Chroot of the executor, the workspace is the `vm_id` directory in it
+    pub chroot: PathBuf,
+    pub socket_path: PathBuf,
+    /// Pid of the firecracker process when the state was written
+    pub pid: Option<i32>,
+    /// Binary spawned again when the machine is rebooted, when it is known
+    pub exec_binary: Option<PathBuf>,
+    pub vsock_uds: Option<PathBuf>,
+    pub metrics_path: Option<PathBuf>,
+    pub allocated_ip: Option<Ipv4Addr>,
+    /// Configuration applied to firecracker, needed to reboot the machine
+    pub(crate) config: Option<AppliedConfig>,
+    /// Devices created on the host for the machine, missing from the states
+    /// written by earlier releases
+    #[serde(default)]
+    pub tap_devices: Vec<TapDevice>,
+    #[serde(default)]
+    pub nat_rules: Vec<NatRules>,
+    #[serde(default)]
+    pub thin_devices: Vec<ThinDevice>,
+    #[serde(default)]
+    pub overlay_devices: Vec<OverlayDevice>,
+    /// Copies of the drives staged outside of the workspace
+    #[serde(default)]
+    pub staged_copies: Vec<PathBuf>,
+}
+
+impl PersistedMachine {
+    /// Read the state written by [PersistedMachine::save]
+    ///
+    /// Until the state is read, errors are about the machine named after the
+    /// workspace the state is in.
+    pub fn load(path: &Path) -> Result<PersistedMachine, FirepilotError> {
+        let vm_id = path
+            .parent()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let content = std::fs::read(path).map_err(|e| {
+            FirepilotError::setup(&vm_id, format!("Failed to read {}", path.display()))
+                .with_source(e)
+        })?;
+        serde_json::from_slice(&content).map_err(|e| {
+            FirepilotError::setup(&vm_id, format!("Invalid machine state {}", path.display()))
+                .with_source(e)
+        })
+    }
+
+    /// Write the state to the given path, replacing the previous one at once
+    /// so a crash never leaves a truncated file behind
+    pub fn save(&self, path: &Path) -> Result<(), FirepilotError> {
+        let content = serde_json::to_vec_pretty(self).map_err(|e| {
+            FirepilotError::setup(&self.vm_id, "Failed to serialize state").with_source(e)
+        })?;
+        let written = path.with_extension("json.tmp");
+        std::fs::write(&written, content)
+            .and_then(|_| std::fs::rename(&written, path))
+            .map_err(|e| {
+                FirepilotError::setup(&self.vm_id, format!("Failed to write {}", path.display()))
+                    .with_source(e)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE);
+        let state = PersistedMachine {
+            vm_id: "vm".to_string(),
+            chroot: dir.path().to_path_buf(),
+            socket_path: dir.path().join("vm/firecracker.socket"),
+            pid: Some(42),
+            exec_binary: Some(PathBuf::from("/usr/bin/firecracker")),
+            vsock_uds: None,
+            metrics_path: None,
+            allocated_ip: Some(Ipv4Addr::new(172, 16, 0, 2)),
+            config: None,
+            tap_devices: Vec::new(),
+            nat_rules: Vec::new(),
+            thin_devices: vec![ThinDevice {
+                name: "firepilot-vm-rootfs".to_string(),
+                pool: "pool".to_string(),
+                dev_id: 2,
+            }],
+            overlay_devices: Vec::new(),
+            staged_copies: vec![PathBuf::from("/srv/staging/vm/data")],
+        };
+        state.save(&path).unwrap();
+        let loaded = PersistedMachine::load(&path).unwrap();
+        assert_eq!(loaded.vm_id, "vm");
+        assert_eq!(loaded.pid, Some(42));
+        assert_eq!(loaded.allocated_ip, state.allocated_ip);
+        assert_eq!(loaded.thin_devices, state.thin_devices);
+        assert_eq!(loaded.staged_copies, state.staged_copies);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(matches!(
+            PersistedMachine::load(&path),
+            Err(FirepilotError::Setup { .. })
+        ));
+    }
+}
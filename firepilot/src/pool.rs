@@ -0,0 +1,437 @@
+//! # Warm pool of microVMs
+//!
+//! Booting a microVM takes a few hundred milliseconds, too much for a request
+//! waiting for it. A [MachinePool] keeps a number of identical machines booted
+//! in advance and hands them out with [MachinePool::acquire]. The pool is
+//! refilled in the background each time a machine is handed out.
+//!
+//! Machines are created from a template, a function returning the
+//! [Configuration] of a machine given its id, as configurations can't be
+//! cloned. The [Scheduler] of the pool decides whether a machine can be
+//! created on the host and which idle machine is handed out.
+//!
+//! ```no_run
+//! use std::{path::PathBuf, sync::Arc};
+//! use firepilot::builder::{Builder, Configuration};
+//! use firepilot::builder::kernel::KernelBuilder;
+//! use firepilot::executor::{Executor, FirecrackerExecutor};
+//! use firepilot::pool::MachinePool;
+//! use firepilot::scheduler::Labels;
+//!
+//! # async fn example() {
+//! let pool = Arc::new(MachinePool::new(4, |id| {
+//!     let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+//!         chroot: "/tmp/firepilot".to_string(),
+//!         exec_binary: PathBuf::from("/usr/bin/firecracker"),
+//!     });
+//!     let kernel = KernelBuilder::new()
+//!         .with_kernel_image_path("/srv/vmlinux".to_string())
+//!         .with_boot_args("console=ttyS0 reboot=k panic=1".to_string())
+//!         .try_build()
+//!         .unwrap();
+//!     Configuration::new(id)
+//!         .with_kernel(kernel)
+//!         .with_executor(executor)
+//! }));
+//! pool.fill();
+//!
+//! let pooled = pool.acquire(&Labels::new()).await.unwrap();
+//! // ... run the workload in pooled.machine
+//! pool.release(pooled).await.unwrap();
+//! # }
+//! ```
+//!
+//! Released machines are purged rather than handed out again, nothing a
+//! workload left in a guest leaks to the next one. A [PooledMachine] dropped
+//! without being released is forgotten by the pool, its machine is killed as
+//! any dropped [Machine].
+//!
+//! Machines are booted rather than restored from a snapshot of the template:
+//! a restored microVM uses the drives and TAP devices of the snapshotted one
+//! as they were, see [Machine::from_snapshot], so all the machines restored
+//! from one snapshot would share them.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::{
+    builder::Configuration,
+    host,
+    machine::{FirepilotError, Machine, MachineState},
+    scheduler::{Candidate, Decision, FirstFit, Labels, PlacementRequest, Scheduler},
+};
+
+/// Prefix of the ids of the machines created by a pool when none is given
+pub const DEFAULT_POOL_PREFIX: &str = "pool";
+
+/// Function returning the configuration of a machine of the pool, given its id
+pub type Template = Box<dyn Fn(String) -> Configuration + Send + Sync>;
+
+/// Labels of the machines handed out, by id
+type Acquired = Arc<StdMutex<HashMap<String, Labels>>>;
+
+/// Machine handed out by a [MachinePool], to be given back with
+/// [MachinePool::release]
+#[derive(Debug)]
+pub struct PooledMachine {
+    pub id: String,
+    pub labels: Labels,
+    pub machine: Machine,
+    /// Machines handed out by the pool, which this one leaves when dropped.
+    /// None while it is idle.
+    acquired: Option<Acquired>,
+}
+
+impl Drop for PooledMachine {
+    fn drop(&mut self) {
+        if let Some(acquired) = self.acquired.take() {
+            acquired.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+/// Machines of the pool, idle or being created
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: Vec<PooledMachine>,
+    /// Labels of the machines being created, by id
+    booting: HashMap<String, Labels>,
+    /// Number of machines being created to refill the pool
+    creating: usize,
+}
+
+/// Pool of machines booted in advance, see [crate::pool]
+pub struct MachinePool {
+    size: usize,
+    template: Template,
+    prefix: String,
+    labels: Labels,
+    scheduler: Box<dyn Scheduler>,
+    state: Mutex<PoolState>,
+    acquired: Acquired,
+}
+
+impl std::fmt::Debug for MachinePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MachinePool")
+            .field("size", &self.size)
+            .field("prefix", &self.prefix)
+            .field("labels", &self.labels)
+            .field("scheduler", &self.scheduler)
+            .field("state", &self.state)
+            .field("acquired", &self.acquired)
+            .finish()
+    }
+}
+
+impl MachinePool {
+    /// Pool keeping `size` idle machines created from the template
+    pub fn new<F>(size: usize, template: F) -> MachinePool
+    where
+        F: Fn(String) -> Configuration + Send + Sync + 'static,
+    {
+        MachinePool {
+            size,
+            template: Box::new(template),
+            prefix: DEFAULT_POOL_PREFIX.to_string(),
+            labels: Labels::new(),
+            scheduler: Box::new(FirstFit),
+            state: Mutex::new(PoolState::default()),
+            acquired: Arc::default(),
+        }
+    }
+
+    /// Name the machines `<prefix>-<uuid>` instead of `pool-<uuid>`
+    pub fn with_prefix(mut self, prefix: String) -> MachinePool {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Labels of the machines of the pool, given to the scheduler
+    pub fn with_labels(mut self, labels: Labels) -> MachinePool {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_scheduler<S: Scheduler + 'static>(mut self, scheduler: S) -> MachinePool {
+        self.scheduler = Box::new(scheduler);
+        self
+    }
+
+    /// Number of idle machines ready to be handed out
+    pub async fn idle(&self) -> usize {
+        self.state.lock().await.idle.len()
+    }
+
+    /// Create the missing idle machines in the background
+    ///
+    /// The refill stops at the first machine which can't be created, it is
+    /// tried again on the next call.
+    pub fn fill(self: &Arc<Self>) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            loop {
+                {
+                    let mut state = pool.state.lock().await;
+                    if state.idle.len() + state.creating >= pool.size {
+                        return;
+                    }
+                    state.creating += 1;
+                }
+                let result = pool.boot(pool.new_id()).await;
+                let mut state = pool.state.lock().await;
+                state.creating -= 1;
+                match result {
+                    Ok(pooled) => {
+                        debug!("Machine {} is idle in the pool", pooled.id);
+                        state.booting.remove(&pooled.id);
+                        state.idle.push(pooled);
+                    }
+                    Err(e) => {
+                        warn!("Failed to refill the pool: {:?}", e);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Hand out an idle machine picked by the scheduler for the given labels,
+    /// or boot one if none is idle. The pool is refilled in the background.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        labels: &Labels,
+    ) -> Result<PooledMachine, FirepilotError> {
+        let picked = {
+            let mut state = self.state.lock().await;
+            let candidates: Vec<Candidate> = state
+                .idle
+                .iter()
+                .map(|pooled| Candidate {
+                    vm_id: &pooled.id,
+                    labels: &pooled.labels,
+                })
+                .collect();
+            match self.scheduler.select(labels, &candidates) {
+                Some(index) if index < state.idle.len() => Some(state.idle.remove(index)),
+                _ => None,
+            }
+        };
+        let booted = picked.is_none();
+        let mut pooled = match picked {
+            Some(pooled) => pooled,
+            None => {
+                debug!("No idle machine in the pool, booting one");
+                self.boot(self.new_id()).await?
+            }
+        };
+        self.acquired
+            .lock()
+            .unwrap()
+            .insert(pooled.id.clone(), pooled.labels.clone());
+        pooled.acquired = Some(self.acquired.clone());
+        if booted {
+            self.state.lock().await.booting.remove(&pooled.id);
+        }
+        info!("Machine {} acquired from the pool", pooled.id);
+        self.fill();
+        Ok(pooled)
+    }
+
+    /// Give back a machine handed out by [MachinePool::acquire], it is purged
+    /// and replaced by a new one in the background
+    pub async fn release(
+        self: &Arc<Self>,
+        mut pooled: PooledMachine,
+    ) -> Result<(), FirepilotError> {
+        info!("Machine {} released to the pool", pooled.id);
+        let result = pooled.machine.purge(false).await;
+        // Only counted as running until it is purged
+        drop(pooled);
+        self.fill();
+        result
+    }
+
+    /// Purge the idle machines, e.g. before shutting down
+    pub async fn drain(&self) -> Result<(), FirepilotError> {
+        let idle = std::mem::take(&mut self.state.lock().await.idle);
+        let mut result = Ok(());
+        for mut pooled in idle {
+            if let Err(e) = pooled.machine.purge(false).await {
+                warn!("Failed to purge idle machine {}: {:?}", pooled.id, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    fn new_id(&self) -> String {
+        format!("{}-{}", self.prefix, uuid::Uuid::new_v4().simple())
+    }
+
+    /// Create and start a machine, once the scheduler accepted it
+    async fn boot(&self, id: String) -> Result<PooledMachine, FirepilotError> {
+        let config = (self.template)(id.clone());
+        let (vcpu_count, mem_size_mib) = match &config.machine_config {
+            Some(machine_config) => (machine_config.vcpu_count, machine_config.mem_size_mib),
+            // Defaults of firecracker
+            None => (1, 128),
+        };
+        let host = host::stats().map_err(|e| FirepilotError::setup(&id, e))?;
+        {
+            let mut state = self.state.lock().await;
+            let acquired = self.acquired.lock().unwrap();
+            // The machines being created count as running, so concurrent
+            // creations don't all fit in the same room on the host
+            let running = state
+                .idle
+                .iter()
+                .map(|pooled| &pooled.labels)
+                .chain(state.booting.values())
+                .chain(acquired.values())
+                .collect();
+            let request = PlacementRequest {
+                vm_id: &id,
+                labels: &self.labels,
+                vcpu_count: vcpu_count.max(0) as u32,
+                mem_size_mib: mem_size_mib.max(0) as u64,
+                running,
+            };
+            if let Decision::Reject(reason) = self.scheduler.place(&request, &host) {
+                return Err(FirepilotError::Rejected { vm_id: id, reason });
+            }
+            drop(acquired);
+            state.booting.insert(id.clone(), self.labels.clone());
+        }
+        // Once booted, it is counted as booting until the caller keeps it as
+        // idle or acquired
+        let result = self.create(&id, config).await;
+        if result.is_err() {
+            self.state.lock().await.booting.remove(&id);
+        }
+        Ok(PooledMachine {
+            id,
+            labels: self.labels.clone(),
+            machine: result?,
+            acquired: None,
+        })
+    }
+
+    async fn create(&self, id: &str, mut config: Configuration) -> Result<Machine, FirepilotError> {
+        let mut machine = Machine::for_config(&mut config)?;
+        let result = match machine.create(config).await {
+            Ok(()) => machine.start().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if machine.lifecycle() != MachineState::Created {
+                let _ = machine.purge(false).await;
+            }
+            return Err(e);
+        }
+        debug!("Machine {} is booted", id);
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{host::HostStats, machine::testing::idle_machine};
+
+    /// Hands out the idle machine with the same tenant, and never creates any
+    #[derive(Debug)]
+    struct SameTenant;
+
+    impl Scheduler for SameTenant {
+        fn place(&self, _request: &PlacementRequest, _host: &HostStats) -> Decision {
+            Decision::Reject("full".to_string())
+        }
+
+        fn select(&self, labels: &Labels, candidates: &[Candidate]) -> Option<usize> {
+            candidates
+                .iter()
+                .position(|candidate| candidate.labels.get("tenant") == labels.get("tenant"))
+        }
+    }
+
+    fn tenant(name: &str) -> Labels {
+        Labels::from([("tenant".to_string(), name.to_string())])
+    }
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let pool = Arc::new(MachinePool::new(0, Configuration::new).with_scheduler(SameTenant));
+        for name in ["a", "b"] {
+            pool.state.lock().await.idle.push(PooledMachine {
+                id: name.to_string(),
+                labels: tenant(name),
+                machine: idle_machine(name),
+                acquired: None,
+            });
+        }
+
+        let pooled = pool.acquire(&tenant("b")).await.unwrap();
+        assert_eq!(pooled.id, "b");
+        assert_eq!(pool.idle().await, 1);
+        assert!(matches!(
+            pool.acquire(&tenant("b")).await,
+            Err(FirepilotError::Rejected { .. })
+        ));
+
+        // Dropped without being released, it isn't counted anymore
+        assert!(pool.acquired.lock().unwrap().contains_key("b"));
+        drop(pooled);
+        assert!(pool.acquired.lock().unwrap().is_empty());
+    }
+
+    /// Rejects every machine, after recording how many were running
+    #[derive(Debug, Default)]
+    struct CountRunning(Arc<StdMutex<Vec<usize>>>);
+
+    impl Scheduler for CountRunning {
+        fn place(&self, request: &PlacementRequest, _host: &HostStats) -> Decision {
+            self.0.lock().unwrap().push(request.running.len());
+            Decision::Reject("full".to_string())
+        }
+
+        fn select(&self, _labels: &Labels, _candidates: &[Candidate]) -> Option<usize> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_place_counts_booting_machines() {
+        let counts = Arc::new(StdMutex::new(Vec::new()));
+        let pool =
+            MachinePool::new(0, Configuration::new).with_scheduler(CountRunning(counts.clone()));
+        pool.state
+            .lock()
+            .await
+            .booting
+            .insert("booting".to_string(), Labels::new());
+        pool.acquired
+            .lock()
+            .unwrap()
+            .insert("acquired".to_string(), Labels::new());
+
+        assert!(pool.boot("new".to_string()).await.is_err());
+        assert_eq!(*counts.lock().unwrap(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_fill_stops_on_failure() {
+        // Without an executor, no machine can be created
+        let pool = Arc::new(MachinePool::new(2, Configuration::new));
+        pool.fill().await.unwrap();
+        assert_eq!(pool.idle().await, 0);
+        assert_eq!(pool.state.lock().await.creating, 0);
+    }
+}
@@ -0,0 +1,226 @@
+//! # Checks of the host before booting microVMs
+//!
+//! Firecracker needs KVM and a few kernel features from the host. When one is
+//! missing, the process exits right after being spawned and the reason is
+//! easily lost. [check] inspects the host up front and returns a
+//! [PreflightReport], which [Machine::create](crate::machine::Machine::create)
+//! turns into an error telling what to fix.
+//!
+//! ```no_run
+//! let report = firepilot::preflight::check();
+//! if !report.kvm.is_passed() {
+//!     eprintln!("{}", report);
+//! }
+//! ```
+use std::{
+    fmt,
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    builder::Configuration,
+    host::{KernelVersion, KERNEL_RELEASE_PATH},
+};
+
+/// Oldest host kernel supported by firecracker
+pub const MIN_HOST_KERNEL: KernelVersion = KernelVersion::new(4, 14, 0);
+
+/// Outcome of one check of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    /// The host lacks the feature, with what to do about it
+    Failed(String),
+    /// The feature couldn't be checked
+    Unknown(String),
+}
+
+impl CheckStatus {
+    pub fn is_passed(&self) -> bool {
+        matches!(self, CheckStatus::Passed)
+    }
+}
+
+/// What the host provides to firecracker, see [check]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightReport {
+    /// `/dev/kvm` exists and can be opened for reading and writing
+    pub kvm: CheckStatus,
+    /// Kernel of the host, when it could be read
+    pub kernel_version: Option<KernelVersion>,
+    /// The kernel of the host is at least [MIN_HOST_KERNEL]
+    pub kernel: CheckStatus,
+    /// `/dev/net/tun` exists, needed by network interfaces
+    pub tun: CheckStatus,
+    /// The `vhost_vsock` module is available. The vsock device of firecracker
+    /// is backed by Unix sockets, so it isn't required to boot.
+    pub vhost_vsock: CheckStatus,
+    /// KVM allows nested virtualization, so guests can run KVM themselves.
+    /// It isn't required to boot.
+    pub nested_virt: CheckStatus,
+}
+
+impl PreflightReport {
+    /// Failed checks preventing a microVM with the given configuration from
+    /// booting, by name
+    pub fn blocking(&self, config: &Configuration) -> Vec<(&'static str, &CheckStatus)> {
+        let mut required = vec![("kvm", &self.kvm), ("kernel", &self.kernel)];
+        if !config.interfaces.is_empty() {
+            required.push(("tun", &self.tun));
+        }
+        required
+            .into_iter()
+            .filter(|(_, status)| matches!(status, CheckStatus::Failed(_)))
+            .collect()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks = [
+            ("kvm", &self.kvm),
+            ("kernel", &self.kernel),
+            ("tun", &self.tun),
+            ("vhost_vsock", &self.vhost_vsock),
+            ("nested_virt", &self.nested_virt),
+        ];
+        for (name, status) in checks {
+            match status {
+                CheckStatus::Passed => writeln!(f, "{}: ok", name)?,
+                CheckStatus::Failed(reason) => writeln!(f, "{}: failed, {}", name, reason)?,
+                CheckStatus::Unknown(reason) => writeln!(f, "{}: unknown, {}", name, reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Inspect the host, see [crate::preflight]
+pub fn check() -> PreflightReport {
+    check_root(Path::new("/"))
+}
+
+/// Same as [check] with `/dev`, `/proc` and `/sys` found under `root`
+fn check_root(root: &Path) -> PreflightReport {
+    let path = |path: &str| -> PathBuf { root.join(path.trim_start_matches('/')) };
+
+    let kvm = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path("/dev/kvm"))
+    {
+        Ok(_) => CheckStatus::Passed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => CheckStatus::Failed(
+            "/dev/kvm doesn't exist, enable virtualization in the firmware and load the \
+             kvm_intel or kvm_amd module"
+                .to_string(),
+        ),
+        Err(e) => CheckStatus::Failed(format!(
+            "/dev/kvm can't be opened for reading and writing ({}), add the user to the kvm \
+             group or grant it access with `setfacl -m u:$USER:rw /dev/kvm`",
+            e
+        )),
+    };
+
+    let kernel_version = std::fs::read_to_string(path(KERNEL_RELEASE_PATH))
+        .map_err(|e| format!("Could not read kernel release: {}", e))
+        .and_then(|release| release.parse::<KernelVersion>());
+    let kernel = match &kernel_version {
+        Ok(version) if *version >= MIN_HOST_KERNEL => CheckStatus::Passed,
+        Ok(version) => CheckStatus::Failed(format!(
+            "kernel {} is older than {}, the oldest supported by firecracker",
+            version, MIN_HOST_KERNEL
+        )),
+        Err(e) => CheckStatus::Unknown(e.clone()),
+    };
+
+    let tun = if path("/dev/net/tun").exists() {
+        CheckStatus::Passed
+    } else {
+        CheckStatus::Failed(
+            "/dev/net/tun doesn't exist, load the module with `modprobe tun`".to_string(),
+        )
+    };
+
+    let vhost_vsock = if path("/dev/vhost-vsock").exists()
+        || path("/sys/module/vhost_vsock").exists()
+    {
+        CheckStatus::Passed
+    } else {
+        CheckStatus::Failed(
+            "the vhost_vsock module isn't loaded, load it with `modprobe vhost_vsock`".to_string(),
+        )
+    };
+
+    let nested = ["kvm_intel", "kvm_amd"].iter().find_map(|module| {
+        std::fs::read_to_string(path(&format!("/sys/module/{}/parameters/nested", module))).ok()
+    });
+    let nested_virt = match nested.as_deref().map(str::trim) {
+        Some("Y") | Some("1") => CheckStatus::Passed,
+        Some(_) => CheckStatus::Failed(
+            "nested virtualization is disabled, enable it with the `nested=1` parameter of the \
+             kvm_intel or kvm_amd module"
+                .to_string(),
+        ),
+        None => CheckStatus::Unknown("neither kvm_intel nor kvm_amd is loaded".to_string()),
+    };
+
+    PreflightReport {
+        kvm,
+        kernel_version: kernel_version.ok(),
+        kernel,
+        tun,
+        vhost_vsock,
+        nested_virt,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use firepilot_models::models::NetworkInterface;
+
+    #[test]
+    fn test_check_root() {
+        let root = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = root.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("proc/sys/kernel/osrelease", "4.9.337\n");
+        write("sys/module/kvm_intel/parameters/nested", "N\n");
+
+        let report = check_root(root.path());
+        assert!(matches!(report.kvm, CheckStatus::Failed(_)));
+        assert_eq!(report.kernel_version, Some(KernelVersion::new(4, 9, 337)));
+        assert!(matches!(report.kernel, CheckStatus::Failed(_)));
+        assert!(matches!(report.tun, CheckStatus::Failed(_)));
+        assert!(matches!(report.nested_virt, CheckStatus::Failed(_)));
+
+        write("dev/kvm", "");
+        write("dev/net/tun", "");
+        write("proc/sys/kernel/osrelease", "6.1.0-13-amd64\n");
+        write("sys/module/vhost_vsock/refcnt", "0\n");
+        let report = check_root(root.path());
+        assert_eq!(report.kvm, CheckStatus::Passed);
+        assert_eq!(report.kernel, CheckStatus::Passed);
+        assert_eq!(report.tun, CheckStatus::Passed);
+        assert_eq!(report.vhost_vsock, CheckStatus::Passed);
+
+        // The tun module is only required by network interfaces
+        let mut config = Configuration::new("preflight".to_string());
+        std::fs::remove_file(root.path().join("dev/net/tun")).unwrap();
+        let report = check_root(root.path());
+        assert!(report.blocking(&config).is_empty());
+        config.interfaces.push(NetworkInterface::new(
+            "tap0".to_string(),
+            "eth0".to_string(),
+        ));
+        let blocking = report.blocking(&config);
+        assert_eq!(blocking.len(), 1);
+        assert_eq!(blocking[0].0, "tun");
+    }
+}
@@ -0,0 +1,242 @@
+//! # Bookkeeping of many microVMs
+//!
+//! A [MachineRegistry] owns the machines of an orchestrator, keyed by their
+//! id, and runs the usual operations on them by id. All the machines of a
+//! registry share the same chroot: an id can only be used once in the
+//! registry, and not while another process holds the workspace with this id,
+//! see [crate::workspace].
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//! use firepilot::builder::Configuration;
+//! use firepilot::executor::{Executor, FirecrackerExecutor};
+//! use firepilot::registry::MachineRegistry;
+//!
+//! # async fn example(config: Configuration) {
+//! let registry = MachineRegistry::new("/tmp/firepilot");
+//! let executor = Executor::new_with_firecracker(FirecrackerExecutor {
+//!     chroot: "/tmp/firepilot".to_string(),
+//!     exec_binary: PathBuf::from("/usr/bin/firecracker"),
+//! });
+//! registry.create(config.with_executor(executor)).await.unwrap();
+//! for id in registry.list().await {
+//!     registry.start(&id).await.unwrap();
+//! }
+//! registry.purge_all(false).await.unwrap();
+//! # }
+//! ```
+//!
+//! Each machine is behind its own [MachineHandle], an operation on one machine
+//! doesn't wait for the operations on the others.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    builder::Configuration,
+    handle::MachineHandle,
+    machine::{FirepilotError, Machine, MachineState},
+    workspace,
+};
+
+/// Machines of an orchestrator, keyed by id, see [crate::registry]
+#[derive(Debug)]
+pub struct MachineRegistry {
+    chroot: PathBuf,
+    machines: Mutex<HashMap<String, MachineHandle>>,
+}
+
+impl MachineRegistry {
+    /// Registry of the machines whose executors use the given chroot
+    pub fn new<P: Into<PathBuf>>(chroot: P) -> MachineRegistry {
+        MachineRegistry {
+            chroot: chroot.into(),
+            machines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Chroot shared by the machines of the registry
+    pub fn chroot(&self) -> &Path {
+        &self.chroot
+    }
+
+    /// Create a machine from the configuration and keep it under its id
+    ///
+    /// The id is reserved before the machine is created, so a concurrent call
+    /// can't use it too. It is released if the creation fails.
+    pub async fn create(&self, mut config: Configuration) -> Result<MachineHandle, FirepilotError> {
+        let id = config.vm_id.clone();
+        // Another process may use the id in the same chroot
+        if workspace::is_locked(&self.chroot.join(&id)) {
+            return Err(already_exists(&id));
+        }
+        let machine = MachineHandle::new(Machine::for_config(&mut config)?);
+        let mut created = machine.lock().await;
+        self.reserve(&id, machine.clone()).await?;
+        if let Err(e) = created.create(config).await {
+            warn!("Failed to create machine {}: {:?}", id, e);
+            self.machines.lock().await.remove(&id);
+            return Err(e);
+        }
+        info!("Machine {} added to the registry", id);
+        drop(created);
+        Ok(machine)
+    }
+
+    /// Keep a machine created elsewhere, e.g. adopted with
+    /// [Machine::from_existing] which locks its workspace, under the given id
+    pub async fn insert(
+        &self,
+        id: &str,
+        machine: Machine,
+    ) -> Result<MachineHandle, FirepilotError> {
+        let machine = MachineHandle::new(machine);
+        self.reserve(id, machine.clone()).await?;
+        Ok(machine)
+    }
+
+    async fn reserve(&self, id: &str, machine: MachineHandle) -> Result<(), FirepilotError> {
+        let mut machines = self.machines.lock().await;
+        if machines.contains_key(id) {
+            return Err(already_exists(id));
+        }
+        machines.insert(id.to_string(), machine);
+        Ok(())
+    }
+
+    /// Ids of the machines, sorted
+    pub async fn list(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.machines.lock().await.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Machine with the given id
+    pub async fn get(&self, id: &str) -> Result<MachineHandle, FirepilotError> {
+        self.machines
+            .lock()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| FirepilotError::UnknownMachine {
+                vm_id: id.to_string(),
+            })
+    }
+
+    /// Forget about a machine and hand it over to the caller, it isn't
+    /// stopped
+    pub async fn remove(&self, id: &str) -> Result<MachineHandle, FirepilotError> {
+        self.machines
+            .lock()
+            .await
+            .remove(id)
+            .ok_or_else(|| FirepilotError::UnknownMachine {
+                vm_id: id.to_string(),
+            })
+    }
+
+    /// Start the machine with the given id, see [Machine::start]
+    pub async fn start(&self, id: &str) -> Result<(), FirepilotError> {
+        self.get(id).await?.start().await
+    }
+
+    /// Ask the guest of the machine with the given id to shut down, see
+    /// [Machine::stop]
+    pub async fn stop(&self, id: &str) -> Result<(), FirepilotError> {
+        self.get(id).await?.stop().await
+    }
+
+    /// Purge every machine and forget about them, see [Machine::purge]
+    ///
+    /// All the machines are purged even if some fail, the ones which failed
+    /// stay in the registry and the first error is returned.
+    pub async fn purge_all(&self, keep_logs: bool) -> Result<(), FirepilotError> {
+        let mut first_error = None;
+        for id in self.list().await {
+            let machine = match self.get(&id).await {
+                Ok(machine) => machine,
+                // Removed concurrently, nothing left to purge
+                Err(_) => continue,
+            };
+            let mut machine = machine.lock().await;
+            // A machine which was never created has nothing to remove
+            let result = match machine.lifecycle() {
+                MachineState::Created => Ok(()),
+                _ => machine.purge(keep_logs).await,
+            };
+            match result {
+                Ok(()) => {
+                    self.machines.lock().await.remove(&id);
+                }
+                Err(e) => {
+                    warn!("Failed to purge machine {}: {:?}", id, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+fn already_exists(id: &str) -> FirepilotError {
+    FirepilotError::AlreadyExists {
+        vm_id: id.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{machine::testing::idle_machine, workspace::WorkspaceLock};
+
+    #[tokio::test]
+    async fn test_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = MachineRegistry::new(dir.path());
+        registry.insert("web", idle_machine("web")).await.unwrap();
+        registry.insert("db", idle_machine("db")).await.unwrap();
+        assert!(matches!(
+            registry.insert("web", idle_machine("web")).await,
+            Err(FirepilotError::AlreadyExists { .. })
+        ));
+        assert_eq!(registry.list().await, vec!["db", "web"]);
+
+        assert!(matches!(
+            registry.start("web").await,
+            Err(FirepilotError::InvalidTransition { .. })
+        ));
+        assert!(matches!(
+            registry.stop("cache").await,
+            Err(FirepilotError::UnknownMachine { .. })
+        ));
+
+        registry.remove("db").await.unwrap();
+        registry.purge_all(false).await.unwrap();
+        assert!(registry.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_registry_locked_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("web");
+        std::fs::create_dir_all(&workspace).unwrap();
+        let _lock = WorkspaceLock::acquire(&workspace).unwrap();
+
+        let registry = MachineRegistry::new(dir.path());
+        let err = registry
+            .create(Configuration::new("web".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FirepilotError::AlreadyExists { .. }));
+        assert!(registry.list().await.is_empty());
+    }
+}
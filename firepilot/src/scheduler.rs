@@ -0,0 +1,133 @@
+//! # Placement of microVMs
+//!
+//! A pool of machines consults a [Scheduler] before creating a machine and
+//! when handing one out, so embedders can implement their own placement
+//! policy (bin-packing, NUMA awareness, tenant anti-affinity, quotas...)
+//! without changing the pool. [FirstFit] is used when none is given.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use firepilot::host::HostStats;
+//! use firepilot::scheduler::{Decision, PlacementRequest, Scheduler};
+//!
+//! /// Never run more than 4 microVMs per tenant
+//! #[derive(Debug)]
+//! struct TenantQuota;
+//!
+//! impl Scheduler for TenantQuota {
+//!     fn place(&self, request: &PlacementRequest, _host: &HostStats) -> Decision {
+//!         let tenant = request.labels.get("tenant");
+//!         let running = request
+//!             .running
+//!             .iter()
+//!             .filter(|labels| labels.get("tenant") == tenant)
+//!             .count();
+//!         match running < 4 {
+//!             true => Decision::Accept,
+//!             false => Decision::Reject("tenant quota reached".to_string()),
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use crate::host::HostStats;
+
+/// Labels attached to a machine, e.g. its tenant
+pub type Labels = HashMap<String, String>;
+
+/// Machine the pool is about to create
+#[derive(Debug, Clone)]
+pub struct PlacementRequest<'a> {
+    pub vm_id: &'a str,
+    pub labels: &'a Labels,
+    pub vcpu_count: u32,
+    pub mem_size_mib: u64,
+    /// Labels of the machines already managed by the pool
+    pub running: Vec<&'a Labels>,
+}
+
+/// Answer of a [Scheduler] to a [PlacementRequest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    /// The machine must not be created, with the reason given to the caller
+    Reject(String),
+}
+
+/// Idle machine which can be handed out by the pool
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub vm_id: &'a str,
+    pub labels: &'a Labels,
+}
+
+/// Placement policy consulted by a pool of machines
+pub trait Scheduler: std::fmt::Debug + Send + Sync {
+    /// Decide whether the machine can be created on this host
+    fn place(&self, request: &PlacementRequest, host: &HostStats) -> Decision;
+
+    /// Pick the idle machine handed out to a caller requesting the given
+    /// labels, by its index in `candidates`. The first one is picked by
+    /// default.
+    fn select(&self, _labels: &Labels, candidates: &[Candidate]) -> Option<usize> {
+        match candidates.is_empty() {
+            true => None,
+            false => Some(0),
+        }
+    }
+}
+
+/// Accept machines as long as the host has enough available memory for them,
+/// and hand out idle machines in order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstFit;
+
+impl Scheduler for FirstFit {
+    fn place(&self, request: &PlacementRequest, host: &HostStats) -> Decision {
+        match request.mem_size_mib <= host.mem_available_mib {
+            true => Decision::Accept,
+            false => Decision::Reject(format!(
+                "{} needs {} MiB but only {} MiB are available",
+                request.vm_id, request.mem_size_mib, host.mem_available_mib
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_fit() {
+        let host = HostStats {
+            cpus: 4,
+            mem_total_mib: 4096,
+            mem_available_mib: 1024,
+            load_average: 0.0,
+        };
+        let labels = Labels::new();
+        let request = |mem_size_mib| PlacementRequest {
+            vm_id: "vm",
+            labels: &labels,
+            vcpu_count: 1,
+            mem_size_mib,
+            running: Vec::new(),
+        };
+        assert_eq!(FirstFit.place(&request(512), &host), Decision::Accept);
+        assert!(matches!(
+            FirstFit.place(&request(2048), &host),
+            Decision::Reject(_)
+        ));
+
+        let candidates = [Candidate {
+            vm_id: "vm",
+            labels: &labels,
+        }];
+        assert_eq!(FirstFit.select(&labels, &candidates), Some(0));
+        assert_eq!(FirstFit.select(&labels, &[]), None);
+    }
+}
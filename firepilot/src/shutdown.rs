@@ -0,0 +1,90 @@
+//! # Shutdown strategies of a microVM
+//!
+//! Guests don't all react the same way to a shutdown request: an image with an
+//! agent can power off cleanly, a stock image only understands CtrlAltDel and a
+//! wedged kernel ignores everything. A [ShutdownPolicy] lists the steps to try
+//! in order, each one being given its own timeout before escalating to the
+//! next one. It is run by [Machine::shutdown](crate::machine::Machine::shutdown).
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use firepilot::shutdown::{ShutdownPolicy, ShutdownStep};
+//!
+//! let policy = ShutdownPolicy::new()
+//!     .then(ShutdownStep::Agent { port: 52, timeout: Duration::from_secs(5) })
+//!     .then(ShutdownStep::CtrlAltDel { timeout: Duration::from_secs(10) })
+//!     .then(ShutdownStep::Kill);
+//! ```
+
+use std::{path::PathBuf, time::Duration};
+
+/// Command written to the guest agent by [ShutdownStep::Agent]
+pub const AGENT_SHUTDOWN_COMMAND: &[u8] = b"SHUTDOWN\n";
+
+/// Default time given to the guest to react to CtrlAltDel
+pub const DEFAULT_CTRL_ALT_DEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A single attempt at stopping the microVM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownStep {
+    /// Ask a guest agent listening on the given vsock port to power off, by
+    /// writing [AGENT_SHUTDOWN_COMMAND], then wait for firecracker to exit
+    Agent { port: u32, timeout: Duration },
+    /// Send CtrlAltDel to the guest, then wait for firecracker to exit. The
+    /// guest must be booted with `reboot=k` so it exits instead of rebooting.
+    CtrlAltDel { timeout: Duration },
+    /// Pause the microVM and save a full snapshot of it before killing the
+    /// process, so a wedged guest can be inspected or resumed later
+    Snapshot {
+        snapshot_path: PathBuf,
+        mem_file_path: PathBuf,
+        timeout: Duration,
+    },
+    /// Kill the firecracker process, it can't fail to stop the microVM
+    Kill,
+}
+
+impl ShutdownStep {
+    /// Short name of the step, used in logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShutdownStep::Agent { .. } => "agent",
+            ShutdownStep::CtrlAltDel { .. } => "ctrl-alt-del",
+            ShutdownStep::Snapshot { .. } => "snapshot",
+            ShutdownStep::Kill => "kill",
+        }
+    }
+}
+
+/// Ordered list of [ShutdownStep] to try until the microVM is stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownPolicy {
+    pub steps: Vec<ShutdownStep>,
+}
+
+impl Default for ShutdownPolicy {
+    /// Send CtrlAltDel and kill the process if the guest didn't stop within
+    /// [DEFAULT_CTRL_ALT_DEL_TIMEOUT]
+    fn default() -> Self {
+        ShutdownPolicy::new()
+            .then(ShutdownStep::CtrlAltDel {
+                timeout: DEFAULT_CTRL_ALT_DEL_TIMEOUT,
+            })
+            .then(ShutdownStep::Kill)
+    }
+}
+
+impl ShutdownPolicy {
+    /// Create an empty policy
+    pub fn new() -> ShutdownPolicy {
+        ShutdownPolicy { steps: Vec::new() }
+    }
+
+    /// Add a step to try after the previous ones failed
+    pub fn then(mut self, step: ShutdownStep) -> ShutdownPolicy {
+        self.steps.push(step);
+        self
+    }
+}
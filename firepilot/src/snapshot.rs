@@ -0,0 +1,109 @@
+//! # Snapshots of a microVM
+//!
+//! A snapshot is made of two files: the state of the microVM (devices, vCPUs)
+//! and the content of the guest memory. It is created with
+//! [Machine::snapshot](crate::machine::Machine::snapshot), which describes the
+//! produced files with a [Snapshot].
+//!
+//! A diff snapshot only contains the memory pages written since the previous
+//! snapshot, the microVM must be configured to track dirty pages, see
+//! [MachineConfigurationBuilder::with_track_dirty_pages](crate::builder::machine_config::MachineConfigurationBuilder::with_track_dirty_pages).
+//!
+//! A new microVM is restored from a snapshot with
+//! [Machine::from_snapshot](crate::machine::Machine::from_snapshot), which
+//! skips the boot of the guest entirely.
+
+use std::path::{Path, PathBuf};
+
+pub use firepilot_models::models::snapshot_create_params::SnapshotType;
+use firepilot_models::models::{SnapshotCreateParams, SnapshotLoadParams};
+
+use crate::version::VmmVersion;
+
+/// Name of the microVM state file in a snapshot directory
+pub const SNAPSHOT_FILE: &str = "vmstate";
+
+/// Name of the guest memory file in a snapshot directory
+pub const MEM_FILE: &str = "memory";
+
+/// Options used when restoring a microVM from a [Snapshot]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RestoreOptions {
+    /// Resume the microVM once loaded, otherwise it stays paused
+    pub resume_vm: bool,
+    /// Track dirty pages of the restored microVM, so diff snapshots can be
+    /// created from it
+    pub enable_diff_snapshots: bool,
+}
+
+/// Files produced by a snapshot of a microVM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// State of the microVM
+    pub snapshot_path: PathBuf,
+    /// Content of the guest memory, only the dirty pages for a diff snapshot
+    pub mem_file_path: PathBuf,
+    pub snapshot_type: SnapshotType,
+    /// Version of firecracker which created the snapshot, when it is known
+    pub vmm_version: Option<VmmVersion>,
+}
+
+impl Snapshot {
+    /// Snapshot stored as [SNAPSHOT_FILE] and [MEM_FILE] in the given
+    /// directory
+    pub fn in_dir(dir: &Path, snapshot_type: SnapshotType) -> Snapshot {
+        Snapshot {
+            snapshot_path: dir.join(SNAPSHOT_FILE),
+            mem_file_path: dir.join(MEM_FILE),
+            snapshot_type,
+            vmm_version: None,
+        }
+    }
+
+    /// Parameters of `PUT /snapshot/create` producing this snapshot
+    pub(crate) fn create_params(&self) -> SnapshotCreateParams {
+        SnapshotCreateParams {
+            snapshot_type: Some(self.snapshot_type),
+            ..SnapshotCreateParams::new(
+                self.mem_file_path.to_string_lossy().into_owned(),
+                self.snapshot_path.to_string_lossy().into_owned(),
+            )
+        }
+    }
+
+    /// Parameters of `PUT /snapshot/load` restoring this snapshot
+    pub(crate) fn load_params(&self, options: RestoreOptions) -> SnapshotLoadParams {
+        SnapshotLoadParams {
+            mem_file_path: Some(self.mem_file_path.to_string_lossy().into_owned()),
+            resume_vm: Some(options.resume_vm),
+            enable_diff_snapshots: Some(options.enable_diff_snapshots),
+            ..SnapshotLoadParams::new(self.snapshot_path.to_string_lossy().into_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_in_dir() {
+        let snapshot = Snapshot::in_dir(Path::new("/srv/snapshots/web"), SnapshotType::Diff);
+        let params = snapshot.create_params();
+        assert_eq!(params.snapshot_path, "/srv/snapshots/web/vmstate");
+        assert_eq!(params.mem_file_path, "/srv/snapshots/web/memory");
+        assert_eq!(params.snapshot_type, Some(SnapshotType::Diff));
+
+        let params = snapshot.load_params(RestoreOptions {
+            resume_vm: true,
+            ..Default::default()
+        });
+        assert_eq!(params.snapshot_path, "/srv/snapshots/web/vmstate");
+        assert_eq!(
+            params.mem_file_path.as_deref(),
+            Some("/srv/snapshots/web/memory")
+        );
+        assert_eq!(params.resume_vm, Some(true));
+        assert_eq!(params.enable_diff_snapshots, Some(false));
+    }
+}
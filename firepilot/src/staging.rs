@@ -0,0 +1,435 @@
+//! # Staging of the artifacts in the workspace
+//!
+//! [Machine::create](crate::machine::Machine::create) stages the kernel, the
+//! initrd and the drives in the workspace of the machine. A full copy of a
+//! multi-GB rootfs is slow, a [CopyStrategy] given to
+//! [Configuration::with_copy_strategy](crate::builder::Configuration::with_copy_strategy)
+//! picks a cheaper way when the host allows it, and falls back to a copy
+//! otherwise. Copies keep the holes of sparse images, see [sparse_copy].
+//!
+//! Artifacts are staged concurrently on the blocking thread pool of tokio,
+//! each one is reported as a [StagedArtifact] once done, see
+//! [Configuration::with_staging_progress](crate::builder::Configuration::with_staging_progress).
+//!
+//! Hard links, symbolic links and in-place references share the original
+//! file with the microVM. They are only used for read-only drives and the
+//! kernel, writable drives fall back to a copy so the original isn't modified
+//! by the guest.
+
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind},
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::{Component, Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::copy_file_range,
+    unistd::{lseek64, Whence},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, info, warn};
+
+/// Binary used to clone files, it issues `FICLONE` on reflink-capable
+/// filesystems (btrfs, xfs, bcachefs...)
+pub const CP_BINARY: &str = "cp";
+
+/// How an artifact is staged in the workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyStrategy {
+    /// Copy every byte of the file
+    Copy,
+    /// Hard link the file, it must be on the same filesystem as the workspace
+    Hardlink,
+    /// Clone the file, only its metadata is copied until either side is
+    /// written. It requires a reflink-capable filesystem.
+    Reflink,
+    /// Symbolic link to the file
+    Symlink,
+    /// Don't stage the file, firecracker is given its original path
+    InPlace,
+}
+
+impl Default for CopyStrategy {
+    fn default() -> Self {
+        CopyStrategy::Copy
+    }
+}
+
+impl CopyStrategy {
+    /// Whether the microVM works on the original file with this strategy
+    pub fn shares_source(&self) -> bool {
+        matches!(
+            self,
+            CopyStrategy::Hardlink | CopyStrategy::Symlink | CopyStrategy::InPlace
+        )
+    }
+
+    /// Strategy used for an artifact, writable ones are never shared
+    pub(crate) fn for_artifact(&self, writable: bool) -> CopyStrategy {
+        match writable && self.shares_source() {
+            true => CopyStrategy::Copy,
+            false => *self,
+        }
+    }
+}
+
+/// Artifact staged in the workspace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedArtifact {
+    /// `kernel`, `initrd` or the id of the drive
+    pub name: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Strategy actually used, after any fallback
+    pub strategy: CopyStrategy,
+    /// Size of the source in bytes
+    pub size: u64,
+    /// Bytes actually copied, holes of sparse images are not copied and
+    /// nothing is copied when the file is shared or cloned
+    pub copied: u64,
+    pub elapsed: Duration,
+}
+
+/// Check the name of an artifact is a single file name, e.g. the id of a
+/// drive, so the files named after it can't escape the workspace
+pub(crate) fn check_file_name(name: &str) -> io::Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not a valid file name", name),
+        )),
+    }
+}
+
+/// Artifact to stage, see [stage_all]
+#[derive(Debug, Clone)]
+pub(crate) struct StagingJob {
+    pub(crate) name: String,
+    pub(crate) source: PathBuf,
+    pub(crate) target: PathBuf,
+    pub(crate) strategy: CopyStrategy,
+}
+
+impl StagingJob {
+    fn run(self) -> io::Result<StagedArtifact> {
+        let started = Instant::now();
+        let context = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to copy {:?} to {:?}: {}",
+                    self.source, self.target, e
+                ),
+            )
+        };
+        let size = fs::metadata(&self.source).map_err(context)?.len();
+        let (strategy, copied) =
+            stage(&self.source, &self.target, self.strategy).map_err(context)?;
+        Ok(StagedArtifact {
+            name: self.name,
+            source: self.source,
+            target: self.target,
+            strategy,
+            size,
+            copied,
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Stage the artifacts concurrently, they are returned in the same order as
+/// the jobs. Each artifact is sent to `progress` as soon as it is staged.
+pub(crate) async fn stage_all(
+    jobs: Vec<StagingJob>,
+    progress: Option<&UnboundedSender<StagedArtifact>>,
+) -> io::Result<Vec<StagedArtifact>> {
+    let handles: Vec<_> = jobs
+        .into_iter()
+        .map(|job| {
+            let progress = progress.cloned();
+            tokio::task::spawn_blocking(move || {
+                let staged = job.run()?;
+                info!(
+                    "Staged {} ({} bytes, {} copied) with {:?} in {:?}",
+                    staged.name, staged.size, staged.copied, staged.strategy, staged.elapsed
+                );
+                if let Some(progress) = progress {
+                    // The receiver may be gone, the progress is only informative
+                    let _ = progress.send(staged.clone());
+                }
+                Ok(staged)
+            })
+        })
+        .collect();
+    // Every job is waited for, so none is left writing in the workspace
+    let mut staged = Vec::with_capacity(handles.len());
+    let mut error = None;
+    for handle in handles {
+        match handle.await {
+            Ok(Ok(artifact)) => staged.push(artifact),
+            Ok(Err(e)) => error = error.or(Some(e)),
+            Err(e) => error = error.or_else(|| Some(io::Error::new(ErrorKind::Other, e))),
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(staged),
+    }
+}
+
+/// Stage `from` at `to` with the given strategy, falling back to a copy when
+/// the strategy can't be used. The strategy actually used is returned along
+/// with the number of bytes copied, with [CopyStrategy::InPlace] nothing is
+/// created at `to`.
+pub(crate) fn stage(
+    from: &Path,
+    to: &Path,
+    strategy: CopyStrategy,
+) -> io::Result<(CopyStrategy, u64)> {
+    let result = match strategy {
+        CopyStrategy::Copy | CopyStrategy::InPlace => return copy_or_skip(from, to, strategy),
+        CopyStrategy::Hardlink => fs::hard_link(from, to),
+        CopyStrategy::Symlink => std::os::unix::fs::symlink(fs::canonicalize(from)?, to),
+        CopyStrategy::Reflink => reflink(from, to),
+    };
+    match result {
+        Ok(()) => {
+            debug!("Staged {:?} at {:?} with {:?}", from, to, strategy);
+            Ok((strategy, 0))
+        }
+        Err(e) => {
+            warn!(
+                "Can't stage {:?} with {:?}, copying it instead: {}",
+                from, strategy, e
+            );
+            // A partial clone may have been left behind
+            let _ = fs::remove_file(to);
+            copy_or_skip(from, to, CopyStrategy::Copy)
+        }
+    }
+}
+
+fn copy_or_skip(from: &Path, to: &Path, strategy: CopyStrategy) -> io::Result<(CopyStrategy, u64)> {
+    match strategy {
+        CopyStrategy::Copy => Ok((strategy, sparse_copy(from, to)?)),
+        _ => Ok((strategy, 0)),
+    }
+}
+
+/// Copy the file without filling its holes, so a sparse image stays sparse.
+/// Only the data regions are copied, the number of bytes copied is returned.
+pub fn sparse_copy(from: &Path, to: &Path) -> io::Result<u64> {
+    let source = File::open(from)?;
+    let target = File::create(to)?;
+    let len = source.metadata()?.len();
+    target.set_len(len)?;
+    target.set_permissions(source.metadata()?.permissions())?;
+
+    let mut copied = 0;
+    let mut offset = 0;
+    while offset < len {
+        let start = match lseek64(source.as_raw_fd(), offset as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // No data after the offset, the rest is a hole
+            Err(Errno::ENXIO) => break,
+            // The filesystem can't tell holes apart, copy everything
+            Err(_) => offset,
+        };
+        let end = match lseek64(source.as_raw_fd(), start as i64, Whence::SeekHole) {
+            Ok(end) => (end as u64).min(len),
+            Err(_) => len,
+        };
+        copy_range(&source, &target, start, end)?;
+        copied += end - start;
+        offset = end;
+    }
+    Ok(copied)
+}
+
+/// Copy a range of the file at the same offset, in the kernel when possible
+fn copy_range(source: &File, target: &File, start: u64, end: u64) -> io::Result<()> {
+    let mut offset = start;
+    while offset < end {
+        let mut off_in = offset as i64;
+        let mut off_out = offset as i64;
+        let remaining = (end - offset) as usize;
+        match copy_file_range(
+            source.as_raw_fd(),
+            Some(&mut off_in),
+            target.as_raw_fd(),
+            Some(&mut off_out),
+            remaining,
+        ) {
+            Ok(0) => break,
+            Ok(written) => offset += written as u64,
+            // Not supported across these filesystems, copy through userspace
+            Err(Errno::EXDEV | Errno::ENOSYS | Errno::EINVAL | Errno::EOPNOTSUPP) => {
+                return copy_range_userspace(source, target, offset, end);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn copy_range_userspace(source: &File, target: &File, start: u64, end: u64) -> io::Result<()> {
+    let mut buffer = vec![0; 1 << 20];
+    let mut offset = start;
+    while offset < end {
+        let len = buffer.len().min((end - offset) as usize);
+        let read = source.read_at(&mut buffer[..len], offset)?;
+        if read == 0 {
+            break;
+        }
+        target.write_all_at(&buffer[..read], offset)?;
+        offset += read as u64;
+    }
+    Ok(())
+}
+
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    let output = Command::new(CP_BINARY)
+        .arg("--reflink=always")
+        .arg(from)
+        .arg(to)
+        .output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file_name() {
+        assert!(check_file_name("rootfs").is_ok());
+        assert!(check_file_name("rootfs.ext4").is_ok());
+        for name in ["", ".", "..", "../rootfs", "/rootfs", "data/rootfs"] {
+            assert_eq!(
+                check_file_name(name).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn test_stage() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("rootfs");
+        fs::write(&source, b"rootfs").unwrap();
+
+        for (strategy, name) in [
+            (CopyStrategy::Copy, "copy"),
+            (CopyStrategy::Hardlink, "hardlink"),
+            (CopyStrategy::Symlink, "symlink"),
+        ] {
+            let target = dir.path().join(name);
+            assert_eq!(stage(&source, &target, strategy).unwrap().0, strategy);
+            assert_eq!(fs::read(&target).unwrap(), b"rootfs");
+        }
+        assert!(fs::symlink_metadata(dir.path().join("symlink"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        // Reflinks are not supported by every filesystem, the copy is the fallback
+        let target = dir.path().join("reflink");
+        stage(&source, &target, CopyStrategy::Reflink).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"rootfs");
+
+        let target = dir.path().join("in-place");
+        assert_eq!(
+            stage(&source, &target, CopyStrategy::InPlace).unwrap(),
+            (CopyStrategy::InPlace, 0)
+        );
+        assert!(!target.exists());
+
+        // Links can't cross filesystems, or point to a missing file
+        let missing = dir.path().join("missing");
+        assert!(stage(&missing, &dir.path().join("other"), CopyStrategy::Hardlink).is_err());
+    }
+
+    #[test]
+    fn test_sparse_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("rootfs");
+        let file = File::create(&source).unwrap();
+        file.set_len(64 << 20).unwrap();
+        file.write_all_at(&[0xab; 4096], 1 << 20).unwrap();
+        file.write_all_at(b"end", (64 << 20) - 3).unwrap();
+        drop(file);
+
+        let target = dir.path().join("copy");
+        let copied = sparse_copy(&source, &target).unwrap();
+        assert_eq!(fs::read(&source).unwrap(), fs::read(&target).unwrap());
+        // Filesystems without hole detection copy everything, e.g. some tmpfs
+        if fs::metadata(&source).unwrap().blocks() < 1024 {
+            assert!(copied < 1 << 20, "{} bytes copied", copied);
+            assert!(fs::metadata(&target).unwrap().blocks() < 1024);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stage_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let job = |name: &str| {
+            fs::write(dir.path().join(name), name).unwrap();
+            StagingJob {
+                name: name.to_string(),
+                source: dir.path().join(name),
+                target: dir.path().join(format!("{}.staged", name)),
+                strategy: CopyStrategy::Copy,
+            }
+        };
+        let jobs = vec![job("kernel"), job("rootfs")];
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let staged = stage_all(jobs, Some(&sender)).await.unwrap();
+        assert_eq!(staged[0].name, "kernel");
+        assert_eq!(staged[1].size, 6);
+        assert_eq!(staged[1].copied, 6);
+        assert_eq!(
+            fs::read(dir.path().join("rootfs.staged")).unwrap(),
+            b"rootfs"
+        );
+        let mut reported = vec![
+            receiver.recv().await.unwrap().name,
+            receiver.recv().await.unwrap().name,
+        ];
+        reported.sort();
+        assert_eq!(reported, vec!["kernel", "rootfs"]);
+
+        let mut missing = job("initrd");
+        missing.source = dir.path().join("missing");
+        assert!(stage_all(vec![job("kernel"), missing], None).await.is_err());
+    }
+
+    #[test]
+    fn test_writable_artifacts() {
+        assert_eq!(
+            CopyStrategy::Hardlink.for_artifact(true),
+            CopyStrategy::Copy
+        );
+        assert_eq!(
+            CopyStrategy::Hardlink.for_artifact(false),
+            CopyStrategy::Hardlink
+        );
+        assert_eq!(
+            CopyStrategy::Reflink.for_artifact(true),
+            CopyStrategy::Reflink
+        );
+    }
+}
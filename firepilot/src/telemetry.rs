@@ -0,0 +1,167 @@
+//! # Operational metrics of firepilot
+//!
+//! Counters and latency histograms about the behaviour of the library itself,
+//! as opposed to the metrics emitted by firecracker for a given microVM. They
+//! are process-wide and always collected, call [snapshot] to read them and
+//! forward them to your own monitoring system.
+//!
+//! ## Example
+//!
+//! ```rust
+//! let metrics = firepilot::telemetry::snapshot();
+//! println!(
+//!     "{} microVMs created, {} running, {} failed",
+//!     metrics.vms_created, metrics.active_vms, metrics.vms_failed
+//! );
+//! ```
+
+use std::{
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds, in milliseconds, of the histogram buckets. A last implicit
+/// bucket holds everything above the last bound.
+pub const LATENCY_BUCKETS_MS: [u64; 9] = [1, 5, 10, 50, 100, 500, 1_000, 5_000, 10_000];
+
+const BUCKET_COUNT: usize = LATENCY_BUCKETS_MS.len() + 1;
+
+static VMS_CREATED: AtomicU64 = AtomicU64::new(0);
+static VMS_FAILED: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_VMS: AtomicI64 = AtomicI64::new(0);
+static CREATE_LATENCY: Histogram = Histogram::new();
+static REQUEST_LATENCY: Histogram = Histogram::new();
+
+/// Lock-free histogram with fixed buckets
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            buckets: [ZERO; BUCKET_COUNT],
+            count: ZERO,
+            sum_us: ZERO,
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let ms = duration.as_millis();
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= u128::from(*bound))
+            .unwrap_or(BUCKET_COUNT - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut buckets = [0; BUCKET_COUNT];
+        for (value, bucket) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *value = bucket.load(Ordering::Relaxed);
+        }
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Point-in-time copy of a latency histogram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Number of observations per bucket, bounds are given by
+    /// [LATENCY_BUCKETS_MS] and the last bucket is unbounded
+    pub buckets: [u64; BUCKET_COUNT],
+    /// Total number of observations
+    pub count: u64,
+    /// Sum of all observations
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    /// Average of all observations, if any
+    pub fn mean(&self) -> Option<Duration> {
+        self.sum.checked_div(u32::try_from(self.count).ok()?)
+    }
+}
+
+/// Point-in-time copy of all firepilot metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Machines successfully created
+    pub vms_created: u64,
+    /// Machines which failed to be created
+    pub vms_failed: u64,
+    /// Firecracker processes currently spawned and not destroyed
+    pub active_vms: i64,
+    /// Time taken by [Machine::create](crate::machine::Machine::create)
+    pub create_latency: HistogramSnapshot,
+    /// Round-trip time of requests sent to the API socket
+    pub request_latency: HistogramSnapshot,
+}
+
+/// Read the current value of all metrics
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        vms_created: VMS_CREATED.load(Ordering::Relaxed),
+        vms_failed: VMS_FAILED.load(Ordering::Relaxed),
+        active_vms: ACTIVE_VMS.load(Ordering::Relaxed),
+        create_latency: CREATE_LATENCY.snapshot(),
+        request_latency: REQUEST_LATENCY.snapshot(),
+    }
+}
+
+pub(crate) fn record_create(duration: Duration, success: bool) {
+    match success {
+        true => VMS_CREATED.fetch_add(1, Ordering::Relaxed),
+        false => VMS_FAILED.fetch_add(1, Ordering::Relaxed),
+    };
+    CREATE_LATENCY.record(duration);
+}
+
+pub(crate) fn record_request(duration: Duration) {
+    REQUEST_LATENCY.record(duration);
+}
+
+pub(crate) fn vm_spawned() {
+    ACTIVE_VMS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn vm_destroyed() {
+    ACTIVE_VMS.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::from_micros(200));
+        histogram.record(Duration::from_millis(7));
+        histogram.record(Duration::from_millis(10));
+        histogram.record(Duration::from_secs(60));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.buckets[0], 1);
+        assert_eq!(snapshot.buckets[2], 2);
+        assert_eq!(snapshot.buckets[BUCKET_COUNT - 1], 1);
+        assert_eq!(
+            snapshot.sum,
+            Duration::from_micros(200) + Duration::from_millis(17) + Duration::from_secs(60)
+        );
+        assert_eq!(Histogram::new().snapshot().mean(), None);
+    }
+}
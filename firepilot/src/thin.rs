@@ -0,0 +1,183 @@
+//! # Device-mapper thin-provisioned drives
+//!
+//! On hosts without a reflink-capable filesystem, copying a rootfs for every
+//! microVM is slow and wastes space. A device-mapper thin pool can instead give
+//! each machine a writable snapshot of a base volume, created in constant time
+//! and only storing the blocks written by the guest.
+//!
+//! Snapshots are created with `dmsetup` when the machine is created, see
+//! [DriveBuilder::with_thin_snapshot](crate::builder::drive::DriveBuilder::with_thin_snapshot),
+//! and deleted from the pool when the machine is killed. The base volume must
+//! not be active while snapshots are taken.
+
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::executor::ExecuteError;
+
+/// Binary used to manage device-mapper devices
+pub const DMSETUP_BINARY: &str = "dmsetup";
+
+/// Directory where device-mapper exposes active devices
+pub const DEVICE_MAPPER_DIR: &str = "/dev/mapper";
+
+/// Number of thin device ids tried before giving up on creating a snapshot
+const MAX_ALLOCATION_ATTEMPTS: u32 = 16;
+
+/// Base volume of a thin pool, snapshotted for each microVM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThinOrigin {
+    /// Name of the thin pool device, as shown by `dmsetup ls`
+    pub pool: String,
+    /// Thin device id of the base volume in the pool
+    pub origin_id: u32,
+    /// Size of the base volume in bytes
+    pub size: u64,
+}
+
+/// Writable snapshot of a [ThinOrigin] owned by a microVM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThinDevice {
+    /// Name of the activated device, under [DEVICE_MAPPER_DIR]
+    pub name: String,
+    /// Name of the thin pool the snapshot lives in
+    pub pool: String,
+    /// Thin device id of the snapshot in the pool
+    pub dev_id: u32,
+}
+
+impl ThinOrigin {
+    /// Take a snapshot of the base volume and activate it under the given name
+    ///
+    /// The snapshot id is picked after the ones already active, other ids are
+    /// tried when the pool reports it is taken.
+    pub(crate) async fn snapshot(&self, name: &str) -> Result<ThinDevice, ExecuteError> {
+        let pool = pool_path(&self.pool);
+        let tables = dmsetup(&["table", "--target", "thin"]).await?;
+        let mut dev_id = next_dev_id(&tables, self.origin_id);
+        let mut attempts = 0;
+        loop {
+            let message = format!("create_snap {} {}", dev_id, self.origin_id);
+            match dmsetup(&["message", &pool, "0", &message]).await {
+                Ok(_) => break,
+                Err(e) if attempts + 1 < MAX_ALLOCATION_ATTEMPTS => {
+                    debug!("Thin device id {} is not available: {}", dev_id, e);
+                    dev_id += 1;
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let device = ThinDevice {
+            name: name.to_string(),
+            pool: self.pool.clone(),
+            dev_id,
+        };
+        let table = format!("0 {} thin {} {}", self.size / 512, pool, dev_id);
+        if let Err(e) = dmsetup(&["create", name, "--table", &table]).await {
+            // The activation failure is what the caller needs to know
+            if let Err(cleanup) = device.delete().await {
+                warn!(
+                    "Failed to delete thin device {} of {}: {}",
+                    dev_id, self.pool, cleanup
+                );
+            }
+            return Err(e);
+        }
+        info!("Created thin snapshot {} of {}", name, self.pool);
+        Ok(device)
+    }
+}
+
+impl ThinDevice {
+    /// Path of the block device given to firecracker
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(DEVICE_MAPPER_DIR).join(&self.name)
+    }
+
+    /// Deactivate the snapshot and release its blocks in the pool
+    pub(crate) async fn remove(&self) -> Result<(), ExecuteError> {
+        dmsetup(&["remove", &self.name]).await?;
+        self.delete().await?;
+        info!("Removed thin snapshot {}", self.name);
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<(), ExecuteError> {
+        let message = format!("delete {}", self.dev_id);
+        dmsetup(&["message", &pool_path(&self.pool), "0", &message]).await?;
+        Ok(())
+    }
+}
+
+fn pool_path(pool: &str) -> String {
+    format!("{}/{}", DEVICE_MAPPER_DIR, pool)
+}
+
+/// First thin device id after the origin and the ones used by active devices,
+/// from the output of `dmsetup table --target thin`
+fn next_dev_id(tables: &str, origin_id: u32) -> u32 {
+    tables
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            match (fields.nth(2), fields.nth(1)) {
+                (Some("thin"), Some(dev_id)) => dev_id.parse::<u32>().ok(),
+                _ => None,
+            }
+        })
+        .chain(std::iter::once(origin_id))
+        .max()
+        .unwrap_or(origin_id)
+        + 1
+}
+
+pub(crate) async fn dmsetup(args: &[&str]) -> Result<String, ExecuteError> {
+    debug!("Running {} {}", DMSETUP_BINARY, args.join(" "));
+    let output = Command::new(DMSETUP_BINARY)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| ExecuteError::CommandExecution(format!("{}: {}", DMSETUP_BINARY, e)))?;
+    if !output.status.success() {
+        return Err(ExecuteError::CommandExecution(format!(
+            "{} {} failed: {}",
+            DMSETUP_BINARY,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_dev_id() {
+        let tables = "\
+vm-a-rootfs: 0 2097152 thin 253:2 4
+vm-b-rootfs: 0 2097152 thin 253:2 7
+";
+        assert_eq!(next_dev_id(tables, 1), 8);
+        assert_eq!(next_dev_id(tables, 12), 13);
+        assert_eq!(next_dev_id("No devices found\n", 1), 2);
+    }
+
+    #[test]
+    fn test_device_path() {
+        let device = ThinDevice {
+            name: "firepilot-vm-rootfs".to_string(),
+            pool: "pool".to_string(),
+            dev_id: 2,
+        };
+        assert_eq!(
+            device.path(),
+            PathBuf::from("/dev/mapper/firepilot-vm-rootfs")
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! # Firecracker versions and capabilities
+//!
+//! Some features of firecracker are only available from a given release. The
+//! version of the running process is fetched from the API when the socket
+//! starts, so requests for unsupported features can be rejected with a clear
+//! error instead of a generic `400 Bad Request` from the VMM.
+use std::{fmt, str::FromStr};
+
+/// Version of the firecracker API the models of `firepilot_models` are
+/// generated from, it follows the `firecracker-*` features. Older releases, or
+/// releases with another major version, may not understand the requests sent
+/// by this crate.
+pub const MODELS_VERSION: VmmVersion = VmmVersion::new(1, MODELS_MINOR, 0);
+
+#[cfg(not(feature = "firecracker-1-4"))]
+const MODELS_MINOR: u32 = 3;
+#[cfg(all(feature = "firecracker-1-4", not(feature = "firecracker-1-7")))]
+const MODELS_MINOR: u32 = 4;
+#[cfg(all(feature = "firecracker-1-7", not(feature = "firecracker-1-10")))]
+const MODELS_MINOR: u32 = 7;
+#[cfg(feature = "firecracker-1-10")]
+const MODELS_MINOR: u32 = 10;
+
+/// Version of a firecracker binary, following semantic versioning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VmmVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl VmmVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> VmmVersion {
+        VmmVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Tells whether this version of firecracker supports the given feature
+    pub fn supports(&self, feature: VmmFeature) -> bool {
+        *self >= feature.minimum_version()
+    }
+}
+
+impl fmt::Display for VmmVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for VmmVersion {
+    type Err = String;
+
+    /// Parse a version as given by `GET /version` (`1.3.0`) or by the binary
+    /// (`Firecracker v1.3.0`), build metadata (`1.4.0-dev`) is ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw = s
+            .split_whitespace()
+            .find(|word| {
+                word.trim_start_matches('v')
+                    .starts_with(|c: char| c.is_ascii_digit())
+            })
+            .ok_or_else(|| format!("No version found in {:?}", s))?
+            .trim_start_matches('v');
+        let core = raw.split(['-', '+']).next().unwrap_or(raw);
+        let mut parts = core.split('.').map(|p| {
+            p.parse::<u32>()
+                .map_err(|e| format!("Invalid version {:?}: {}", s, e))
+        });
+        let major = parts
+            .next()
+            .ok_or_else(|| format!("Invalid version {:?}", s))??;
+        let minor = parts.next().transpose()?.unwrap_or(0);
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        Ok(VmmVersion::new(major, minor, patch))
+    }
+}
+
+/// Optional features of firecracker which are not available in every release
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VmmFeature {
+    /// Version 2 of the MMDS protocol, with session tokens
+    MmdsV2,
+    /// Custom CPU templates through `PUT /cpu-config`
+    CpuConfig,
+    /// virtio-rng entropy device through `PUT /entropy`
+    Entropy,
+    /// `Async` (io_uring) io engine for block devices
+    AsyncIoEngine,
+}
+
+impl VmmFeature {
+    /// First firecracker release which supports the feature
+    pub fn minimum_version(&self) -> VmmVersion {
+        match self {
+            VmmFeature::MmdsV2 => VmmVersion::new(1, 0, 0),
+            VmmFeature::CpuConfig => VmmVersion::new(1, 4, 0),
+            VmmFeature::Entropy => VmmVersion::new(1, 4, 0),
+            VmmFeature::AsyncIoEngine => VmmVersion::new(1, 0, 0),
+        }
+    }
+}
+
+impl fmt::Display for VmmFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VmmFeature::MmdsV2 => "MMDS v2",
+            VmmFeature::CpuConfig => "custom CPU templates",
+            VmmFeature::Entropy => "entropy device",
+            VmmFeature::AsyncIoEngine => "Async io engine",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            "1.3.0".parse::<VmmVersion>().unwrap(),
+            VmmVersion::new(1, 3, 0)
+        );
+        assert_eq!(
+            "Firecracker v1.4.1".parse::<VmmVersion>().unwrap(),
+            VmmVersion::new(1, 4, 1)
+        );
+        assert_eq!(
+            "v1.5.0-dev".parse::<VmmVersion>().unwrap(),
+            VmmVersion::new(1, 5, 0)
+        );
+        assert_eq!(
+            "1.7".parse::<VmmVersion>().unwrap(),
+            VmmVersion::new(1, 7, 0)
+        );
+        assert!("firecracker".parse::<VmmVersion>().is_err());
+        assert!("1.x.0".parse::<VmmVersion>().is_err());
+    }
+
+    #[test]
+    fn test_supports_feature() {
+        let version = VmmVersion::new(1, 3, 0);
+        assert!(version.supports(VmmFeature::MmdsV2));
+        assert!(!version.supports(VmmFeature::CpuConfig));
+        assert!(VmmVersion::new(1, 10, 0).supports(VmmFeature::Entropy));
+    }
+}
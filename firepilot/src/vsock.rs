@@ -0,0 +1,454 @@
+//! # Host side of vsock connections
+//!
+//! Firecracker proxies host-initiated vsock connections through the Unix
+//! socket given as `uds_path` in the vsock configuration: the host connects to
+//! it, writes `CONNECT <port>\n` and firecracker answers `OK <host port>\n`
+//! once the guest accepted the connection. [Machine::vsock_connect] performs
+//! this handshake and returns a [VsockStream] to talk to any guest service.
+//!
+//! ## File transfer protocol
+//!
+//! [Machine::push_file] and [Machine::pull_file] expect a guest service
+//! listening on [FILE_TRANSFER_PORT] which speaks the following line-based
+//! protocol, one transfer per connection:
+//!
+//! - push: the host sends `PUT <size> <remote path>\n`, then `<size>` bytes,
+//!   then the hex SHA-256 of the content followed by `\n`. The guest answers
+//!   `OK\n` once the file is written and the checksum matched.
+//! - pull: the host sends `GET <remote path>\n`, the guest answers
+//!   `OK <size>\n`, then `<size>` bytes, then the hex SHA-256 of the content
+//!   followed by `\n`.
+//!
+//! At any point, the guest can answer `ERR <message>\n` to abort.
+//!
+//! [Machine::vsock_connect]: crate::machine::Machine::vsock_connect
+//! [Machine::push_file]: crate::machine::Machine::push_file
+//! [Machine::pull_file]: crate::machine::Machine::pull_file
+
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::UnixStream,
+};
+use tracing::debug;
+
+use crate::machine::{FirepilotError, IntoFirepilotError};
+
+/// Guest vsock port of the file transfer service
+pub const FILE_TRANSFER_PORT: u32 = 10_240;
+
+/// Size of the chunks in which files are streamed
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest answer expected from firecracker to a `CONNECT` request
+const HANDSHAKE_MAX_LEN: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VsockError {
+    #[error("Could not connect to guest port {0}, reason: {1}")]
+    Connect(u32, String),
+    #[error("Transfer failed, reason: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unexpected answer from the guest: {0}")]
+    Protocol(String),
+    #[error("Checksum mismatch, expected {expected} but got {actual}")]
+    Checksum { expected: String, actual: String },
+    #[error("Invalid remote path {0:?}, it must fit on a single line")]
+    InvalidPath(String),
+}
+
+impl IntoFirepilotError for VsockError {
+    fn into_firepilot_error(self, vm_id: &str) -> FirepilotError {
+        FirepilotError::execute(vm_id, "Vsock exchange with the guest failed").with_source(self)
+    }
+}
+
+/// Connection to a guest vsock port, established through the firecracker
+/// Unix socket
+#[derive(Debug)]
+pub struct VsockStream {
+    inner: UnixStream,
+    port: u32,
+}
+
+impl VsockStream {
+    /// Guest port this stream is connected to
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Underlying Unix socket to firecracker
+    pub fn into_inner(self) -> UnixStream {
+        self.inner
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Open a connection to the given guest port through the vsock Unix socket
+pub async fn connect(uds_path: &Path, port: u32) -> Result<VsockStream, VsockError> {
+    let mut stream = UnixStream::connect(uds_path)
+        .await
+        .map_err(|e| VsockError::Connect(port, e.to_string()))?;
+    stream
+        .write_all(format!("CONNECT {}\n", port).as_bytes())
+        .await?;
+
+    // The guest may send data right after the handshake, so the answer is
+    // read byte by byte to leave it in the stream
+    let mut answer = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        answer.push(byte);
+        if answer.len() > HANDSHAKE_MAX_LEN {
+            return Err(VsockError::Protocol(
+                "handshake answer is too long".to_string(),
+            ));
+        }
+    }
+    let answer = String::from_utf8_lossy(&answer);
+    if !answer.starts_with("OK ") {
+        return Err(VsockError::Connect(port, answer.into_owned()));
+    }
+    debug!("Connected to guest vsock port {}", port);
+    Ok(VsockStream {
+        inner: stream,
+        port,
+    })
+}
+
+async fn read_line(stream: &mut BufReader<UnixStream>) -> Result<String, VsockError> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    if !line.ends_with('\n') {
+        return Err(VsockError::Protocol(format!(
+            "connection closed, received {:?}",
+            line
+        )));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Read an `OK [args]` answer and return its arguments
+async fn read_ok(stream: &mut BufReader<UnixStream>) -> Result<String, VsockError> {
+    let answer = read_line(stream).await?;
+    match answer.split_once(' ') {
+        Some(("OK", args)) => Ok(args.to_string()),
+        _ if answer == "OK" => Ok(String::new()),
+        Some(("ERR", message)) => Err(VsockError::Protocol(message.to_string())),
+        _ => Err(VsockError::Protocol(answer)),
+    }
+}
+
+/// Reject remote paths which would end the request line early
+fn check_remote(remote: &str) -> Result<(), VsockError> {
+    if remote.contains('\n') {
+        return Err(VsockError::InvalidPath(remote.to_string()));
+    }
+    Ok(())
+}
+
+/// Send a local file to the guest file transfer service
+pub(crate) async fn push_file(
+    stream: VsockStream,
+    local: &Path,
+    remote: &str,
+) -> Result<u64, VsockError> {
+    check_remote(remote)?;
+    let mut file = File::open(local).await?;
+    let size = file.metadata().await?.len();
+    let mut stream = BufReader::new(stream.into_inner());
+    stream
+        .get_mut()
+        .write_all(format!("PUT {} {}\n", size, remote).as_bytes())
+        .await?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    while sent < size {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(VsockError::Protocol(format!(
+                "{} was truncated during the transfer",
+                local.display()
+            )));
+        }
+        let chunk = &buffer[..read.min((size - sent) as usize)];
+        hasher.update(chunk);
+        stream.get_mut().write_all(chunk).await?;
+        sent += chunk.len() as u64;
+    }
+    let checksum = format!("{:x}\n", hasher.finalize());
+    stream.get_mut().write_all(checksum.as_bytes()).await?;
+    read_ok(&mut stream).await?;
+    debug!("Pushed {} bytes to {}", size, remote);
+    Ok(size)
+}
+
+/// Fetch a file from the guest file transfer service, the local file is only
+/// replaced once the checksum is verified
+pub(crate) async fn pull_file(
+    stream: VsockStream,
+    remote: &str,
+    local: &Path,
+) -> Result<u64, VsockError> {
+    check_remote(remote)?;
+    let mut stream = BufReader::new(stream.into_inner());
+    stream
+        .get_mut()
+        .write_all(format!("GET {}\n", remote).as_bytes())
+        .await?;
+    let size = read_ok(&mut stream).await?;
+    let size: u64 = size
+        .parse()
+        .map_err(|_| VsockError::Protocol(format!("invalid size {:?}", size)))?;
+
+    let mut partial = local.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = Path::new(&partial);
+    if let Err(e) = receive_file(&mut stream, size, partial, local).await {
+        let _ = tokio::fs::remove_file(partial).await;
+        return Err(e);
+    }
+    debug!("Pulled {} bytes from {}", size, remote);
+    Ok(size)
+}
+
+/// Write `size` bytes from the stream to the partial file, then move it to
+/// `local` once the checksum which follows is verified
+async fn receive_file(
+    stream: &mut BufReader<UnixStream>,
+    size: u64,
+    partial: &Path,
+    local: &Path,
+) -> Result<(), VsockError> {
+    let mut file = File::create(partial).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    while received < size {
+        let wanted = CHUNK_SIZE.min((size - received) as usize);
+        let read = stream.read(&mut buffer[..wanted]).await?;
+        if read == 0 {
+            return Err(VsockError::Protocol(format!(
+                "connection closed after {} of {} bytes",
+                received, size
+            )));
+        }
+        hasher.update(&buffer[..read]);
+        file.write_all(&buffer[..read]).await?;
+        received += read as u64;
+    }
+    file.sync_all().await?;
+
+    let expected = read_line(stream).await?;
+    let actual = format!("{:x}", hasher.finalize());
+    if expected != actual {
+        return Err(VsockError::Checksum { expected, actual });
+    }
+    tokio::fs::rename(partial, local).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    /// Pretend to be firecracker and the guest service: accept the handshake
+    /// and play the given exchange
+    async fn fake_guest(
+        dir: &Path,
+        exchange: Vec<u8>,
+    ) -> (PathBuf, tokio::task::JoinHandle<Vec<u8>>) {
+        let uds = dir.join("vsock.sock");
+        let listener = UnixListener::bind(&uds).unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, format!("CONNECT {}\n", FILE_TRANSFER_PORT));
+            stream
+                .get_mut()
+                .write_all(b"OK 1073741824\n")
+                .await
+                .unwrap();
+            stream.get_mut().write_all(&exchange).await.unwrap();
+            let mut received = Vec::new();
+            let _ = tokio::time::timeout(
+                std::time::Duration::from_millis(200),
+                stream.read_to_end(&mut received),
+            )
+            .await;
+            received
+        });
+        (uds, handle)
+    }
+
+    fn checksum(content: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(content))
+    }
+
+    #[tokio::test]
+    async fn test_push_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("artifact");
+        std::fs::write(&local, b"hello guest").unwrap();
+        let (uds, guest) = fake_guest(dir.path(), b"OK\n".to_vec()).await;
+
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        let sent = push_file(stream, &local, "/tmp/artifact").await.unwrap();
+        assert_eq!(sent, 11);
+
+        let received = String::from_utf8(guest.await.unwrap()).unwrap();
+        assert_eq!(
+            received,
+            format!(
+                "PUT 11 /tmp/artifact\nhello guest{}\n",
+                checksum(b"hello guest")
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("result");
+        let answer = format!("OK 10\nhello host{}\n", checksum(b"hello host"));
+        let (uds, _guest) = fake_guest(dir.path(), answer.into_bytes()).await;
+
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        pull_file(stream, "/tmp/result", &local).await.unwrap();
+        assert_eq!(std::fs::read(&local).unwrap(), b"hello host");
+    }
+
+    #[tokio::test]
+    async fn test_pull_file_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("result");
+        let answer = format!("OK 5\nhello{}\n", checksum(b"world"));
+        let (uds, _guest) = fake_guest(dir.path(), answer.into_bytes()).await;
+
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        let err = pull_file(stream, "/tmp/result", &local).await.unwrap_err();
+        assert!(matches!(err, VsockError::Checksum { .. }));
+        assert!(!local.exists());
+        assert!(!dir.path().join("result.part").exists());
+    }
+
+    #[tokio::test]
+    async fn test_pull_file_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("result");
+        let (uds, _guest) = fake_guest(
+            dir.path(),
+            b"OK 10
+hello"
+                .to_vec(),
+        )
+        .await;
+
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        let err = pull_file(stream, "/tmp/result", &local).await.unwrap_err();
+        assert!(matches!(err, VsockError::Protocol(_)));
+        assert!(!local.exists());
+        assert!(!dir.path().join("result.part").exists());
+    }
+
+    #[tokio::test]
+    async fn test_remote_path_on_several_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("artifact");
+        std::fs::write(&local, b"hello guest").unwrap();
+        let (uds, guest) = fake_guest(dir.path(), Vec::new()).await;
+
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        let err = push_file(stream, &local, "/tmp/a\nGET /etc/shadow")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, VsockError::InvalidPath(_)));
+        assert!(guest.await.unwrap().is_empty());
+
+        std::fs::create_dir(dir.path().join("pull")).unwrap();
+        let (uds, guest) = fake_guest(&dir.path().join("pull"), Vec::new()).await;
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        let err = pull_file(stream, "/etc/shadow\n", &local)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, VsockError::InvalidPath(_)));
+        assert!(guest.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_echo() {
+        let dir = tempfile::tempdir().unwrap();
+        let (uds, guest) = fake_guest(dir.path(), b"pong".to_vec()).await;
+
+        let mut stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        assert_eq!(stream.port(), FILE_TRANSFER_PORT);
+        stream.write_all(b"ping").await.unwrap();
+        let mut answer = [0; 4];
+        stream.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"pong");
+        drop(stream);
+        assert_eq!(guest.await.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_guest_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let answer = b"ERR no such file\n".to_vec();
+        let (uds, _guest) = fake_guest(dir.path(), answer).await;
+
+        let stream = connect(&uds, FILE_TRANSFER_PORT).await.unwrap();
+        let err = pull_file(stream, "/missing", &dir.path().join("missing"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected answer from the guest: no such file"
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! # Machine workspaces
+//!
+//! Each microVM has its own workspace, a directory holding its socket, drives
+//! and kernel. An advisory lock is taken on [LOCK_FILE] in the workspace for
+//! the lifetime of the [Executor](crate::executor::Executor) using it, so two
+//! processes, or two machines of the same process, can't operate on the same
+//! workspace. The lock is released by the kernel when its owner exits, a
+//! workspace which isn't locked anymore was left behind by a dead process.
+//!
+//! ## Recovery
+//!
+//! After a crash of the controller, or of the whole host, [list] finds the
+//! workspaces left under a chroot base and tells whether their microVM is
+//! still running. Running ones can be adopted with
+//! [Machine::from_existing](crate::machine::Machine::from_existing), or
+//! [Machine::reattach](crate::machine::Machine::reattach) from the state they
+//! persisted, see [crate::persist]. The others can be removed.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use firepilot::workspace::{self, WorkspaceStatus};
+//!
+//! for workspace in workspace::list(Path::new("/tmp/firepilot")).unwrap() {
+//!     if workspace.status == WorkspaceStatus::Orphaned {
+//!         workspace.remove().unwrap();
+//!     }
+//! }
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+
+use crate::executor::ExecuteError;
+
+/// Name of the lock file in each workspace, it contains the pid of its owner
+pub const LOCK_FILE: &str = "firepilot.lock";
+
+/// Exclusive lock on a workspace, released when dropped
+#[derive(Debug)]
+pub struct WorkspaceLock {
+    /// Kept open as the lock lives as long as the file description
+    _file: File,
+    path: PathBuf,
+}
+
+impl WorkspaceLock {
+    /// Lock the workspace, failing with [ExecuteError::WorkspaceLocked] if it
+    /// is already in use
+    pub fn acquire(workspace: &Path) -> Result<WorkspaceLock, ExecuteError> {
+        let path = workspace.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| {
+                ExecuteError::WorkspaceCreation(format!("Failed to open {:?}: {}", path, e))
+            })?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => {
+                return Err(ExecuteError::WorkspaceLocked(format!(
+                    "{} is used by another executor",
+                    workspace.display()
+                )))
+            }
+            Err(e) => {
+                return Err(ExecuteError::WorkspaceCreation(format!(
+                    "Failed to lock {:?}: {}",
+                    path, e
+                )))
+            }
+        }
+        // Only informative, the content is ignored when locking
+        file.set_len(0)
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| {
+                ExecuteError::WorkspaceCreation(format!("Failed to write {:?}: {}", path, e))
+            })?;
+        Ok(WorkspaceLock { _file: file, path })
+    }
+
+    /// Path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Whether the workspace is currently locked by a live executor. A workspace
+/// without lock file was never locked and is reported as not locked.
+pub fn is_locked(workspace: &Path) -> bool {
+    match File::open(workspace.join(LOCK_FILE)) {
+        // The probe lock is released when the file is closed
+        Ok(file) => matches!(
+            flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock),
+            Err(Errno::EWOULDBLOCK)
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Name of the API socket of firecracker in each workspace
+pub(crate) const SOCKET_FILE: &str = "firecracker.socket";
+/// Name of the file holding the pid of firecracker in each workspace, for
+/// supervisors and debugging tools. It exists while the process runs.
+pub const PID_FILE: &str = "firecracker.pid";
+/// Name of the configuration file firecracker boots from, in the workspace
+/// of executors which don't configure it through its API, see
+/// [BootMode](crate::executor::BootMode)
+pub const CONFIG_FILE: &str = "vm_config.json";
+
+/// State of a workspace found by [list]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkspaceStatus {
+    /// Locked by a live executor, it must be left alone
+    InUse,
+    /// Not locked but its firecracker process still answers, it can be adopted
+    Running,
+    /// Neither locked nor running, it can be removed
+    Orphaned,
+}
+
+/// Workspace of a microVM found under a chroot base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    /// Id of the microVM, which is the name of the workspace directory
+    pub id: String,
+    pub path: PathBuf,
+    pub status: WorkspaceStatus,
+}
+
+impl Workspace {
+    /// Remove the workspace and everything in it, it is refused unless the
+    /// workspace is [WorkspaceStatus::Orphaned]
+    pub fn remove(&self) -> io::Result<()> {
+        if self.status != WorkspaceStatus::Orphaned || is_locked(&self.path) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Workspace {} is still in use", self.id),
+            ));
+        }
+        if let Some(base) = self.path.parent() {
+            crate::network::ipam::release_all(base, &self.id)?;
+        }
+        std::fs::remove_dir_all(&self.path)
+    }
+}
+
+/// List and classify the workspaces under a chroot base, i.e. the `chroot` of
+/// the executors which created them
+pub fn list(base: &Path) -> io::Result<Vec<Workspace>> {
+    let mut workspaces = Vec::new();
+    for entry in std::fs::read_dir(base)? {
+        let entry = entry?;
+        // Hidden directories hold the state of firepilot, e.g. the leases
+        if !entry.file_type()?.is_dir() || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let status = if is_locked(&path) {
+            WorkspaceStatus::InUse
+        } else if UnixStream::connect(path.join(SOCKET_FILE)).is_ok() {
+            WorkspaceStatus::Running
+        } else {
+            WorkspaceStatus::Orphaned
+        };
+        workspaces.push(Workspace {
+            id: entry.file_name().to_string_lossy().into_owned(),
+            path,
+            status,
+        });
+    }
+    workspaces.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(workspaces)
+}
+
+/// Find the pid of the process started with the given API socket, by looking
+/// at the command line of running processes
+pub(crate) fn find_process(socket: &Path) -> Option<i32> {
+    std::fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .find(|pid| serves(*pid, socket))
+}
+
+/// Whether the process was started with the given API socket, rather than
+/// being another process which reused its pid
+pub(crate) fn serves(pid: i32, socket: &Path) -> bool {
+    let socket = socket.to_string_lossy();
+    std::fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| {
+            cmdline
+                .split(|byte| *byte == 0)
+                .any(|arg| arg == socket.as_bytes())
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ipam::IpPool;
+
+    #[test]
+    fn test_workspace_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!is_locked(dir.path()));
+
+        let lock = WorkspaceLock::acquire(dir.path()).unwrap();
+        assert!(is_locked(dir.path()));
+        assert!(matches!(
+            WorkspaceLock::acquire(dir.path()),
+            Err(ExecuteError::WorkspaceLocked(_))
+        ));
+        let pid = std::fs::read_to_string(lock.path()).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+
+        drop(lock);
+        assert!(!is_locked(dir.path()));
+    }
+
+    #[test]
+    fn test_list_workspaces() {
+        let base = tempfile::tempdir().unwrap();
+        for id in ["vm-a", "vm-b", "vm-c"] {
+            std::fs::create_dir(base.path().join(id)).unwrap();
+        }
+        std::fs::write(base.path().join("not-a-workspace"), "").unwrap();
+        let pool = IpPool::new(base.path(), "172.16.0.0".parse().unwrap(), 24).unwrap();
+        pool.allocate("vm-c").unwrap();
+        let _lock = WorkspaceLock::acquire(&base.path().join("vm-a")).unwrap();
+        let _listener =
+            std::os::unix::net::UnixListener::bind(base.path().join("vm-b").join(SOCKET_FILE))
+                .unwrap();
+
+        let workspaces = list(base.path()).unwrap();
+        let statuses: Vec<_> = workspaces
+            .iter()
+            .map(|workspace| (workspace.id.as_str(), workspace.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("vm-a", WorkspaceStatus::InUse),
+                ("vm-b", WorkspaceStatus::Running),
+                ("vm-c", WorkspaceStatus::Orphaned),
+            ]
+        );
+        assert!(workspaces[0].remove().is_err());
+        workspaces[2].remove().unwrap();
+        assert!(!workspaces[2].path.exists());
+        assert_eq!(pool.lease("vm-c").unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_process() {
+        let mut child = std::process::Command::new("/bin/sleep")
+            .arg("30.5")
+            .spawn()
+            .unwrap();
+        // The command line is only visible once the child called exec
+        let mut pid = None;
+        for _ in 0..20 {
+            pid = find_process(Path::new("30.5"));
+            if pid.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert_eq!(pid, Some(child.id() as i32));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 #!/bin/bash
 
-wget https://raw.githubusercontent.com/firecracker-microvm/firecracker/main/src/api_server/swagger/firecracker.yaml -O firepilot_models/firecracker.yaml
+# Release of firecracker the models are generated from, e.g. v1.7.0. Fields
+# added since 1.3 must be gated behind the matching cargo feature by hand.
+VERSION=${1:-main}
+
+wget https://raw.githubusercontent.com/firecracker-microvm/firecracker/$VERSION/src/api_server/swagger/firecracker.yaml -O firepilot_models/firecracker.yaml
 docker run --rm \
     -u $(id -u):$(id -g) \
     -v $PWD/firepilot_models:/local openapitools/openapi-generator-cli generate \
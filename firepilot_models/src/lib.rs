@@ -1,3 +1,12 @@
+// Lints fired by the generated models, fix the templates or the upstream
+// OpenAPI file instead of the generated code
+#![allow(
+    clippy::derivable_impls,
+    clippy::empty_docs,
+    clippy::empty_line_after_doc_comments,
+    clippy::to_string_trait_impl
+)]
+
 #[macro_use]
 extern crate serde_derive;
 
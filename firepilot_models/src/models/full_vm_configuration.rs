@@ -30,6 +30,10 @@ pub struct FullVmConfiguration {
     pub network_interfaces: Option<Vec<crate::models::NetworkInterface>>,
     #[serde(rename = "vsock", skip_serializing_if = "Option::is_none")]
     pub vsock: Option<Box<crate::models::Vsock>>,
+    /// Entropy device, since firecracker 1.4.
+    #[cfg(feature = "firecracker-1-4")]
+    #[serde(rename = "entropy", skip_serializing_if = "Option::is_none")]
+    pub entropy: Option<Box<crate::models::EntropyDevice>>,
 }
 
 impl FullVmConfiguration {
@@ -44,6 +48,8 @@ impl FullVmConfiguration {
             mmds_config: None,
             network_interfaces: None,
             vsock: None,
+            #[cfg(feature = "firecracker-1-4")]
+            entropy: None,
         }
     }
 }
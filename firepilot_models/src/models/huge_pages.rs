@@ -0,0 +1,36 @@
+/*
+ * Firecracker API
+ *
+ * RESTful public-facing API. The API is accessible through HTTP calls on specific URLs carrying JSON modeled data. The transport medium is a Unix Domain Socket.
+ *
+ * The version of the OpenAPI document: 1.7.0
+ * Contact: compute-capsule@amazon.com
+ * Written after the OpenAPI document of firecracker v1.7.0, as generate.sh
+ * couldn't be run for it: replace it with the generated file
+ */
+
+/// HugePages : Which huge pages configuration (if any) should be used to back guest memory.
+
+/// Which huge pages configuration (if any) should be used to back guest memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum HugePages {
+    #[serde(rename = "None")]
+    None,
+    #[serde(rename = "2M")]
+    Variant2M,
+}
+
+impl ToString for HugePages {
+    fn to_string(&self) -> String {
+        match self {
+            Self::None => String::from("None"),
+            Self::Variant2M => String::from("2M"),
+        }
+    }
+}
+
+impl Default for HugePages {
+    fn default() -> HugePages {
+        Self::None
+    }
+}
@@ -26,6 +26,10 @@ pub struct MachineConfiguration {
     /// Number of vCPUs (either 1 or an even number)
     #[serde(rename = "vcpu_count")]
     pub vcpu_count: i32,
+    /// Which huge pages configuration (if any) should be used to back guest memory, since firecracker 1.7.
+    #[cfg(feature = "firecracker-1-7")]
+    #[serde(rename = "huge_pages", skip_serializing_if = "Option::is_none")]
+    pub huge_pages: Option<crate::models::HugePages>,
 }
 
 impl MachineConfiguration {
@@ -37,6 +41,8 @@ impl MachineConfiguration {
             mem_size_mib,
             track_dirty_pages: None,
             vcpu_count,
+            #[cfg(feature = "firecracker-1-7")]
+            huge_pages: None,
         }
     }
 }
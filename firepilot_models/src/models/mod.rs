@@ -12,12 +12,20 @@ pub mod cpu_template;
 pub use self::cpu_template::CpuTemplate;
 pub mod drive;
 pub use self::drive::Drive;
+#[cfg(feature = "firecracker-1-4")]
+pub mod entropy_device;
+#[cfg(feature = "firecracker-1-4")]
+pub use self::entropy_device::EntropyDevice;
 pub mod error;
 pub use self::error::Error;
 pub mod firecracker_version;
 pub use self::firecracker_version::FirecrackerVersion;
 pub mod full_vm_configuration;
 pub use self::full_vm_configuration::FullVmConfiguration;
+#[cfg(feature = "firecracker-1-7")]
+pub mod huge_pages;
+#[cfg(feature = "firecracker-1-7")]
+pub use self::huge_pages::HugePages;
 pub mod instance_action_info;
 pub use self::instance_action_info::InstanceActionInfo;
 pub mod instance_info;